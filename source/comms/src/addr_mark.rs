@@ -0,0 +1,190 @@
+//! Address-mark framing, for UARTs with 9-bit mode and address matching
+//!
+//! By default, frames are only delimited by a line break, and every node
+//! receives, and wakes up for, every frame on the bus. Many MCUs, like the
+//! STM32 family, also support a 9-bit "multiprocessor" mode, where the 9th
+//! bit of each word marks it as an address. Their UARTs can stay muted until
+//! an address-marked word matching the node's own address is received.
+//!
+//! In address-mark framing, each frame is sent as 9-bit words:
+//!
+//! * The [CmdAddr] byte is sent with the [ADDR_MARK] bit set
+//! * All following bytes of the frame are sent with the [ADDR_MARK] bit clear
+//!
+//! See [to_words()]. The end of a frame is still detected by the backend,
+//! with a line break, or an idle line. Frames are unchanged at the
+//! [FrameSerial] level, so the Controller and Target logic is the same in both
+//! framing modes.
+//!
+//! The Target tells its serial port which frames it is interested in with
+//! [FrameSerial::set_addr_filter()], which is called whenever that changes. A
+//! backend can use the [AddrFilter] to configure its hardware address match,
+//! see [AddrFilter::match_byte()]. Backends without address matching simply
+//! ignore the filter, which is the default.
+//!
+//! [AddrMatch] is a software reference implementation, which filters the
+//! frames of any [FrameSerial] after receiving them.
+
+use embassy_time::Duration;
+
+use crate::{blocking::BlockingFrameSerial, CmdAddr, Error, FrameSerial, TimedFrame};
+
+/// The 9th bit of a word, marking it as a [CmdAddr] byte
+pub const ADDR_MARK: u16 = 1 << 8;
+
+/// The 9-bit words of `frame`, as sent in address-mark framing
+pub fn to_words(frame: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    frame.iter().enumerate().map(|(i, b)| {
+        if i == 0 {
+            ADDR_MARK | u16::from(*b)
+        } else {
+            u16::from(*b)
+        }
+    })
+}
+
+/// The frames a node wants to receive, by their [CmdAddr] byte
+///
+/// A backend may pass on frames the filter does not accept, but must never
+/// drop frames that it does accept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AddrFilter {
+    /// Receive every frame. This is the initial filter of every backend, and
+    /// is used by the Controller
+    #[default]
+    Any,
+    /// An unaddressed Target, receiving all [CmdAddr::DiscoveryOffer]s
+    Discovery,
+    /// A Target that has claimed the given address, waiting for its
+    /// [CmdAddr::DiscoverySuccess]
+    Claiming(u8),
+    /// A Target that has joined with the given address, receiving
+    /// [CmdAddr::SelectAddr] for that address
+    Joined(u8),
+}
+
+impl AddrFilter {
+    /// Is a frame starting with `cmd_addr` accepted by this filter?
+    pub fn accepts(&self, cmd_addr: u8) -> bool {
+        match (self, CmdAddr::try_from(cmd_addr)) {
+            (AddrFilter::Any, _) => true,
+            (AddrFilter::Discovery, Ok(CmdAddr::DiscoveryOffer(_))) => true,
+            (AddrFilter::Claiming(ours), Ok(CmdAddr::DiscoverySuccess(addr))) => *ours == addr,
+            (AddrFilter::Joined(ours), Ok(CmdAddr::SelectAddr(addr))) => *ours == addr,
+            _ => false,
+        }
+    }
+
+    /// The only [CmdAddr] byte accepted by this filter, if there is just one
+    ///
+    /// This is the address to program into a hardware address match. If
+    /// `None`, the backend must wake up for every address-marked word, and may
+    /// check it with [AddrFilter::accepts()].
+    pub fn match_byte(&self) -> Option<u8> {
+        match *self {
+            AddrFilter::Any | AddrFilter::Discovery => None,
+            AddrFilter::Claiming(addr) => Some(CmdAddr::DiscoverySuccess(addr).into()),
+            AddrFilter::Joined(addr) => Some(CmdAddr::SelectAddr(addr).into()),
+        }
+    }
+}
+
+/// A software address match, for any [FrameSerial]
+///
+/// Received frames that are not accepted by the current [AddrFilter] are
+/// dropped, as a UART with a hardware address match would. This doesn't save
+/// any wakeups, but behaves the same on the bus.
+pub struct AddrMatch<T> {
+    serial: T,
+    filter: AddrFilter,
+    muted: u32,
+}
+
+impl<T> AddrMatch<T> {
+    /// Wrap `serial`, initially receiving every frame
+    pub const fn new(serial: T) -> Self {
+        Self {
+            serial,
+            filter: AddrFilter::Any,
+            muted: 0,
+        }
+    }
+
+    /// The current filter
+    pub fn filter(&self) -> AddrFilter {
+        self.filter
+    }
+
+    /// The number of received frames dropped by the filter
+    pub fn muted(&self) -> u32 {
+        self.muted
+    }
+
+    /// Consume the wrapper, returning the serial port
+    pub fn into_inner(self) -> T {
+        self.serial
+    }
+
+    /// Is the received frame accepted? If not, count it.
+    fn check(&mut self, frame: &[u8]) -> bool {
+        // Empty frames can't be address-marked, let the node discard them
+        let accepted = frame.first().is_none_or(|b| self.filter.accepts(*b));
+        if !accepted {
+            self.muted = self.muted.wrapping_add(1);
+        }
+        accepted
+    }
+}
+
+impl<T: FrameSerial> FrameSerial for AddrMatch<T> {
+    type SerError = T::SerError;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.serial.send_frame(data).await
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        loop {
+            let tf = self.serial.recv(&mut *frame).await?;
+            let (end_of_rx, len) = (tf.end_of_rx, tf.frame.len());
+            if self.check(&frame[..len]) {
+                return Ok(TimedFrame {
+                    end_of_rx,
+                    frame: &mut frame[..len],
+                });
+            }
+        }
+    }
+
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        self.filter = filter;
+    }
+}
+
+impl<T: BlockingFrameSerial> BlockingFrameSerial for AddrMatch<T> {
+    type SerError = T::SerError;
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.serial.send_frame(frame)
+    }
+
+    /// Receive like the wrapped serial port. A dropped frame counts as
+    /// nothing being received, without waiting for the rest of `timeout`.
+    fn recv_timeout<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<TimedFrame<'a>>, Error<Self::SerError>> {
+        match self.serial.recv_timeout(frame, timeout)? {
+            Some(tf) if !self.check(tf.frame) => Ok(None),
+            got => Ok(got),
+        }
+    }
+
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        self.filter = filter;
+    }
+}
@@ -0,0 +1,276 @@
+//! Blocking interfaces, for use without an async executor
+//!
+//! Some nodes run a bare metal "superloop", or only talk to the bus from
+//! interrupts. For these, this module provides:
+//!
+//! * [BlockingFrameSerial], a blocking counterpart of [FrameSerial][crate::FrameSerial],
+//!   which measures its own receive timeouts
+//! * [Controller::step_blocking()][crate::Controller::step_blocking], a
+//!   blocking counterpart of [Controller::step()][crate::Controller::step]
+//! * [BlockingTarget], a poll-driven counterpart of [Target][crate::target::Target]
+//!
+//! Both use the same protocol code as their async counterparts, and behave
+//! the same on the bus.
+
+use embassy_sync::{
+    blocking_mutex::raw::RawMutex,
+    channel::{Receiver, Sender},
+};
+use embassy_time::{Duration, Instant};
+use rand_core::RngCore;
+
+use crate::{
+    addr_mark::AddrFilter,
+    clock::Clock,
+    frame_pool::{FrameBox, RawFrameSlice},
+    target::{Action, TargetState, TargetTiming, INCOMING_SIZE, OUTGOING_SIZE},
+    CmdAddr, Error, Mac, TimedFrame,
+};
+
+/// A trait representing the communication interface of the RS-485 bus,
+/// for blocking use
+pub trait BlockingFrameSerial {
+    /// The error type of the underlying serial port
+    type SerError;
+
+    /// Send a single frame, blocking until it has been sent.
+    ///
+    /// Implementors are responsible for sending the line break at the end of
+    /// the frame.
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>>;
+
+    /// Receive a single frame, blocking for up to `timeout`.
+    ///
+    /// Returns `Ok(None)` if no complete frame was received within `timeout`.
+    /// A zero `timeout` only checks for a frame that has already been received.
+    fn recv_timeout<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<TimedFrame<'a>>, Error<Self::SerError>>;
+
+    /// Only wake up for frames accepted by `filter`, if supported
+    ///
+    /// See [FrameSerial::set_addr_filter()][crate::FrameSerial::set_addr_filter].
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        let _ = filter;
+    }
+}
+
+/// Metadata trait to contain relevant generics of a [BlockingTarget]
+pub trait BlockingTgtCfg {
+    /// Mutex type used for channels
+    type Mutex: RawMutex + 'static;
+
+    /// Serial interface type
+    type Serial: BlockingFrameSerial;
+
+    /// Random number generator
+    type Rand: RngCore;
+
+    /// Clock used to check the current time. Only [Clock::now()] is used.
+    type Clock: Clock;
+
+    /// Amount of time to delay from hearing a response to
+    /// sending a reply.
+    const TURNAROUND_DELAY: Duration;
+
+    /// Amount of time from initiating a claim to getting an address
+    const ADDRESS_CLAIM_TIMEOUT: Duration;
+
+    /// Amount of time being unaddressed before trying to get a new
+    /// address
+    const SELECT_TIMEOUT: Duration;
+}
+
+/// A poll-driven interface for the Target
+///
+/// Like the async [Target][crate::target::Target], frames are exchanged with
+/// the application over a pair of [`Channel`][embassy_sync::channel::Channel]s,
+/// which are only ever used without waiting. A frame from the Controller is
+/// dropped if the application's channel is full.
+///
+/// [BlockingTarget::poll()] must be called regularly, for example in every
+/// iteration of a superloop, or from a UART interrupt whenever a frame has
+/// been received. Replies are sent [TURNAROUND_DELAY][BlockingTgtCfg::TURNAROUND_DELAY]
+/// after the frame they answer, by a later call to `poll`, so an interrupt
+/// driven application should also call `poll` at [BlockingTarget::next_deadline()].
+pub struct BlockingTarget<
+    'a,
+    Cfg,
+    const IN: usize = INCOMING_SIZE,
+    const OUT: usize = OUTGOING_SIZE,
+> where
+    Cfg: BlockingTgtCfg,
+{
+    serial: Cfg::Serial,
+    to_app: Sender<'a, Cfg::Mutex, FrameBox, IN>,
+    from_app: Receiver<'a, Cfg::Mutex, FrameBox, OUT>,
+    pool: RawFrameSlice,
+    rand: Cfg::Rand,
+    state: TargetState,
+    /// Our address, and when to reply, after being selected
+    reply: Option<(u8, Instant)>,
+}
+
+impl<'a, Cfg, const IN: usize, const OUT: usize> BlockingTarget<'a, Cfg, IN, OUT>
+where
+    Cfg: BlockingTgtCfg,
+{
+    /// Create a new [BlockingTarget].
+    pub fn new(
+        serial: Cfg::Serial,
+        to_app: Sender<'a, Cfg::Mutex, FrameBox, IN>,
+        from_app: Receiver<'a, Cfg::Mutex, FrameBox, OUT>,
+        pool: RawFrameSlice,
+        mac: Mac,
+        rand: Cfg::Rand,
+    ) -> Self {
+        Self {
+            serial,
+            to_app,
+            from_app,
+            pool,
+            rand,
+            state: TargetState::new(
+                mac,
+                TargetTiming {
+                    turnaround_delay: Cfg::TURNAROUND_DELAY,
+                    address_claim_timeout: Cfg::ADDRESS_CLAIM_TIMEOUT,
+                    select_timeout: Cfg::SELECT_TIMEOUT,
+                },
+            ),
+            reply: None,
+        }
+    }
+
+    /// Our logical address, if we have joined the bus
+    pub fn addr(&self) -> Option<u8> {
+        self.state.addr()
+    }
+
+    /// The next time [BlockingTarget::poll()] has something to do, even if no
+    /// frame is received: sending a reply, or giving up on our address
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match (self.reply, self.state.deadline()) {
+            (Some((_, at)), Some(d)) => Some(at.min(d)),
+            (Some((_, at)), None) => Some(at),
+            (None, d) => d,
+        }
+    }
+
+    /// Send any reply that is due, then wait up to `timeout` for a frame and
+    /// act on it
+    ///
+    /// The wait is cut short at [BlockingTarget::next_deadline()], so that
+    /// replies are never late. At most one frame is received per call.
+    ///
+    /// After a serial error, the Target gives up its address if needed, and the
+    /// error is returned. Polling can continue as normal.
+    pub fn poll(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(), Error<<Cfg::Serial as BlockingFrameSerial>::SerError>> {
+        self.send_reply()?;
+
+        let now = Cfg::Clock::now();
+        if self.state.check_timeout(now) {
+            self.reply = None;
+        }
+        let until = now.checked_add(timeout).unwrap_or(Instant::MAX);
+        let until = self.next_deadline().map_or(until, |d| d.min(until));
+        let timeout = until.saturating_duration_since(now);
+
+        self.recv_one(timeout)?;
+        self.send_reply()
+    }
+
+    /// Receive up to one frame, and act on it
+    fn recv_one(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(), Error<<Cfg::Serial as BlockingFrameSerial>::SerError>> {
+        // Frames for the application need a pool frame, the discovery messages
+        // fit in 1 + 8 bytes, plus one for the line break
+        let mut scratch = [0u8; 16];
+        let mut pooled = None;
+        self.serial.set_addr_filter(self.state.addr_filter());
+        let buf = match self.state.addr() {
+            Some(_) => {
+                let Some(frame) = self.pool.allocate_raw() else {
+                    nut_warn!("Couldn't alloc incoming!");
+                    self.state.reset();
+                    return Ok(());
+                };
+                &mut pooled.insert(frame)[..]
+            }
+            None => &mut scratch[..],
+        };
+
+        let (action, len) = match self.serial.recv_timeout(buf, timeout) {
+            Ok(Some(tf)) => (
+                self.state
+                    .handle_frame(tf.end_of_rx, tf.frame, &mut self.rand),
+                tf.frame.len(),
+            ),
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                self.state.recv_failed();
+                return Err(e);
+            }
+        };
+
+        let res = match action {
+            Action::Ignore => Ok(()),
+            Action::Claim(claim) => self.serial.send_frame(&claim),
+            Action::Ack(addr) => self
+                .serial
+                .send_frame(&[CmdAddr::ReplyFromAddr(addr).into()]),
+            Action::Reply {
+                addr,
+                reply_at,
+                deliver,
+            } => {
+                // Pass on the frame if we got one
+                if let (true, Some(mut frame)) = (deliver, pooled) {
+                    frame.set_len(len);
+                    if self.to_app.try_send(frame).is_err() {
+                        nut_warn!("Application queue full, dropping frame");
+                    }
+                }
+                self.reply = Some((addr, reply_at));
+                Ok(())
+            }
+        };
+        if res.is_err() {
+            self.state.reset();
+        }
+        res
+    }
+
+    /// Send our reply, if it is due
+    fn send_reply(&mut self) -> Result<(), Error<<Cfg::Serial as BlockingFrameSerial>::SerError>> {
+        let Some((addr, at)) = self.reply else {
+            return Ok(());
+        };
+        if Cfg::Clock::now() < at {
+            return Ok(());
+        }
+        self.reply = None;
+
+        // Is there something to send now? If not, empty-ack.
+        let mut tx_frame = self.from_app.try_receive().ok();
+        let mut fallback = [0u8; 1];
+        let out = match tx_frame.as_deref_mut() {
+            Some(g) => g,
+            None => fallback.as_mut_slice(),
+        };
+        out[0] = CmdAddr::ReplyFromAddr(addr).into();
+
+        let res = self.serial.send_frame(out);
+        if res.is_err() {
+            self.state.reset();
+        }
+        res
+    }
+}
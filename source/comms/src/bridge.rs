@@ -0,0 +1,442 @@
+//! A bridge between a host PC and the [Controller]
+//!
+//! The canonical deployment of a [Controller] is as a bridge or router, with
+//! a host PC attached over USB or a serial port. This module defines a
+//! postcard-rpc based protocol for the host link, and a [run()] function that
+//! serves it over any byte stream implementing [embedded_io_async]'s `Read`
+//! and `Write` traits.
+//!
+//! ## Host link framing
+//!
+//! Each message on the host link is a postcard-rpc [WireHeader][postcard_rpc::WireHeader]
+//! followed by the postcard serialized body, and is COBS encoded, terminated
+//! by a single `0x00` byte.
+//!
+//! ## Protocol
+//!
+//! The host may send the following requests. Each response uses the `seq_no`
+//! of the request it answers:
+//!
+//! * [ListPeersEndpoint] - get the MAC addresses of all connected Targets
+//! * [SendToEndpoint] - send a frame to the Target with the given MAC address
+//! * [StatsEndpoint] - get the [BridgeStats] of the bridge
+//!
+//! Requests with an unknown key, or that cannot be deserialized, are answered
+//! with a [WireError], sent with the [ERROR_KEY].
+//!
+//! The bridge also sends the following messages to the host, unprompted:
+//!
+//! * [FrameReceivedTopic] - a frame received from a Target
+//! * [TopologyTopic] - a Target has joined or left the bus
+//!
+//! Frames received from Targets are forwarded to the host as they arrive, so
+//! the host does not need to poll each Target for them.
+//!
+//! ## The bridge owns received frames
+//!
+//! The bridge takes EVERY frame received from a Target with
+//! [Controller::recv_from()], so that the host sees all of them. While [run()]
+//! is serving the host link, the application must not receive frames from the
+//! same [Controller] in any other way: any call to [Controller::recv_from()],
+//! [Controller::request()], or a [Subscription][crate::controller::Subscription]
+//! races with the bridge, and each frame ends up in only one of them. Requests
+//! to Targets should be made by the host instead, which matches responses to
+//! requests itself.
+
+use core::{cell::Cell, convert::Infallible, pin::pin};
+
+use crate::{
+    clock::Clock,
+    controller::{AddrState, Controller, SendError, INCOMING_SIZE, OUTGOING_SIZE},
+    frame_pool::{RawFrameSlice, SendFrameBox, DEFAULT_FRAME_SIZE},
+    wirehelp::{WireError, ERROR_KEY},
+    Mac, MAX_TARGETS,
+};
+use embassy_sync::{
+    blocking_mutex::raw::{NoopRawMutex, RawMutex},
+    mutex::Mutex,
+};
+use embassy_time::Duration;
+use embedded_io_async::{Read, Write};
+use futures::future::{select, Either};
+use postcard::experimental::schema::Schema;
+use postcard_rpc::{headered::extract_header_from_bytes, Endpoint, Key, Topic};
+use serde::{Deserialize, Serialize};
+
+/// The maximum payload of a frame sent to or received from a Target
+///
+/// This is one less than [DEFAULT_FRAME_SIZE], as the first byte of each
+/// frame is used for the address.
+pub const MAX_PAYLOAD: usize = DEFAULT_FRAME_SIZE - 1;
+
+/// The maximum size of a single COBS encoded message on the host link
+pub const MAX_HOST_MSG: usize = 512;
+
+/// The payload of a frame sent to or received from a Target
+pub type FrameData = heapless::Vec<u8, MAX_PAYLOAD>;
+
+/// The MAC addresses of all connected Targets
+pub type PeerList = heapless::Vec<Mac, { MAX_TARGETS + 1 }>;
+
+/// A frame received from a Target
+pub use self::proto::FrameReceivedTopic;
+/// Get the MAC addresses of all connected Targets
+pub use self::proto::ListPeersEndpoint;
+/// Send a frame to the Target with the given MAC address
+pub use self::proto::SendToEndpoint;
+/// Get the [BridgeStats] of the bridge
+pub use self::proto::StatsEndpoint;
+/// A Target has joined or left the bus
+pub use self::proto::TopologyTopic;
+
+// The `endpoint!` and `topic!` macros don't accept doc comments, these are
+// documented on the re-exports above.
+mod proto {
+    #![allow(missing_docs)]
+
+    use super::{BridgeError, BridgeStats, PeerList, Received, SendTo, TopologyEvent};
+    use postcard_rpc::{endpoint, topic};
+
+    endpoint!(ListPeersEndpoint, (), PeerList, "erdnuss/bridge/peers");
+    endpoint!(
+        SendToEndpoint,
+        SendTo,
+        Result<(), BridgeError>,
+        "erdnuss/bridge/send"
+    );
+    endpoint!(StatsEndpoint, (), BridgeStats, "erdnuss/bridge/stats");
+    topic!(FrameReceivedTopic, Received, "erdnuss/bridge/received");
+    topic!(TopologyTopic, TopologyEvent, "erdnuss/bridge/topology");
+}
+
+/// A request to send a frame to a Target
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema)]
+pub struct SendTo {
+    /// The MAC address of the Target
+    pub mac: Mac,
+    /// The payload of the frame
+    pub data: FrameData,
+}
+
+/// A frame received from a Target
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema)]
+pub struct Received {
+    /// The MAC address of the Target that sent the frame
+    pub mac: Mac,
+    /// The payload of the frame
+    pub data: FrameData,
+}
+
+/// A change in the set of connected Targets
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema)]
+pub enum TopologyEvent {
+    /// A Target with the given MAC address has joined the bus
+    Joined(Mac),
+    /// A Target with the given MAC address has left the bus
+    Left(Mac),
+}
+
+/// An error when sending a frame to a Target on behalf of the host
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema)]
+pub enum BridgeError {
+    /// No Target with the given MAC address is connected
+    NoMatchingMac,
+    /// The outgoing queue for this Target is full
+    QueueFull,
+    /// No frames were available to hold the message
+    NoFrames,
+}
+
+/// Statistics of a running bridge
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct BridgeStats {
+    /// Number of frames sent to Targets
+    pub frames_to_targets: u32,
+    /// Number of frames received from Targets
+    pub frames_from_targets: u32,
+    /// Number of [SendToEndpoint] requests that failed
+    pub send_errors: u32,
+    /// Number of messages from the host that could not be decoded
+    pub host_errors: u32,
+    /// Number of frames in the bridge's frame pool
+    pub pool_capacity: u32,
+    /// Number of frames in the bridge's frame pool currently in use
+    pub pool_in_use: u32,
+}
+
+/// An error on the host link, which ends the bridge
+#[derive(Debug, PartialEq)]
+pub enum BridgeIoError<RE, WE> {
+    /// Reading from the host failed
+    Read(RE),
+    /// Writing to the host failed
+    Write(WE),
+}
+
+#[derive(Default)]
+struct Counters {
+    to_targets: Cell<u32>,
+    from_targets: Cell<u32>,
+    send_errors: Cell<u32>,
+    host_errors: Cell<u32>,
+    seq_no: Cell<u32>,
+}
+
+impl Counters {
+    fn incr(c: &Cell<u32>) {
+        c.set(c.get().wrapping_add(1));
+    }
+}
+
+/// Serve the host link, until the host closes it or an I/O error occurs
+///
+/// Requests from the host are read from `rx`, and responses and topic messages
+/// are written to `tx`. Frames sent to Targets are allocated from `pool`.
+///
+/// Every `poll_interval`, the bridge checks the [Controller] for Targets that
+/// have joined or left the bus, and for frames received from Targets. The
+/// bridge must be the only consumer of received frames, see the
+/// [module documentation][self].
+///
+/// The [Controller] must be stepped separately, by the application.
+///
+/// Returns `Ok(())` if `rx` reaches the end of the stream.
+pub async fn run<R, C, Rx, Tx>(
+    ctl: &Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C>,
+    pool: &mut RawFrameSlice,
+    mut rx: Rx,
+    tx: Tx,
+    poll_interval: Duration,
+) -> Result<(), BridgeIoError<Rx::Error, Tx::Error>>
+where
+    R: RawMutex + 'static,
+    C: Clock,
+    Rx: Read,
+    Tx: Write,
+{
+    let tx = Mutex::<NoopRawMutex, Tx>::new(tx);
+    let ctrs = Counters::default();
+
+    let upstream = pin!(host_to_bus(ctl, pool, &mut rx, &tx, &ctrs));
+    let downstream = pin!(bus_to_host(ctl, &tx, &ctrs, poll_interval));
+
+    match select(upstream, downstream).await {
+        Either::Left((res, _)) => res,
+        Either::Right((res, _)) => match res {
+            Ok(never) => match never {},
+            Err(e) => Err(BridgeIoError::Write(e)),
+        },
+    }
+}
+
+/// Handle requests from the host, until the host link is closed
+async fn host_to_bus<R, C, Rx, Tx>(
+    ctl: &Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C>,
+    pool: &mut RawFrameSlice,
+    rx: &mut Rx,
+    tx: &Mutex<NoopRawMutex, Tx>,
+    ctrs: &Counters,
+) -> Result<(), BridgeIoError<Rx::Error, Tx::Error>>
+where
+    R: RawMutex + 'static,
+    C: Clock,
+    Rx: Read,
+    Tx: Write,
+{
+    let mut msg = [0u8; MAX_HOST_MSG];
+    let mut used = 0;
+    let mut overflow = false;
+    let mut chunk = [0u8; 64];
+
+    loop {
+        let ct = rx.read(&mut chunk).await.map_err(BridgeIoError::Read)?;
+        if ct == 0 {
+            return Ok(());
+        }
+        for &b in &chunk[..ct] {
+            if b != 0 {
+                match msg.get_mut(used) {
+                    Some(slot) => {
+                        *slot = b;
+                        used += 1;
+                    }
+                    None => overflow = true,
+                }
+                continue;
+            }
+
+            // End of a message
+            let len = core::mem::take(&mut used);
+            if core::mem::take(&mut overflow) || len == 0 {
+                nut_warn!("Discarding oversized or empty host message");
+                Counters::incr(&ctrs.host_errors);
+                continue;
+            }
+            let Ok(len) = cobs::decode_in_place(&mut msg[..len]) else {
+                nut_warn!("Discarding malformed host message");
+                Counters::incr(&ctrs.host_errors);
+                continue;
+            };
+            handle_request(ctl, pool, &msg[..len], tx, ctrs)
+                .await
+                .map_err(BridgeIoError::Write)?;
+        }
+    }
+}
+
+/// Handle a single decoded request from the host
+async fn handle_request<R, C, Tx>(
+    ctl: &Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C>,
+    pool: &mut RawFrameSlice,
+    msg: &[u8],
+    tx: &Mutex<NoopRawMutex, Tx>,
+    ctrs: &Counters,
+) -> Result<(), Tx::Error>
+where
+    R: RawMutex + 'static,
+    C: Clock,
+    Tx: Write,
+{
+    let Ok((wh, body)) = extract_header_from_bytes(msg) else {
+        nut_warn!("Discarding host message without a header");
+        Counters::incr(&ctrs.host_errors);
+        return Ok(());
+    };
+    let seq_no = wh.seq_no;
+
+    if wh.key == ListPeersEndpoint::REQ_KEY {
+        let peers = ctl.connected().await;
+        write_msg(tx, ListPeersEndpoint::RESP_KEY, seq_no, &peers).await
+    } else if wh.key == StatsEndpoint::REQ_KEY {
+        let pool_stats = pool.stats();
+        let stats = BridgeStats {
+            frames_to_targets: ctrs.to_targets.get(),
+            frames_from_targets: ctrs.from_targets.get(),
+            send_errors: ctrs.send_errors.get(),
+            host_errors: ctrs.host_errors.get(),
+            pool_capacity: pool_stats.capacity as u32,
+            pool_in_use: pool_stats.in_use as u32,
+        };
+        write_msg(tx, StatsEndpoint::RESP_KEY, seq_no, &stats).await
+    } else if wh.key == SendToEndpoint::REQ_KEY {
+        let Ok(req) = postcard::from_bytes::<SendTo>(body) else {
+            Counters::incr(&ctrs.host_errors);
+            return write_msg(tx, ERROR_KEY, seq_no, &WireError::DeserFailed).await;
+        };
+        let res = send_to(ctl, pool, &req).await;
+        match res {
+            Ok(()) => Counters::incr(&ctrs.to_targets),
+            Err(_) => Counters::incr(&ctrs.send_errors),
+        }
+        write_msg(tx, SendToEndpoint::RESP_KEY, seq_no, &res).await
+    } else {
+        Counters::incr(&ctrs.host_errors);
+        let err = WireError::UnknownKey(wh.key.to_bytes());
+        write_msg(tx, ERROR_KEY, seq_no, &err).await
+    }
+}
+
+/// Copy a frame from the host into a [SendFrameBox], and enqueue it for sending
+async fn send_to<R: RawMutex + 'static, C: Clock>(
+    ctl: &Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C>,
+    pool: &mut RawFrameSlice,
+    req: &SendTo,
+) -> Result<(), BridgeError> {
+    let fb = pool.allocate_raw().ok_or(BridgeError::NoFrames)?;
+    let mut sfb = SendFrameBox::from(fb);
+    sfb.set_len(req.data.len());
+    sfb.payload_mut().copy_from_slice(&req.data);
+    ctl.send(req.mac, sfb).await.map_err(|e| match e {
+        SendError::NoMatchingMac => BridgeError::NoMatchingMac,
+        SendError::QueueFull(_) => BridgeError::QueueFull,
+    })
+}
+
+/// Forward topology changes and received frames to the host, forever
+async fn bus_to_host<R, C, Tx>(
+    ctl: &Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C>,
+    tx: &Mutex<NoopRawMutex, Tx>,
+    ctrs: &Counters,
+    poll_interval: Duration,
+) -> Result<Infallible, Tx::Error>
+where
+    R: RawMutex + 'static,
+    C: Clock,
+    Tx: Write,
+{
+    let mut known = [AddrState::default(); MAX_TARGETS];
+    loop {
+        let now = ctl.topology().await;
+
+        // Compare join counts rather than MACs, so a Target that left and
+        // rejoined since the last poll is still reported as both. All Lefts
+        // are sent before any Joined, in case a Target changed address.
+        for (old, new) in known.iter().zip(now.iter()) {
+            if let Some(mac) = old.mac {
+                if new.mac.is_none() || new.joins != old.joins {
+                    send_topic::<TopologyTopic, _>(tx, ctrs, &TopologyEvent::Left(mac)).await?;
+                }
+            }
+        }
+        for (old, new) in known.iter().zip(now.iter()) {
+            if let Some(mac) = new.mac {
+                if new.joins != old.joins {
+                    send_topic::<TopologyTopic, _>(tx, ctrs, &TopologyEvent::Joined(mac)).await?;
+                }
+            }
+        }
+        known = now;
+
+        for mac in known.iter().filter_map(|s| s.mac) {
+            while let Ok(wfb) = ctl.recv_from(mac).await {
+                let Ok(data) = FrameData::from_slice(wfb.payload()) else {
+                    nut_warn!("Discarding oversized frame from target");
+                    continue;
+                };
+                Counters::incr(&ctrs.from_targets);
+                let msg = Received { mac, data };
+                send_topic::<FrameReceivedTopic, _>(tx, ctrs, &msg).await?;
+            }
+        }
+
+        C::wait(poll_interval).await;
+    }
+}
+
+/// Send a `Topic` message to the host
+async fn send_topic<T, Tx>(
+    tx: &Mutex<NoopRawMutex, Tx>,
+    ctrs: &Counters,
+    msg: &T::Message,
+) -> Result<(), Tx::Error>
+where
+    T: Topic,
+    T::Message: Serialize,
+    Tx: Write,
+{
+    let seq_no = ctrs.seq_no.get();
+    ctrs.seq_no.set(seq_no.wrapping_add(1));
+    write_msg(tx, T::TOPIC_KEY, seq_no, msg).await
+}
+
+/// Serialize and COBS encode a message, and write it to the host
+async fn write_msg<T, Tx>(
+    tx: &Mutex<NoopRawMutex, Tx>,
+    key: Key,
+    seq_no: u32,
+    msg: &T,
+) -> Result<(), Tx::Error>
+where
+    T: Serialize + Schema,
+    Tx: Write,
+{
+    let mut buf = [0u8; MAX_HOST_MSG];
+    let Ok(used) = postcard_rpc::headered::to_slice_cobs_keyed(seq_no, key, msg, &mut buf) else {
+        // All messages of the protocol fit in MAX_HOST_MSG
+        nut_error!("Failed to encode host message");
+        return Ok(());
+    };
+    let mut tx = tx.lock().await;
+    tx.write_all(used).await?;
+    tx.flush().await
+}
@@ -0,0 +1,478 @@
+//! Packet capture of bus traffic
+//!
+//! [CaptureSerial] wraps any [FrameSerial], and records every frame sent or
+//! received through it into a [PcapngWriter], which streams a [pcapng] file
+//! into a [CaptureSink]. On std hosts, any [std::io::Write] can be used as a
+//! sink with [IoSink] (requires the `std` feature). On embedded nodes, a sink
+//! can forward the capture over a debug link, or into a ring buffer.
+//!
+//! ## Record layout
+//!
+//! The capture uses a single interface with the link-layer type
+//! [LINKTYPE_ERDNUSS] (`LINKTYPE_USER0`, 147), and microsecond timestamps,
+//! taken from [embassy_time::Instant]. Each packet contains:
+//!
+//! | Offset | Length | Contents                                          |
+//! | :----- | :----- | :------------------------------------------------ |
+//! | 0      | 1      | Direction, [DIR_SENT] (0) or [DIR_RECEIVED] (1)    |
+//! | 1      | 1      | The [CmdAddr] byte, command in the top 3 bits     |
+//! | 2      | N      | The payload of the frame, if any                  |
+//!
+//! Frames longer than [MAX_CAPTURED] bytes are truncated, keeping their original
+//! length in the packet block.
+//!
+//! Received frames are timestamped at the end of reception, as reported by the
+//! wrapped [FrameSerial]. Sent frames are timestamped at the start of sending.
+//! The direction is also recorded in the `epb_flags` option of each packet, so
+//! tools like Wireshark can show it without a dissector.
+//!
+//! Captures can be read back with [PcapngReader], for example to feed the
+//! frames to a [Decoder][crate::decode::Decoder].
+//!
+//! [pcapng]: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html
+//! [CmdAddr]: crate::CmdAddr
+
+use core::marker::PhantomData;
+
+use embassy_time::Instant;
+
+use crate::{
+    addr_mark::AddrFilter,
+    clock::{Clock, EmbassyClock},
+    frame_pool::DEFAULT_FRAME_SIZE,
+    Error, FrameSerial, TimedFrame,
+};
+
+/// The link-layer type used for erdnuss captures, `LINKTYPE_USER0`
+pub const LINKTYPE_ERDNUSS: u16 = 147;
+
+/// The direction byte of a frame sent by this node
+pub const DIR_SENT: u8 = 0;
+
+/// The direction byte of a frame received by this node
+pub const DIR_RECEIVED: u8 = 1;
+
+const SHB_TYPE: u32 = 0x0A0D_0D0A;
+const IDB_TYPE: u32 = 0x0000_0001;
+const EPB_TYPE: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAG_INBOUND: u32 = 0b01;
+const EPB_FLAG_OUTBOUND: u32 = 0b10;
+
+/// The maximum number of bytes of each frame recorded by [PcapngWriter]
+pub const MAX_CAPTURED: usize = DEFAULT_FRAME_SIZE;
+
+/// The largest block written: an enhanced packet block with the direction byte
+/// and [MAX_CAPTURED] bytes of frame, padded, the flags option and end of
+/// options, and the trailing length
+const MAX_BLOCK: usize = 28 + (MAX_CAPTURED + 1).next_multiple_of(4) + 12 + 4;
+
+/// A destination for capture data
+pub trait CaptureSink {
+    /// The error type of the sink
+    type Error;
+
+    /// Write all of `data` to the sink
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A [CaptureSink] for any [std::io::Write]
+#[cfg(feature = "std")]
+pub struct IoSink<W: std::io::Write>(pub W);
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> CaptureSink for IoSink<W> {
+    type Error = std::io::Error;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.0.write_all(data)
+    }
+}
+
+/// The direction of a captured frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// The frame was sent by this node
+    Sent,
+    /// The frame was received by this node
+    Received,
+}
+
+/// An error when recording a frame
+#[derive(Debug, PartialEq)]
+pub enum CaptureError<E> {
+    /// Writing to the sink failed
+    Sink(E),
+    /// An earlier write failed, so nothing more is written
+    Poisoned,
+}
+
+/// A streaming pcapng writer
+///
+/// The section and interface headers are written before the first packet.
+///
+/// Each block is written to the sink with a single call to
+/// [CaptureSink::write_all()]. If a write fails, the sink may hold part of a
+/// block, and anything written after it could not be read back, so the
+/// writer is poisoned, and records nothing more.
+pub struct PcapngWriter<S: CaptureSink> {
+    sink: S,
+    started: bool,
+    poisoned: bool,
+}
+
+impl<S: CaptureSink> PcapngWriter<S> {
+    /// Create a new writer
+    pub const fn new(sink: S) -> Self {
+        Self {
+            sink,
+            started: false,
+            poisoned: false,
+        }
+    }
+
+    /// Record a single frame
+    pub fn record(
+        &mut self,
+        at: Instant,
+        dir: Direction,
+        frame: &[u8],
+    ) -> Result<(), CaptureError<S::Error>> {
+        if !self.started {
+            let mut block = Block::new();
+            // Section Header Block, with an unknown section length
+            block.u32s(&[SHB_TYPE, 28, BYTE_ORDER_MAGIC]);
+            block.bytes(&1u16.to_le_bytes());
+            block.bytes(&0u16.to_le_bytes());
+            block.bytes(&(-1i64).to_le_bytes());
+            block.u32s(&[28]);
+
+            // Interface Description Block, with the default microsecond
+            // timestamp resolution
+            block.u32s(&[IDB_TYPE, 20]);
+            block.bytes(&LINKTYPE_ERDNUSS.to_le_bytes());
+            block.bytes(&0u16.to_le_bytes());
+            block.u32s(&[(MAX_CAPTURED + 1) as u32, 20]);
+            self.write(&block)?;
+            self.started = true;
+        }
+
+        let (dir_byte, flags) = match dir {
+            Direction::Sent => (DIR_SENT, EPB_FLAG_OUTBOUND),
+            Direction::Received => (DIR_RECEIVED, EPB_FLAG_INBOUND),
+        };
+        let orig_len = frame.len() + 1;
+        let frame = &frame[..frame.len().min(MAX_CAPTURED)];
+        let cap_len = frame.len() + 1;
+        let padding = (4 - (cap_len % 4)) % 4;
+        // Block header, five fixed fields, data, the flags option and
+        // end of options, and the trailing length
+        let block_len = (8 + 20 + cap_len + padding + 8 + 4 + 4) as u32;
+        let micros = at.as_micros();
+
+        let mut block = Block::new();
+        block.u32s(&[
+            EPB_TYPE,
+            block_len,
+            0,
+            (micros >> 32) as u32,
+            micros as u32,
+            cap_len as u32,
+            orig_len as u32,
+        ]);
+        block.bytes(&[dir_byte]);
+        block.bytes(frame);
+        block.bytes(&[0u8; 3][..padding]);
+        block.bytes(&OPT_EPB_FLAGS.to_le_bytes());
+        block.bytes(&4u16.to_le_bytes());
+        block.u32s(&[flags, 0, block_len]);
+        self.write(&block)
+    }
+
+    /// Has a write to the sink failed?
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Get a reference to the sink
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    /// Consume the writer, returning the sink
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+
+    fn write(&mut self, block: &Block) -> Result<(), CaptureError<S::Error>> {
+        if self.poisoned {
+            return Err(CaptureError::Poisoned);
+        }
+        self.sink.write_all(block.as_slice()).map_err(|e| {
+            self.poisoned = true;
+            CaptureError::Sink(e)
+        })
+    }
+}
+
+/// One or more whole blocks, serialized before writing
+struct Block {
+    buf: [u8; MAX_BLOCK],
+    len: usize,
+}
+
+impl Block {
+    const fn new() -> Self {
+        Self {
+            buf: [0; MAX_BLOCK],
+            len: 0,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.buf[self.len..][..data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    fn u32s(&mut self, vals: &[u32]) {
+        vals.iter().for_each(|v| self.bytes(&v.to_le_bytes()));
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// A frame read back from a capture
+#[derive(Debug, PartialEq)]
+pub struct CapturedFrame<'a> {
+    /// The timestamp of the frame
+    pub at: Instant,
+    /// The direction of the frame, relative to the node that captured it
+    pub dir: Direction,
+    /// The frame, starting with the [CmdAddr][crate::CmdAddr] byte
+    pub frame: &'a [u8],
+}
+
+/// An error when reading a capture
+#[derive(Debug, PartialEq)]
+pub enum ReadError {
+    /// The capture ended in the middle of a block
+    Truncated,
+    /// The capture did not start with a valid section header
+    BadHeader,
+    /// A packet was too short to contain the direction byte
+    BadPacket,
+}
+
+/// The maximum number of interfaces supported by [PcapngReader]
+pub const MAX_INTERFACES: usize = 8;
+
+/// A reader for pcapng captures of bus traffic
+///
+/// Yields every packet from an interface with the [LINKTYPE_ERDNUSS] link-layer
+/// type, skipping all other blocks and packets. Timestamps are assumed to use
+/// the default microsecond resolution, as written by [PcapngWriter].
+pub struct PcapngReader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+    in_section: bool,
+    ours: heapless::Vec<bool, MAX_INTERFACES>,
+}
+
+impl<'a> PcapngReader<'a> {
+    /// Create a reader over a complete capture
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            big_endian: false,
+            in_section: false,
+            ours: heapless::Vec::new(),
+        }
+    }
+
+    fn u32_at(&self, block: &[u8], at: usize) -> Result<u32, ReadError> {
+        let bytes: [u8; 4] = block
+            .get(at..at + 4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(ReadError::Truncated)?;
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn u16_at(&self, block: &[u8], at: usize) -> Result<u16, ReadError> {
+        let bytes: [u8; 2] = block
+            .get(at..at + 2)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(ReadError::Truncated)?;
+        Ok(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn next_block(&mut self) -> Result<Option<CapturedFrame<'a>>, ReadError> {
+        if self.data.len() < 12 {
+            return Err(ReadError::Truncated);
+        }
+        let data = self.data;
+        let block_type = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if block_type == SHB_TYPE {
+            // The byte order of each section is set by its header
+            self.big_endian = match data[8..12] {
+                [0x1A, 0x2B, 0x3C, 0x4D] => true,
+                [0x4D, 0x3C, 0x2B, 0x1A] => false,
+                _ => return Err(ReadError::BadHeader),
+            };
+            self.ours.clear();
+            self.in_section = true;
+        } else if !self.in_section {
+            return Err(ReadError::BadHeader);
+        }
+
+        let block_len = self.u32_at(data, 4)? as usize;
+        if block_len < 12 || !block_len.is_multiple_of(4) {
+            return Err(ReadError::BadHeader);
+        }
+        let block = data.get(..block_len).ok_or(ReadError::Truncated)?;
+        self.data = &data[block_len..];
+
+        match self.u32_at(block, 0)? {
+            IDB_TYPE => {
+                let linktype = self.u16_at(block, 8)?;
+                // Interfaces past the limit are ignored
+                let _ = self.ours.push(linktype == LINKTYPE_ERDNUSS);
+                Ok(None)
+            }
+            EPB_TYPE => {
+                let iface = self.u32_at(block, 8)? as usize;
+                if !self.ours.get(iface).copied().unwrap_or(false) {
+                    return Ok(None);
+                }
+                let hi = self.u32_at(block, 12)? as u64;
+                let lo = self.u32_at(block, 16)? as u64;
+                let cap_len = self.u32_at(block, 20)? as usize;
+                let packet = block.get(28..28 + cap_len).ok_or(ReadError::Truncated)?;
+                let (dir, frame) = packet.split_first().ok_or(ReadError::BadPacket)?;
+                let dir = match *dir {
+                    DIR_SENT => Direction::Sent,
+                    DIR_RECEIVED => Direction::Received,
+                    _ => return Err(ReadError::BadPacket),
+                };
+                Ok(Some(CapturedFrame {
+                    at: Instant::from_micros((hi << 32) | lo),
+                    dir,
+                    frame,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<'a> Iterator for PcapngReader<'a> {
+    type Item = Result<CapturedFrame<'a>, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.data.is_empty() {
+            match self.next_block() {
+                Ok(Some(frame)) => return Some(Ok(frame)),
+                Ok(None) => {}
+                Err(e) => {
+                    // Stop at the first error
+                    self.data = &[];
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A [FrameSerial] that records all frames sent and received through it
+///
+/// Errors from the [CaptureSink] do not interrupt bus traffic, they are
+/// counted, and can be checked with [CaptureSerial::sink_errors()]. After the
+/// first error the [PcapngWriter] is poisoned, so every later frame is counted
+/// as well.
+///
+/// Sent frames are timestamped with the [Clock] `C`, received frames keep the
+/// timestamp of the wrapped serial port.
+pub struct CaptureSerial<T: FrameSerial, S: CaptureSink, C: Clock = EmbassyClock> {
+    serial: T,
+    writer: PcapngWriter<S>,
+    sink_errors: u32,
+    _clock: PhantomData<fn() -> C>,
+}
+
+#[cfg(feature = "embassy-clock")]
+impl<T: FrameSerial, S: CaptureSink> CaptureSerial<T, S> {
+    /// Wrap `serial` like [CaptureSerial::with_clock()], timestamping with the
+    /// [EmbassyClock]
+    pub const fn new(serial: T, sink: S) -> Self {
+        Self::with_clock(serial, sink)
+    }
+}
+
+impl<T: FrameSerial, S: CaptureSink, C: Clock> CaptureSerial<T, S, C> {
+    /// Wrap `serial`, writing the capture to `sink`, and timestamping with the
+    /// [Clock] `C`
+    pub const fn with_clock(serial: T, sink: S) -> Self {
+        Self {
+            serial,
+            writer: PcapngWriter::new(sink),
+            sink_errors: 0,
+            _clock: PhantomData,
+        }
+    }
+
+    /// The number of frames that could not be written to the sink
+    pub fn sink_errors(&self) -> u32 {
+        self.sink_errors
+    }
+
+    /// Get a reference to the capture writer
+    pub fn writer(&self) -> &PcapngWriter<S> {
+        &self.writer
+    }
+
+    /// Consume the wrapper, returning the serial port and the sink
+    pub fn into_inner(self) -> (T, S) {
+        (self.serial, self.writer.into_inner())
+    }
+
+    fn record(&mut self, at: Instant, dir: Direction, frame: &[u8]) {
+        if self.writer.record(at, dir, frame).is_err() {
+            self.sink_errors = self.sink_errors.wrapping_add(1);
+        }
+    }
+}
+
+impl<T: FrameSerial, S: CaptureSink, C: Clock> FrameSerial for CaptureSerial<T, S, C> {
+    type SerError = T::SerError;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        let start = C::now();
+        let res = self.serial.send_frame(data).await;
+        if res.is_ok() {
+            self.record(start, Direction::Sent, data);
+        }
+        res
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        let tf = self.serial.recv(frame).await?;
+        self.record(tf.end_of_rx, Direction::Received, tf.frame);
+        Ok(tf)
+    }
+
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        self.serial.set_addr_filter(filter);
+    }
+}
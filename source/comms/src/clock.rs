@@ -0,0 +1,111 @@
+//! Clocks and timers
+//!
+//! All timing in the protocol logic, like the Controller's
+//! [REPLY_TIMEOUT][crate::controller::REPLY_TIMEOUT], or a Target's
+//! [SELECT_TIMEOUT][crate::target::TgtCfg::SELECT_TIMEOUT], goes through a
+//! [Clock]. This allows the same [Controller][crate::Controller] and
+//! [Target][crate::target::Target] to run on different async runtimes:
+//!
+//! * [EmbassyClock] uses the `embassy-time` driver, and is what bare metal
+//!   devices use
+//! * `TokioClock`, with the `tokio` feature, uses the timers of the tokio
+//!   runtime, for example on a Linux gateway with a USB RS-485 adapter
+//!
+//! Times are always expressed with the [Instant] and [Duration] types of
+//! `embassy-time`, which are plain tick counts, and don't need the
+//! `embassy-time` driver unless [EmbassyClock] is used. Timestamps, like
+//! [TimedFrame::end_of_rx][crate::TimedFrame::end_of_rx], must come from the
+//! same [Clock] as the node using them.
+//!
+//! ## Choosing a clock
+//!
+//! Every type that uses a clock has a `with_clock` style constructor, like
+//! [FrameStorage::take_with_clock()][crate::frame_pool::FrameStorage::take_with_clock],
+//! that takes the [Clock] as a type parameter. With the `embassy-clock` feature,
+//! enabled by default, shorthand constructors like `FrameStorage::take()` use
+//! the [EmbassyClock].
+//!
+//! The clock type parameters, like the `C` of [Controller][crate::Controller],
+//! default to [EmbassyClock] with or without that feature, as a default can't
+//! depend on one. Naming the type is harmless, but on a runtime without an
+//! `embassy-time` driver, the clock must be named explicitly, as using the
+//! [EmbassyClock] fails to link.
+
+use core::{future::Future, pin::pin};
+
+use embassy_time::{Duration, Instant, TimeoutError, Timer};
+use futures::future::{select, Either};
+
+/// A source of time, and timers
+///
+/// Clocks are global, like the runtimes they come from, so a `Clock` is only
+/// ever used as a type parameter, and never instantiated.
+pub trait Clock {
+    /// The current time
+    fn now() -> Instant;
+
+    /// Wait until `at`, returning immediately if it is in the past
+    async fn wait_until(at: Instant);
+
+    /// Wait for `dur`
+    async fn wait(dur: Duration) {
+        Self::wait_until(Self::now() + dur).await
+    }
+
+    /// Run `fut`, giving up if it hasn't completed within `dur`
+    async fn with_timeout<F: Future>(dur: Duration, fut: F) -> Result<F::Output, TimeoutError> {
+        Self::with_deadline(Self::now() + dur, fut).await
+    }
+
+    /// Run `fut`, giving up if it hasn't completed by `at`
+    async fn with_deadline<F: Future>(at: Instant, fut: F) -> Result<F::Output, TimeoutError> {
+        match select(pin!(fut), pin!(Self::wait_until(at))).await {
+            Either::Left((out, _)) => Ok(out),
+            Either::Right(_) => Err(TimeoutError),
+        }
+    }
+}
+
+/// A [Clock] using the `embassy-time` driver
+pub struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now() -> Instant {
+        Instant::now()
+    }
+
+    async fn wait_until(at: Instant) {
+        Timer::at(at).await
+    }
+
+    async fn with_timeout<F: Future>(dur: Duration, fut: F) -> Result<F::Output, TimeoutError> {
+        embassy_time::with_timeout(dur, fut).await
+    }
+}
+
+/// A [Clock] using the timers of the tokio runtime
+///
+/// Time is measured from the first time this clock is used in the process.
+/// As this uses [tokio::time], it also follows tokio's paused time in tests.
+#[cfg(feature = "tokio")]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio")]
+impl TokioClock {
+    fn epoch() -> tokio::time::Instant {
+        static EPOCH: std::sync::OnceLock<tokio::time::Instant> = std::sync::OnceLock::new();
+        *EPOCH.get_or_init(tokio::time::Instant::now)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now() -> Instant {
+        Instant::from_micros(Self::epoch().elapsed().as_micros() as u64)
+    }
+
+    async fn wait_until(at: Instant) {
+        let at = Self::epoch() + std::time::Duration::from_micros(at.as_micros());
+        tokio::time::sleep_until(at).await
+    }
+}
@@ -0,0 +1,191 @@
+//! COBS framing over a byte stream
+//!
+//! The bus normally delimits frames with a line break, which needs UART
+//! hardware that can send and detect one. [CobsSerial] instead implements
+//! [FrameSerial] over any [embedded-io-async] byte stream, for links where
+//! that isn't possible, like a USB-serial adapter, a TCP socket, or a UART
+//! without break detection.
+//!
+//! Each frame is [COBS] encoded, which removes all zero bytes from it, and
+//! is followed by a single zero byte as the delimiter. Received data that
+//! doesn't decode to a frame, or that is too long for the receive buffer, is
+//! discarded up to the next delimiter, and counted in [CobsSerial::discarded()].
+//! Empty frames, e.g. from repeated delimiters, are silently skipped.
+//!
+//! As the delimiter is only seen once it has been read from the stream,
+//! [TimedFrame::end_of_rx] is taken when the delimiter is processed, and is
+//! less precise than a line break interrupt.
+//!
+//! [embedded-io-async]: embedded_io_async
+//! [COBS]: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
+
+use core::marker::PhantomData;
+
+use embedded_io_async::{Read, Write};
+
+use crate::{
+    clock::{Clock, EmbassyClock},
+    frame_pool::DEFAULT_FRAME_SIZE,
+    Error, FrameSerial, TimedFrame,
+};
+
+/// The default buffer size of a [CobsSerial]: one COBS encoded frame of
+/// [DEFAULT_FRAME_SIZE], plus its delimiter
+pub const DEFAULT_BUF_SIZE: usize = DEFAULT_FRAME_SIZE + DEFAULT_FRAME_SIZE.div_ceil(254) + 1;
+
+/// The error type of a [CobsSerial]
+#[derive(Debug, PartialEq)]
+pub enum CobsError<RE, WE> {
+    /// An error reading from the stream
+    Read(RE),
+    /// An error writing to the stream
+    Write(WE),
+    /// The stream was closed
+    Closed,
+    /// An outgoing frame was too long to encode in the buffer
+    FrameTooLong,
+}
+
+/// A [FrameSerial] using COBS framing over a pair of async byte streams
+///
+/// `N` is the size of the receive and transmit buffers, which must fit one
+/// encoded frame and its delimiter. Frames are timestamped with the [Clock] `C`.
+///
+/// Receiving is cancellation safe: a partially received frame stays buffered
+/// until the next call. Cancelling a send may leave a partial frame on the
+/// stream, which the other side discards.
+pub struct CobsSerial<Rx, Tx, const N: usize = DEFAULT_BUF_SIZE, C: Clock = EmbassyClock> {
+    rx: Rx,
+    tx: Tx,
+    buf: [u8; N],
+    /// The number of received bytes in `buf`
+    used: usize,
+    /// Set when a frame overflowed `buf`, discarding until the next delimiter
+    overflowed: bool,
+    discarded: u32,
+    _clock: PhantomData<fn() -> C>,
+}
+
+#[cfg(feature = "embassy-clock")]
+impl<Rx: Read, Tx: Write> CobsSerial<Rx, Tx> {
+    /// Create a [CobsSerial] like [CobsSerial::with_clock()], with the default
+    /// buffer size, and timestamps from the [EmbassyClock]
+    pub fn new(rx: Rx, tx: Tx) -> Self {
+        Self::with_clock(rx, tx)
+    }
+}
+
+impl<Rx: Read, Tx: Write, const N: usize, C: Clock> CobsSerial<Rx, Tx, N, C> {
+    /// Create a new [CobsSerial], receiving from `rx`, and sending on `tx`, with
+    /// a buffer size of `N`, and timestamps from the [Clock] `C`
+    pub fn with_clock(rx: Rx, tx: Tx) -> Self {
+        Self {
+            rx,
+            tx,
+            buf: [0u8; N],
+            used: 0,
+            overflowed: false,
+            discarded: 0,
+            _clock: PhantomData,
+        }
+    }
+
+    /// The number of malformed or oversized frames discarded so far
+    pub fn discarded(&self) -> u32 {
+        self.discarded
+    }
+
+    /// Consume the adapter, returning the streams
+    ///
+    /// Any buffered data is lost.
+    pub fn into_inner(self) -> (Rx, Tx) {
+        (self.rx, self.tx)
+    }
+
+    /// Take the next delimited message out of the buffer, and decode it
+    ///
+    /// Returns `None` if no complete message is buffered, and `Some(None)` if
+    /// a message was discarded.
+    fn take_frame(&mut self, frame: &mut [u8]) -> Option<Option<usize>> {
+        let end = self.buf[..self.used].iter().position(|b| *b == 0)?;
+
+        let res = if core::mem::take(&mut self.overflowed) || end == 0 {
+            // Already counted, or just an extra delimiter
+            None
+        } else if let Ok(len) = cobs::decode(&self.buf[..end], frame) {
+            Some(len)
+        } else {
+            nut_warn!("Discarding malformed frame");
+            self.discarded = self.discarded.wrapping_add(1);
+            None
+        };
+
+        self.buf.copy_within(end + 1..self.used, 0);
+        self.used -= end + 1;
+        Some(res)
+    }
+}
+
+impl<Rx: Read, Tx: Write, const N: usize, C: Clock> FrameSerial for CobsSerial<Rx, Tx, N, C> {
+    type SerError = CobsError<Rx::Error, Tx::Error>;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        let mut out = [0u8; N];
+        let Some(len) = out
+            .len()
+            .checked_sub(1)
+            .and_then(|max| cobs::try_encode(data, &mut out[..max]).ok())
+        else {
+            return Err(Error::Serial(CobsError::FrameTooLong));
+        };
+        out[len] = 0;
+
+        self.tx
+            .write_all(&out[..len + 1])
+            .await
+            .map_err(|e| Error::Serial(CobsError::Write(e)))?;
+        self.tx
+            .flush()
+            .await
+            .map_err(|e| Error::Serial(CobsError::Write(e)))
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        loop {
+            match self.take_frame(frame) {
+                Some(Some(len)) => {
+                    return Ok(TimedFrame {
+                        end_of_rx: C::now(),
+                        frame: &mut frame[..len],
+                    });
+                }
+                Some(None) => continue,
+                None => {}
+            }
+
+            // No delimiter, and no room for one: drop what we have, and
+            // the rest of the message when it arrives
+            if self.used == N {
+                if !self.overflowed {
+                    nut_warn!("Discarding oversized frame");
+                    self.discarded = self.discarded.wrapping_add(1);
+                }
+                self.overflowed = true;
+                self.used = 0;
+            }
+
+            let ct = self
+                .rx
+                .read(&mut self.buf[self.used..])
+                .await
+                .map_err(|e| Error::Serial(CobsError::Read(e)))?;
+            if ct == 0 {
+                return Err(Error::Serial(CobsError::Closed));
+            }
+            self.used += ct;
+        }
+    }
+}
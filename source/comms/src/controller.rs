@@ -2,21 +2,66 @@
 //!
 //! The Controller is responsible for running the bus.
 
-use core::{fmt::Debug, ops::DerefMut};
+#[cfg(feature = "postcard-rpc-helpers")]
+use core::{cell::Cell, future::poll_fn};
+use core::{
+    cell::RefCell,
+    fmt::Debug,
+    future::Future,
+    marker::PhantomData,
+    pin::pin,
+    task::{Context, Poll},
+};
 
-use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
-use embassy_time::{with_timeout, Duration, TimeoutError};
+use embassy_sync::{
+    blocking_mutex::{raw::RawMutex, Mutex as BlockingMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, TimeoutError};
+use futures::task::noop_waker_ref;
 use rand_core::RngCore;
+#[cfg(feature = "postcard-rpc-helpers")]
+use {
+    crate::{
+        peer::Reply,
+        wirehelp::{send_request, WhBody, WireError, ERROR_KEY},
+    },
+    embassy_sync::waitqueue::WakerRegistration,
+    heapless::Deque,
+    postcard_rpc::{Endpoint, Key, Topic},
+    serde::{de::DeserializeOwned, Serialize},
+};
 
 use crate::{
+    blocking::BlockingFrameSerial,
+    clock::{Clock, EmbassyClock},
     frame_pool::{FrameBox, RawFrameSlice, SendFrameBox, WireFrameBox},
-    peer::{Peer, INCOMING_SIZE, OUTGOING_SIZE},
-    CmdAddr, Error, FrameSerial, MAX_TARGETS,
+    peer::Peer,
+    CmdAddr, Error, FrameSerial, Mac, TimedFrame, MAX_TARGETS,
 };
 
+pub use crate::peer::{INCOMING_SIZE, OUTGOING_SIZE};
+
 /// Time that a Controller will wait for a Target to respond
 pub const REPLY_TIMEOUT: Duration = Duration::from_millis(1);
 
+/// The maximum number of simultaneous [Subscription]s to a [Controller]
+#[cfg(feature = "postcard-rpc-helpers")]
+pub const MAX_SUBSCRIPTIONS: usize = 8;
+
+/// The number of received messages each [Subscription] can hold
+#[cfg(feature = "postcard-rpc-helpers")]
+pub const SUBSCRIPTION_DEPTH: usize = 4;
+
+/// The maximum number of frames a [Controller] keeps for building requests,
+/// see [Controller::init()]
+#[cfg(feature = "postcard-rpc-helpers")]
+pub const REQUEST_FRAMES: usize = 4;
+
+/// A single peer, behind its own short-lived lock
+type PeerCell<R, const IN: usize = INCOMING_SIZE, const OUT: usize = OUTGOING_SIZE> =
+    BlockingMutex<R, RefCell<Peer<IN, OUT>>>;
+
 /// Controller interface and data storage
 ///
 /// The static Controller is intended to be used in two separate places
@@ -32,19 +77,39 @@ pub const REPLY_TIMEOUT: Duration = Duration::from_millis(1);
 /// rate, and place 2. would be grouped with the USB interface for sending/receiving
 /// frames over USB.
 ///
-/// The Controller contains an async Mutex which provides interior mutable access to
-/// information such as the table of connected Targets, or any "in flight" messages.
+/// Each Target's state, such as its logical address, MAC, and "in flight" messages,
+/// is kept behind its own blocking Mutex. These locks are only ever held for a few
+/// instructions at a time, and NEVER across an `.await`, including while [Controller::step()]
+/// is waiting on the bus. This means that application tasks can enqueue and dequeue
+/// frames concurrently with an in-progress step, without waiting for it to complete.
+///
+/// All bus timeouts are measured with the [Clock] `C`, which defaults to the
+/// `embassy-time` driver. Other runtimes must name their clock, see the
+/// [clock][crate::clock] module.
 pub struct Controller<
     R: RawMutex + 'static,
     const IN: usize = INCOMING_SIZE,
     const OUT: usize = OUTGOING_SIZE,
+    C: Clock = EmbassyClock,
 > {
-    peers: Mutex<R, [Peer<IN, OUT>; MAX_TARGETS]>,
+    peers: [PeerCell<R, IN, OUT>; MAX_TARGETS],
+    stepping: Mutex<R, ()>,
+    #[cfg(feature = "postcard-rpc-helpers")]
+    seq_no: BlockingMutex<R, Cell<u32>>,
+    #[cfg(feature = "postcard-rpc-helpers")]
+    requests: Mutex<R, RawFrameSlice>,
+    #[cfg(feature = "postcard-rpc-helpers")]
+    subs: SubCell<R>,
+    _clock: PhantomData<fn() -> C>,
 }
 
 /// Instantiation and Initialization methods
-impl<R: RawMutex + 'static, const IN: usize, const OUT: usize> Controller<R, IN, OUT> {
-    const ONE: Peer<IN, OUT> = Peer::<IN, OUT>::const_new();
+impl<R: RawMutex + 'static, const IN: usize, const OUT: usize, C: Clock> Controller<R, IN, OUT, C> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ONE: PeerCell<R, IN, OUT> =
+        BlockingMutex::new(RefCell::new(Peer::<IN, OUT>::const_new()));
+    #[cfg(feature = "postcard-rpc-helpers")]
+    const ONE_SUB: SubSlot = SubSlot::new();
 
     /// Create a new, uninitialized controller structure
     ///
@@ -61,9 +126,17 @@ impl<R: RawMutex + 'static, const IN: usize, const OUT: usize> Controller<R, IN,
     /// // if you not using this from an interrupt context
     /// static CONTROLLER: Controller<CriticalSectionRawMutex> = Controller::uninit();
     /// ```
-    pub const fn uninit() -> Controller<R, IN, OUT> {
+    pub const fn uninit() -> Controller<R, IN, OUT, C> {
         Self {
-            peers: Mutex::new([Self::ONE; MAX_TARGETS]),
+            peers: [Self::ONE; MAX_TARGETS],
+            stepping: Mutex::new(()),
+            #[cfg(feature = "postcard-rpc-helpers")]
+            seq_no: BlockingMutex::new(Cell::new(0)),
+            #[cfg(feature = "postcard-rpc-helpers")]
+            requests: Mutex::new(RawFrameSlice::uninit()),
+            #[cfg(feature = "postcard-rpc-helpers")]
+            subs: BlockingMutex::new(RefCell::new([Self::ONE_SUB; MAX_SUBSCRIPTIONS])),
+            _clock: PhantomData,
         }
     }
 
@@ -72,20 +145,28 @@ impl<R: RawMutex + 'static, const IN: usize, const OUT: usize> Controller<R, IN,
     /// This initialization provides the backing storage for the incoming target
     /// frames. [RawFrameSlice] must contain AT LEAST `IN` times `OUT`
     /// frame storage slots. `sli`'s capacity will be reduced by this amount.
+    ///
+    /// With the `postcard-rpc-helpers` feature, up to [REQUEST_FRAMES] of any
+    /// remaining frames are also taken, to build the requests sent by
+    /// [Controller::request()].
     pub async fn init(&self, sli: &mut RawFrameSlice) {
         assert!(sli.capacity() >= (INCOMING_SIZE * MAX_TARGETS));
-        let mut inner = self.peers.lock().await;
-        for m in inner.iter_mut() {
+        let _stepping = self.stepping.lock().await;
+        for m in self.peers.iter() {
             let mut split = sli.split(INCOMING_SIZE).unwrap();
             core::mem::swap(sli, &mut split);
             assert_eq!(split.capacity(), INCOMING_SIZE);
-            m.set_pool(split);
+            m.lock(|p| p.borrow_mut().set_pool(split));
+        }
+        #[cfg(feature = "postcard-rpc-helpers")]
+        if let Some(rest) = sli.split(sli.capacity().min(REQUEST_FRAMES)) {
+            *self.requests.lock().await = core::mem::replace(sli, rest);
         }
     }
 }
 
 /// Bus management and operation method(s)
-impl<R: RawMutex + 'static> Controller<R> {
+impl<R: RawMutex + 'static, C: Clock> Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C> {
     /// Perform one "step" of the bus
     ///
     /// One call to `step` will:
@@ -106,10 +187,10 @@ impl<R: RawMutex + 'static> Controller<R> {
     /// * Make these generics NOT required for the shared `send`/`recv_from`
     ///   interface methods
     ///
-    /// The inner async mutex will be locked for the entire duration of the call to
-    /// `step`, which may be held for some amount of time, depending on the number of
+    /// A call to `step` may take some amount of time, depending on the number of
     /// bus timeouts and total amount of data transferred. This may be on the order of
-    /// millisecond(s).
+    /// millisecond(s). Concurrent calls to `step` wait for each other, however
+    /// `send`, `recv_from`, and `connected` do NOT wait for `step` to complete.
     pub async fn step<T, Rand>(
         &self,
         serial: &mut T,
@@ -119,39 +200,94 @@ impl<R: RawMutex + 'static> Controller<R> {
         T: FrameSerial,
         Rand: RngCore,
     {
-        let mut inner = self.peers.lock().await;
-        serve_peers(inner.deref_mut(), serial).await?;
-        complete_pendings(inner.deref_mut(), serial).await?;
-        offer_addr(inner.deref_mut(), serial, rand).await?;
+        let _stepping = self.stepping.lock().await;
+        let mut bus = AsyncBus::<T, C> {
+            serial,
+            _clock: PhantomData,
+        };
+        self.step_with(&mut bus, rand).await
+    }
+
+    /// Perform one "step" of the bus, blocking until it is complete
+    ///
+    /// This does exactly the same as [Controller::step()], for applications
+    /// without an async executor, such as a bare metal superloop. Bus timeouts
+    /// are measured by the `serial` port, see [BlockingFrameSerial].
+    ///
+    /// If a call to `step` or `step_blocking` is already in progress, this does
+    /// nothing, and returns [StepError::Busy]. A blocking step can never wait for
+    /// an async step to complete, so the async and blocking interfaces must not be
+    /// mixed on one core: an async step suspended while waiting on the bus keeps
+    /// every blocking step on the same core busy, until the executor resumes it.
+    pub fn step_blocking<T, Rand>(
+        &self,
+        serial: &mut T,
+        rand: &mut Rand,
+    ) -> Result<(), StepError<T::SerError>>
+    where
+        T: BlockingFrameSerial,
+        Rand: RngCore,
+    {
+        let _stepping = self.stepping.try_lock().map_err(|_| StepError::Busy)?;
+        poll_ready(self.step_with(&mut BlockingBus(serial), rand)).map_err(StepError::Bus)
+    }
+
+    /// The protocol of a single step, shared by the async and blocking versions
+    async fn step_with<B, Rand>(
+        &self,
+        bus: &mut B,
+        rand: &mut Rand,
+    ) -> Result<(), Error<B::SerError>>
+    where
+        B: StepBus,
+        Rand: RngCore,
+    {
+        #[cfg(feature = "postcard-rpc-helpers")]
+        make_room(&self.peers, &self.subs);
+        serve_peers(&self.peers, bus).await?;
+        #[cfg(feature = "postcard-rpc-helpers")]
+        route_topics(&self.peers, &self.subs);
+        complete_pendings(&self.peers, bus).await?;
+        #[cfg(feature = "postcard-rpc-helpers")]
+        release_freed(&self.peers, &self.subs);
+        offer_addr(&self.peers, bus, rand).await?;
         Ok(())
     }
 }
 
 /// Bus I/O methods
-impl<R: RawMutex + 'static> Controller<R> {
-    /// Attempt to enqueue a message for sending
-    pub async fn send(&self, mac: u64, frame: SendFrameBox) -> Result<(), SendError> {
-        self.peers
-            .lock()
-            .await
-            .iter_mut()
-            .find(|p| p.is_active_mac(mac))
-            .ok_or(SendError::NoMatchingMac)
-            .and_then(|p| {
-                p.enqueue_outgoing(frame.into_inner())
-                    .map_err(SendError::QueueFull)
+impl<R: RawMutex + 'static, C: Clock> Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C> {
+    /// Find the active peer with the given MAC, and run `f` on it
+    fn with_active_mac<U>(&self, mac: Mac, f: impl FnOnce(&mut Peer) -> U) -> Option<U> {
+        let mut f = Some(f);
+        self.peers.iter().find_map(|cell| {
+            cell.lock(|p| {
+                let mut p = p.borrow_mut();
+                if p.is_active_mac(mac) {
+                    f.take().map(|f| f(&mut p))
+                } else {
+                    None
+                }
             })
+        })
+    }
+
+    /// Attempt to enqueue a message for sending
+    pub async fn send(&self, mac: Mac, frame: SendFrameBox) -> Result<(), SendError> {
+        self.with_active_mac(mac, |p| p.enqueue_outgoing(frame.into_inner()))
+            .ok_or(SendError::NoMatchingMac)?
+            .map_err(SendError::QueueFull)
     }
 
     /// Attempt to receive a message from the given unique address
-    pub async fn recv_from(&self, mac: u64) -> Result<WireFrameBox, RecvError> {
-        self.peers
-            .lock()
-            .await
-            .iter_mut()
-            .find(|p| p.is_active_mac(mac))
-            .ok_or(RecvError::NoMatchingMac)
-            .and_then(|p| p.dequeue_incoming().ok_or(RecvError::NoMessage))
+    ///
+    /// Each received message is only returned once. When the same Controller is
+    /// also used by the `bridge` module's `run()`, the bridge takes every received
+    /// message, and this should not be called.
+    pub async fn recv_from(&self, mac: Mac) -> Result<WireFrameBox, RecvError> {
+        self.with_active_mac(mac, |p| p.dequeue_incoming())
+            .ok_or(RecvError::NoMatchingMac)?
+            .ok_or(RecvError::NoMessage)
             .map(WireFrameBox::new_unchecked)
     }
 
@@ -160,14 +296,328 @@ impl<R: RawMutex + 'static> Controller<R> {
     /// This list DOES NOT include the Controller's MAC address, but the returned
     /// [`heapless::Vec`] *does* reserve enough room to contain all [MAX_TARGETS]
     /// plus one.
-    pub async fn connected(&self) -> heapless::Vec<u64, { MAX_TARGETS + 1 }> {
+    pub async fn connected(&self) -> heapless::Vec<Mac, { MAX_TARGETS + 1 }> {
         self.peers
-            .lock()
-            .await
             .iter()
-            .filter_map(|p| p.is_active().then_some(p.mac()))
+            .filter_map(|cell| {
+                cell.lock(|p| {
+                    let p = p.borrow();
+                    p.is_active().then_some(p.mac())
+                })
+            })
             .collect()
     }
+
+    /// Get the state of every address on the bus
+    ///
+    /// Unlike [Controller::connected()], this also reports how many times a
+    /// Target has joined at each address, so a Target that left and rejoined
+    /// between two calls can be told apart from one that stayed connected.
+    pub async fn topology(&self) -> [AddrState; MAX_TARGETS] {
+        let mut out = [AddrState::default(); MAX_TARGETS];
+        for (state, cell) in out.iter_mut().zip(self.peers.iter()) {
+            cell.lock(|p| {
+                let p = p.borrow();
+                *state = AddrState {
+                    mac: p.is_active().then_some(p.mac()),
+                    joins: p.joins(),
+                };
+            });
+        }
+        out
+    }
+}
+
+/// The state of one address on the bus, see [Controller::topology()]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AddrState {
+    /// The MAC address of the Target currently active at this address, if any
+    pub mac: Option<Mac>,
+    /// The number of times a Target has become active at this address,
+    /// wrapping on overflow
+    pub joins: u32,
+}
+
+/// postcard-rpc methods
+#[cfg(feature = "postcard-rpc-helpers")]
+impl<R: RawMutex + 'static, C: Clock> Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C> {
+    /// Send a postcard-rpc request to a Target, and wait for the matching response
+    ///
+    /// The request is serialized with a header containing `E::REQ_KEY` and a new
+    /// sequence number, into one of the frames kept by [Controller::init()], and
+    /// enqueued for sending like [Controller::send()]. If all of these frames are
+    /// in use by other requests, we wait for one to be sent.
+    ///
+    /// We then wait for a frame from the same Target with a header containing
+    /// `E::RESP_KEY` and the same sequence number. If the Target replies with a
+    /// [WireError] instead, with the [ERROR_KEY] and the same sequence number,
+    /// it is returned as [RequestError::Remote]. Any other frames
+    /// received from the Target in the meantime are left in place, to be received
+    /// with [Controller::recv_from()]. Several requests to the same Target may be
+    /// waiting at once, each only takes its own response.
+    ///
+    /// All of this must complete within `timeout`. If the response arrives after
+    /// that, it is discarded.
+    pub async fn request<E>(
+        &self,
+        mac: Mac,
+        req: &E::Request,
+        timeout: Duration,
+    ) -> Result<E::Response, RequestError>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        let seq_no = self.seq_no.lock(|s| {
+            let seq_no = s.get();
+            s.set(seq_no.wrapping_add(1));
+            seq_no
+        });
+        let reply = Reply {
+            key: E::RESP_KEY,
+            seq_no,
+        };
+        let exchange = async {
+            let buf = {
+                let mut pool = self.requests.lock().await;
+                if pool.capacity() == 0 {
+                    return Err(RequestError::NoFrames);
+                }
+                pool.allocate().await
+            };
+            let frame = send_request::<E>(buf, seq_no, req).ok_or(RequestError::Encode)?;
+            self.send(mac, frame.into())
+                .await
+                .map_err(RequestError::Send)?;
+            self.wait_for_incoming(mac, |fb| reply.matches(fb)).await
+        };
+        let resp = match C::with_timeout(timeout, exchange).await {
+            Ok(resp) => resp?,
+            Err(_) => {
+                // Discard the response, whenever it arrives
+                self.with_active_mac(mac, |p| p.abandon(reply));
+                return Err(RequestError::Timeout);
+            }
+        };
+
+        let body = WhBody::try_from(&resp).ok_or(RequestError::Decode)?;
+        if body.wh.key == ERROR_KEY {
+            let err = body.decode_error().ok_or(RequestError::Decode)?;
+            return Err(RequestError::Remote(err));
+        }
+        body.decode_response::<E>().ok_or(RequestError::Decode)
+    }
+
+    /// Wait until an incoming frame from the given unique address matches `pred`,
+    /// and remove it from the incoming queue
+    async fn wait_for_incoming(
+        &self,
+        mac: Mac,
+        mut pred: impl FnMut(&FrameBox) -> bool,
+    ) -> Result<FrameBox, RequestError> {
+        poll_fn(|cx| {
+            self.with_active_mac(mac, |p| match p.take_incoming(&mut pred) {
+                Some(fb) => Poll::Ready(Ok(fb)),
+                None => {
+                    p.register_incoming_waker(cx.waker());
+                    Poll::Pending
+                }
+            })
+            .unwrap_or(Poll::Ready(Err(RequestError::Disconnected)))
+        })
+        .await
+    }
+}
+
+/// postcard-rpc topic subscriptions
+#[cfg(feature = "postcard-rpc-helpers")]
+impl<R: RawMutex + 'static, C: Clock> Controller<R, INCOMING_SIZE, OUTGOING_SIZE, C> {
+    /// Subscribe to a `Topic` sent by any Target
+    ///
+    /// While the returned [Subscription] exists, any frame received from a Target
+    /// with a header containing `T::TOPIC_KEY` is routed to the subscription during
+    /// [Controller::step()], instead of being left for [Controller::recv_from()].
+    ///
+    /// Waiting messages are never allowed to stall the bus. If the subscription is
+    /// full, its oldest message is discarded to make room. Each waiting message also
+    /// holds one of the sending Target's incoming frames, and one must always be free
+    /// to receive into, so the oldest waiting message from a Target is discarded when
+    /// it runs out. Waiting messages from a Target that is dropped from the bus are
+    /// discarded too, before its address is offered again. See [Subscription::dropped()].
+    ///
+    /// Returns [None] if there is already a subscription to this `Topic`, or if all
+    /// [MAX_SUBSCRIPTIONS] are in use.
+    pub fn subscribe<T>(&self) -> Option<Subscription<'_, R, T>>
+    where
+        T: Topic,
+        T::Message: DeserializeOwned,
+    {
+        let idx = self.subs.lock(|subs| {
+            let mut subs = subs.borrow_mut();
+            if subs.iter().any(|s| s.key == Some(T::TOPIC_KEY)) {
+                return None;
+            }
+            let idx = subs.iter().position(|s| s.key.is_none())?;
+            subs[idx].key = Some(T::TOPIC_KEY);
+            Some(idx)
+        })?;
+        Some(Subscription {
+            subs: &self.subs,
+            idx,
+            _pd: PhantomData,
+        })
+    }
+}
+
+/// All subscription slots, behind one short-lived lock
+#[cfg(feature = "postcard-rpc-helpers")]
+type SubCell<R> = BlockingMutex<R, RefCell<[SubSlot; MAX_SUBSCRIPTIONS]>>;
+
+/// A message waiting in a subscription
+#[cfg(feature = "postcard-rpc-helpers")]
+struct Queued {
+    /// The logical address of the sending Target, whose incoming frame this holds
+    addr: u8,
+    mac: Mac,
+    fb: FrameBox,
+}
+
+#[cfg(feature = "postcard-rpc-helpers")]
+struct SubSlot {
+    key: Option<Key>,
+    queue: Deque<Queued, SUBSCRIPTION_DEPTH>,
+    waker: WakerRegistration,
+    dropped: u32,
+}
+
+#[cfg(feature = "postcard-rpc-helpers")]
+impl SubSlot {
+    const fn new() -> Self {
+        Self {
+            key: None,
+            queue: Deque::new(),
+            waker: WakerRegistration::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Discard up to `max` waiting messages matching `pred`, oldest first,
+    /// keeping the order of all others. Returns the number discarded.
+    fn discard(&mut self, max: usize, mut pred: impl FnMut(&Queued) -> bool) -> usize {
+        let mut ct = 0;
+        // Rotate through the whole queue once, oldest first
+        for _ in 0..self.queue.len() {
+            let msg = self.queue.pop_back().unwrap();
+            if ct < max && pred(&msg) {
+                ct += 1;
+            } else {
+                self.queue.push_front(msg).map_err(drop).unwrap();
+            }
+        }
+        self.dropped = self.dropped.wrapping_add(ct as u32);
+        ct
+    }
+}
+
+/// A typed subscription to a `Topic`, created by [Controller::subscribe()]
+///
+/// Dropping the Subscription unsubscribes, and discards any messages not yet
+/// received.
+#[cfg(feature = "postcard-rpc-helpers")]
+pub struct Subscription<'a, R: RawMutex + 'static, T> {
+    subs: &'a SubCell<R>,
+    idx: usize,
+    _pd: PhantomData<fn() -> T>,
+}
+
+#[cfg(feature = "postcard-rpc-helpers")]
+impl<'a, R, T> Subscription<'a, R, T>
+where
+    R: RawMutex + 'static,
+    T: Topic,
+    T::Message: DeserializeOwned,
+{
+    /// Wait for the next message, returning it along with the MAC address
+    /// of the Target that sent it
+    ///
+    /// Messages that fail to deserialize are skipped.
+    pub async fn recv(&mut self) -> (Mac, T::Message) {
+        poll_fn(|cx| {
+            self.subs.lock(|subs| {
+                let s = &mut subs.borrow_mut()[self.idx];
+                while let Some(Queued { mac, fb, .. }) = s.queue.pop_back() {
+                    if let Some(msg) = Self::decode(&fb) {
+                        return Poll::Ready((mac, msg));
+                    }
+                }
+                s.waker.register(cx.waker());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    /// Attempt to receive a message, without waiting
+    ///
+    /// Messages that fail to deserialize are skipped.
+    pub fn try_recv(&mut self) -> Option<(Mac, T::Message)> {
+        self.subs.lock(|subs| {
+            let s = &mut subs.borrow_mut()[self.idx];
+            while let Some(Queued { mac, fb, .. }) = s.queue.pop_back() {
+                if let Some(msg) = Self::decode(&fb) {
+                    return Some((mac, msg));
+                }
+            }
+            None
+        })
+    }
+
+    /// The number of messages discarded without being received, to make room
+    /// for newer ones, or because their Target was dropped from the bus
+    pub fn dropped(&self) -> u32 {
+        self.subs.lock(|subs| subs.borrow()[self.idx].dropped)
+    }
+
+    fn decode(fb: &FrameBox) -> Option<T::Message> {
+        let msg = WhBody::try_from(fb)?.decode_topic::<T>();
+        if msg.is_none() {
+            nut_warn!("Failed to decode topic message");
+        }
+        msg
+    }
+}
+
+#[cfg(feature = "postcard-rpc-helpers")]
+impl<'a, R: RawMutex + 'static, T> Drop for Subscription<'a, R, T> {
+    fn drop(&mut self) {
+        self.subs.lock(|subs| {
+            let s = &mut subs.borrow_mut()[self.idx];
+            s.key = None;
+            s.queue.clear();
+            s.dropped = 0;
+        });
+    }
+}
+
+/// An error when making a postcard-rpc request to a Target
+#[cfg(feature = "postcard-rpc-helpers")]
+#[derive(Debug)]
+pub enum RequestError {
+    /// Sending the request failed
+    Send(SendError),
+    /// The Controller has no frames to build requests in, see [Controller::init()]
+    NoFrames,
+    /// The request could not be serialized into a frame
+    Encode,
+    /// The response could not be deserialized
+    Decode,
+    /// The Target did not respond within the timeout
+    Timeout,
+    /// The Target was dropped from the bus while waiting for the response
+    Disconnected,
+    /// The Target replied with an error, instead of a response
+    Remote(WireError),
 }
 
 /// An error when sending a frame to a Target
@@ -190,6 +640,15 @@ impl Debug for SendError {
     }
 }
 
+/// An error when stepping the bus with [Controller::step_blocking()]
+#[derive(Debug, PartialEq)]
+pub enum StepError<E> {
+    /// Another step was already in progress, so nothing was done
+    Busy,
+    /// An error on the bus, like those returned by [Controller::step()]
+    Bus(Error<E>),
+}
+
 /// An error when attempting to receive a frame from a Target
 #[derive(Debug, PartialEq)]
 pub enum RecvError {
@@ -202,30 +661,41 @@ pub enum RecvError {
 
 /// A helper function that serves all currently active peers, exchanging
 /// zero or one frames in each direction
-async fn serve_peers<T: FrameSerial>(
-    inner: &mut [Peer; MAX_TARGETS],
-    serial: &mut T,
-) -> Result<(), Error<T::SerError>> {
+///
+/// Each peer is only locked while preparing for, and processing the result
+/// of, its exchange. The lock is NOT held while waiting on the bus.
+async fn serve_peers<R: RawMutex, B: StepBus>(
+    peers: &[PeerCell<R>; MAX_TARGETS],
+    bus: &mut B,
+) -> Result<(), Error<B::SerError>> {
     // First pass: poll all active devices
-    for (i, p) in inner.iter_mut().enumerate() {
-        // We only care about active devices
-        if !p.is_active() {
-            continue;
-        }
+    for (i, cell) in peers.iter().enumerate() {
+        let prepared = cell.lock(|p| {
+            let mut p = p.borrow_mut();
+
+            // We only care about active devices
+            if !p.is_active() {
+                return None;
+            }
+
+            // Can we allocate a reception frame? If not: we can't talk to the device, skip it
+            // for a single round. In the future, we might want to increment error here to
+            // eventually time out devices, but this isn't really the fault of the target, it's
+            // a fault of the firmware driving the "controller".
+            let Some(rx) = p.alloc_incoming() else {
+                nut_warn!("Couldn't alloc incoming!");
+                p.increment_error();
+                return None;
+            };
 
-        // Can we allocate a reception frame? If not: we can't talk to the device, skip it
-        // for a single round. In the future, we might want to increment error here to
-        // eventually time out devices, but this isn't really the fault of the target, it's
-        // a fault of the firmware driving the "controller".
-        let Some(mut rx) = p.alloc_incoming() else {
-            nut_warn!("Couldn't alloc incoming!");
-            p.increment_error();
+            Some((rx, p.dequeue_outgoing()))
+        });
+        let Some((mut rx, mut maybe_out)) = prepared else {
             continue;
         };
 
         // Is there any outgoing frame? If not, we use a one byte fallback buffer
         // to place the "Select" command in.
-        let mut maybe_out = p.dequeue_outgoing();
         let mut fallback = [0u8; 1];
         let to_send = match maybe_out.as_deref_mut() {
             Some(fb) => fb,
@@ -235,14 +705,19 @@ async fn serve_peers<T: FrameSerial>(
         // Fill in the cmdaddr, send the message, and start listening with a
         // timeout
         to_send[0] = CmdAddr::SelectAddr(i as u8).into();
-        serial.send_frame(to_send).await?;
-        let rxto = with_timeout(REPLY_TIMEOUT, serial.recv(&mut rx));
+        bus.send_frame(to_send).await?;
+        let rxto = bus.recv_timeout(&mut rx, REPLY_TIMEOUT);
 
-        match rxto.await {
-            Ok(Ok(tf)) => {
-                // We received a message within the timeout!
-                let len = tf.frame.len();
-                if len != 0 && tf.frame[0] == CmdAddr::ReplyFromAddr(i as u8).into() {
+        // Pull the header and length out, so we are no longer borrowing `rx`
+        let res = rxto
+            .await
+            .map(|r| r.map(|tf| (tf.frame.first().copied(), tf.frame.len())));
+
+        cell.lock(|p| {
+            let mut p = p.borrow_mut();
+            match res {
+                Ok(Ok((Some(hdr), len))) if hdr == CmdAddr::ReplyFromAddr(i as u8).into() => {
+                    // We received a message within the timeout!
                     // We got AT least an ack, mark that as a success
                     p.set_success();
 
@@ -252,85 +727,183 @@ async fn serve_peers<T: FrameSerial>(
                         rx.set_len(len);
                         p.enqueue_incoming(rx);
                     }
-                } else {
+                    Ok(())
+                }
+                Ok(Ok((_hdr, len))) => {
                     // We got a zero len message, OR an unexpected reply. Mark an error.
                     nut_warn!("Error with {=usize} len is {=usize}", i, len);
                     p.increment_error();
+                    Ok(())
+                }
+                Ok(Err(e)) => {
+                    // We finished within the timeout, but got some kind of error
+                    // while receiving. Increment the error, in case we don't just
+                    // decide to reset or something.
+                    p.increment_error();
+
+                    // then bubble up the error.
+                    Err(e)
+                }
+                Err(TimeoutError) => {
+                    // We timed out, increment error
+                    p.increment_error();
+                    Ok(())
                 }
             }
-            Ok(Err(e)) => {
-                // We finished within the timeout, but got some kind of error
-                // while receiving. Increment the error, in case we don't just
-                // decide to reset or something.
-                p.increment_error();
+        })?;
+    }
+    Ok(())
+}
 
-                // then bubble up the error.
-                return Err(e);
+/// A helper function that moves any incoming frames for subscribed `Topic`s from
+/// the queues of active peers to the matching subscription
+#[cfg(feature = "postcard-rpc-helpers")]
+fn route_topics<R: RawMutex>(peers: &[PeerCell<R>; MAX_TARGETS], subs: &SubCell<R>) {
+    // Find the subscription for this key
+    let slot_for =
+        |key: Key| subs.lock(|subs| subs.borrow().iter().position(|s| s.key == Some(key)));
+
+    for (i, cell) in peers.iter().enumerate() {
+        cell.lock(|p| {
+            let mut p = p.borrow_mut();
+            if !p.is_active() {
+                return;
             }
-            Err(TimeoutError) => {
-                // We timed out, increment error
-                p.increment_error();
+            let mac = p.mac();
+            let mut dest = None;
+            while let Some(fb) = p.take_incoming(|fb| {
+                dest = WhBody::try_from(fb).and_then(|b| slot_for(b.wh.key));
+                dest.is_some()
+            }) {
+                // `dest` was set by the last call of the predicate, the one that matched
+                let Some(idx) = dest else {
+                    break;
+                };
+                subs.lock(|subs| {
+                    let s = &mut subs.borrow_mut()[idx];
+                    if s.queue.is_full() {
+                        nut_warn!("Subscription full, dropping oldest message");
+                        s.queue.pop_back();
+                        s.dropped = s.dropped.wrapping_add(1);
+                    }
+                    // There is room now, and only step routes frames
+                    let msg = Queued {
+                        addr: i as u8,
+                        mac,
+                        fb,
+                    };
+                    s.queue.push_front(msg).map_err(drop).unwrap();
+                    s.waker.wake();
+                });
             }
+        });
+    }
+}
+
+/// Make sure every active Target has an incoming frame to receive into, by
+/// discarding its oldest message waiting in a subscription if necessary
+#[cfg(feature = "postcard-rpc-helpers")]
+fn make_room<R: RawMutex>(peers: &[PeerCell<R>; MAX_TARGETS], subs: &SubCell<R>) {
+    for (i, cell) in peers.iter().enumerate() {
+        let full = cell.lock(|p| {
+            let p = p.borrow();
+            p.is_active() && !p.can_alloc_incoming()
+        });
+        if !full {
+            continue;
+        }
+        // Drop the oldest message from this Target in the first subscription
+        // holding one
+        let dropped = subs.lock(|subs| {
+            subs.borrow_mut()
+                .iter_mut()
+                .any(|s| s.discard(1, |q| q.addr == i as u8) != 0)
+        });
+        if dropped {
+            nut_warn!("Target out of frames, dropping a subscribed message");
         }
     }
-    Ok(())
+}
+
+/// Discard all messages waiting in subscriptions from Targets that have been
+/// dropped from the bus, as they hold incoming frames that must all be free
+/// again before the address is offered to a new Target
+#[cfg(feature = "postcard-rpc-helpers")]
+fn release_freed<R: RawMutex>(peers: &[PeerCell<R>; MAX_TARGETS], subs: &SubCell<R>) {
+    for (i, cell) in peers.iter().enumerate() {
+        let held = cell.lock(|p| {
+            let p = p.borrow();
+            p.is_free() && !p.is_idle()
+        });
+        if !held {
+            continue;
+        }
+        subs.lock(|subs| {
+            for s in subs.borrow_mut().iter_mut() {
+                s.discard(SUBSCRIPTION_DEPTH, |q| q.addr == i as u8);
+            }
+        });
+    }
 }
 
 /// A helper function for moving targets from the Pending stage to the Active stage
-async fn complete_pendings<T: FrameSerial>(
-    inner: &mut [Peer; MAX_TARGETS],
-    serial: &mut T,
-) -> Result<(), Error<T::SerError>> {
-    for (i, p) in inner.iter_mut().enumerate() {
+async fn complete_pendings<R: RawMutex, B: StepBus>(
+    peers: &[PeerCell<R>; MAX_TARGETS],
+    bus: &mut B,
+) -> Result<(), Error<B::SerError>> {
+    for (i, cell) in peers.iter().enumerate() {
         // Only worry about pending nodes
-        let Some(mac) = p.is_pending() else {
+        let Some(mac) = cell.lock(|p| p.borrow().is_pending()) else {
             continue;
         };
 
         // Send a message with the expected MAC address for confirmation
         let mut out_buf = [0u8; 9];
         out_buf[0] = CmdAddr::DiscoverySuccess(i as u8).into();
-        out_buf[1..9].copy_from_slice(&mac.to_le_bytes());
+        out_buf[1..9].copy_from_slice(&mac.to_wire_bytes());
 
         // We should only get back an empty ACK and nothing else
         let mut in_buf = [0u8; 2];
-        serial.send_frame(&out_buf).await?;
-        let rxto = with_timeout(REPLY_TIMEOUT, serial.recv(&mut in_buf));
+        bus.send_frame(&out_buf).await?;
+        let rxto = bus.recv_timeout(&mut in_buf, REPLY_TIMEOUT);
 
-        match rxto.await {
+        let good = match rxto.await {
             Ok(Ok(tf)) => {
                 let frame = tf.frame;
                 let good_len = frame.len() == 1;
-                let good_hdr = good_len && frame[0] == CmdAddr::ReplyFromAddr(i as u8).into();
-                if good_hdr {
-                    nut_info!("Promoting to active {=usize} {=u64}", i, mac);
-                    p.promote_to_active();
-                } else {
-                    p.increment_error();
-                }
+                good_len && frame[0] == CmdAddr::ReplyFromAddr(i as u8).into()
             }
-            Ok(Err(_e)) => {
-                // We got some kind of receive error, just mark this as
-                // an error and move on
-                p.increment_error();
-                continue;
-            }
-            Err(TimeoutError) => {
-                // No answer? No address.
+            // We got some kind of receive error, just mark this as
+            // an error and move on
+            Ok(Err(_e)) => false,
+            // No answer? No address.
+            Err(TimeoutError) => false,
+        };
+
+        cell.lock(|p| {
+            let mut p = p.borrow_mut();
+            if good {
+                nut_info!("Promoting to active {=usize} {=u64}", i, mac.to_u64());
+                p.promote_to_active();
+            } else {
                 p.increment_error();
             }
-        }
+        });
     }
     Ok(())
 }
 
 /// A helper function for moving new nodes into the Pending stage
-async fn offer_addr<T: FrameSerial, R: RngCore>(
-    inner: &mut [Peer; MAX_TARGETS],
-    serial: &mut T,
-    rand: &mut R,
-) -> Result<(), Error<T::SerError>> {
-    let Some((i, p)) = inner.iter_mut().enumerate().find(|(_i, p)| p.is_idle()) else {
+async fn offer_addr<R: RawMutex, B: StepBus, Rand: RngCore>(
+    peers: &[PeerCell<R>; MAX_TARGETS],
+    bus: &mut B,
+    rand: &mut Rand,
+) -> Result<(), Error<B::SerError>> {
+    let Some((i, cell)) = peers
+        .iter()
+        .enumerate()
+        .find(|(_i, cell)| cell.lock(|p| p.borrow().is_idle()))
+    else {
         return Ok(());
     };
 
@@ -338,10 +911,10 @@ async fn offer_addr<T: FrameSerial, R: RngCore>(
     let mut out_buf = [0u8; 9];
     out_buf[0] = CmdAddr::DiscoveryOffer(i as u8).into();
     rand.fill_bytes(&mut out_buf[1..9]);
-    serial.send_frame(&out_buf).await?;
+    bus.send_frame(&out_buf).await?;
 
     let mut in_buf = [0u8; 10];
-    let rxto = with_timeout(Duration::from_millis(1), serial.recv(&mut in_buf));
+    let rxto = bus.recv_timeout(&mut in_buf, Duration::from_millis(1));
     match rxto.await {
         Ok(Ok(tf)) => {
             let frame = tf.frame;
@@ -357,7 +930,7 @@ async fn offer_addr<T: FrameSerial, R: RngCore>(
                     .zip(rand_iter.zip(resp_iter))
                     .for_each(|(d, (a, b))| *d = *a ^ *b);
 
-                p.promote_to_pending(u64::from_le_bytes(mac));
+                cell.lock(|p| p.borrow_mut().promote_to_pending(Mac::from_wire_bytes(mac)));
             }
         }
         Ok(Err(e)) => return Err(e),
@@ -368,3 +941,75 @@ async fn offer_addr<T: FrameSerial, R: RngCore>(
 
     Ok(())
 }
+
+/// The bus I/O of a single step, shared by [Controller::step()] and
+/// [Controller::step_blocking()]
+trait StepBus {
+    type SerError;
+
+    /// Send a single frame
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>>;
+
+    /// Receive a single frame, giving up after `timeout`
+    async fn recv_timeout<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+        timeout: Duration,
+    ) -> Result<Result<TimedFrame<'a>, Error<Self::SerError>>, TimeoutError>;
+}
+
+/// A [FrameSerial], with timeouts measured by the [Clock] `C`
+struct AsyncBus<'a, T, C> {
+    serial: &'a mut T,
+    _clock: PhantomData<fn() -> C>,
+}
+
+impl<T: FrameSerial, C: Clock> StepBus for AsyncBus<'_, T, C> {
+    type SerError = T::SerError;
+
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.serial.send_frame(frame).await
+    }
+
+    async fn recv_timeout<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+        timeout: Duration,
+    ) -> Result<Result<TimedFrame<'a>, Error<Self::SerError>>, TimeoutError> {
+        C::with_timeout(timeout, self.serial.recv(frame)).await
+    }
+}
+
+/// A [BlockingFrameSerial], whose futures are always ready on the first poll
+struct BlockingBus<'a, T>(&'a mut T);
+
+impl<T: BlockingFrameSerial> StepBus for BlockingBus<'_, T> {
+    type SerError = T::SerError;
+
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.0.send_frame(frame)
+    }
+
+    async fn recv_timeout<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+        timeout: Duration,
+    ) -> Result<Result<TimedFrame<'a>, Error<Self::SerError>>, TimeoutError> {
+        match self.0.recv_timeout(frame, timeout) {
+            Ok(Some(tf)) => Ok(Ok(tf)),
+            Ok(None) => Err(TimeoutError),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+}
+
+/// Run a future that never waits, like one only using a [BlockingBus], to completion
+fn poll_ready<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(noop_waker_ref());
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Decoding of captured bus traffic
+//!
+//! The [Decoder] is fed every frame seen on the bus, in order, and turns each
+//! one into a typed [Event]. It tracks the request/response pairing between
+//! the Controller and Targets, so it can:
+//!
+//! * Recover the MAC address of a Target from an offer and the matching claim
+//! * Report whether the discovery handshake for an address completed
+//! * Measure the latency of each Target response
+//! * Flag [Violation]s of the protocol, like replies from the wrong address
+//!
+//! Frames can come from any source, such as the [capture][crate::capture]
+//! module, or a sniffer. Only the [CmdAddr] of each frame is used to tell who
+//! sent it, so frames sent and received by a node can be mixed freely.
+
+use embassy_time::{Duration, Instant};
+
+use crate::{CmdAddr, Mac};
+
+#[cfg(feature = "postcard-rpc-helpers")]
+use postcard_rpc::WireHeader;
+
+/// The maximum number of [Violation]s reported for a single frame
+pub const MAX_VIOLATIONS: usize = 4;
+
+/// A decoded frame
+#[derive(Debug, PartialEq)]
+pub struct Event<'a> {
+    /// The timestamp of the frame
+    pub at: Instant,
+    /// What kind of frame this was
+    pub kind: EventKind,
+    /// For frames sent by a Target, the time since the Controller frame it answers
+    pub latency: Option<Duration>,
+    /// The postcard-rpc header of the payload, if there is a payload and it
+    /// starts with a valid header
+    #[cfg(feature = "postcard-rpc-helpers")]
+    pub header: Option<WireHeader>,
+    /// The frame, after the [CmdAddr] byte
+    pub payload: &'a [u8],
+    /// Any protocol violations found while decoding this frame
+    pub violations: heapless::Vec<Violation, MAX_VIOLATIONS>,
+}
+
+/// The kind of a decoded frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// The Controller selected a Target, sending zero or one data frames
+    Select {
+        /// The logical address of the Target
+        addr: u8,
+    },
+    /// A Target replied to a select, sending zero or one data frames
+    Reply {
+        /// The logical address of the Target
+        addr: u8,
+    },
+    /// The Controller offered an unused logical address
+    Offer {
+        /// The offered address
+        addr: u8,
+        /// The random challenge of the offer
+        challenge: [u8; 8],
+    },
+    /// A Target attempted to claim an offered address
+    Claim {
+        /// The claimed address
+        addr: u8,
+        /// The MAC address of the Target, recovered from the challenge of the
+        /// preceding offer, if there was one
+        mac: Option<Mac>,
+    },
+    /// The Controller confirmed a claim
+    Success {
+        /// The claimed address
+        addr: u8,
+        /// The MAC address the Controller heard in the claim
+        mac: Mac,
+    },
+    /// A Target acknowledged a [EventKind::Success], completing the discovery
+    /// handshake, and joining the bus
+    Joined {
+        /// The logical address of the Target
+        addr: u8,
+        /// The MAC address of the Target
+        mac: Mac,
+    },
+    /// A frame with a reserved command, or with no bytes at all
+    Invalid,
+}
+
+/// A violation of the protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// The frame was empty
+    Empty,
+    /// The frame used a reserved command
+    ReservedCommand(u8),
+    /// The frame was the wrong length for its command
+    BadLength {
+        /// The expected length, including the [CmdAddr] byte
+        expected: usize,
+        /// The actual length
+        got: usize,
+    },
+    /// A Target responded from a different address than was selected or offered
+    WrongAddress {
+        /// The expected address
+        expected: u8,
+        /// The actual address
+        got: u8,
+    },
+    /// A Target sent a frame that wasn't a response to the Controller
+    Unsolicited,
+    /// A selected Target, or one sent a success, didn't respond before the
+    /// Controller's next frame
+    NoResponse {
+        /// The address of the Target
+        addr: u8,
+    },
+    /// The MAC confirmed by the Controller didn't match the one recovered from
+    /// the claim
+    MacMismatch {
+        /// The MAC recovered from the claim
+        claimed: Mac,
+        /// The MAC sent in the success
+        confirmed: Mac,
+    },
+}
+
+/// What the last Controller frame expects as a response
+enum Expect {
+    Nothing,
+    Reply {
+        addr: u8,
+        at: Instant,
+    },
+    Claim {
+        addr: u8,
+        at: Instant,
+        challenge: [u8; 8],
+    },
+    Ack {
+        addr: u8,
+        at: Instant,
+        mac: Mac,
+    },
+}
+
+/// A stateful decoder of bus traffic
+pub struct Decoder {
+    expect: Expect,
+    last_claim: Option<(u8, Mac)>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    /// Create a new decoder
+    pub const fn new() -> Self {
+        Self {
+            expect: Expect::Nothing,
+            last_claim: None,
+        }
+    }
+
+    /// Decode the next frame seen on the bus
+    pub fn feed<'a>(&mut self, at: Instant, frame: &'a [u8]) -> Event<'a> {
+        let mut ev = Event {
+            at,
+            kind: EventKind::Invalid,
+            latency: None,
+            #[cfg(feature = "postcard-rpc-helpers")]
+            header: None,
+            payload: frame.get(1..).unwrap_or(&[]),
+            violations: heapless::Vec::new(),
+        };
+
+        let Some(&first) = frame.first() else {
+            ev.flag(Violation::Empty);
+            return ev;
+        };
+        let Ok(ca) = CmdAddr::try_from(first) else {
+            ev.flag(Violation::ReservedCommand(first >> 5));
+            return ev;
+        };
+
+        match ca {
+            CmdAddr::SelectAddr(addr) => {
+                self.controller_frame(&mut ev);
+                self.expect = Expect::Reply { addr, at };
+                ev.kind = EventKind::Select { addr };
+                ev.decode_header();
+            }
+            CmdAddr::DiscoveryOffer(addr) => {
+                self.controller_frame(&mut ev);
+                // Only claims of this offer are compared with the next success
+                self.last_claim = None;
+                let mut challenge = [0u8; 8];
+                if ev.check_len(frame.len(), 9) {
+                    challenge.copy_from_slice(&frame[1..9]);
+                    self.expect = Expect::Claim {
+                        addr,
+                        at,
+                        challenge,
+                    };
+                }
+                ev.kind = EventKind::Offer { addr, challenge };
+            }
+            CmdAddr::DiscoverySuccess(addr) => {
+                self.controller_frame(&mut ev);
+                let mut mac = Mac::new(0);
+                if ev.check_len(frame.len(), 9) {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(&frame[1..9]);
+                    mac = Mac::from_wire_bytes(bytes);
+                    self.expect = Expect::Ack { addr, at, mac };
+                    match self.last_claim {
+                        Some((claim_addr, claimed)) if claim_addr == addr => {
+                            self.last_claim = None;
+                            if claimed != mac {
+                                ev.flag(Violation::MacMismatch {
+                                    claimed,
+                                    confirmed: mac,
+                                });
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                ev.kind = EventKind::Success { addr, mac };
+            }
+            CmdAddr::ReplyFromAddr(addr) => {
+                ev.kind = EventKind::Reply { addr };
+                match core::mem::replace(&mut self.expect, Expect::Nothing) {
+                    Expect::Reply {
+                        addr: expected,
+                        at: sent,
+                    } => {
+                        ev.latency = at.checked_duration_since(sent);
+                        ev.check_addr(expected, addr);
+                        ev.decode_header();
+                    }
+                    Expect::Ack {
+                        addr: expected,
+                        at: sent,
+                        mac,
+                    } => {
+                        ev.latency = at.checked_duration_since(sent);
+                        let good_addr = ev.check_addr(expected, addr);
+                        let good_len = ev.check_len(frame.len(), 1);
+                        if good_addr && good_len {
+                            ev.kind = EventKind::Joined { addr, mac };
+                        }
+                    }
+                    Expect::Claim { .. } | Expect::Nothing => ev.flag(Violation::Unsolicited),
+                }
+            }
+            CmdAddr::DiscoveryClaim(addr) => {
+                let mut mac = None;
+                match core::mem::replace(&mut self.expect, Expect::Nothing) {
+                    Expect::Claim {
+                        addr: expected,
+                        at: sent,
+                        challenge,
+                    } => {
+                        ev.latency = at.checked_duration_since(sent);
+                        let good_addr = ev.check_addr(expected, addr);
+                        if ev.check_len(frame.len(), 9) && good_addr {
+                            let mut bytes = [0u8; 8];
+                            bytes
+                                .iter_mut()
+                                .zip(frame[1..9].iter().zip(challenge.iter()))
+                                .for_each(|(d, (a, b))| *d = *a ^ *b);
+                            let recovered = Mac::from_wire_bytes(bytes);
+                            self.last_claim = Some((addr, recovered));
+                            mac = Some(recovered);
+                        }
+                    }
+                    _ => ev.flag(Violation::Unsolicited),
+                }
+                ev.kind = EventKind::Claim { addr, mac };
+            }
+        }
+
+        ev
+    }
+
+    /// Handle the start of a new Controller frame, checking whether the last
+    /// one was answered when it should have been
+    fn controller_frame(&mut self, ev: &mut Event<'_>) {
+        match core::mem::replace(&mut self.expect, Expect::Nothing) {
+            Expect::Reply { addr, .. } | Expect::Ack { addr, .. } => {
+                ev.flag(Violation::NoResponse { addr })
+            }
+            // Offers are often not claimed, this is not an error
+            Expect::Claim { .. } | Expect::Nothing => {}
+        }
+    }
+}
+
+impl Event<'_> {
+    fn flag(&mut self, v: Violation) {
+        // Only the first MAX_VIOLATIONS are kept
+        let _ = self.violations.push(v);
+    }
+
+    fn check_len(&mut self, got: usize, expected: usize) -> bool {
+        let good = got == expected;
+        if !good {
+            self.flag(Violation::BadLength { expected, got });
+        }
+        good
+    }
+
+    fn check_addr(&mut self, expected: u8, got: u8) -> bool {
+        let good = got == expected;
+        if !good {
+            self.flag(Violation::WrongAddress { expected, got });
+        }
+        good
+    }
+
+    fn decode_header(&mut self) {
+        #[cfg(feature = "postcard-rpc-helpers")]
+        if !self.payload.is_empty() {
+            self.header = postcard_rpc::headered::extract_header_from_bytes(self.payload)
+                .ok()
+                .map(|(wh, _body)| wh);
+        }
+    }
+}
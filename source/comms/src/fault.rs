@@ -0,0 +1,296 @@
+//! Fault injection for robustness testing
+//!
+//! [FaultySerial] wraps any [FrameSerial], and randomly corrupts the traffic
+//! passing through it, as described by a [FaultPolicy]. The randomness comes
+//! from a caller provided [RngCore], so a seeded RNG gives a reproducible
+//! sequence of faults.
+//!
+//! The following faults can be injected, each with its own [Chance]:
+//!
+//! * Dropping a sent or received frame
+//! * Flipping a single bit of a frame
+//! * Truncating a frame
+//! * Inserting a spurious line break, splitting a received frame in two
+//! * Delaying the completion of a receive, e.g. past the Controller's
+//!   [REPLY_TIMEOUT][crate::controller::REPLY_TIMEOUT]
+//! * Failing with an injected serial error
+//!
+//! Outgoing faults are applied to a copy of the frame, so frames longer than
+//! [DEFAULT_FRAME_SIZE] are only ever dropped, or sent unmodified. Likewise,
+//! received frames longer than [DEFAULT_FRAME_SIZE] are never split by a
+//! spurious break, as the second half is kept until the next receive.
+
+use core::marker::PhantomData;
+
+use embassy_time::Duration;
+use rand_core::RngCore;
+
+use crate::{
+    addr_mark::AddrFilter,
+    clock::{Clock, EmbassyClock},
+    frame_pool::DEFAULT_FRAME_SIZE,
+    Error, FrameSerial, TimedFrame,
+};
+
+/// The probability of a fault being injected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chance(u32);
+
+impl Chance {
+    /// The fault is never injected
+    pub const NEVER: Self = Self(0);
+    /// The fault is always injected
+    pub const ALWAYS: Self = Self(u32::MAX);
+
+    /// The fault is injected `pct` percent of the time, saturating at 100
+    pub const fn percent(pct: u32) -> Self {
+        if pct >= 100 {
+            return Self::ALWAYS;
+        }
+        Self(((pct as u64 * (1 << 32)) / 100) as u32)
+    }
+
+    /// The fault is injected once in every `n` opportunities, on average
+    pub const fn one_in(n: u32) -> Self {
+        match n {
+            0 => Self::NEVER,
+            1 => Self::ALWAYS,
+            n => Self(u32::MAX / n),
+        }
+    }
+}
+
+/// The faults to inject, and how often
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultPolicy {
+    /// Silently drop an outgoing frame, without sending it
+    pub drop_tx: Chance,
+    /// Discard a received frame, and wait for the next one
+    pub drop_rx: Chance,
+    /// Flip one random bit of a frame, in either direction
+    pub flip_bit: Chance,
+    /// Cut a frame short at a random length, in either direction
+    pub truncate: Chance,
+    /// Split a received frame of up to [DEFAULT_FRAME_SIZE] bytes in two, as if
+    /// a line break occurred in the middle
+    pub spurious_break: Chance,
+    /// Wait for [FaultPolicy::delay] before completing a receive
+    pub delay_rx: Chance,
+    /// How long to delay a receive by
+    pub delay: Duration,
+    /// Fail a send or receive with [FaultError::Injected]
+    pub serial_error: Chance,
+}
+
+impl Default for FaultPolicy {
+    /// A policy that never injects any faults
+    fn default() -> Self {
+        Self {
+            drop_tx: Chance::NEVER,
+            drop_rx: Chance::NEVER,
+            flip_bit: Chance::NEVER,
+            truncate: Chance::NEVER,
+            spurious_break: Chance::NEVER,
+            delay_rx: Chance::NEVER,
+            delay: Duration::from_millis(5),
+            serial_error: Chance::NEVER,
+        }
+    }
+}
+
+/// The number of faults injected so far, of each kind
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FaultStats {
+    /// Number of outgoing frames dropped
+    pub dropped_tx: u32,
+    /// Number of received frames dropped
+    pub dropped_rx: u32,
+    /// Number of frames with a flipped bit
+    pub flipped: u32,
+    /// Number of truncated frames
+    pub truncated: u32,
+    /// Number of received frames split in two
+    pub breaks: u32,
+    /// Number of delayed receives
+    pub delayed: u32,
+    /// Number of injected serial errors
+    pub errors: u32,
+}
+
+/// The error type of a [FaultySerial]
+#[derive(Debug, PartialEq)]
+pub enum FaultError<E> {
+    /// An error from the wrapped serial port
+    Inner(E),
+    /// An error injected by the [FaultPolicy]
+    Injected,
+}
+
+/// A [FrameSerial] that injects faults into the traffic of the wrapped serial port
+///
+/// Receive delays are measured with the [Clock] `C`.
+pub struct FaultySerial<T: FrameSerial, Rng: RngCore, C: Clock = EmbassyClock> {
+    serial: T,
+    rng: Rng,
+    policy: FaultPolicy,
+    stats: FaultStats,
+    /// The second half of a received frame split by a spurious break
+    split_tail: heapless::Vec<u8, DEFAULT_FRAME_SIZE>,
+    split_at: embassy_time::Instant,
+    _clock: PhantomData<fn() -> C>,
+}
+
+#[cfg(feature = "embassy-clock")]
+impl<T: FrameSerial, Rng: RngCore> FaultySerial<T, Rng> {
+    /// Wrap `serial` like [FaultySerial::with_clock()], with delays measured by
+    /// the [EmbassyClock]
+    pub fn new(serial: T, rng: Rng, policy: FaultPolicy) -> Self {
+        Self::with_clock(serial, rng, policy)
+    }
+}
+
+impl<T: FrameSerial, Rng: RngCore, C: Clock> FaultySerial<T, Rng, C> {
+    /// Wrap `serial`, injecting faults according to `policy`, with delays
+    /// measured by the [Clock] `C`
+    pub fn with_clock(serial: T, rng: Rng, policy: FaultPolicy) -> Self {
+        Self {
+            serial,
+            rng,
+            policy,
+            stats: FaultStats::default(),
+            split_tail: heapless::Vec::new(),
+            split_at: embassy_time::Instant::from_ticks(0),
+            _clock: PhantomData,
+        }
+    }
+
+    /// Replace the fault policy, e.g. to stop injecting faults
+    pub fn set_policy(&mut self, policy: FaultPolicy) {
+        self.policy = policy;
+    }
+
+    /// The faults injected so far
+    pub fn stats(&self) -> &FaultStats {
+        &self.stats
+    }
+
+    /// Consume the wrapper, returning the serial port
+    pub fn into_inner(self) -> T {
+        self.serial
+    }
+
+    fn roll(&mut self, chance: Chance) -> bool {
+        match chance {
+            Chance::NEVER => false,
+            Chance::ALWAYS => true,
+            Chance(c) => self.rng.next_u32() < c,
+        }
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.rng.next_u32() as usize) % n
+    }
+
+    /// Apply the bit flip and truncation faults to a frame, returning its new length
+    fn corrupt(&mut self, frame: &mut [u8]) -> usize {
+        let mut len = frame.len();
+        if len == 0 {
+            return 0;
+        }
+        if self.roll(self.policy.flip_bit) {
+            let bit = self.below(len * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+            self.stats.flipped += 1;
+        }
+        if self.roll(self.policy.truncate) {
+            len = self.below(len);
+            self.stats.truncated += 1;
+        }
+        len
+    }
+}
+
+impl<T: FrameSerial, Rng: RngCore, C: Clock> FrameSerial for FaultySerial<T, Rng, C> {
+    type SerError = FaultError<T::SerError>;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        if self.roll(self.policy.serial_error) {
+            self.stats.errors += 1;
+            return Err(Error::Serial(FaultError::Injected));
+        }
+        if self.roll(self.policy.drop_tx) {
+            self.stats.dropped_tx += 1;
+            return Ok(());
+        }
+
+        let mut copy = heapless::Vec::<u8, DEFAULT_FRAME_SIZE>::new();
+        let res = match copy.extend_from_slice(data) {
+            Ok(()) => {
+                let len = self.corrupt(&mut copy);
+                self.serial.send_frame(&copy[..len]).await
+            }
+            Err(()) => self.serial.send_frame(data).await,
+        };
+        res.map_err(|Error::Serial(e)| Error::Serial(FaultError::Inner(e)))
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        // Deliver the rest of a frame split by a spurious break
+        if !self.split_tail.is_empty() {
+            let len = self.split_tail.len().min(frame.len());
+            frame[..len].copy_from_slice(&self.split_tail[..len]);
+            self.split_tail.clear();
+            return Ok(TimedFrame {
+                end_of_rx: self.split_at,
+                frame: &mut frame[..len],
+            });
+        }
+
+        if self.roll(self.policy.serial_error) {
+            self.stats.errors += 1;
+            return Err(Error::Serial(FaultError::Injected));
+        }
+
+        let (end_of_rx, mut len) = loop {
+            let tf = self
+                .serial
+                .recv(frame)
+                .await
+                .map_err(|Error::Serial(e)| Error::Serial(FaultError::Inner(e)))?;
+            let got = (tf.end_of_rx, tf.frame.len());
+            if self.roll(self.policy.drop_rx) {
+                self.stats.dropped_rx += 1;
+                continue;
+            }
+            break got;
+        };
+
+        len = self.corrupt(&mut frame[..len]);
+
+        // The tail is shorter than the frame, so it always fits
+        if len > 1 && len <= DEFAULT_FRAME_SIZE && self.roll(self.policy.spurious_break) {
+            let at = 1 + self.below(len - 1);
+            let _ = self.split_tail.extend_from_slice(&frame[at..len]);
+            self.split_at = end_of_rx;
+            len = at;
+            self.stats.breaks += 1;
+        }
+
+        if self.roll(self.policy.delay_rx) {
+            self.stats.delayed += 1;
+            C::wait(self.policy.delay).await;
+        }
+
+        Ok(TimedFrame {
+            end_of_rx,
+            frame: &mut frame[..len],
+        })
+    }
+
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        self.serial.set_addr_filter(filter);
+    }
+}
@@ -4,53 +4,126 @@
 //! opinionated for the following cases:
 //!
 //! * Use where you want to store multiple chunks of bytes,
-//!   in the size range of 1..=255 bytes
+//!   in the size range of `1..=S` bytes, where `S` is chosen per-pool,
+//!   and defaults to [DEFAULT_FRAME_SIZE] (255) bytes
 //! * Use in cases where the target may not have CAS atomics,
 //!   so only `load` and `stores` are used for synchronization
 //!
+//! The Controller, Target, and Sniffer roles, the bridge, and the
+//! `wirehelp` builders all exchange frames of [DEFAULT_FRAME_SIZE]. Pools
+//! with other frame sizes are for an application's own buffering, for
+//! example of small acknowledgements, or of bulk data that is split into
+//! bus-sized frames before sending.
+//!
 //! This allows for the creation of [`FrameBox`] allocations, that
 //! can only be allocated with exclusive access to a [`RawFrameSlice`],
 //! but can be deallocated by dropping (just like a Box from the standard
 //! library), and do not require any kind of mutex at the time of drop.
+//! Allocating is `O(1)`, and so is counting the free frames of a slice
+//! while none of them are lent out.
+//!
+//! If a task is waiting for a free frame of a [`RawFrameSlice`] with
+//! [`RawFrameSlice::allocate()`], dropping a [`FrameBox`] of that slice will
+//! additionally wake that task, which takes a short
+//! [critical section][critical_section::with].
+//!
+//! ## Diagnostics
+//!
+//! [`RawFrameSlice::stats()`] reports the current usage, high-water mark,
+//! and number of failed allocations of a slice.
+//!
+//! With the `pool-debug` feature enabled, every allocation additionally
+//! records a timestamp and an optional [`OwnerTag`], which can be set with
+//! [`FrameBox::set_owner()`] as a frame is passed between subsystems. The
+//! outstanding allocations of a slice can then be listed with
+//! [`RawFrameSlice::outstanding()`], or filtered to those held for too long
+//! with [`RawFrameSlice::held_longer_than()`].
 
+#[cfg(feature = "pool-debug")]
+use core::sync::atomic::AtomicU32;
 use core::{
+    cell::Cell,
+    future::poll_fn,
     ops::{Deref, DerefMut},
     ptr::{addr_of, addr_of_mut, NonNull},
-    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU16, Ordering},
+    task::{Poll, Waker},
     unreachable,
 };
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Duration;
+#[cfg(feature = "pool-debug")]
+use embassy_time::Instant;
 use grounded::{const_init::ConstInit, uninit::GroundedArrayCell};
 
-use crate::CmdAddr;
+#[cfg(feature = "embassy-clock")]
+use crate::clock::EmbassyClock;
+use crate::{clock::Clock, CmdAddr};
+
+/// The default capacity, in bytes, of a single frame
+pub const DEFAULT_FRAME_SIZE: usize = 255;
 
-/// Storage for exactly N frames
-pub struct FrameStorage<const N: usize> {
-    frames: GroundedArrayCell<RawFrame, N>,
+/// Storage for exactly N frames, each able to hold up to S bytes
+///
+/// Different pools may use different frame sizes, for example a pool of
+/// small frames for ACK-sized traffic, and a pool of larger frames for
+/// bulk data:
+///
+/// ```rust
+/// use erdnuss_comms::frame_pool::FrameStorage;
+///
+/// static SMALL: FrameStorage<32, 16> = FrameStorage::new();
+/// static LARGE: FrameStorage<4, 1024> = FrameStorage::new();
+/// ```
+pub struct FrameStorage<const N: usize, const S: usize = DEFAULT_FRAME_SIZE> {
+    frames: GroundedArrayCell<RawFrame<S>, N>,
     once: AtomicBool,
 }
 
-impl<const N: usize> FrameStorage<N> {
+impl<const N: usize, const S: usize> Default for FrameStorage<N, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const S: usize> FrameStorage<N, S> {
     /// Create a new frame storage buffer
     ///
     /// Intended for static usage.
+    ///
+    /// ## Panics
+    ///
+    /// `S` must be >= 1 and <= 65535 or this function will panic. When used
+    /// to initialize a `static`, this is checked at compile time.
     pub const fn new() -> Self {
+        assert!(S >= 1 && S <= RawFrame::<S>::LEN_LIMIT);
         Self {
             frames: GroundedArrayCell::const_init(),
             once: AtomicBool::new(false),
         }
     }
 
+    /// Attempt to take the storage like [FrameStorage::take_with_clock()],
+    /// timestamping allocations with the [EmbassyClock]
+    #[cfg(feature = "embassy-clock")]
+    pub fn take(&'static self) -> Option<RawFrameSlice<S>> {
+        self.take_with_clock::<EmbassyClock>()
+    }
+
     /// Attempt to take the storage as a [RawFrameSlice]
     ///
     /// The first call will return Some, all later calls will
     /// return None. Uses a [critical section][critical_section::with]
-    /// to ensure it only works once, even on targets without atomics
-    pub fn take(&'static self) -> Option<RawFrameSlice> {
+    /// to ensure it only works once, even on targets without atomics.
+    ///
+    /// With the `pool-debug` feature, allocations are timestamped with the
+    /// [Clock] `C`.
+    pub fn take_with_clock<C: Clock>(&'static self) -> Option<RawFrameSlice<S>> {
         self.take_gac()
-            .map(|s| unsafe { RawFrameSlice::from_static(s) })
+            .map(|s| unsafe { RawFrameSlice::from_static::<N, C>(s) })
     }
 
-    fn take_gac(&'static self) -> Option<&'static GroundedArrayCell<RawFrame, N>> {
+    fn take_gac(&'static self) -> Option<&'static GroundedArrayCell<RawFrame<S>, N>> {
         critical_section::with(|_| {
             let old = self.once.load(Ordering::Acquire);
             self.once.store(true, Ordering::Release);
@@ -58,6 +131,112 @@ impl<const N: usize> FrameStorage<N> {
         })
         .then_some(&self.frames)
     }
+
+    /// Count the number of frames currently allocated, across all
+    /// [RawFrameSlice]s taken from this storage.
+    ///
+    /// This inspects every frame, and is `O(N)`.
+    pub fn in_use(&self) -> usize {
+        let start: *mut RawFrame<S> = self.frames.as_mut_ptr();
+        (0..N)
+            .filter(|idx| {
+                // SAFETY: idx is in bounds, and we only access the atomic field
+                let fl: &AtomicU16 = unsafe { &*addr_of!((*start.add(*idx)).freelen) };
+                fl.load(Ordering::Acquire) != RawFrame::<S>::FREE
+            })
+            .count()
+    }
+}
+
+/// Usage statistics of a [RawFrameSlice]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PoolStats {
+    /// The total number of frames in the slice
+    pub capacity: usize,
+    /// The number of frames currently allocated
+    pub in_use: usize,
+    /// The largest number of frames allocated at once, since creation
+    /// or the last call to [RawFrameSlice::reset_stats()]
+    pub high_water: usize,
+    /// The number of calls to [RawFrameSlice::allocate_raw()] that
+    /// returned [None]
+    pub alloc_failures: usize,
+}
+
+/// A tag identifying the current owner of a [FrameBox]
+///
+/// Only available with the `pool-debug` feature. Tags are intended to
+/// be declared as statics, so they can be cheaply stored in every frame:
+///
+/// ```rust
+/// use erdnuss_comms::frame_pool::OwnerTag;
+///
+/// static USB_TX: OwnerTag = OwnerTag("usb-tx");
+/// ```
+#[cfg(feature = "pool-debug")]
+#[derive(Debug, PartialEq)]
+pub struct OwnerTag(pub &'static str);
+
+/// Information about a single outstanding allocation
+///
+/// Only available with the `pool-debug` feature.
+#[cfg(feature = "pool-debug")]
+#[derive(Debug, Clone, Copy)]
+pub struct Outstanding {
+    /// The index of the frame within its [RawFrameSlice]
+    pub index: usize,
+    /// The most recent owner set with [FrameBox::set_owner()], if any
+    pub owner: Option<&'static OwnerTag>,
+    /// The time at which the frame was allocated, from the [Clock][crate::clock::Clock]
+    /// given to [FrameStorage::take_with_clock()]
+    pub allocated_at: Instant,
+}
+
+/// The waker of a single [RawFrameSlice]
+///
+/// Every frame has one, but only the one of the first frame of each slice is
+/// used. As [RawFrameSlice::allocate()] takes the slice mutably, at most one
+/// task waits on each slice, and a newly registered waker can replace the
+/// old one without waking it. Only [FrameBox::drop()] wakes the task.
+///
+/// `waiting` is only set while a task has a registered waker, which
+/// allows [FrameBox]es to skip the critical section on drop in the
+/// common case where nobody is waiting.
+pub(crate) struct PoolWaker {
+    waiting: AtomicBool,
+    waker: Mutex<CriticalSectionRawMutex, Cell<Option<Waker>>>,
+}
+
+impl PoolWaker {
+    const fn new() -> Self {
+        Self {
+            waiting: AtomicBool::new(false),
+            waker: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    fn register(&self, new: &Waker) {
+        self.waker.lock(|w| {
+            self.waiting.store(true, Ordering::Relaxed);
+            match w.take() {
+                Some(old) if old.will_wake(new) => w.set(Some(old)),
+                _ => w.set(Some(new.clone())),
+            }
+        });
+    }
+
+    fn wake(&self) {
+        if !self.waiting.load(Ordering::Relaxed) {
+            return;
+        }
+        let old = self.waker.lock(|w| {
+            self.waiting.store(false, Ordering::Relaxed);
+            w.take()
+        });
+        if let Some(old) = old {
+            old.wake();
+        }
+    }
 }
 
 /// The Rules:
@@ -75,24 +254,52 @@ impl<const N: usize> FrameStorage<N> {
 /// ONLY the FrameBox is allowed to make the nonzero -> zero transition.
 /// Setting freelen to zero represents giving up exclusive access to the
 /// contents of the data field.
+///
+/// `link` is ONLY ever accessed by the RawFrameSlice that owns this frame,
+/// regardless of the state of `freelen`. It holds the index (relative to the
+/// start of the slice) of the next frame in whichever of the slice's lists
+/// this frame is currently in. The FrameBox never touches it.
+///
+/// `home` points to the `waker` of the first frame of the RawFrameSlice that
+/// owns this frame. It is ONLY written by the RawFrameSlice, while allocating
+/// and when splitting, and is read by the FrameBox on drop. A drop racing
+/// with a split may wake the slice the frame was split from, which is
+/// harmless, as neither half can be waiting while it is being split.
+///
+/// `waker` is ONLY used in the first frame of each RawFrameSlice, see
+/// [PoolWaker].
+///
+/// With the `pool-debug` feature:
+///
+/// * `alloc_ticks` is ONLY written by the RawFrameSlice, while allocating.
+/// * `owner` is written by the RawFrameSlice while allocating, and by the
+///   FrameBox while allocated. It is a single pointer, so readers never
+///   observe a torn value.
 #[repr(C)]
-pub(crate) struct RawFrame {
-    data: [u8; 255],
-    freelen: AtomicU8,
+pub(crate) struct RawFrame<const S: usize> {
+    data: [u8; S],
+    freelen: AtomicU16,
+    link: AtomicU16,
+    home: AtomicPtr<PoolWaker>,
+    waker: PoolWaker,
+    #[cfg(feature = "pool-debug")]
+    owner: AtomicPtr<OwnerTag>,
+    #[cfg(feature = "pool-debug")]
+    alloc_ticks: [AtomicU32; 2],
 }
 
 /// An allocated frame storage
 ///
-/// Stores `1..=255` bytes. Storage can be accessed through the
+/// Stores `1..=S` bytes. Storage can be accessed through the
 /// [Deref] and [DerefMut] traits.
-pub struct FrameBox {
-    ptr: NonNull<RawFrame>,
+pub struct FrameBox<const S: usize = DEFAULT_FRAME_SIZE> {
+    ptr: NonNull<RawFrame<S>>,
 }
 
-unsafe impl Send for FrameBox {}
+unsafe impl<const S: usize> Send for FrameBox<S> {}
 
-impl FrameBox {
-    unsafe fn freelen_ref(&self) -> &AtomicU8 {
+impl<const S: usize> FrameBox<S> {
+    unsafe fn freelen_ref(&self) -> &AtomicU16 {
         let fl_ptr = addr_of!((*self.ptr.as_ptr()).freelen);
         &*fl_ptr
     }
@@ -101,19 +308,35 @@ impl FrameBox {
     ///
     /// ## Panics
     ///
-    /// `len` must be >= 1 and <= 255 or this function will panic
+    /// `len` must be >= 1 and <= `S` or this function will panic
     pub fn set_len(&mut self, len: usize) {
-        if len == 0 || len > 255 {
+        if len == 0 || len > S {
             unreachable!()
         }
         unsafe {
             let fl = self.freelen_ref();
-            fl.store(len as u8, Ordering::Relaxed);
+            fl.store(len as u16, Ordering::Relaxed);
         }
     }
+
+    /// The maximum number of bytes this frame can hold
+    #[inline]
+    pub const fn capacity(&self) -> usize {
+        S
+    }
+
+    /// Record the subsystem that currently owns this frame
+    ///
+    /// Only available with the `pool-debug` feature.
+    #[cfg(feature = "pool-debug")]
+    pub fn set_owner(&mut self, owner: &'static OwnerTag) {
+        // SAFETY: We only access the atomic owner field
+        let owner_ref: &AtomicPtr<OwnerTag> = unsafe { &*addr_of!((*self.ptr.as_ptr()).owner) };
+        owner_ref.store((owner as *const OwnerTag).cast_mut(), Ordering::Relaxed);
+    }
 }
 
-impl Deref for FrameBox {
+impl<const S: usize> Deref for FrameBox<S> {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
@@ -123,14 +346,14 @@ impl Deref for FrameBox {
         let len = unsafe { self.freelen_ref().load(Ordering::Relaxed) };
         assert!(len != 0);
         let data_ptr: *const u8 = unsafe {
-            let arr_ptr: *const [u8; 255] = addr_of!((*self.ptr.as_ptr()).data);
+            let arr_ptr: *const [u8; S] = addr_of!((*self.ptr.as_ptr()).data);
             arr_ptr.cast()
         };
         unsafe { core::slice::from_raw_parts(data_ptr, len as usize) }
     }
 }
 
-impl DerefMut for FrameBox {
+impl<const S: usize> DerefMut for FrameBox<S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         // Ordering can be relaxed as we have exclusive access to the
         // backing storage, and exclusive WRITE access to freelen as
@@ -138,63 +361,123 @@ impl DerefMut for FrameBox {
         let len = unsafe { self.freelen_ref().load(Ordering::Relaxed) };
         assert!(len != 0);
         let data_ptr: *mut u8 = unsafe {
-            let arr_ptr: *mut [u8; 255] = addr_of_mut!((*self.ptr.as_ptr()).data);
+            let arr_ptr: *mut [u8; S] = addr_of_mut!((*self.ptr.as_ptr()).data);
             arr_ptr.cast()
         };
         unsafe { core::slice::from_raw_parts_mut(data_ptr, len as usize) }
     }
 }
 
-impl Drop for FrameBox {
+impl<const S: usize> Drop for FrameBox<S> {
     fn drop(&mut self) {
-        let ptr: *mut RawFrame = self.ptr.as_ptr();
+        let ptr: *mut RawFrame<S> = self.ptr.as_ptr();
         // SAFETY: FrameBox represents ownership of `data`, and we have the right
-        // to release on drop
+        // to release on drop. `home` always points to a waker of the same storage
+        // while the frame is allocated.
         unsafe {
-            let atom_ptr: *mut AtomicU8 = addr_of_mut!((*ptr).freelen);
-            let atom: &AtomicU8 = &*atom_ptr;
-            atom.store(RawFrame::FREE, Ordering::Release);
+            let atom: &AtomicU16 = &*addr_of!((*ptr).freelen);
+            atom.store(RawFrame::<S>::FREE, Ordering::Release);
+
+            // Make sure our release is visible before we check whether anyone is
+            // waiting, pairs with the fence in `RawFrameSlice::allocate()`.
+            fence(Ordering::SeqCst);
+            let home: &AtomicPtr<PoolWaker> = &*addr_of!((*ptr).home);
+            (*home.load(Ordering::Relaxed)).wake();
         }
     }
 }
 
-impl RawFrame {
-    const FREE: u8 = 0;
-    const MAX_LEN: u8 = 255;
+impl<const S: usize> RawFrame<S> {
+    const FREE: u16 = 0;
+    const MAX_LEN: u16 = S as u16;
+    /// The largest `S` that can be represented by `freelen`
+    const LEN_LIMIT: usize = u16::MAX as usize;
+    /// Sentinel `link` value marking the end of a list
+    const NIL: u16 = u16::MAX;
 }
 
-impl ConstInit for RawFrame {
+impl<const S: usize> ConstInit for RawFrame<S> {
     #[allow(clippy::declare_interior_mutable_const)]
     const VAL: Self = RawFrame {
-        data: [0u8; Self::MAX_LEN as usize],
-        freelen: AtomicU8::new(0),
+        data: [0u8; S],
+        freelen: AtomicU16::new(0),
+        link: AtomicU16::new(Self::NIL),
+        home: AtomicPtr::new(core::ptr::null_mut()),
+        waker: PoolWaker::new(),
+        #[cfg(feature = "pool-debug")]
+        owner: AtomicPtr::new(core::ptr::null_mut()),
+        #[cfg(feature = "pool-debug")]
+        alloc_ticks: [AtomicU32::new(0), AtomicU32::new(0)],
     };
 }
 
-unsafe impl Send for RawFrameSlice {}
+unsafe impl<const S: usize> Send for RawFrameSlice<S> {}
 
 /// A sliceable allocation pool
 ///
-/// Can be created via [FrameStorage::take()], or by splitting
+/// Can be created via [FrameStorage::take_with_clock()], or by splitting
 /// via [RawFrameSlice::split()].
-pub struct RawFrameSlice {
-    start: NonNull<RawFrame>,
+///
+/// The slice keeps two intrusive lists threaded through the `link` field
+/// of its frames:
+///
+/// * The "free" stack, which contains frames the slice KNOWS are free.
+///   Allocation pops from this stack, and is `O(1)`.
+/// * The "lent" list, which contains every frame handed out as a
+///   [FrameBox] that has not yet been reclaimed.
+///
+/// As a [FrameBox] can be dropped anywhere (including other threads or
+/// interrupts), and we only have `load` and `store` available, dropping
+/// a [FrameBox] cannot push it back onto the free stack, or update any
+/// count shared with other frames. Instead, the drop only clears the
+/// `freelen` of that frame, which marks it as released. The slice picks up
+/// these marks lazily, moving released frames from the "lent" list back to
+/// the "free" stack only once the free stack runs dry. This costs `O(k)`,
+/// where `k` is the number of frames currently lent out, NOT the capacity
+/// of the slice, and is shared by the `k` following allocations.
+pub struct RawFrameSlice<const S: usize = DEFAULT_FRAME_SIZE> {
+    start: NonNull<RawFrame<S>>,
     len: usize,
-    next_idx: usize,
+    free_head: u16,
+    free_ct: usize,
+    lent_head: u16,
+    lent_ct: usize,
+    high_water: usize,
+    alloc_failures: usize,
+    waker: Option<&'static PoolWaker>,
+    /// The clock used to timestamp allocations
+    #[cfg(feature = "pool-debug")]
+    now: Option<fn() -> Instant>,
 }
 
-impl RawFrameSlice {
+impl<const S: usize> RawFrameSlice<S> {
     /// ## Safety
     ///
     /// You must only ever call this once
-    pub(crate) unsafe fn from_static<const N: usize>(
-        buf: &'static GroundedArrayCell<RawFrame, N>,
+    #[cfg_attr(
+        not(feature = "pool-debug"),
+        allow(clippy::extra_unused_type_parameters)
+    )]
+    pub(crate) unsafe fn from_static<const N: usize, C: Clock>(
+        buf: &'static GroundedArrayCell<RawFrame<S>, N>,
     ) -> Self {
-        Self {
-            start: NonNull::new_unchecked(buf.as_mut_ptr()),
+        assert!(N < RawFrame::<S>::NIL as usize);
+        let start: *mut RawFrame<S> = buf.as_mut_ptr();
+        let mut me = Self {
+            start: NonNull::new_unchecked(start),
             len: N,
-            next_idx: 0,
-        }
+            free_head: RawFrame::<S>::NIL,
+            free_ct: 0,
+            lent_head: RawFrame::<S>::NIL,
+            lent_ct: 0,
+            high_water: 0,
+            alloc_failures: 0,
+            waker: (N != 0).then(|| &*addr_of!((*start).waker)),
+            #[cfg(feature = "pool-debug")]
+            now: Some(C::now),
+        };
+        me.rebuild_lists();
+        me
     }
 
     /// Create a new, empty [RawFrameSlice] that has no
@@ -203,29 +486,136 @@ impl RawFrameSlice {
         Self {
             start: NonNull::dangling(),
             len: 0,
-            next_idx: 0,
+            free_head: RawFrame::<S>::NIL,
+            free_ct: 0,
+            lent_head: RawFrame::<S>::NIL,
+            lent_ct: 0,
+            high_water: 0,
+            alloc_failures: 0,
+            waker: None,
+            #[cfg(feature = "pool-debug")]
+            now: None,
         }
     }
 
-    /// Count the number of allocatable items
-    pub fn count_allocatable(&self) -> usize {
-        if self.len == 0 {
-            return 0;
+    /// Obtain the `freelen` field of the frame at `idx`.
+    ///
+    /// ## Safety
+    ///
+    /// `idx` must be `< self.len`.
+    unsafe fn freelen_at(&self, idx: u16) -> &AtomicU16 {
+        let ptr: *mut RawFrame<S> = self.start.as_ptr().add(idx as usize);
+        let atom_ptr: *const AtomicU16 = addr_of!((*ptr).freelen);
+        &*atom_ptr
+    }
+
+    /// Obtain the `link` field of the frame at `idx`.
+    ///
+    /// ## Safety
+    ///
+    /// `idx` must be `< self.len`.
+    unsafe fn link_at(&self, idx: u16) -> &AtomicU16 {
+        let ptr: *mut RawFrame<S> = self.start.as_ptr().add(idx as usize);
+        let atom_ptr: *const AtomicU16 = addr_of!((*ptr).link);
+        &*atom_ptr
+    }
+
+    /// Push the frame at `idx` onto the free stack
+    ///
+    /// ## Safety
+    ///
+    /// `idx` must be `< self.len`, must be free, and must not be in any list
+    unsafe fn push_free(&mut self, idx: u16) {
+        self.link_at(idx).store(self.free_head, Ordering::Relaxed);
+        self.free_head = idx;
+        self.free_ct += 1;
+    }
+
+    /// Re-create the free and lent lists from scratch by inspecting every frame.
+    ///
+    /// This is `O(n)`, and is only used when creating or splitting slices.
+    fn rebuild_lists(&mut self) {
+        self.free_head = RawFrame::<S>::NIL;
+        self.free_ct = 0;
+        self.lent_head = RawFrame::<S>::NIL;
+        self.lent_ct = 0;
+
+        // Walk backwards, so the free stack pops in ascending order
+        for idx in (0..self.len as u16).rev() {
+            unsafe {
+                if self.freelen_at(idx).load(Ordering::Acquire) == RawFrame::<S>::FREE {
+                    self.push_free(idx);
+                } else {
+                    self.link_at(idx).store(self.lent_head, Ordering::Relaxed);
+                    self.lent_head = idx;
+                    self.lent_ct += 1;
+                    self.home_at(idx).store(self.waker_ptr(), Ordering::Relaxed);
+                }
+            }
         }
-        let mut ct = 0;
-        let start_ptr: *mut RawFrame = self.start.as_ptr();
-        for idx in 0..self.len {
-            let ptr: *mut RawFrame = unsafe { start_ptr.add(idx) };
-            // Scope access of subfield so all references are dropped before we make the
-            // non-null
-            {
-                let atom_ptr: *const AtomicU8 = unsafe { addr_of!((*ptr).freelen) };
-                let atom: &AtomicU8 = unsafe { &*atom_ptr };
+        self.high_water = self.high_water.max(self.lent_ct);
+    }
+
+    /// Obtain the `home` field of the frame at `idx`.
+    ///
+    /// ## Safety
+    ///
+    /// `idx` must be `< self.len`.
+    unsafe fn home_at(&self, idx: u16) -> &AtomicPtr<PoolWaker> {
+        let ptr: *mut RawFrame<S> = self.start.as_ptr().add(idx as usize);
+        &*addr_of!((*ptr).home)
+    }
+
+    /// The waker of this slice, to be stored in the `home` of its frames
+    fn waker_ptr(&self) -> *mut PoolWaker {
+        self.waker.map_or(core::ptr::null_mut(), |w| {
+            (w as *const PoolWaker).cast_mut()
+        })
+    }
+
+    /// Move any frames on the "lent" list that have since been released by
+    /// their [FrameBox] back onto the free stack.
+    fn reclaim(&mut self) {
+        let mut prev = RawFrame::<S>::NIL;
+        let mut cur = self.lent_head;
+        while cur != RawFrame::<S>::NIL {
+            unsafe {
+                let next = self.link_at(cur).load(Ordering::Relaxed);
 
-                // IF the value is zero, we have mutable exclusive access to allocate it.
-                if atom.load(Ordering::Acquire) == RawFrame::FREE {
+                // IF the value is zero, the FrameBox has given up access, and we
+                // have exclusive access to it once again.
+                if self.freelen_at(cur).load(Ordering::Acquire) == RawFrame::<S>::FREE {
+                    if prev == RawFrame::<S>::NIL {
+                        self.lent_head = next;
+                    } else {
+                        self.link_at(prev).store(next, Ordering::Relaxed);
+                    }
+                    self.lent_ct -= 1;
+                    self.push_free(cur);
+                } else {
+                    prev = cur;
+                }
+                cur = next;
+            }
+        }
+    }
+
+    /// Count the number of allocatable items
+    ///
+    /// Frames known to be free are counted in `O(1)`, frames released since
+    /// they were last reclaimed are found by checking each lent frame. This
+    /// is `O(k)`, where `k` is the number of frames currently allocated, and
+    /// `O(1)` while no frames are allocated.
+    pub fn count_allocatable(&self) -> usize {
+        let mut ct = self.free_ct;
+        let mut cur = self.lent_head;
+        while cur != RawFrame::<S>::NIL {
+            // SAFETY: everything on the lent list is in bounds
+            unsafe {
+                if self.freelen_at(cur).load(Ordering::Acquire) == RawFrame::<S>::FREE {
                     ct += 1;
                 }
+                cur = self.link_at(cur).load(Ordering::Relaxed);
             }
         }
         ct
@@ -234,42 +624,110 @@ impl RawFrameSlice {
     /// Attempt to allocate a [FrameBox] from the backing storage
     /// available to this [RawFrameSlice].
     ///
-    /// This allocation performs a linear search of the backing
-    /// storage, so allocation is `O(n)`. Returns [None] if no
-    /// storage slots were available.
-    pub fn allocate_raw(&mut self) -> Option<FrameBox> {
-        if self.len == 0 {
-            return None;
+    /// This allocation is `O(1)` as long as frames known to be free
+    /// remain. Otherwise, released frames are reclaimed first, which
+    /// is `O(k)` where `k` is the number of frames currently allocated.
+    /// Returns [None] if no storage slots were available.
+    pub fn allocate_raw(&mut self) -> Option<FrameBox<S>> {
+        let res = self.try_allocate();
+        if res.is_none() {
+            self.alloc_failures = self.alloc_failures.wrapping_add(1);
         }
-        if self.next_idx >= self.len {
-            self.next_idx = 0;
+        res
+    }
+
+    fn try_allocate(&mut self) -> Option<FrameBox<S>> {
+        if self.free_head == RawFrame::<S>::NIL {
+            self.reclaim();
         }
-        let start_ptr: *mut RawFrame = self.start.as_ptr();
-        let idxes = (self.next_idx..self.len).chain(0..self.next_idx);
-        for idx in idxes {
-            let ptr: *mut RawFrame = unsafe { start_ptr.add(idx) };
-            // Scope access of subfield so all references are dropped before we make the
-            // non-null
+        let idx = self.free_head;
+        if idx == RawFrame::<S>::NIL {
+            return None;
+        }
+
+        unsafe {
+            // Pop from the free stack, and push to the lent list
+            self.free_head = self.link_at(idx).load(Ordering::Relaxed);
+            self.free_ct -= 1;
+            self.link_at(idx).store(self.lent_head, Ordering::Relaxed);
+            self.lent_head = idx;
+            self.lent_ct += 1;
+            self.home_at(idx).store(self.waker_ptr(), Ordering::Relaxed);
+
+            #[cfg(feature = "pool-debug")]
             {
-                let atom_ptr: *mut AtomicU8 = unsafe { addr_of_mut!((*ptr).freelen) };
-                let atom: &AtomicU8 = unsafe { &*atom_ptr };
+                let ptr: *mut RawFrame<S> = self.start.as_ptr().add(idx as usize);
+                let ticks = self.now().as_ticks();
+                let ticks_ptr: *const [AtomicU32; 2] = addr_of!((*ptr).alloc_ticks);
+                (*ticks_ptr)[0].store(ticks as u32, Ordering::Relaxed);
+                (*ticks_ptr)[1].store((ticks >> 32) as u32, Ordering::Relaxed);
+                let owner_ptr: *const AtomicPtr<OwnerTag> = addr_of!((*ptr).owner);
+                (*owner_ptr).store(core::ptr::null_mut(), Ordering::Relaxed);
+            }
 
-                // IF the value is zero, we have mutable exclusive access to allocate it.
-                if atom.load(Ordering::Acquire) == RawFrame::FREE {
-                    atom.store(RawFrame::MAX_LEN, Ordering::Release);
-                    self.next_idx = idx + 1;
-                } else {
-                    continue;
-                }
+            // Frames on the free stack always have a freelen of zero, which
+            // means we have mutable exclusive access to allocate it.
+            self.freelen_at(idx)
+                .store(RawFrame::<S>::MAX_LEN, Ordering::Release);
+
+            // Only frames that are still allocated count towards the high-water
+            // mark, so check for released ones before raising it
+            if self.lent_ct > self.high_water {
+                self.high_water = self.high_water.max(self.in_use());
             }
-            // If we didn't continue, we succeeded, and the len is now MAX_LEN
-            return Some(FrameBox {
-                ptr: NonNull::new(ptr)?,
-            });
+
+            Some(FrameBox {
+                ptr: NonNull::new_unchecked(self.start.as_ptr().add(idx as usize)),
+            })
         }
+    }
+
+    /// Allocate a [FrameBox], waiting until one is available
+    ///
+    /// If no storage slots are available, the task will be woken when a
+    /// [FrameBox] belonging to this [RawFrameSlice] is dropped. Tasks waiting
+    /// on other slices of the same [FrameStorage] are not woken.
+    ///
+    /// A [RawFrameSlice] with no backing storage, e.g. one created with
+    /// [RawFrameSlice::uninit()], will never complete.
+    pub async fn allocate(&mut self) -> FrameBox<S> {
+        poll_fn(|cx| {
+            if let Some(fb) = self.try_allocate() {
+                return Poll::Ready(fb);
+            }
+            let Some(waker) = self.waker else {
+                return Poll::Pending;
+            };
+            waker.register(cx.waker());
 
-        // End of search, none found
-        None
+            // Check again, in case a frame was released between our first attempt
+            // and registering our waker. Pairs with the fence in `FrameBox::drop()`.
+            fence(Ordering::SeqCst);
+            match self.try_allocate() {
+                Some(fb) => Poll::Ready(fb),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Allocate a [FrameBox] like [RawFrameSlice::allocate_timeout_with_clock()],
+    /// timing out with the [EmbassyClock]
+    #[cfg(feature = "embassy-clock")]
+    pub async fn allocate_timeout(&mut self, timeout: Duration) -> Option<FrameBox<S>> {
+        self.allocate_timeout_with_clock::<EmbassyClock>(timeout)
+            .await
+    }
+
+    /// Allocate a [FrameBox], waiting up to `timeout`, measured with the
+    /// [Clock] `C`, for one to become available
+    ///
+    /// Returns [None] if no storage slot became available within the timeout.
+    pub async fn allocate_timeout_with_clock<C: Clock>(
+        &mut self,
+        timeout: Duration,
+    ) -> Option<FrameBox<S>> {
+        C::with_timeout(timeout, self.allocate()).await.ok()
     }
 
     /// Splits the tail starting at `at` from self.
@@ -277,6 +735,9 @@ impl RawFrameSlice {
     /// Additionally will refuse to split if `at` is `0` or the current capacity.
     ///
     /// Self is left with elements `[..at]`, and the new item is left with elements `[at..]`.
+    ///
+    /// Splitting is `O(n)`, as the free and lent lists of both halves are
+    /// rebuilt.
     pub fn split(&mut self, at: usize) -> Option<Self> {
         if (at == 0) || (at > self.len) {
             return None;
@@ -287,31 +748,142 @@ impl RawFrameSlice {
         // new.len becomes 3 (2, 3, 4)
         let len_new = self.len - at;
         self.len = at;
+        let start: *mut RawFrame<S> = unsafe { self.start.as_ptr().add(at) };
 
-        Some(RawFrameSlice {
-            start: unsafe { NonNull::new_unchecked(self.start.as_ptr().add(at)) },
+        let mut new = RawFrameSlice {
+            start: unsafe { NonNull::new_unchecked(start) },
             len: len_new,
-            next_idx: 0,
-        })
+            free_head: RawFrame::<S>::NIL,
+            free_ct: 0,
+            lent_head: RawFrame::<S>::NIL,
+            lent_ct: 0,
+            high_water: 0,
+            alloc_failures: 0,
+            waker: (len_new != 0).then(|| unsafe { &*addr_of!((*start).waker) }),
+            #[cfg(feature = "pool-debug")]
+            now: self.now,
+        };
+        self.rebuild_lists();
+        new.rebuild_lists();
+        Some(new)
     }
 
     /// The backing capacity of this [RawFrameSlice].
     pub fn capacity(&self) -> usize {
         self.len
     }
+
+    /// The maximum number of bytes each frame in this [RawFrameSlice] can hold
+    pub const fn frame_size(&self) -> usize {
+        S
+    }
+
+    /// The number of frames currently allocated
+    fn in_use(&self) -> usize {
+        self.len - self.count_allocatable()
+    }
+
+    /// Obtain the current usage statistics of this [RawFrameSlice]
+    ///
+    /// This is `O(k)`, like [RawFrameSlice::count_allocatable()].
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.len,
+            in_use: self.in_use(),
+            high_water: self.high_water,
+            alloc_failures: self.alloc_failures,
+        }
+    }
+
+    /// Reset the high-water mark to the current usage, and the allocation
+    /// failure count to zero.
+    pub fn reset_stats(&mut self) {
+        self.high_water = self.in_use();
+        self.alloc_failures = 0;
+    }
+
+    /// The current time, from the [Clock] of the [FrameStorage]
+    #[cfg(feature = "pool-debug")]
+    fn now(&self) -> Instant {
+        self.now.map_or(Instant::from_ticks(0), |now| now())
+    }
+
+    /// List all outstanding allocations of this [RawFrameSlice]
+    ///
+    /// Only available with the `pool-debug` feature.
+    #[cfg(feature = "pool-debug")]
+    pub fn outstanding(&self) -> impl Iterator<Item = Outstanding> + '_ {
+        let mut cur = self.lent_head;
+        core::iter::from_fn(move || {
+            while cur != RawFrame::<S>::NIL {
+                let idx = cur;
+                // SAFETY: everything on the lent list is in bounds. We only access
+                // the atomic and slice-owned fields, never `data`.
+                unsafe {
+                    cur = self.link_at(idx).load(Ordering::Relaxed);
+                    if self.freelen_at(idx).load(Ordering::Acquire) == RawFrame::<S>::FREE {
+                        continue;
+                    }
+                    let ptr: *mut RawFrame<S> = self.start.as_ptr().add(idx as usize);
+                    let ticks_ptr: *const [AtomicU32; 2] = addr_of!((*ptr).alloc_ticks);
+                    let lo = (*ticks_ptr)[0].load(Ordering::Relaxed) as u64;
+                    let hi = (*ticks_ptr)[1].load(Ordering::Relaxed) as u64;
+                    let owner_ptr: *const AtomicPtr<OwnerTag> = addr_of!((*ptr).owner);
+                    let owner = (*owner_ptr).load(Ordering::Relaxed);
+                    return Some(Outstanding {
+                        index: idx as usize,
+                        owner: owner.cast_const().as_ref(),
+                        allocated_at: Instant::from_ticks((hi << 32) | lo),
+                    });
+                }
+            }
+            None
+        })
+    }
+
+    /// List all outstanding allocations of this [RawFrameSlice] that have
+    /// been held for longer than `threshold`
+    ///
+    /// Only available with the `pool-debug` feature.
+    #[cfg(feature = "pool-debug")]
+    pub fn held_longer_than(&self, threshold: Duration) -> impl Iterator<Item = Outstanding> + '_ {
+        let now = self.now();
+        self.outstanding()
+            .filter(move |o| now.saturating_duration_since(o.allocated_at) > threshold)
+    }
 }
 
 /// WireFrameBox represents a valid packet received from the wire
 ///
 /// It is guaranteed to have a valid `CmdAddr`. It may have no data if
 /// it is an empty message
-pub struct WireFrameBox {
-    fb: FrameBox,
+///
+/// Protocol layers can strip their headers and trailers from the payload
+/// with [WireFrameBox::pull_header()] and [WireFrameBox::trim_trailer()],
+/// without copying the remaining data.
+pub struct WireFrameBox<const S: usize = DEFAULT_FRAME_SIZE> {
+    fb: FrameBox<S>,
+    head: usize,
+    tail: usize,
 }
 
-impl WireFrameBox {
-    pub(crate) fn new_unchecked(fb: FrameBox) -> Self {
-        Self { fb }
+impl<const S: usize> TryFrom<FrameBox<S>> for WireFrameBox<S> {
+    type Error = FrameBox<S>;
+
+    /// Check that a received frame starts with a valid [CmdAddr], returning
+    /// the frame unchanged if not
+    fn try_from(fb: FrameBox<S>) -> Result<Self, Self::Error> {
+        match CmdAddr::try_from(fb[0]) {
+            Ok(_) => Ok(Self::new_unchecked(fb)),
+            Err(_) => Err(fb),
+        }
+    }
+}
+
+impl<const S: usize> WireFrameBox<S> {
+    pub(crate) fn new_unchecked(fb: FrameBox<S>) -> Self {
+        let tail = fb.len();
+        Self { fb, head: 1, tail }
     }
 
     /// Get the [CmdAddr] of this message
@@ -323,36 +895,67 @@ impl WireFrameBox {
     /// Borrow the payload
     #[inline]
     pub fn payload(&self) -> &[u8] {
-        &self.fb[1..]
+        &self.fb[self.head..self.tail]
     }
 
     /// Mutably borrow the payload
     #[inline]
     pub fn payload_mut(&mut self) -> &mut [u8] {
-        &mut self.fb[1..]
+        &mut self.fb[self.head..self.tail]
+    }
+
+    /// Remove `len` bytes from the front of the payload, returning them
+    ///
+    /// Returns [None], and leaves the payload unchanged, if the payload is
+    /// shorter than `len`.
+    pub fn pull_header(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        let start = self.head;
+        self.head += len;
+        Some(&self.fb[start..self.head])
+    }
+
+    /// Remove `len` bytes from the end of the payload, returning them
+    ///
+    /// Returns [None], and leaves the payload unchanged, if the payload is
+    /// shorter than `len`.
+    pub fn trim_trailer(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        let end = self.tail;
+        self.tail -= len;
+        Some(&self.fb[self.tail..end])
     }
 
     /// Deconstruct the WireFrameBox
+    ///
+    /// The returned frame is the complete frame, including the [CmdAddr],
+    /// as well as any headers or trailers that were pulled or trimmed.
     #[inline]
-    pub fn into_inner(self) -> FrameBox {
+    pub fn into_inner(self) -> FrameBox<S> {
         self.fb
     }
 
     /// Is the PAYLOAD empty?
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.fb.len() == 1
+        self.head == self.tail
     }
 
     /// The PAYLOAD len
     #[inline]
     pub fn len(&self) -> usize {
-        self.fb.len() - 1
+        self.tail - self.head
     }
 
-    /// Set the length of the frame, NOT counting the [CmdAddr] field
+    /// Set the length of the payload, NOT counting the [CmdAddr] field,
+    /// or any pulled headers.
     pub fn set_len(&mut self, len: usize) {
-        self.fb.set_len(1 + len)
+        self.tail = self.head + len;
+        self.fb.set_len(self.tail)
     }
 }
 
@@ -360,49 +963,175 @@ impl WireFrameBox {
 ///
 /// Unlike [WireFrameBox], it does NOT have a valid [CmdAddr], which is
 /// assigned at sending time.
-pub struct SendFrameBox {
-    fb: FrameBox,
+///
+/// ## Layering
+///
+/// Similar to an `sk_buff`, a SendFrameBox can reserve "headroom" in front
+/// of the payload, and "tailroom" after it, so that each protocol layer can
+/// add its own headers and trailers in place:
+///
+/// ```rust
+/// # use erdnuss_comms::{clock::EmbassyClock, frame_pool::{FrameStorage, SendFrameBox}};
+/// # static STORAGE: FrameStorage<1> = FrameStorage::new();
+/// # fn demo() {
+/// # let mut pool = STORAGE.take_with_clock::<EmbassyClock>().unwrap();
+/// let mut sfb = SendFrameBox::from(pool.allocate_raw().unwrap());
+///
+/// // Leave room for up to 16 bytes of headers
+/// sfb.reserve(16);
+///
+/// // The innermost layer writes its payload
+/// sfb.put_trailer(5).unwrap().copy_from_slice(b"hello");
+///
+/// // Outer layers prepend their headers, and append their trailers
+/// sfb.push_header(2).unwrap().copy_from_slice(&[0x01, 0x02]);
+/// sfb.put_trailer(2).unwrap().copy_from_slice(&[0xAA, 0xBB]);
+///
+/// assert_eq!(sfb.payload(), &[0x01, 0x02, b'h', b'e', b'l', b'l', b'o', 0xAA, 0xBB]);
+/// # }
+/// ```
+///
+/// Any headroom that remains unused when the frame is sent is removed by
+/// moving the payload forward once, in [SendFrameBox::into_inner()].
+pub struct SendFrameBox<const S: usize = DEFAULT_FRAME_SIZE> {
+    fb: FrameBox<S>,
+    head: usize,
+    tail: usize,
 }
 
-impl From<FrameBox> for SendFrameBox {
-    fn from(value: FrameBox) -> Self {
-        Self { fb: value }
+impl<const S: usize> From<FrameBox<S>> for SendFrameBox<S> {
+    fn from(mut value: FrameBox<S>) -> Self {
+        let tail = value.len();
+        // Allow access to the whole frame while building, the length
+        // is fixed up again in `into_inner`.
+        value.set_len(S);
+        Self {
+            fb: value,
+            head: 1,
+            tail,
+        }
     }
 }
 
-impl SendFrameBox {
+impl<const S: usize> SendFrameBox<S> {
     /// Borrow the payload
     #[inline]
     pub fn payload(&self) -> &[u8] {
-        &self.fb[1..]
+        &self.fb[self.head..self.tail]
     }
 
     /// Mutably borrow the payload
     #[inline]
     pub fn payload_mut(&mut self) -> &mut [u8] {
-        &mut self.fb[1..]
+        &mut self.fb[self.head..self.tail]
+    }
+
+    /// Empty the payload, and reserve `len` bytes of headroom in front of it
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `len` is larger than the space available after the [CmdAddr].
+    pub fn reserve(&mut self, len: usize) {
+        assert!(len < S);
+        self.head = 1 + len;
+        self.tail = self.head;
+    }
+
+    /// The number of bytes available in front of the payload for headers
+    #[inline]
+    pub fn headroom(&self) -> usize {
+        self.head - 1
+    }
+
+    /// The number of bytes available after the payload for trailers
+    #[inline]
+    pub fn tailroom(&self) -> usize {
+        S - self.tail
+    }
+
+    /// Prepend `len` bytes to the payload, returning them to be filled in
+    ///
+    /// Returns [None] if there is not enough headroom.
+    pub fn push_header(&mut self, len: usize) -> Option<&mut [u8]> {
+        if len > self.headroom() {
+            return None;
+        }
+        self.head -= len;
+        Some(&mut self.fb[self.head..][..len])
+    }
+
+    /// Remove `len` bytes from the front of the payload, returning them
+    ///
+    /// The removed bytes become headroom. Returns [None] if the payload
+    /// is shorter than `len`.
+    pub fn pull_header(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        let start = self.head;
+        self.head += len;
+        Some(&self.fb[start..self.head])
+    }
+
+    /// Append `len` bytes to the payload, returning them to be filled in
+    ///
+    /// Returns [None] if there is not enough tailroom.
+    pub fn put_trailer(&mut self, len: usize) -> Option<&mut [u8]> {
+        if len > self.tailroom() {
+            return None;
+        }
+        let end = self.tail;
+        self.tail += len;
+        Some(&mut self.fb[end..self.tail])
+    }
+
+    /// Remove `len` bytes from the end of the payload, returning them
+    ///
+    /// The removed bytes become tailroom. Returns [None] if the payload
+    /// is shorter than `len`.
+    pub fn trim_trailer(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        let end = self.tail;
+        self.tail -= len;
+        Some(&self.fb[self.tail..end])
     }
 
     /// Deconstruct the SendFrameBox
+    ///
+    /// The returned frame has one byte reserved for the [CmdAddr], followed
+    /// by the payload. If there is unused headroom, the payload is moved
+    /// forward to close the gap.
     #[inline]
-    pub fn into_inner(self) -> FrameBox {
-        self.fb
+    pub fn into_inner(self) -> FrameBox<S> {
+        let Self { mut fb, head, tail } = self;
+        if head > 1 {
+            fb.copy_within(head..tail, 1);
+        }
+        fb.set_len(1 + tail - head);
+        fb
     }
 
     /// Is the PAYLOAD empty?
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.fb.len() == 1
+        self.head == self.tail
     }
 
     /// The PAYLOAD len
     #[inline]
     pub fn len(&self) -> usize {
-        self.fb.len() - 1
+        self.tail - self.head
     }
 
-    /// Set the length of the frame, NOT counting the [CmdAddr] field
+    /// Set the length of the payload, NOT counting the [CmdAddr] field
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the payload would not fit in the remaining space.
     pub fn set_len(&mut self, len: usize) {
-        self.fb.set_len(1 + len)
+        assert!(self.head + len <= S);
+        self.tail = self.head + len;
     }
 }
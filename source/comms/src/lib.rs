@@ -40,11 +40,17 @@
 //!    If no: ignore the frame and return to step 1.
 //!
 //! A line break was chosen because it is well supported by the RP2040 hardware
-//! UART implementation.
+//! UART implementation. With the `rs485` feature, the `rs485` module provides a
+//! driver for any UART that can send and detect line breaks.
+//!
+//! Links without line break support, like a USB-serial adapter, can instead
+//! use COBS framing with the `cobs-serial` feature, see the `cobs_serial` module.
 //!
 //! A line break was chosen over 9-bit messages (where the msbit is used as an
 //! address/data flag), because the RP2040 doesn't support 9-bit serial, and
-//! has no address-match interrupt, which devices like STM32 often have.
+//! has no address-match interrupt, which devices like STM32 often have. Nodes
+//! that do have these can additionally mark the first byte of each frame as an
+//! address, and sleep through frames for other nodes, see the [addr_mark] module.
 //!
 //! A line break was chosen over a "line idle" interrupt, because while the RP2040
 //! DOES have a line idle interrupt, it does not work when using DMA, as the idle
@@ -80,7 +86,8 @@
 //!
 //! All devices are expected to have universally unique 64-bit hardware address, analogous
 //! to a MAC-address on ethernet/wifi devices. For RP2040 based nodes, this is typically
-//! achieved by using the unique serial number of the QSPI flash chip.
+//! achieved by using the unique serial number of the QSPI flash chip. This address is a
+//! [Mac]. In discovery messages, it is always sent little endian, see the [mac] module.
 //!
 //! In order to reduce overhead on the bus for addressing, devices are dynamically assigned
 //! a 5-bit address (0..32).
@@ -147,6 +154,19 @@
 //! a "three strikes you're out" rule to avoid wasting bus time on timeouts from
 //! unresponsive Targets. If a Target fails to respond three times in a row, it is dropped, and
 //! the address is marked as free.
+//!
+//! ## Clocks
+//!
+//! All timeouts and delays of the Controller and Target are measured with a
+//! [Clock][clock::Clock]. By default this is the `embassy-time` driver, as used
+//! on bare metal devices, and the `embassy-clock` feature provides shorthand
+//! constructors using it. With the `tokio` feature, the `TokioClock` allows the
+//! same Controller to run on a Linux gateway, for example with a USB RS-485
+//! adapter. See the [clock] module for details.
+//!
+//! With the `net` feature, the `net` module also allows nodes in separate
+//! processes to share a bus over the network, for tests and demos without
+//! hardware.
 
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![allow(async_fn_in_trait)]
@@ -155,18 +175,37 @@
 #[macro_use]
 mod macros;
 
+pub mod addr_mark;
+pub mod blocking;
+#[cfg(feature = "bridge")]
+pub mod bridge;
+pub mod capture;
+pub mod clock;
+#[cfg(feature = "cobs-serial")]
+pub mod cobs_serial;
 pub mod controller;
+pub mod decode;
+pub mod fault;
 pub mod frame_pool;
+pub mod mac;
+#[cfg(feature = "net")]
+pub mod net;
 mod peer;
+#[cfg(feature = "rs485")]
+pub mod rs485;
+pub mod sniffer;
 pub mod target;
 #[cfg(feature = "postcard-rpc-helpers")]
 pub mod wirehelp;
 use embassy_time::Instant;
 
+use crate::addr_mark::AddrFilter;
+
 /// The maximum number of Targets supported by a Controller.
 pub const MAX_TARGETS: usize = 31;
 
 pub use crate::controller::Controller;
+pub use crate::mac::Mac;
 
 /// An error type for the [`FrameSerial`] trait
 #[derive(Debug, PartialEq)]
@@ -216,6 +255,17 @@ pub trait FrameSerial {
         &mut self,
         frame: &'a mut [u8],
     ) -> Result<TimedFrame<'a>, Error<Self::SerError>>;
+
+    /// Only wake up for frames accepted by `filter`, if supported
+    ///
+    /// This is called by the Target whenever the frames it is interested in
+    /// change, and is never called by the Controller. Backends with hardware
+    /// address matching can use it to stay muted until a frame for this node
+    /// starts, see [addr_mark]. The default implementation ignores the filter,
+    /// and receives every frame.
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        let _ = filter;
+    }
 }
 
 /// Command + Address byte
@@ -0,0 +1,207 @@
+//! Hardware addresses
+//!
+//! Every node has a universally unique 64-bit hardware address, its [Mac].
+//!
+//! ## Byte order
+//!
+//! A [Mac] is a 64-bit number. There are two byte orders for it:
+//!
+//! * On the wire, in discovery messages, the [Mac] is sent little endian, see
+//!   [Mac::to_wire_bytes()]
+//! * For humans, the [Mac] is written big endian, as eight hex bytes
+//!   separated by colons, like `01:23:45:67:89:ab:cd:ef` for
+//!   `0x0123_4567_89AB_CDEF`. This is the format of [Display] and [FromStr],
+//!   and of serde, for human readable formats.
+//!
+//! Unique IDs are usually read from hardware as a byte array, for example
+//! from a flash chip. [Mac::from_be_bytes()] keeps these bytes in the order
+//! they were read, when written for humans.
+
+use core::{
+    fmt::{self, Debug, Display},
+    str::FromStr,
+};
+
+/// The universally unique hardware address of a node
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(
+    feature = "postcard-rpc-helpers",
+    derive(postcard::experimental::schema::Schema)
+)]
+pub struct Mac(u64);
+
+impl Mac {
+    /// Create a [Mac] from its numeric value
+    pub const fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    /// The numeric value of the [Mac]
+    pub const fn to_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Create a [Mac] from bytes in the order they are written for humans
+    pub const fn from_be_bytes(bytes: [u8; 8]) -> Self {
+        Self(u64::from_be_bytes(bytes))
+    }
+
+    /// The bytes of the [Mac], in the order they are written for humans
+    pub const fn to_be_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    /// Create a [Mac] from bytes in the order they are sent on the bus
+    pub const fn from_wire_bytes(bytes: [u8; 8]) -> Self {
+        Self(u64::from_le_bytes(bytes))
+    }
+
+    /// The bytes of the [Mac], in the order they are sent on the bus
+    pub const fn to_wire_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+}
+
+impl From<u64> for Mac {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Mac> for u64 {
+    fn from(mac: Mac) -> Self {
+        mac.0
+    }
+}
+
+impl Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.to_be_bytes().iter().enumerate() {
+            if i != 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Mac({self})")
+    }
+}
+
+#[cfg(feature = "defmt-logging")]
+impl defmt::Format for Mac {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let b = self.to_be_bytes();
+        defmt::write!(
+            f,
+            "{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}",
+            b[0],
+            b[1],
+            b[2],
+            b[3],
+            b[4],
+            b[5],
+            b[6],
+            b[7]
+        )
+    }
+}
+
+/// The error returned when parsing an invalid [Mac]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseMacError;
+
+impl Display for ParseMacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected eight hex bytes, like 01:23:45:67:89:ab:cd:ef")
+    }
+}
+
+impl FromStr for Mac {
+    type Err = ParseMacError;
+
+    /// Parse a [Mac] written as eight colon separated hex bytes, or as sixteen
+    /// hex digits without separators. Hex digits may be in either case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 8];
+        let mut parts = s.split(':');
+        match (parts.next(), parts.next()) {
+            (Some(digits), None) if digits.len() == 16 => {
+                for (b, pair) in bytes.iter_mut().zip(digits.as_bytes().chunks(2)) {
+                    *b = hex_byte(pair)?;
+                }
+            }
+            (Some(first), Some(second)) => {
+                let mut parts = [first, second].into_iter().chain(parts);
+                for b in bytes.iter_mut() {
+                    *b = hex_byte(parts.next().ok_or(ParseMacError)?.as_bytes())?;
+                }
+                if parts.next().is_some() {
+                    return Err(ParseMacError);
+                }
+            }
+            _ => return Err(ParseMacError),
+        }
+        Ok(Mac::from_be_bytes(bytes))
+    }
+}
+
+/// Parse exactly two hex digits
+fn hex_byte(pair: &[u8]) -> Result<u8, ParseMacError> {
+    let digit = |d: u8| (d as char).to_digit(16).ok_or(ParseMacError);
+    match pair {
+        [hi, lo] => Ok((digit(*hi)? << 4 | digit(*lo)?) as u8),
+        _ => Err(ParseMacError),
+    }
+}
+
+/// Serialized as a string for human readable formats, and as a `u64`
+/// newtype otherwise, like postcard on the bus
+#[cfg(feature = "postcard-rpc-helpers")]
+mod serde_impls {
+    use super::Mac;
+    use core::fmt;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    impl Serialize for Mac {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                serializer.collect_str(self)
+            } else {
+                serializer.serialize_newtype_struct("Mac", &self.0)
+            }
+        }
+    }
+
+    struct MacVisitor;
+
+    impl<'de> de::Visitor<'de> for MacVisitor {
+        type Value = Mac;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a MAC address")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Mac, E> {
+            v.parse().map_err(E::custom)
+        }
+
+        fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<Mac, D::Error> {
+            u64::deserialize(d).map(Mac)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Mac {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            if deserializer.is_human_readable() {
+                deserializer.deserialize_str(MacVisitor)
+            } else {
+                deserializer.deserialize_newtype_struct("Mac", MacVisitor)
+            }
+        }
+    }
+}
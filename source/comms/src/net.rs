@@ -0,0 +1,339 @@
+//! Network transports, for running nodes in separate processes
+//!
+//! These [FrameSerial]s use a network as the shared bus, so that a Controller
+//! and any number of Targets can run in separate processes, or containers, on
+//! one machine, without any hardware:
+//!
+//! * [UdpBus] uses a UDP multicast group as the bus. Every node joins the same
+//!   group, and there is no central process.
+//! * [TcpSerial] connects to a [TcpHub], which passes every frame it receives
+//!   to all other connected nodes. This works where multicast is unavailable.
+//!
+//! Like on a real bus, nodes never receive their own frames. Unlike a real
+//! bus, frames sent at the same time don't collide. Each frame is sent after
+//! a configurable latency, see `set_latency`, to test the timing margins of
+//! the protocol. Timestamps come from the [TokioClock], so all nodes should
+//! use the [TokioClock] as well.
+//!
+//! Receiving and sending are both cancellation safe. A [TcpSerial] writes its
+//! frames from a separate task, so a frame whose send was cancelled is either
+//! written in full straight away, or not at all, and never delays the next one.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    pin::pin,
+};
+
+use embassy_time::Duration;
+use futures::future::{select, Either};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+    },
+    sync::{broadcast, mpsc},
+};
+
+use crate::{
+    clock::{Clock, TokioClock},
+    Error, FrameSerial, TimedFrame,
+};
+
+/// The multicast group used by [UdpBus::join_default()]
+pub const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 72, 85), 48_585);
+
+/// The largest frame that can be sent over a network transport
+pub const MAX_NET_FRAME: usize = u16::MAX as usize;
+
+/// The length of the sender ID at the start of each [UdpBus] datagram
+const NODE_ID_LEN: usize = 8;
+
+/// The number of frames a [TcpHub] buffers for each connection
+const HUB_DEPTH: usize = 64;
+
+/// The number of sent frames a [TcpSerial] queues for writing
+const TX_DEPTH: usize = 8;
+
+fn too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "frame too long")
+}
+
+/// Wait for the injected latency before sending
+async fn delay(latency: Duration) {
+    if latency != Duration::from_ticks(0) {
+        TokioClock::wait(latency).await;
+    }
+}
+
+/// A [FrameSerial] using a UDP multicast group as the bus
+///
+/// Datagrams start with a random ID of the sending node, which is used to
+/// discard our own frames, as multicast loops them back to us.
+pub struct UdpBus {
+    socket: UdpSocket,
+    group: SocketAddr,
+    id: [u8; NODE_ID_LEN],
+    latency: Duration,
+    buf: Vec<u8>,
+}
+
+impl UdpBus {
+    /// Join the bus at the multicast `group`, on the loopback interface
+    ///
+    /// Any number of processes on the machine can join the same group.
+    pub fn join(group: SocketAddrV4) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::LOCALHOST)?;
+        socket.set_multicast_if_v4(&Ipv4Addr::LOCALHOST)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            group: group.into(),
+            id: RandomState::new().build_hasher().finish().to_le_bytes(),
+            latency: Duration::from_ticks(0),
+            buf: vec![0; NODE_ID_LEN + MAX_NET_FRAME],
+        })
+    }
+
+    /// Join the bus at the [DEFAULT_GROUP]
+    pub fn join_default() -> io::Result<Self> {
+        Self::join(DEFAULT_GROUP)
+    }
+
+    /// Set the latency injected before each sent frame
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+}
+
+impl FrameSerial for UdpBus {
+    type SerError = io::Error;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        if data.len() > MAX_NET_FRAME {
+            return Err(Error::Serial(too_long()));
+        }
+        let mut dgram = Vec::with_capacity(NODE_ID_LEN + data.len());
+        dgram.extend_from_slice(&self.id);
+        dgram.extend_from_slice(data);
+
+        delay(self.latency).await;
+        self.socket.send_to(&dgram, self.group).await?;
+        Ok(())
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        loop {
+            let (ct, _from) = self.socket.recv_from(&mut self.buf).await?;
+            let Some((id, data)) = self.buf[..ct].split_first_chunk::<NODE_ID_LEN>() else {
+                nut_warn!("Discarding short datagram");
+                continue;
+            };
+            if *id == self.id {
+                // One of our own frames, looped back
+                continue;
+            }
+            let buf = frame.get_mut(..data.len()).ok_or_else(too_long)?;
+            buf.copy_from_slice(data);
+            return Ok(TimedFrame {
+                end_of_rx: TokioClock::now(),
+                frame: buf,
+            });
+        }
+    }
+}
+
+/// A hub for [TcpSerial] nodes, passing every frame to all other nodes
+///
+/// On the wire, each frame is preceded by its length, as a little endian `u16`.
+pub struct TcpHub {
+    listener: TcpListener,
+}
+
+impl TcpHub {
+    /// Listen for nodes at `addr`
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// The address nodes should connect to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept nodes and pass on their frames, until accepting fails
+    ///
+    /// Each node is served by its own tokio task. A node that falls more than
+    /// a few frames behind misses the frames it couldn't keep up with.
+    pub async fn run(self) -> io::Result<()> {
+        let (tx, _) = broadcast::channel::<(u64, Vec<u8>)>(HUB_DEPTH);
+        let mut next_id = 0u64;
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            stream.set_nodelay(true)?;
+            let id = next_id;
+            next_id += 1;
+            tokio::spawn(serve_node(id, stream, tx.clone(), tx.subscribe()));
+        }
+    }
+}
+
+/// Pass frames between one node and the [TcpHub]
+async fn serve_node(
+    id: u64,
+    stream: TcpStream,
+    tx: broadcast::Sender<(u64, Vec<u8>)>,
+    mut rx: broadcast::Receiver<(u64, Vec<u8>)>,
+) {
+    let (mut read, mut write) = stream.into_split();
+    let reading = async {
+        loop {
+            let mut len = [0u8; 2];
+            read.read_exact(&mut len).await?;
+            let mut frame = vec![0; usize::from(u16::from_le_bytes(len))];
+            read.read_exact(&mut frame).await?;
+            // Can't fail, as we hold a receiver ourselves
+            let _ = tx.send((id, frame));
+        }
+    };
+    let writing = async {
+        loop {
+            let frame = match rx.recv().await {
+                Ok((from, _)) if from == id => continue,
+                Ok((_, frame)) => frame,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    nut_warn!("Hub node lagging, dropped {=u64} frames", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+            write.write_all(&(frame.len() as u16).to_le_bytes()).await?;
+            write.write_all(&frame).await?;
+        }
+    };
+    // Either side ending means the node is gone
+    let res: io::Result<()> = match select(pin!(reading), pin!(writing)).await {
+        Either::Left((res, _)) | Either::Right((res, _)) => res,
+    };
+    if res.is_err() {
+        nut_info!("Hub node {=u64} disconnected", id);
+    }
+}
+
+/// A [FrameSerial] connected to a [TcpHub]
+///
+/// Sent frames are written to the stream by a tokio task, which ends when the
+/// [TcpSerial] is dropped, once every queued frame is written.
+pub struct TcpSerial {
+    read: OwnedReadHalf,
+    latency: Duration,
+    /// Received bytes, not yet returned as a frame
+    rx: Vec<u8>,
+    /// Sent frames, with their length, to be written by [write_frames()]
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl TcpSerial {
+    /// Connect to the [TcpHub] at `addr`
+    ///
+    /// Must be called from within a tokio runtime.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (read, write) = stream.into_split();
+        let (tx, frames) = mpsc::channel(TX_DEPTH);
+        tokio::spawn(write_frames(write, frames));
+        Ok(Self {
+            read,
+            latency: Duration::from_ticks(0),
+            rx: Vec::new(),
+            tx,
+        })
+    }
+
+    /// Set the latency injected before each sent frame
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+}
+
+/// Write the frames sent by a [TcpSerial], until it is dropped, or writing fails
+///
+/// Once queued, a frame is written in full, even if its send was cancelled.
+/// Finishing it at the next send instead would delay that frame.
+async fn write_frames(mut write: OwnedWriteHalf, mut frames: mpsc::Receiver<Vec<u8>>) {
+    while let Some(frame) = frames.recv().await {
+        if write.write_all(&frame).await.is_err() {
+            nut_warn!("TcpSerial write failed, disconnecting");
+            return;
+        }
+    }
+}
+
+impl FrameSerial for TcpSerial {
+    type SerError = io::Error;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        let len = u16::try_from(data.len()).map_err(|_| too_long())?;
+        let mut frame = Vec::with_capacity(2 + data.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(data);
+
+        delay(self.latency).await;
+        // If cancelled while the queue is full, the frame is never written
+        self.tx
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(())
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        loop {
+            if let [l0, l1, rest @ ..] = self.rx.as_slice() {
+                let len = usize::from(u16::from_le_bytes([*l0, *l1]));
+                if rest.len() >= len {
+                    let res = match frame.get_mut(..len) {
+                        Some(buf) => {
+                            buf.copy_from_slice(&rest[..len]);
+                            Ok(len)
+                        }
+                        None => Err(too_long()),
+                    };
+                    self.rx.drain(..2 + len);
+                    let len = res?;
+                    return Ok(TimedFrame {
+                        end_of_rx: TokioClock::now(),
+                        frame: &mut frame[..len],
+                    });
+                }
+            }
+
+            let mut chunk = [0u8; 256];
+            let ct = self.read.read(&mut chunk).await?;
+            if ct == 0 {
+                return Err(Error::Serial(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.rx.extend_from_slice(&chunk[..ct]);
+        }
+    }
+}
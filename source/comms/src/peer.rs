@@ -1,12 +1,51 @@
 //! Peer
 
-use crate::frame_pool::{FrameBox, RawFrameSlice};
+#[cfg(feature = "postcard-rpc-helpers")]
+use {
+    crate::wirehelp::{WhBody, ERROR_KEY},
+    core::task::Waker,
+    postcard_rpc::{Key, WireHeader},
+};
+
+use crate::{
+    frame_pool::{FrameBox, RawFrameSlice},
+    Mac,
+};
+use embassy_sync::waitqueue::MultiWakerRegistration;
 use heapless::Deque;
 
 /// The default number of "in-flight" packets FROM Controller TO Target
 pub const OUTGOING_SIZE: usize = 8;
 /// The default number of "in-flight" packets FROM Target TO Controller
 pub const INCOMING_SIZE: usize = 4;
+/// The number of tasks that can wait for incoming packets from one Target,
+/// before they start waking each other
+const INCOMING_WAITERS: usize = 4;
+/// The number of timed out requests to one Target whose late responses are
+/// discarded, before the oldest is forgotten
+#[cfg(feature = "postcard-rpc-helpers")]
+const ABANDONED_REPLIES: usize = 4;
+
+/// The response expected by a request, see [Controller::request()][crate::Controller::request]
+#[cfg(feature = "postcard-rpc-helpers")]
+#[derive(Clone, Copy)]
+pub(crate) struct Reply {
+    pub(crate) key: Key,
+    pub(crate) seq_no: u32,
+}
+
+#[cfg(feature = "postcard-rpc-helpers")]
+impl Reply {
+    /// Is this frame the response, or a [WireError][crate::wirehelp::WireError]
+    /// sent instead?
+    pub(crate) fn matches(&self, fb: &FrameBox) -> bool {
+        WhBody::try_from(fb).is_some_and(|b| self.matches_header(&b.wh))
+    }
+
+    fn matches_header(&self, wh: &WireHeader) -> bool {
+        (wh.key == self.key || wh.key == ERROR_KEY) && wh.seq_no == self.seq_no
+    }
+}
 
 #[derive(Debug, PartialEq)]
 enum State {
@@ -18,10 +57,14 @@ enum State {
 pub(crate) struct Peer<const IN: usize = INCOMING_SIZE, const OUT: usize = OUTGOING_SIZE> {
     state: State,
     counter: u8,
+    joins: u32,
     incoming_pool: RawFrameSlice,
-    mac: u64,
+    mac: Mac,
     to_peer: Deque<FrameBox, IN>,
     from_peer: Deque<FrameBox, OUT>,
+    incoming_waker: MultiWakerRegistration<INCOMING_WAITERS>,
+    #[cfg(feature = "postcard-rpc-helpers")]
+    abandoned: Deque<Reply, ABANDONED_REPLIES>,
 }
 
 impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
@@ -29,19 +72,27 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
         Self {
             state: State::Free,
             counter: 0,
+            joins: 0,
             incoming_pool: RawFrameSlice::uninit(),
-            mac: 0,
+            mac: Mac::new(0),
             to_peer: Deque::new(),
             from_peer: Deque::new(),
+            incoming_waker: MultiWakerRegistration::new(),
+            #[cfg(feature = "postcard-rpc-helpers")]
+            abandoned: Deque::new(),
         }
     }
 
     fn reset_to_free(&mut self) {
         self.to_peer.clear();
         self.from_peer.clear();
-        self.mac = 0;
+        self.mac = Mac::new(0);
         self.state = State::Free;
         self.counter = 0;
+        #[cfg(feature = "postcard-rpc-helpers")]
+        self.abandoned.clear();
+        // Let anyone waiting for a message know this peer is gone
+        self.incoming_waker.wake();
     }
 
     pub(crate) fn promote_to_active(&mut self) {
@@ -53,9 +104,10 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
         self.from_peer.clear();
         self.state = State::Active;
         self.counter = 0;
+        self.joins = self.joins.wrapping_add(1);
     }
 
-    pub(crate) fn promote_to_pending(&mut self, mac: u64) {
+    pub(crate) fn promote_to_pending(&mut self, mac: Mac) {
         if self.state != State::Free {
             panic!();
         }
@@ -98,7 +150,7 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
     }
 
     #[inline]
-    pub(crate) fn is_pending(&self) -> Option<u64> {
+    pub(crate) fn is_pending(&self) -> Option<Mac> {
         if self.state == State::Pending {
             Some(self.mac)
         } else {
@@ -106,16 +158,28 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
         }
     }
 
+    #[cfg(feature = "postcard-rpc-helpers")]
+    #[inline]
+    pub(crate) fn is_free(&self) -> bool {
+        self.state == State::Free
+    }
+
     #[inline]
     pub(crate) fn is_active(&self) -> bool {
         self.state == State::Active
     }
 
     #[inline]
-    pub(crate) fn mac(&self) -> u64 {
+    pub(crate) fn mac(&self) -> Mac {
         self.mac
     }
 
+    /// The number of times a Target has become active at this address
+    #[inline]
+    pub(crate) fn joins(&self) -> u32 {
+        self.joins
+    }
+
     #[inline]
     pub(crate) fn is_idle(&self) -> bool {
         if self.state != State::Free {
@@ -124,12 +188,18 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
         self.incoming_pool.count_allocatable() == INCOMING_SIZE
     }
 
+    #[cfg(feature = "postcard-rpc-helpers")]
+    #[inline]
+    pub(crate) fn can_alloc_incoming(&self) -> bool {
+        self.incoming_pool.count_allocatable() != 0
+    }
+
     pub(crate) fn alloc_incoming(&mut self) -> Option<FrameBox> {
         self.incoming_pool.allocate_raw()
     }
 
     #[inline]
-    pub(crate) fn is_active_mac(&self, mac: u64) -> bool {
+    pub(crate) fn is_active_mac(&self, mac: Mac) -> bool {
         if self.state != State::Active {
             return false;
         }
@@ -138,9 +208,15 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
 
     #[inline]
     pub(crate) fn enqueue_incoming(&mut self, msg: FrameBox) {
+        #[cfg(feature = "postcard-rpc-helpers")]
+        if self.take_abandoned(&msg) {
+            nut_warn!("Discarding a response that arrived after its request timed out");
+            return;
+        }
         // The deque length is the same as the pool size,
         // so this should never fail.
         self.from_peer.push_front(msg).map_err(drop).unwrap();
+        self.incoming_waker.wake();
     }
 
     #[inline]
@@ -153,6 +229,74 @@ impl<const IN: usize, const OUT: usize> Peer<IN, OUT> {
         self.from_peer.pop_back()
     }
 
+    /// Remove the oldest incoming message that matches `pred`, leaving the
+    /// order of all other incoming messages unchanged
+    #[cfg(feature = "postcard-rpc-helpers")]
+    pub(crate) fn take_incoming(
+        &mut self,
+        mut pred: impl FnMut(&FrameBox) -> bool,
+    ) -> Option<FrameBox> {
+        let mut found = None;
+        // Rotate through the whole queue once, oldest first, keeping everything
+        // but the first match
+        for _ in 0..self.from_peer.len() {
+            let msg = self.from_peer.pop_back()?;
+            if found.is_none() && pred(&msg) {
+                found = Some(msg);
+            } else {
+                self.from_peer.push_front(msg).map_err(drop).unwrap();
+            }
+        }
+        found
+    }
+
+    /// Discard the response to a request that timed out, now if it is already
+    /// waiting, or as soon as it arrives otherwise
+    #[cfg(feature = "postcard-rpc-helpers")]
+    pub(crate) fn abandon(&mut self, reply: Reply) {
+        if self.take_incoming(|fb| reply.matches(fb)).is_some() {
+            return;
+        }
+        if self.abandoned.is_full() {
+            self.abandoned.pop_back();
+        }
+        self.abandoned.push_front(reply).map_err(drop).unwrap();
+    }
+
+    /// Is this frame the response to a request that timed out? If so, it is
+    /// no longer expected
+    #[cfg(feature = "postcard-rpc-helpers")]
+    fn take_abandoned(&mut self, msg: &FrameBox) -> bool {
+        if self.abandoned.is_empty() {
+            return false;
+        }
+        let Some(body) = WhBody::try_from(msg) else {
+            return false;
+        };
+        let mut found = false;
+        // Rotate through the whole queue once, keeping everything but the first match
+        for _ in 0..self.abandoned.len() {
+            let reply = self.abandoned.pop_back().unwrap();
+            if !found && reply.matches_header(&body.wh) {
+                found = true;
+            } else {
+                self.abandoned.push_front(reply).map_err(drop).unwrap();
+            }
+        }
+        found
+    }
+
+    /// Register a waker to be woken when a new incoming message arrives,
+    /// or when this peer is reset
+    ///
+    /// Every registered waker is woken, so that each waiting task can check
+    /// for its own message.
+    #[cfg(feature = "postcard-rpc-helpers")]
+    #[inline]
+    pub(crate) fn register_incoming_waker(&mut self, waker: &Waker) {
+        self.incoming_waker.register(waker);
+    }
+
     #[inline]
     pub(crate) fn dequeue_outgoing(&mut self) -> Option<FrameBox> {
         self.to_peer.pop_back()
@@ -0,0 +1,168 @@
+//! A hardware-independent RS-485 driver
+//!
+//! [Rs485] implements [FrameSerial] for any UART that can send and detect
+//! line breaks, described by the [BreakUart] trait, and an `embedded-hal`
+//! [OutputPin] driving the DE (driver enable) pin of the transceiver.
+//!
+//! DE is only asserted while a frame is being sent. It is released by a drop
+//! guard, so it is also deasserted if [FrameSerial::send_frame()] returns
+//! early with an error, or if its future is dropped mid-frame, for example
+//! by a timeout. A stuck DE would hold the bus for every other node.
+
+use core::marker::PhantomData;
+
+use embedded_hal::digital::OutputPin;
+
+use crate::{
+    clock::{Clock, EmbassyClock},
+    Error, FrameSerial, TimedFrame,
+};
+
+/// An async UART which can send and detect line breaks
+pub trait BreakUart {
+    /// The error type of the UART
+    type Error;
+
+    /// Write all of `data`
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Wait until all written data has been sent, including the last byte
+    /// in the transmit shift register
+    async fn flush(&mut self) -> Result<(), Self::Error>;
+
+    /// Send a line break, returning once it has been sent
+    async fn send_break(&mut self) -> Result<(), Self::Error>;
+
+    /// Receive into `buf` until a line break is detected, returning the
+    /// number of bytes received
+    async fn read_to_break(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Enable or disable the receiver
+    ///
+    /// The receiver is disabled before DE is asserted, and re-enabled after DE
+    /// is deasserted. This is needed on hardware where the RX line floats while
+    /// sending, which looks like a line break. The default does nothing.
+    fn set_rx_enabled(&mut self, enabled: bool) {
+        let _ = enabled;
+    }
+}
+
+/// The error type of a [Rs485] port
+#[derive(Debug, PartialEq)]
+pub enum Rs485Error<UE, PE> {
+    /// An error from the UART
+    Uart(UE),
+    /// An error setting the DE pin
+    Pin(PE),
+}
+
+/// An RS-485 port, made of a [BreakUart] and a DE pin
+///
+/// Received frames are timestamped with the [Clock] `C`.
+pub struct Rs485<U, DE, C: Clock = EmbassyClock> {
+    uart: U,
+    de: DE,
+    _clock: PhantomData<fn() -> C>,
+}
+
+#[cfg(feature = "embassy-clock")]
+impl<U: BreakUart, DE: OutputPin> Rs485<U, DE> {
+    /// Create a new port like [Rs485::with_clock()], timestamping with the
+    /// [EmbassyClock]
+    pub fn new(uart: U, de: DE) -> Self {
+        Self::with_clock(uart, de)
+    }
+}
+
+impl<U: BreakUart, DE: OutputPin, C: Clock> Rs485<U, DE, C> {
+    /// Create a new port, deasserting DE, and timestamping with the [Clock] `C`
+    ///
+    /// An error deasserting DE is ignored here, as it is when a send is
+    /// cancelled.
+    pub fn with_clock(uart: U, mut de: DE) -> Self {
+        let _ = de.set_low();
+        Self {
+            uart,
+            de,
+            _clock: PhantomData,
+        }
+    }
+
+    /// Consume the port, returning the UART and DE pin
+    pub fn into_inner(self) -> (U, DE) {
+        (self.uart, self.de)
+    }
+}
+
+/// The transceiver's transmit mode, which is left when dropped
+struct TxGuard<'a, U: BreakUart, DE: OutputPin> {
+    uart: &'a mut U,
+    de: &'a mut DE,
+    active: bool,
+}
+
+impl<'a, U: BreakUart, DE: OutputPin> TxGuard<'a, U, DE> {
+    /// Disable the receiver, then assert DE
+    fn enter(uart: &'a mut U, de: &'a mut DE) -> Result<Self, DE::Error> {
+        uart.set_rx_enabled(false);
+        let guard = Self {
+            uart,
+            de,
+            active: true,
+        };
+        // If this fails, dropping the guard re-enables the receiver
+        guard.de.set_high()?;
+        Ok(guard)
+    }
+
+    /// Deassert DE, then re-enable the receiver
+    fn leave(&mut self) -> Result<(), DE::Error> {
+        if !core::mem::take(&mut self.active) {
+            return Ok(());
+        }
+        let res = self.de.set_low();
+        self.uart.set_rx_enabled(true);
+        res
+    }
+}
+
+impl<U: BreakUart, DE: OutputPin> Drop for TxGuard<'_, U, DE> {
+    fn drop(&mut self) {
+        let _ = self.leave();
+    }
+}
+
+impl<U: BreakUart, DE: OutputPin, C: Clock> FrameSerial for Rs485<U, DE, C> {
+    type SerError = Rs485Error<U::Error, DE::Error>;
+
+    async fn send_frame(&mut self, data: &[u8]) -> Result<(), Error<Self::SerError>> {
+        let mut guard = TxGuard::enter(&mut self.uart, &mut self.de)
+            .map_err(|e| Error::Serial(Rs485Error::Pin(e)))?;
+
+        let res = async {
+            guard.uart.write_all(data).await?;
+            guard.uart.flush().await?;
+            guard.uart.send_break().await
+        }
+        .await;
+
+        let left = guard.leave();
+        res.map_err(|e| Error::Serial(Rs485Error::Uart(e)))?;
+        left.map_err(|e| Error::Serial(Rs485Error::Pin(e)))
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        let ct = self
+            .uart
+            .read_to_break(frame)
+            .await
+            .map_err(|e| Error::Serial(Rs485Error::Uart(e)))?;
+        Ok(TimedFrame {
+            end_of_rx: C::now(),
+            frame: &mut frame[..ct],
+        })
+    }
+}
@@ -0,0 +1,255 @@
+//! Sniffer interface
+//!
+//! This interface is used when passively observing a bus, without taking part.
+//!
+//! A [Sniffer] never transmits. It receives every frame on the bus, decodes it
+//! with a [Decoder], and forwards it to the application as a [Sniffed] frame.
+//! Along the way, it rebuilds the Controller's peer table in a [PeerTable], by
+//! watching discovery handshakes, and which Targets answer their selects.
+//!
+//! If the sniffer is started while a bus is already running, Targets that were
+//! discovered before it started are added to the table when they are first
+//! selected, but their MAC address stays unknown until they rejoin.
+
+use core::cell::RefCell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::RawMutex, Mutex as BlockingMutex},
+    channel::Sender,
+};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    decode::{Decoder, Event, EventKind, Violation, MAX_VIOLATIONS},
+    frame_pool::{FrameBox, RawFrameSlice, DEFAULT_FRAME_SIZE},
+    FrameSerial, Mac, MAX_TARGETS,
+};
+
+/// The default number of "in-flight" sniffed frames to the application
+pub const SNIFFED_SIZE: usize = 8;
+
+/// The number of consecutive failures after which the Controller drops a Target
+const MAX_ERRORS: u8 = 3;
+
+/// A frame observed on the bus
+pub struct Sniffed {
+    /// The timestamp of the end of the frame
+    pub at: Instant,
+    /// What kind of frame this was
+    pub kind: EventKind,
+    /// For frames sent by a Target, the time since the Controller frame it answers
+    pub latency: Option<Duration>,
+    /// Any protocol violations found while decoding this frame
+    pub violations: heapless::Vec<Violation, MAX_VIOLATIONS>,
+    /// The frame, including the [CmdAddr][crate::CmdAddr] byte
+    pub frame: FrameBox,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    mac: Option<Mac>,
+    errors: u8,
+}
+
+/// The Controller's peer table, as observed by a [Sniffer]
+///
+/// The table can be shared between the [Sniffer], and any number of
+/// application tasks.
+pub struct PeerTable<R: RawMutex> {
+    peers: BlockingMutex<R, RefCell<[Option<Entry>; MAX_TARGETS]>>,
+}
+
+impl<R: RawMutex> Default for PeerTable<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: RawMutex> PeerTable<R> {
+    /// Create an empty table
+    pub const fn new() -> Self {
+        Self {
+            peers: BlockingMutex::new(RefCell::new([None; MAX_TARGETS])),
+        }
+    }
+
+    /// Is a Target currently assigned the given logical address?
+    pub fn is_active(&self, addr: u8) -> bool {
+        self.with_entry(addr, |e| e.is_some()).unwrap_or(false)
+    }
+
+    /// The MAC address of the Target with the given logical address, if known
+    pub fn mac_of(&self, addr: u8) -> Option<Mac> {
+        self.with_entry(addr, |e| e.and_then(|e| e.mac)).flatten()
+    }
+
+    /// The logical address of the Target with the given MAC address
+    pub fn addr_of(&self, mac: Mac) -> Option<u8> {
+        self.peers.lock(|p| {
+            p.borrow()
+                .iter()
+                .position(|e| e.is_some_and(|e| e.mac == Some(mac)))
+                .map(|i| i as u8)
+        })
+    }
+
+    /// Get the MAC addresses of all Targets with known MAC addresses
+    pub fn connected(&self) -> heapless::Vec<Mac, { MAX_TARGETS + 1 }> {
+        self.peers.lock(|p| {
+            p.borrow()
+                .iter()
+                .filter_map(|e| e.and_then(|e| e.mac))
+                .collect()
+        })
+    }
+
+    fn with_entry<U>(&self, addr: u8, f: impl FnOnce(Option<Entry>) -> U) -> Option<U> {
+        self.peers
+            .lock(|p| p.borrow().get(usize::from(addr)).map(|e| f(*e)))
+    }
+
+    fn update(&self, addr: u8, f: impl FnOnce(&mut Option<Entry>)) {
+        self.peers.lock(|p| {
+            if let Some(e) = p.borrow_mut().get_mut(usize::from(addr)) {
+                f(e);
+            }
+        })
+    }
+
+    /// Record a decoded frame
+    fn observe(&self, kind: &EventKind, violations: &[Violation]) {
+        for v in violations {
+            match *v {
+                Violation::NoResponse { addr } | Violation::WrongAddress { expected: addr, .. } => {
+                    self.update(addr, |e| {
+                        if let Some(ent) = e {
+                            ent.errors += 1;
+                            if ent.errors > MAX_ERRORS {
+                                *e = None;
+                            }
+                        }
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        match *kind {
+            // The Controller only offers free addresses
+            EventKind::Offer { addr, .. } => self.update(addr, |e| *e = None),
+            EventKind::Joined { addr, mac } => self.update(addr, |e| {
+                *e = Some(Entry {
+                    mac: Some(mac),
+                    errors: 0,
+                })
+            }),
+            // The Controller only selects active addresses
+            EventKind::Select { addr } => self.update(addr, |e| {
+                e.get_or_insert(Entry {
+                    mac: None,
+                    errors: 0,
+                });
+            }),
+            EventKind::Reply { addr } if violations.is_empty() => self.update(addr, |e| {
+                if let Some(ent) = e {
+                    ent.errors = 0;
+                }
+            }),
+            _ => {}
+        }
+    }
+}
+
+/// Interface for the Sniffer
+pub struct Sniffer<'a, R, T, const N: usize = SNIFFED_SIZE>
+where
+    R: RawMutex + 'static,
+    T: FrameSerial,
+{
+    serial: T,
+    to_app: Sender<'a, R, Sniffed, N>,
+    pool: RawFrameSlice,
+    table: &'a PeerTable<R>,
+    decoder: Decoder,
+    dropped: u32,
+}
+
+impl<'a, R, T, const N: usize> Sniffer<'a, R, T, N>
+where
+    R: RawMutex + 'static,
+    T: FrameSerial,
+{
+    /// Create a new [Sniffer] worker.
+    pub fn new(
+        serial: T,
+        to_app: Sender<'a, R, Sniffed, N>,
+        pool: RawFrameSlice,
+        table: &'a PeerTable<R>,
+    ) -> Self {
+        Self {
+            serial,
+            to_app,
+            pool,
+            table,
+            decoder: Decoder::new(),
+            dropped: 0,
+        }
+    }
+
+    /// The number of frames that were not forwarded to the application, because
+    /// no frames could be allocated, or the channel was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Run forever, observing the bus
+    ///
+    /// Frames are never held up waiting for the application: if the channel is
+    /// full, or the pool is empty, the frame is still decoded and used to update
+    /// the [PeerTable], but it is not forwarded.
+    pub async fn run(&mut self) {
+        let mut scratch = [0u8; DEFAULT_FRAME_SIZE];
+        loop {
+            let mut fb = self.pool.allocate_raw();
+            let buf = match fb.as_deref_mut() {
+                Some(fb) => fb,
+                None => &mut scratch,
+            };
+            let (at, len) = match self.serial.recv(buf).await {
+                Ok(tf) => (tf.end_of_rx, tf.frame.len()),
+                Err(_) => {
+                    nut_warn!("Sniffer receive error");
+                    continue;
+                }
+            };
+
+            // Keep only the owned parts of the event, releasing the borrow of `buf`
+            let Event {
+                kind,
+                latency,
+                violations,
+                ..
+            } = self.decoder.feed(at, &buf[..len]);
+            self.table.observe(&kind, &violations);
+
+            if len == 0 {
+                continue;
+            }
+            let Some(mut frame) = fb else {
+                self.dropped = self.dropped.wrapping_add(1);
+                continue;
+            };
+            frame.set_len(len);
+            let sniffed = Sniffed {
+                at,
+                kind,
+                latency,
+                violations,
+                frame,
+            };
+            if self.to_app.try_send(sniffed).is_err() {
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+        }
+    }
+}
@@ -6,13 +6,15 @@ use embassy_sync::{
     blocking_mutex::raw::RawMutex,
     channel::{Receiver, Sender},
 };
-use embassy_time::{with_timeout, Duration, Timer};
+use embassy_time::Duration;
 use futures::FutureExt;
 use rand_core::RngCore;
 
 use crate::{
+    addr_mark::AddrFilter,
+    clock::Clock,
     frame_pool::{FrameBox, RawFrameSlice},
-    CmdAddr, FrameSerial,
+    CmdAddr, FrameSerial, Mac,
 };
 
 /// The default number of "in-flight" packets FROM Target TO Controller
@@ -31,6 +33,10 @@ pub trait TgtCfg {
     /// Random number generator
     type Rand: RngCore;
 
+    /// Clock used for all timeouts and delays, such as
+    /// [EmbassyClock][crate::clock::EmbassyClock]
+    type Clock: Clock;
+
     /// Amount of time to delay from hearing a response to
     /// sending a reply.
     const TURNAROUND_DELAY: Duration;
@@ -72,8 +78,8 @@ where
     to_app: Sender<'a, Cfg::Mutex, FrameBox, IN>,
     from_app: Receiver<'a, Cfg::Mutex, FrameBox, OUT>,
     pool: RawFrameSlice,
-    mac: [u8; 8],
     rand: Cfg::Rand,
+    state: TargetState,
 }
 
 impl<'a, Cfg, const IN: usize, const OUT: usize> Target<'a, Cfg, IN, OUT>
@@ -86,218 +92,297 @@ where
         to_app: Sender<'a, Cfg::Mutex, FrameBox, IN>,
         from_app: Receiver<'a, Cfg::Mutex, FrameBox, OUT>,
         pool: RawFrameSlice,
-        mac: [u8; 8],
+        mac: Mac,
         rand: Cfg::Rand,
     ) -> Self {
         Self {
             serial,
             to_app,
             from_app,
-            mac,
             rand,
             pool,
+            state: TargetState::new(
+                mac,
+                TargetTiming {
+                    turnaround_delay: Cfg::TURNAROUND_DELAY,
+                    address_claim_timeout: Cfg::ADDRESS_CLAIM_TIMEOUT,
+                    select_timeout: Cfg::SELECT_TIMEOUT,
+                },
+            ),
         }
     }
 
     /// Run forever, exchanging messages
     pub async fn run(&mut self) {
-        'outer: loop {
-            let addr = self.get_addr().await;
-            nut_info!("Got addr: {=u8}", addr);
-
-            loop {
-                match with_timeout(Cfg::SELECT_TIMEOUT, self.exchange_one(addr)).await {
-                    // Exchange happened w/in timeout
-                    Ok(Ok(())) => {}
-                    // Exchange happened w/in timeout, but errored
-                    Ok(Err(_)) => {
-                        nut_error!("Error :(");
-                        continue 'outer;
-                    }
-                    // Timed out
-                    Err(_) => {
-                        nut_warn!("Timed out!");
-                        continue 'outer;
-                    }
+        loop {
+            let res = match self.state.deadline() {
+                Some(at) => Cfg::Clock::with_deadline(at, self.exchange_one()).await,
+                None => Ok(self.exchange_one().await),
+            };
+            match res {
+                // Exchange happened w/in timeout
+                Ok(Ok(())) => {}
+                // Exchange happened w/in timeout, but errored
+                Ok(Err(_)) => {
+                    nut_error!("Error :(");
+                    self.state.reset();
+                }
+                // Timed out
+                Err(_) => {
+                    nut_warn!("Timed out!");
+                    self.state.reset();
                 }
             }
         }
     }
 
+    /// Receive one frame, and act on it
     async fn exchange_one(
         &mut self,
-        addr: u8,
     ) -> Result<(), TargetError<<Cfg::Serial as FrameSerial>::SerError>> {
-        // Wait for us to be acknowledged, and pass on the frame if we get one
-        let time = self.get_incoming(addr).await?;
-
-        // Is there something to send now? If not, empty-ack.
-        let mut tx_frame = self.from_app.receive().now_or_never();
-        let mut fallback = [0u8; 1];
-        let out = match tx_frame.as_deref_mut() {
-            Some(g) => g,
-            None => fallback.as_mut_slice(),
+        // Frames for the application need a pool frame, the discovery messages
+        // fit in 1 + 8 bytes, plus one for the line break
+        let mut scratch = [0u8; 16];
+        let mut pooled = None;
+        self.serial.set_addr_filter(self.state.addr_filter());
+        let buf = match self.state.addr() {
+            Some(_) => {
+                let frame = self.pool.allocate_raw().ok_or(TargetError::Oom)?;
+                &mut pooled.insert(frame)[..]
+            }
+            None => &mut scratch[..],
         };
-        out[0] = CmdAddr::ReplyFromAddr(addr).into();
-
-        // Send reply
-        Timer::at(time + Cfg::TURNAROUND_DELAY).await;
-        self.serial.send_frame(out).await?;
-        Ok(())
-    }
 
-    async fn get_incoming(
-        &mut self,
-        addr: u8,
-    ) -> Result<crate::Instant, TargetError<<Cfg::Serial as FrameSerial>::SerError>> {
-        let mut frame = self.pool.allocate_raw().ok_or(TargetError::Oom)?;
-        loop {
-            let buf = &mut frame[..];
-            let got = self.serial.recv(buf).await?;
-            if got.frame.is_empty() {
-                continue;
+        let (action, len) = match self.serial.recv(buf).await {
+            Ok(tf) => (
+                self.state
+                    .handle_frame(tf.end_of_rx, tf.frame, &mut self.rand),
+                tf.frame.len(),
+            ),
+            Err(_) => {
+                self.state.recv_failed();
+                return Ok(());
             }
-            let Ok(cmd_addr) = CmdAddr::try_from(got.frame[0]) else {
-                continue;
-            };
-            if cmd_addr != CmdAddr::SelectAddr(addr) {
-                continue;
+        };
+
+        match action {
+            Action::Ignore => {}
+            Action::Claim(claim) => self.serial.send_frame(&claim).await?,
+            Action::Ack(addr) => {
+                let msg: [u8; 1] = [CmdAddr::ReplyFromAddr(addr).into()];
+                self.serial.send_frame(&msg).await?;
             }
-            let len = got.frame.len();
-            let stamp = got.end_of_rx;
+            Action::Reply {
+                addr,
+                reply_at,
+                deliver,
+            } => {
+                // Pass on the frame if we got one
+                if let (true, Some(mut frame)) = (deliver, pooled) {
+                    frame.set_len(len);
+                    self.to_app.send(frame).await;
+                }
 
-            if len != 1 {
-                frame.set_len(len);
-                self.to_app.send(frame).await;
+                // Is there something to send now? If not, empty-ack.
+                let mut tx_frame = self.from_app.receive().now_or_never();
+                let mut fallback = [0u8; 1];
+                let out = match tx_frame.as_deref_mut() {
+                    Some(g) => g,
+                    None => fallback.as_mut_slice(),
+                };
+                out[0] = CmdAddr::ReplyFromAddr(addr).into();
+
+                // Send reply
+                Cfg::Clock::wait_until(reply_at).await;
+                self.serial.send_frame(out).await?;
             }
-            return Ok(stamp);
         }
+        Ok(())
     }
+}
 
-    async fn get_addr(&mut self) -> u8 {
-        loop {
-            nut_info!("get_addr...");
-            let goforit = self.rand.next_u32();
+/// The timing of a Target's side of the protocol, see [TgtCfg]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetTiming {
+    /// Amount of time to delay from hearing a response to
+    /// sending a reply.
+    pub turnaround_delay: Duration,
+    /// Amount of time from initiating a claim to getting an address
+    pub address_claim_timeout: Duration,
+    /// Amount of time being unaddressed before trying to get a new
+    /// address
+    pub select_timeout: Duration,
+}
 
-            // Wait for an offer frame
-            let (offer_addr, offer_challenge) = self.get_offer().await;
+/// What a Target should do with a received frame, as decided by
+/// [TargetState::handle_frame()]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Nothing, the frame was not for us
+    Ignore,
+    /// Immediately send this claim of an offered address
+    Claim([u8; 9]),
+    /// The Controller confirmed our claim of this address, immediately
+    /// acknowledge it with a bare [CmdAddr::ReplyFromAddr]
+    Ack(u8),
+    /// We were selected by the Controller
+    Reply {
+        /// Our address
+        addr: u8,
+        /// When to send our reply, a [CmdAddr::ReplyFromAddr] followed by
+        /// zero or one outgoing frames from the application
+        reply_at: crate::Instant,
+        /// Whether the received frame has a payload, which should be passed
+        /// on to the application
+        deliver: bool,
+    },
+}
 
-            // do we go for it? (1/8 chance)
-            if goforit & 0b0000_0111 != 0 {
-                nut_info!("skipping!");
-                continue;
-            } else {
-                nut_info!("going for it!");
-            }
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Unaddressed,
+    Claiming { addr: u8, deadline: crate::Instant },
+    Joined { addr: u8, deadline: crate::Instant },
+}
 
-            let claim_dance = async {
-                self.send_claim(offer_addr, &offer_challenge).await?;
-                self.get_success(offer_addr).await?;
-                let msg: [u8; 1] = [CmdAddr::ReplyFromAddr(offer_addr).into()];
-                self.serial.send_frame(&msg).await?;
-                Result::<(), TargetError<<Cfg::Serial as FrameSerial>::SerError>>::Ok(())
-            };
+/// The protocol state of a Target, without any I/O
+///
+/// This is shared by the async [Target], and the
+/// [BlockingTarget][crate::blocking::BlockingTarget]. It can also be used
+/// directly, for example from a UART interrupt: feed every received frame to
+/// [TargetState::handle_frame()], and carry out the returned [Action]. Call
+/// [TargetState::check_timeout()] at, or after, [TargetState::deadline()], to
+/// notice when the Controller has stopped talking to us.
+pub struct TargetState {
+    mac: Mac,
+    timing: TargetTiming,
+    phase: Phase,
+}
 
-            // Give ourselves some time to complete, if not try again
-            match with_timeout(Cfg::ADDRESS_CLAIM_TIMEOUT, claim_dance).await {
-                Ok(Ok(())) => return offer_addr,
-                _ => continue,
-            }
+impl TargetState {
+    /// Create the state of a Target with the given MAC address, which has not
+    /// yet joined the bus
+    pub const fn new(mac: Mac, timing: TargetTiming) -> Self {
+        Self {
+            mac,
+            timing,
+            phase: Phase::Unaddressed,
         }
     }
 
-    async fn get_success(
-        &mut self,
-        offer_addr: u8,
-    ) -> Result<(), TargetError<<Cfg::Serial as FrameSerial>::SerError>> {
-        // A success packet should be 9 bytes plus one extra for
-        // the line break.
-        let mut scratch = [0u8; 16];
-        loop {
-            nut_info!("get success");
-            let Ok(tframe) = self.serial.recv(&mut scratch).await else {
-                // log: wat
-                // NOTE: I feel like this is probably going to be due to
-                // hearing messages longer than `scratch`.
-                continue;
-            };
-            // Is this a valid cmd addr?
-            let Some(ca) = tframe
-                .frame
-                .first()
-                .and_then(|b| CmdAddr::try_from(*b).ok())
-            else {
-                continue;
-            };
-            // Is this an offer?
-            let CmdAddr::DiscoverySuccess(addr) = ca else {
-                continue;
-            };
-
-            // Is this long enough?
-            if tframe.frame.len() < 9 {
-                continue;
-            }
+    /// Our logical address, if we have joined the bus
+    pub fn addr(&self) -> Option<u8> {
+        match self.phase {
+            Phase::Joined { addr, .. } => Some(addr),
+            _ => None,
+        }
+    }
 
-            // Is this for us?
-            if addr == offer_addr && (tframe.frame[1..9] == self.mac) {
-                return Ok(());
-            } else {
-                continue;
-            }
+    /// The frames we are interested in, in our current phase
+    pub fn addr_filter(&self) -> AddrFilter {
+        match self.phase {
+            Phase::Unaddressed => AddrFilter::Discovery,
+            Phase::Claiming { addr, .. } => AddrFilter::Claiming(addr),
+            Phase::Joined { addr, .. } => AddrFilter::Joined(addr),
         }
     }
 
-    async fn send_claim(
-        &mut self,
-        offer_addr: u8,
-        challenge: &[u8; 8],
-    ) -> Result<(), TargetError<<Cfg::Serial as FrameSerial>::SerError>> {
-        nut_info!("send claim");
-        let mut claim = [0u8; 9];
+    /// The time by which we must hear from the Controller, before giving up
+    /// on the address we have, or are claiming
+    pub fn deadline(&self) -> Option<crate::Instant> {
+        match self.phase {
+            Phase::Unaddressed => None,
+            Phase::Claiming { deadline, .. } | Phase::Joined { deadline, .. } => Some(deadline),
+        }
+    }
 
-        claim[0] = CmdAddr::DiscoveryClaim(offer_addr).into();
-        let data = &mut claim[1..9];
-        data.copy_from_slice(&self.mac);
+    /// Give up our address if the [TargetState::deadline()] has passed,
+    /// returning whether we did
+    pub fn check_timeout(&mut self, now: crate::Instant) -> bool {
+        let expired = self.deadline().is_some_and(|d| now >= d);
+        if expired {
+            nut_warn!("Timed out!");
+            self.reset();
+        }
+        expired
+    }
 
-        data.iter_mut()
-            .zip(challenge.iter())
-            .for_each(|(a, b)| *a ^= *b);
+    /// Give up our address, and look for a new one
+    pub fn reset(&mut self) {
+        self.phase = Phase::Unaddressed;
+    }
 
-        self.serial.send_frame(&claim).await?;
-        Ok(())
+    /// A receive failed, giving up our address if we have one
+    ///
+    /// While looking for an address, receive errors are expected, as we hear
+    /// frames of any length, for any Target.
+    pub fn recv_failed(&mut self) {
+        if let Phase::Joined { .. } = self.phase {
+            nut_error!("Error :(");
+            self.reset();
+        }
     }
 
-    async fn get_offer(&mut self) -> (u8, [u8; 8]) {
-        // offer should be 1 + 8 + 1 for line break
-        let mut scratch = [0u8; 16];
-        loop {
-            let Ok(tframe) = self.serial.recv(&mut scratch).await else {
-                // log: wat
-                // probably due to scratch being too small if we hear
-                // someone elses frame
-                continue;
-            };
-            // Is this a valid cmd addr?
-            let Some(ca) = tframe
-                .frame
-                .first()
-                .and_then(|b| CmdAddr::try_from(*b).ok())
-            else {
-                continue;
-            };
-            // Is this an offer?
-            let CmdAddr::DiscoveryOffer(addr) = ca else {
-                continue;
-            };
-            // Is this long enough?
-            if tframe.frame.len() < 9 {
-                continue;
-            }
-            let mut challenge = [0u8; 8];
-            challenge.copy_from_slice(&tframe.frame[1..9]);
+    /// Process a frame received at `at`, deciding what to do about it
+    ///
+    /// `rand` is used to decide whether to claim an offered address.
+    pub fn handle_frame<R: RngCore>(
+        &mut self,
+        at: crate::Instant,
+        frame: &[u8],
+        rand: &mut R,
+    ) -> Action {
+        let Some(cmd_addr) = frame.first().and_then(|b| CmdAddr::try_from(*b).ok()) else {
+            return Action::Ignore;
+        };
+
+        match (self.phase, cmd_addr) {
+            (Phase::Unaddressed, CmdAddr::DiscoveryOffer(addr)) if frame.len() >= 9 => {
+                // do we go for it? (1/8 chance)
+                if rand.next_u32() & 0b0000_0111 != 0 {
+                    nut_info!("skipping!");
+                    return Action::Ignore;
+                }
+                nut_info!("going for it!");
 
-            return (addr, challenge);
+                let mut claim = [0u8; 9];
+                claim[0] = CmdAddr::DiscoveryClaim(addr).into();
+                claim[1..9]
+                    .iter_mut()
+                    .zip(self.mac.to_wire_bytes().iter().zip(&frame[1..9]))
+                    .for_each(|(c, (m, r))| *c = *m ^ *r);
+
+                // Give ourselves some time to complete, if not try again
+                self.phase = Phase::Claiming {
+                    addr,
+                    deadline: at + self.timing.address_claim_timeout,
+                };
+                Action::Claim(claim)
+            }
+            (Phase::Claiming { addr: claimed, .. }, CmdAddr::DiscoverySuccess(addr))
+                if addr == claimed
+                    && frame.len() >= 9
+                    && frame[1..9] == self.mac.to_wire_bytes() =>
+            {
+                nut_info!("Got addr: {=u8}", addr);
+                self.phase = Phase::Joined {
+                    addr,
+                    deadline: at + self.timing.select_timeout,
+                };
+                Action::Ack(addr)
+            }
+            (Phase::Joined { addr: ours, .. }, CmdAddr::SelectAddr(addr)) if addr == ours => {
+                self.phase = Phase::Joined {
+                    addr,
+                    deadline: at + self.timing.select_timeout,
+                };
+                Action::Reply {
+                    addr,
+                    reply_at: at + self.timing.turnaround_delay,
+                    deliver: frame.len() != 1,
+                }
+            }
+            _ => Action::Ignore,
         }
     }
 }
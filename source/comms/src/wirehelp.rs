@@ -1,8 +1,43 @@
 //! Wire data format helper functions
+//!
+//! ## Dispatching requests on a Target
+//!
+//! The [`define_dispatch!`][crate::define_dispatch] macro can be used to
+//! generate a function that handles `Endpoint` requests received by a Target,
+//! calling one async handler per `Endpoint`, and producing the reply frame.
+//! Requests with an unknown key are answered with a [`WireError`], sent with
+//! [`ERROR_KEY`].
+//!
+//! ## Frame sizes
+//!
+//! Like the Controller and Target roles, the builders in this module work
+//! with frames of the [default size][crate::frame_pool::DEFAULT_FRAME_SIZE].
+//! Received frames of any size can be decoded with [`WhBody::try_from()`].
 
-use crate::frame_pool::FrameBox;
-use postcard_rpc::{Endpoint, Topic, WireHeader};
-use serde::Serialize;
+use crate::frame_pool::{FrameBox, SendFrameBox, DEFAULT_FRAME_SIZE};
+use postcard::experimental::schema::Schema;
+use postcard_rpc::{Endpoint, Key, Topic, WireHeader};
+use serde::{Deserialize, Serialize};
+
+/// The path used to derive [ERROR_KEY]
+pub const ERROR_PATH: &str = "erdnuss/error";
+
+/// The key used for [WireError] responses
+pub const ERROR_KEY: Key = Key::for_path::<WireError>(ERROR_PATH);
+
+/// A standard error response, sent in place of an `Endpoint` response
+///
+/// Error responses are sent with the [ERROR_KEY], and the same `seq_no`
+/// as the request that caused them.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema)]
+pub enum WireError {
+    /// No handler exists for the contained request key
+    UnknownKey([u8; 8]),
+    /// The request body could not be deserialized
+    DeserFailed,
+    /// The response could not be serialized into the reply frame
+    SerFailed,
+}
 
 /// A borrowed view of a frame that contains a postcard-rpc message.
 pub struct WhBody<'a> {
@@ -14,39 +49,117 @@ pub struct WhBody<'a> {
 
 impl<'a> WhBody<'a> {
     /// Attempt to decode a [postcard-rpc] frame from a FrameBox
-    pub fn try_from(fb: &'a FrameBox) -> Option<Self> {
+    pub fn try_from<const S: usize>(fb: &'a FrameBox<S>) -> Option<Self> {
         let (_a, remain) = fb.split_first()?;
         let (wh, body) = postcard_rpc::headered::extract_header_from_bytes(remain).ok()?;
         Some(WhBody { wh, body })
     }
+
+    /// Deserialize the body as an `Endpoint` `Request`
+    ///
+    /// Returns [None] if the key is not `E::REQ_KEY`, if deserialization fails, or if
+    /// any bytes are left over after the message.
+    /// The request may borrow from the body of the frame.
+    pub fn decode_request<E>(&self) -> Option<E::Request>
+    where
+        E: Endpoint,
+        E::Request: Deserialize<'a>,
+    {
+        self.decode_keyed(E::REQ_KEY)
+    }
+
+    /// Deserialize the body as an `Endpoint` `Response`
+    ///
+    /// Returns [None] if the key is not `E::RESP_KEY`, if deserialization fails, or if
+    /// any bytes are left over after the message.
+    /// The response may borrow from the body of the frame.
+    pub fn decode_response<E>(&self) -> Option<E::Response>
+    where
+        E: Endpoint,
+        E::Response: Deserialize<'a>,
+    {
+        self.decode_keyed(E::RESP_KEY)
+    }
+
+    /// Deserialize the body as a `Topic` `Message`
+    ///
+    /// Returns [None] if the key is not `T::TOPIC_KEY`, if deserialization fails, or if
+    /// any bytes are left over after the message.
+    /// The message may borrow from the body of the frame.
+    pub fn decode_topic<T>(&self) -> Option<T::Message>
+    where
+        T: Topic,
+        T::Message: Deserialize<'a>,
+    {
+        self.decode_keyed(T::TOPIC_KEY)
+    }
+
+    /// Deserialize the body as a [WireError]
+    ///
+    /// Returns [None] if the key is not [ERROR_KEY], if deserialization fails, or if
+    /// any bytes are left over after the message.
+    pub fn decode_error(&self) -> Option<WireError> {
+        self.decode_keyed(ERROR_KEY)
+    }
+
+    fn decode_keyed<T: Deserialize<'a>>(&self, key: Key) -> Option<T> {
+        if self.wh.key != key {
+            return None;
+        }
+        match postcard::take_from_bytes(self.body) {
+            Ok((msg, [])) => Some(msg),
+            _ => None,
+        }
+    }
 }
 
-#[inline]
-fn build_reply_keyed<T: Serialize>(
-    mut buf: FrameBox,
+/// Write the header and message into the payload of `sfb`
+///
+/// On failure, the length of `sfb` is left unchanged.
+fn encode_keyed<T: Serialize, const S: usize>(
+    sfb: &mut SendFrameBox<S>,
     wh: &WireHeader,
     msg: &T,
-) -> Option<FrameBox> {
-    if buf.is_empty() {
-        return None;
-    }
-    // Split off the address, but DON'T write it!
-    //
-    // "userspace" doesn't actually know our wire addr, it gets
-    // added at send time.
-    let (_a, remain) = buf.split_first_mut()?;
+) -> Option<()> {
+    let remain = sfb.payload_mut();
     // Then add the wireheader
     let used1 = postcard::to_slice(wh, remain).ok()?.len();
     let (_hdr, remain) = remain.split_at_mut(used1);
     // Then add the body
     let used2 = postcard::to_slice(msg, remain).ok()?.len();
+    sfb.set_len(used1 + used2);
 
-    // TODO: Add CRC?
+    // TODO: Add CRC? This could be appended with `put_trailer`.
 
-    let ttl_len = 1 + used1 + used2;
-    buf.set_len(ttl_len);
+    Some(())
+}
 
-    Some(buf)
+#[inline]
+pub(crate) fn build_reply_keyed<T: Serialize, const S: usize>(
+    buf: FrameBox<S>,
+    wh: &WireHeader,
+    msg: &T,
+) -> Option<FrameBox<S>> {
+    // The SendFrameBox leaves room for the address, but DOESN'T write it!
+    //
+    // "userspace" doesn't actually know our wire addr, it gets
+    // added at send time.
+    let mut sfb = SendFrameBox::from(buf);
+    encode_keyed(&mut sfb, wh, msg)?;
+    Some(sfb.into_inner())
+}
+
+/// Prepare an `Endpoint` `Request` message for sending
+pub fn send_request<E>(buf: FrameBox, seq_no: u32, msg: &E::Request) -> Option<FrameBox>
+where
+    E: Endpoint,
+    E::Request: Serialize,
+{
+    let wh = WireHeader {
+        key: E::REQ_KEY,
+        seq_no,
+    };
+    build_reply_keyed(buf, &wh, msg)
 }
 
 /// Prepare a `Topic` message for sending
@@ -59,7 +172,7 @@ where
         key: T::TOPIC_KEY,
         seq_no,
     };
-    build_reply_keyed::<T::Message>(buf, &wh, msg)
+    build_reply_keyed(buf, &wh, msg)
 }
 
 /// Prepare an `Endpoint` `Response` message for sending
@@ -72,5 +185,163 @@ where
         key: E::RESP_KEY,
         seq_no,
     };
-    build_reply_keyed::<E::Response>(buf, &wh, msg)
+    build_reply_keyed(buf, &wh, msg)
+}
+
+/// Prepare a [WireError] response for sending
+pub fn reply_error(buf: FrameBox, seq_no: u32, err: &WireError) -> Option<FrameBox> {
+    let wh = WireHeader {
+        key: ERROR_KEY,
+        seq_no,
+    };
+    build_reply_keyed(buf, &wh, err)
+}
+
+/// Decode the header of a received request. Used by [define_dispatch!][crate::define_dispatch].
+#[doc(hidden)]
+pub fn __dispatch_header(fb: &FrameBox) -> Option<(Key, u32)> {
+    let whb = WhBody::try_from(fb)?;
+    Some((whb.wh.key, whb.wh.seq_no))
+}
+
+/// Decode the body of a received request. Used by [define_dispatch!][crate::define_dispatch].
+#[doc(hidden)]
+pub fn __dispatch_request<'a, E>(fb: &'a FrameBox) -> Option<E::Request>
+where
+    E: Endpoint,
+    E::Request: Deserialize<'a>,
+{
+    WhBody::try_from(fb)?.decode_request::<E>()
 }
+
+/// Re-use a received request frame for the response, falling back to a
+/// [WireError::SerFailed] response. Used by [define_dispatch!][crate::define_dispatch].
+#[doc(hidden)]
+pub fn __dispatch_reply<E>(mut fb: FrameBox, seq_no: u32, resp: &E::Response) -> Option<FrameBox>
+where
+    E: Endpoint,
+    E::Response: Serialize,
+{
+    fb.set_len(DEFAULT_FRAME_SIZE);
+    let mut sfb = SendFrameBox::from(fb);
+    let wh = WireHeader {
+        key: E::RESP_KEY,
+        seq_no,
+    };
+    if encode_keyed(&mut sfb, &wh, resp).is_none() {
+        let wh = WireHeader {
+            key: ERROR_KEY,
+            seq_no,
+        };
+        encode_keyed(&mut sfb, &wh, &WireError::SerFailed)?;
+    }
+    Some(sfb.into_inner())
+}
+
+/// Re-use a received request frame for an error response. Used by
+/// [define_dispatch!][crate::define_dispatch].
+#[doc(hidden)]
+pub fn __dispatch_error(mut fb: FrameBox, seq_no: u32, err: &WireError) -> Option<FrameBox> {
+    fb.set_len(DEFAULT_FRAME_SIZE);
+    reply_error(fb, seq_no, err)
+}
+
+/// Define an async function that dispatches postcard-rpc requests to handlers
+///
+/// The generated function takes a context, and a [FrameBox] received by a
+/// [Target][crate::target::Target], and returns the frame to send back in
+/// reply, if any. The received frame is re-used for the reply, so no extra
+/// allocation is necessary.
+///
+/// For each request:
+///
+/// * If the frame does not contain a valid header, nothing is returned
+/// * If the key matches one of the `Endpoint`s, the request is deserialized and
+///   passed to the handler, and its response is sent with the `Endpoint`'s
+///   response key, and the `seq_no` of the request
+/// * Otherwise, a [WireError] is sent with the [ERROR_KEY], and the `seq_no` of
+///   the request
+///
+/// [Controller::request()][crate::Controller::request] returns any [WireError]
+/// sent in reply as [RequestError::Remote][crate::controller::RequestError::Remote].
+///
+/// Handlers are async functions taking the context, and the deserialized request,
+/// and returning the response.
+///
+/// ```rust
+/// use erdnuss_comms::define_dispatch;
+/// use postcard::experimental::schema::Schema;
+/// use postcard_rpc::endpoint;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize, Schema)]
+/// pub struct SetLed {
+///     on: bool,
+/// }
+///
+/// endpoint!(PingEndpoint, u32, u32, "demo/ping");
+/// endpoint!(LedEndpoint, SetLed, (), "demo/led");
+///
+/// pub struct Context {
+///     led: bool,
+/// }
+///
+/// async fn ping(_ctx: &mut Context, req: u32) -> u32 {
+///     req
+/// }
+///
+/// async fn set_led(ctx: &mut Context, req: SetLed) {
+///     ctx.led = req.on;
+/// }
+///
+/// define_dispatch! {
+///     pub async fn dispatch(ctx: &mut Context);
+///     PingEndpoint => ping,
+///     LedEndpoint => set_led,
+/// }
+/// ```
+///
+/// The generated function has the signature:
+///
+/// ```text
+/// pub async fn dispatch(ctx: &mut Context, frame: FrameBox) -> Option<FrameBox>;
+/// ```
+#[macro_export]
+macro_rules! define_dispatch {
+    (
+        $(#[$meta:meta])*
+        $vis:vis async fn $name:ident($ctx:ident: $cty:ty);
+        $($ep:ty => $handler:path),* $(,)?
+    ) => {
+        $(#[$meta])*
+        $vis async fn $name(
+            $ctx: $cty,
+            frame: $crate::frame_pool::FrameBox,
+        ) -> ::core::option::Option<$crate::frame_pool::FrameBox> {
+            let (key, seq_no) = $crate::wirehelp::__dispatch_header(&frame)?;
+            $(
+                if key == <$ep as $crate::wirehelp::__Endpoint>::REQ_KEY {
+                    let ::core::option::Option::Some(req) =
+                        $crate::wirehelp::__dispatch_request::<$ep>(&frame)
+                    else {
+                        return $crate::wirehelp::__dispatch_error(
+                            frame,
+                            seq_no,
+                            &$crate::wirehelp::WireError::DeserFailed,
+                        );
+                    };
+                    let resp = $handler($ctx, req).await;
+                    return $crate::wirehelp::__dispatch_reply::<$ep>(frame, seq_no, &resp);
+                }
+            )*
+            $crate::wirehelp::__dispatch_error(
+                frame,
+                seq_no,
+                &$crate::wirehelp::WireError::UnknownKey(key.to_bytes()),
+            )
+        }
+    };
+}
+
+#[doc(hidden)]
+pub use postcard_rpc::Endpoint as __Endpoint;
@@ -0,0 +1,132 @@
+//! Exercise the host side of the bridge protocol over a pair of in-memory pipes
+
+use core::pin::pin;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_time::Duration;
+use erdnuss_comms::{
+    bridge::{
+        run, BridgeError, BridgeStats, ListPeersEndpoint, PeerList, SendTo, SendToEndpoint,
+        StatsEndpoint, MAX_HOST_MSG,
+    },
+    frame_pool::FrameStorage,
+    wirehelp::{WireError, ERROR_KEY},
+    Controller, Mac,
+};
+use futures::{
+    executor::block_on,
+    future::{select, Either},
+};
+use postcard::experimental::schema::Schema;
+use postcard_rpc::{
+    headered::{extract_header_from_bytes, to_slice_cobs_keyed},
+    Endpoint, Key, WireHeader,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+type HostPipe = Pipe<CriticalSectionRawMutex, 1024>;
+
+static STORAGE: FrameStorage<4> = FrameStorage::new();
+static CONTROLLER: Controller<CriticalSectionRawMutex> = Controller::uninit();
+
+/// The host end of the link
+struct Host<'a> {
+    to_bridge: &'a HostPipe,
+    from_bridge: &'a HostPipe,
+    rx_buf: Vec<u8>,
+}
+
+impl Host<'_> {
+    async fn send<T: Serialize + Schema>(&mut self, key: Key, seq_no: u32, msg: &T) {
+        let mut buf = [0u8; MAX_HOST_MSG];
+        let enc = to_slice_cobs_keyed(seq_no, key, msg, &mut buf).unwrap();
+        self.to_bridge.write_all(enc).await;
+    }
+
+    async fn recv<T: DeserializeOwned>(&mut self) -> (WireHeader, T) {
+        loop {
+            if let Some(pos) = self.rx_buf.iter().position(|b| *b == 0) {
+                let mut frame: Vec<u8> = self.rx_buf.drain(..=pos).collect();
+                let len = cobs::decode_in_place(&mut frame[..pos]).unwrap();
+                let (wh, body) = extract_header_from_bytes(&frame[..len]).unwrap();
+                return (wh, postcard::from_bytes(body).unwrap());
+            }
+            let mut chunk = [0u8; 64];
+            let ct = self.from_bridge.read(&mut chunk).await;
+            self.rx_buf.extend_from_slice(&chunk[..ct]);
+        }
+    }
+}
+
+#[test]
+fn bridge_over_pipe() {
+    let to_bridge = HostPipe::new();
+    let from_bridge = HostPipe::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    let bridge = pin!(run(
+        &CONTROLLER,
+        &mut pool,
+        &to_bridge,
+        &from_bridge,
+        Duration::from_millis(1),
+    ));
+
+    let host = pin!(async {
+        let mut host = Host {
+            to_bridge: &to_bridge,
+            from_bridge: &from_bridge,
+            rx_buf: Vec::new(),
+        };
+
+        // No Targets are connected
+        host.send(ListPeersEndpoint::REQ_KEY, 1, &()).await;
+        let (wh, peers) = host.recv::<PeerList>().await;
+        assert_eq!(wh.key, ListPeersEndpoint::RESP_KEY);
+        assert_eq!(wh.seq_no, 1);
+        assert!(peers.is_empty());
+
+        // Sending to an unknown Target fails, and does not leak the frame
+        let req = SendTo {
+            mac: Mac::new(0x0123_4567_89AB_CDEF),
+            data: heapless::Vec::from_slice(&[1, 2, 3]).unwrap(),
+        };
+        host.send(SendToEndpoint::REQ_KEY, 2, &req).await;
+        let (wh, resp) = host.recv::<Result<(), BridgeError>>().await;
+        assert_eq!(wh.key, SendToEndpoint::RESP_KEY);
+        assert_eq!(wh.seq_no, 2);
+        assert_eq!(resp, Err(BridgeError::NoMatchingMac));
+
+        // Unknown keys are answered with an error
+        let bogus = Key::for_path::<u8>("not/a/real/path");
+        host.send(bogus, 3, &0u8).await;
+        let (wh, resp) = host.recv::<WireError>().await;
+        assert_eq!(wh.key, ERROR_KEY);
+        assert_eq!(wh.seq_no, 3);
+        assert_eq!(resp, WireError::UnknownKey(bogus.to_bytes()));
+
+        // Malformed messages are counted, but not answered
+        host.to_bridge.write_all(&[0x05, 0x00]).await;
+
+        host.send(StatsEndpoint::REQ_KEY, 4, &()).await;
+        let (wh, stats) = host.recv::<BridgeStats>().await;
+        assert_eq!(wh.key, StatsEndpoint::RESP_KEY);
+        assert_eq!(wh.seq_no, 4);
+        assert_eq!(
+            stats,
+            BridgeStats {
+                frames_to_targets: 0,
+                frames_from_targets: 0,
+                send_errors: 1,
+                host_errors: 2,
+                pool_capacity: 4,
+                pool_in_use: 0,
+            }
+        );
+    });
+
+    match block_on(select(bridge, host)) {
+        Either::Left((res, _)) => panic!("bridge ended early: {res:?}"),
+        Either::Right(((), _)) => {}
+    }
+}
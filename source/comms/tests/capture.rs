@@ -0,0 +1,175 @@
+//! Writing pcapng captures, and reading them back
+
+use embassy_time::Instant;
+use erdnuss_comms::capture::{
+    CaptureError, CaptureSink, CapturedFrame, Direction, PcapngReader, PcapngWriter, ReadError,
+    MAX_CAPTURED,
+};
+
+/// A sink that keeps each write separately, and fails after `fail_after` writes
+#[derive(Default)]
+struct VecSink {
+    writes: Vec<Vec<u8>>,
+    fail_after: Option<usize>,
+}
+
+impl VecSink {
+    fn data(&self) -> Vec<u8> {
+        self.writes.concat()
+    }
+}
+
+impl CaptureSink for VecSink {
+    type Error = ();
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), ()> {
+        if self.fail_after == Some(self.writes.len()) {
+            // Like `std::io::Write::write_all`, part of the data may be written
+            self.writes.push(data[..data.len() / 2].to_vec());
+            return Err(());
+        }
+        self.writes.push(data.to_vec());
+        Ok(())
+    }
+}
+
+fn frames() -> Vec<(Instant, Direction, Vec<u8>)> {
+    (0..8u8)
+        .map(|i| {
+            let dir = match i % 2 {
+                0 => Direction::Sent,
+                _ => Direction::Received,
+            };
+            // Cover every amount of padding
+            let frame = (0..i).map(|b| b.wrapping_mul(37)).collect();
+            (Instant::from_micros(1_000 * u64::from(i) + 7), dir, frame)
+        })
+        .collect()
+}
+
+fn read(data: &[u8]) -> Vec<(Instant, Direction, Vec<u8>)> {
+    PcapngReader::new(data)
+        .map(|f| {
+            let CapturedFrame { at, dir, frame } = f.unwrap();
+            (at, dir, frame.to_vec())
+        })
+        .collect()
+}
+
+#[test]
+fn frames_round_trip() {
+    let mut writer = PcapngWriter::new(VecSink::default());
+    for (at, dir, frame) in frames() {
+        writer.record(at, dir, &frame).unwrap();
+    }
+    assert_eq!(read(&writer.sink().data()), frames());
+}
+
+#[test]
+fn large_timestamps_round_trip() {
+    let at = Instant::from_micros(0x1234_5678_9ABC);
+    let mut writer = PcapngWriter::new(VecSink::default());
+    writer.record(at, Direction::Received, &[0x41]).unwrap();
+    assert_eq!(
+        read(&writer.sink().data()),
+        [(at, Direction::Received, vec![0x41])]
+    );
+}
+
+#[test]
+fn each_block_is_one_write() {
+    let mut writer = PcapngWriter::new(VecSink::default());
+    for (at, dir, frame) in frames() {
+        writer.record(at, dir, &frame).unwrap();
+    }
+    // The headers, then one write per frame
+    let sink = writer.into_inner();
+    assert_eq!(sink.writes.len(), 1 + frames().len());
+    for write in &sink.writes[1..] {
+        assert_eq!(write.len() % 4, 0);
+        assert_eq!(&write[..4], &6u32.to_le_bytes());
+    }
+}
+
+#[test]
+fn long_frames_are_truncated() {
+    let frame: Vec<u8> = (0..MAX_CAPTURED + 10).map(|b| b as u8).collect();
+    let mut writer = PcapngWriter::new(VecSink::default());
+    writer
+        .record(Instant::from_micros(1), Direction::Sent, &frame)
+        .unwrap();
+    let data = writer.sink().data();
+    let got = read(&data);
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0].2, frame[..MAX_CAPTURED]);
+    // The original length is kept, including the direction byte
+    let epb = &data[48..];
+    assert_eq!(epb[20..24], ((MAX_CAPTURED + 1) as u32).to_le_bytes());
+    assert_eq!(epb[24..28], ((MAX_CAPTURED + 11) as u32).to_le_bytes());
+}
+
+#[test]
+fn sink_error_poisons_the_writer() {
+    let mut writer = PcapngWriter::new(VecSink {
+        fail_after: Some(4),
+        ..VecSink::default()
+    });
+    let mut results = vec![];
+    for (at, dir, frame) in frames() {
+        results.push(writer.record(at, dir, &frame));
+    }
+    assert!(results[..3].iter().all(Result::is_ok));
+    assert_eq!(results[3], Err(CaptureError::Sink(())));
+    assert!(results[4..]
+        .iter()
+        .all(|r| *r == Err(CaptureError::Poisoned)));
+    assert!(writer.is_poisoned());
+
+    // Everything before the failed write reads back, then the partial block
+    let data = writer.sink().data();
+    let mut reader = PcapngReader::new(&data);
+    for expected in &frames()[..3] {
+        let f = reader.next().unwrap().unwrap();
+        assert_eq!((f.at, f.dir, f.frame.to_vec()), *expected);
+    }
+    assert_eq!(reader.next(), Some(Err(ReadError::Truncated)));
+    assert_eq!(reader.next(), None);
+}
+
+#[test]
+fn failed_headers_poison_the_writer() {
+    let mut writer = PcapngWriter::new(VecSink {
+        fail_after: Some(0),
+        ..VecSink::default()
+    });
+    let (at, dir, frame) = &frames()[1];
+    assert_eq!(writer.record(*at, *dir, frame), Err(CaptureError::Sink(())));
+    assert_eq!(writer.record(*at, *dir, frame), Err(CaptureError::Poisoned));
+    assert_eq!(writer.sink().writes.len(), 1);
+}
+
+#[test]
+fn reader_rejects_bad_captures() {
+    let mut writer = PcapngWriter::new(VecSink::default());
+    let (at, dir, frame) = &frames()[3];
+    writer.record(*at, *dir, frame).unwrap();
+    let data = writer.sink().data();
+
+    // No section header
+    assert_eq!(
+        PcapngReader::new(&data[28..]).next(),
+        Some(Err(ReadError::BadHeader))
+    );
+    // Cut short
+    assert_eq!(
+        PcapngReader::new(&data[..data.len() - 1]).last(),
+        Some(Err(ReadError::Truncated))
+    );
+    // A bad direction byte
+    let mut bad = data.clone();
+    bad[48 + 28] = 7;
+    assert_eq!(
+        PcapngReader::new(&bad).next(),
+        Some(Err(ReadError::BadPacket))
+    );
+}
@@ -0,0 +1,192 @@
+//! Exchange frames with [CobsSerial] over in-memory pipes
+
+use core::pin::pin;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, pipe::Pipe};
+use embassy_time::{with_timeout, Duration, Timer};
+use erdnuss_comms::{
+    clock::EmbassyClock,
+    cobs_serial::{CobsError, CobsSerial, DEFAULT_BUF_SIZE},
+    frame_pool::{FrameBox, FrameStorage, DEFAULT_FRAME_SIZE},
+    target::{self, Target, TgtCfg},
+    Controller, Error, FrameSerial, Mac, MAX_TARGETS,
+};
+use futures::{
+    executor::block_on,
+    future::{select, Either},
+};
+use rand_core::RngCore;
+
+type BytePipe = Pipe<CriticalSectionRawMutex, 512>;
+type PipeSerial<'a> = CobsSerial<&'a BytePipe, &'a BytePipe>;
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+
+static CONTROLLER: Controller<CriticalSectionRawMutex> = Controller::uninit();
+static CTL_STORAGE: FrameStorage<{ erdnuss_comms::controller::INCOMING_SIZE * MAX_TARGETS }> =
+    FrameStorage::new();
+static TGT_STORAGE: FrameStorage<8> = FrameStorage::new();
+
+#[test]
+fn frames_round_trip() {
+    let pipe = BytePipe::new();
+    let mut tx = PipeSerial::new(&pipe, &pipe);
+    let mut rx = PipeSerial::new(&pipe, &pipe);
+    let long = [0xA5u8; DEFAULT_FRAME_SIZE];
+
+    block_on(async {
+        for sent in [&[0x21, 0x00, 0x00, 0x07][..], &[0x00], &long] {
+            tx.send_frame(sent).await.unwrap();
+            let mut buf = [0u8; DEFAULT_FRAME_SIZE];
+            let got = rx.recv(&mut buf).await.unwrap();
+            assert_eq!(got.frame, sent);
+        }
+    });
+    assert_eq!(rx.discarded(), 0);
+}
+
+#[test]
+fn several_frames_in_one_read() {
+    let pipe = BytePipe::new();
+    let mut rx = PipeSerial::new(&pipe, &pipe);
+
+    block_on(async {
+        // "1", then an extra delimiter, then "2 0 3"
+        pipe.write_all(&[0x02, 0x01, 0x00, 0x00, 0x02, 0x02, 0x02, 0x03, 0x00])
+            .await;
+        let mut buf = [0u8; 8];
+        assert_eq!(rx.recv(&mut buf).await.unwrap().frame, [1]);
+        assert_eq!(rx.recv(&mut buf).await.unwrap().frame, [2, 0, 3]);
+    });
+    assert_eq!(rx.discarded(), 0);
+}
+
+#[test]
+fn bad_frames_are_discarded() {
+    let pipe = BytePipe::new();
+    let mut tx = PipeSerial::new(&pipe, &pipe);
+    let mut rx = PipeSerial::new(&pipe, &pipe);
+
+    block_on(async {
+        // A code byte pointing past the delimiter
+        pipe.write_all(&[0x05, 0x01, 0x00]).await;
+        // More data than fits in the buffer before a delimiter
+        pipe.write_all(&[0x11; DEFAULT_BUF_SIZE + 10]).await;
+        pipe.write_all(&[0x00]).await;
+        // A frame that doesn't fit the caller's buffer
+        tx.send_frame(&[1; 16]).await.unwrap();
+        tx.send_frame(&[1, 2, 3]).await.unwrap();
+
+        let mut buf = [0u8; 8];
+        assert_eq!(rx.recv(&mut buf).await.unwrap().frame, [1, 2, 3]);
+    });
+    assert_eq!(rx.discarded(), 3);
+}
+
+#[test]
+fn oversized_sends_fail() {
+    let pipe = BytePipe::new();
+    let mut tx = CobsSerial::<_, _, 8, EmbassyClock>::with_clock(&pipe, &pipe);
+
+    block_on(async {
+        assert_eq!(
+            tx.send_frame(&[1; 8]).await,
+            Err(Error::Serial(CobsError::FrameTooLong))
+        );
+        tx.send_frame(&[1; 6]).await.unwrap();
+    });
+    // Six data bytes, plus the COBS code byte and delimiter
+    assert_eq!(pipe.len(), 8);
+}
+
+#[test]
+fn cancelled_recv_keeps_partial_frame() {
+    let pipe = BytePipe::new();
+    let mut rx = PipeSerial::new(&pipe, &pipe);
+
+    block_on(async {
+        let mut buf = [0u8; 8];
+        pipe.write_all(&[0x04, 0x01, 0x02]).await;
+        let res = with_timeout(Duration::from_millis(5), rx.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        pipe.write_all(&[0x03, 0x00]).await;
+        assert_eq!(rx.recv(&mut buf).await.unwrap().frame, [1, 2, 3]);
+    });
+}
+
+/// A random number generator that makes the Target claim every offer
+struct Eager;
+
+impl RngCore for Eager {
+    fn next_u32(&mut self) -> u32 {
+        0
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.fill(0);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        dest.fill(0);
+        Ok(())
+    }
+}
+
+struct Cfg;
+
+impl TgtCfg for Cfg {
+    type Mutex = CriticalSectionRawMutex;
+    type Serial = PipeSerial<'static>;
+    type Rand = Eager;
+    type Clock = EmbassyClock;
+
+    const TURNAROUND_DELAY: Duration = Duration::from_micros(10);
+    const ADDRESS_CLAIM_TIMEOUT: Duration = Duration::from_millis(50);
+    const SELECT_TIMEOUT: Duration = Duration::from_millis(500);
+}
+
+#[test]
+fn target_joins_over_cobs() {
+    static TO_TGT: BytePipe = Pipe::new();
+    static TO_CTL: BytePipe = Pipe::new();
+    static TO_APP: Channel<CriticalSectionRawMutex, FrameBox, { target::INCOMING_SIZE }> =
+        Channel::new();
+    static FROM_APP: Channel<CriticalSectionRawMutex, FrameBox, { target::OUTGOING_SIZE }> =
+        Channel::new();
+
+    let mut ctl_serial = PipeSerial::new(&TO_CTL, &TO_TGT);
+    let mut target = Target::<Cfg>::new(
+        PipeSerial::new(&TO_TGT, &TO_CTL),
+        TO_APP.sender(),
+        FROM_APP.receiver(),
+        TGT_STORAGE.take().unwrap(),
+        MAC,
+        Eager,
+    );
+
+    block_on(async {
+        CONTROLLER.init(&mut CTL_STORAGE.take().unwrap()).await;
+        let stepping = async {
+            while CONTROLLER.connected().await.is_empty() {
+                CONTROLLER.step(&mut ctl_serial, &mut Eager).await.unwrap();
+                Timer::after(Duration::from_millis(1)).await;
+            }
+        };
+        let running = target.run();
+        let joined = matches!(
+            with_timeout(
+                Duration::from_secs(5),
+                select(pin!(stepping), pin!(running)),
+            )
+            .await,
+            Ok(Either::Left(_))
+        );
+        assert!(joined);
+        assert_eq!(CONTROLLER.connected().await, [MAC]);
+    });
+}
@@ -0,0 +1,205 @@
+//! Decoding bus traffic, and flagging protocol violations
+
+use embassy_time::{Duration, Instant};
+use erdnuss_comms::{
+    decode::{Decoder, EventKind, Violation},
+    CmdAddr, Mac,
+};
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+const OTHER: Mac = Mac::new(0x1111_2222_3333_4444);
+const CHALLENGE: [u8; 8] = [0x5A, 1, 2, 3, 4, 5, 6, 7];
+
+fn frame(ca: CmdAddr, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![u8::from(ca)];
+    frame.extend_from_slice(data);
+    frame
+}
+
+fn offer(addr: u8) -> Vec<u8> {
+    frame(CmdAddr::DiscoveryOffer(addr), &CHALLENGE)
+}
+
+/// A claim of `addr` by `mac`, answering an offer with [CHALLENGE]
+fn claim(addr: u8, mac: Mac) -> Vec<u8> {
+    let mut data = mac.to_wire_bytes();
+    data.iter_mut()
+        .zip(CHALLENGE.iter())
+        .for_each(|(d, c)| *d ^= *c);
+    frame(CmdAddr::DiscoveryClaim(addr), &data)
+}
+
+fn success(addr: u8, mac: Mac) -> Vec<u8> {
+    frame(CmdAddr::DiscoverySuccess(addr), &mac.to_wire_bytes())
+}
+
+fn select(addr: u8) -> Vec<u8> {
+    frame(CmdAddr::SelectAddr(addr), &[])
+}
+
+fn reply(addr: u8) -> Vec<u8> {
+    frame(CmdAddr::ReplyFromAddr(addr), &[])
+}
+
+/// Feed `frames` 100us apart, returning the kind and violations of each event
+fn feed(frames: &[Vec<u8>]) -> Vec<(EventKind, Vec<Violation>)> {
+    let mut dec = Decoder::new();
+    frames
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let ev = dec.feed(Instant::from_micros(100 * i as u64), f);
+            (ev.kind, ev.violations.to_vec())
+        })
+        .collect()
+}
+
+#[test]
+fn handshake_and_exchange_are_clean() {
+    let events = feed(&[
+        offer(1),
+        claim(1, MAC),
+        success(1, MAC),
+        reply(1),
+        select(1),
+        reply(1),
+    ]);
+    assert_eq!(
+        events.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+        [
+            EventKind::Offer {
+                addr: 1,
+                challenge: CHALLENGE
+            },
+            EventKind::Claim {
+                addr: 1,
+                mac: Some(MAC)
+            },
+            EventKind::Success { addr: 1, mac: MAC },
+            EventKind::Joined { addr: 1, mac: MAC },
+            EventKind::Select { addr: 1 },
+            EventKind::Reply { addr: 1 },
+        ]
+    );
+    assert!(events.iter().all(|(_, v)| v.is_empty()));
+
+    // Target frames carry the time since the Controller frame they answer
+    let mut dec = Decoder::new();
+    dec.feed(Instant::from_micros(1_000), &select(1));
+    let answer = reply(1);
+    let ev = dec.feed(Instant::from_micros(1_250), &answer);
+    assert_eq!(ev.latency, Some(Duration::from_micros(250)));
+}
+
+#[test]
+fn wrong_address() {
+    // A reply to a select, from another address
+    let events = feed(&[select(3), reply(4)]);
+    assert_eq!(events[1].0, EventKind::Reply { addr: 4 });
+    assert_eq!(
+        events[1].1,
+        [Violation::WrongAddress {
+            expected: 3,
+            got: 4
+        }]
+    );
+
+    // A claim of another address than was offered, whose MAC isn't trusted
+    let events = feed(&[offer(5), claim(6, MAC)]);
+    assert_eq!(events[1].0, EventKind::Claim { addr: 6, mac: None });
+    assert_eq!(
+        events[1].1,
+        [Violation::WrongAddress {
+            expected: 5,
+            got: 6
+        }]
+    );
+
+    // An acknowledgement of a success, from another address, doesn't join
+    let events = feed(&[offer(5), claim(5, MAC), success(5, MAC), reply(7)]);
+    assert_eq!(events[3].0, EventKind::Reply { addr: 7 });
+    assert_eq!(
+        events[3].1,
+        [Violation::WrongAddress {
+            expected: 5,
+            got: 7
+        }]
+    );
+}
+
+#[test]
+fn unsolicited() {
+    let events = feed(&[
+        // Nothing was sent yet
+        reply(1),
+        // Offers are answered with claims, not replies
+        offer(2),
+        reply(2),
+        // Selects are answered with replies, not claims
+        select(1),
+        claim(1, MAC),
+        // Only one reply per select
+        select(1),
+        reply(1),
+        reply(1),
+    ]);
+    let unsolicited: Vec<usize> = events
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, v))| v.as_slice() == [Violation::Unsolicited])
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(unsolicited, [0, 2, 4, 7]);
+    assert_eq!(events[4].0, EventKind::Claim { addr: 1, mac: None });
+
+    // The rest of the traffic is clean
+    for i in [1, 3, 5, 6] {
+        assert_eq!(events[i].1, []);
+    }
+}
+
+#[test]
+fn mac_mismatch() {
+    let events = feed(&[offer(1), claim(1, MAC), success(1, OTHER), reply(1)]);
+    assert_eq!(
+        events[2].0,
+        EventKind::Success {
+            addr: 1,
+            mac: OTHER
+        }
+    );
+    assert_eq!(
+        events[2].1,
+        [Violation::MacMismatch {
+            claimed: MAC,
+            confirmed: OTHER
+        }]
+    );
+    // The Target joins with the MAC the Controller confirmed
+    assert_eq!(
+        events[3].0,
+        EventKind::Joined {
+            addr: 1,
+            mac: OTHER
+        }
+    );
+    assert_eq!(events[3].1, []);
+
+    // A success for another address isn't compared with the claim
+    let events = feed(&[offer(1), claim(1, MAC), success(2, OTHER)]);
+    assert_eq!(events[2].1, []);
+
+    // Claims are only compared with the first success for their address
+    let events = feed(&[
+        offer(1),
+        claim(1, MAC),
+        success(1, MAC),
+        reply(1),
+        success(1, OTHER),
+    ]);
+    assert_eq!(events[4].1, []);
+
+    // Or until the next offer, whose claim may not have been heard
+    let events = feed(&[offer(1), claim(1, MAC), offer(1), success(1, OTHER)]);
+    assert_eq!(events[3].1, []);
+}
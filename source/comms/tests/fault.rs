@@ -0,0 +1,250 @@
+//! Injecting faults with a FaultySerial
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use embassy_time::Instant;
+use erdnuss_comms::{
+    fault::{Chance, FaultError, FaultPolicy, FaultStats, FaultySerial},
+    frame_pool::DEFAULT_FRAME_SIZE,
+    Error, FrameSerial, TimedFrame,
+};
+use futures::executor::block_on;
+use rand_core::RngCore;
+
+/// A serial port that receives queued frames, and records the frames sent
+#[derive(Default, Clone)]
+struct Loopback {
+    rx: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    sent: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Empty;
+
+impl FrameSerial for Loopback {
+    type SerError = Empty;
+
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Empty>> {
+        self.sent.borrow_mut().push(frame.to_vec());
+        Ok(())
+    }
+
+    async fn recv<'a>(&mut self, frame: &'a mut [u8]) -> Result<TimedFrame<'a>, Error<Empty>> {
+        let data = self
+            .rx
+            .borrow_mut()
+            .pop_front()
+            .ok_or(Error::Serial(Empty))?;
+        let buf = &mut frame[..data.len()];
+        buf.copy_from_slice(&data);
+        Ok(TimedFrame {
+            end_of_rx: Instant::from_ticks(data.len() as u64),
+            frame: buf,
+        })
+    }
+}
+
+/// A seeded xorshift generator
+struct XorShift(u64);
+
+impl RngCore for XorShift {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+fn faulty(policy: FaultPolicy) -> (FaultySerial<Loopback, XorShift>, Loopback) {
+    let port = Loopback::default();
+    let serial = FaultySerial::new(port.clone(), XorShift(0x2545_F491_4F6C_DD1D), policy);
+    (serial, port)
+}
+
+fn frame(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+/// Receive every queued frame
+fn recv_all(serial: &mut FaultySerial<Loopback, XorShift>) -> Vec<(Instant, Vec<u8>)> {
+    let mut buf = [0u8; 4 * DEFAULT_FRAME_SIZE];
+    let mut got = vec![];
+    while let Ok(tf) = block_on(serial.recv(&mut buf)) {
+        got.push((tf.end_of_rx, tf.frame.to_vec()));
+    }
+    got
+}
+
+#[test]
+fn chance_bounds() {
+    assert_eq!(Chance::percent(0), Chance::NEVER);
+    assert_eq!(Chance::percent(100), Chance::ALWAYS);
+    assert_eq!(Chance::percent(250), Chance::ALWAYS);
+    assert_eq!(Chance::one_in(0), Chance::NEVER);
+    assert_eq!(Chance::one_in(1), Chance::ALWAYS);
+}
+
+#[test]
+fn chance_sets_the_rate_of_faults() {
+    for (chance, lo, hi) in [
+        (Chance::NEVER, 0, 0),
+        (Chance::percent(10), 900, 1100),
+        (Chance::one_in(2), 4800, 5200),
+        (Chance::ALWAYS, 10_000, 10_000),
+    ] {
+        let (mut serial, port) = faulty(FaultPolicy {
+            drop_tx: chance,
+            ..FaultPolicy::default()
+        });
+        for _ in 0..10_000 {
+            block_on(serial.send_frame(&[1, 2, 3])).unwrap();
+        }
+        let dropped = serial.stats().dropped_tx;
+        assert!((lo..=hi).contains(&dropped), "{chance:?}: {dropped}");
+        assert_eq!(port.sent.borrow().len(), 10_000 - dropped as usize);
+    }
+}
+
+#[test]
+fn default_policy_injects_nothing() {
+    let (mut serial, port) = faulty(FaultPolicy::default());
+    for len in 1..=DEFAULT_FRAME_SIZE {
+        block_on(serial.send_frame(&frame(len))).unwrap();
+        port.rx.borrow_mut().push_back(frame(len));
+    }
+    let expected: Vec<Vec<u8>> = (1..=DEFAULT_FRAME_SIZE).map(frame).collect();
+    assert_eq!(*port.sent.borrow(), expected);
+    let got: Vec<Vec<u8>> = recv_all(&mut serial).into_iter().map(|(_, f)| f).collect();
+    assert_eq!(got, expected);
+    assert_eq!(*serial.stats(), FaultStats::default());
+}
+
+#[test]
+fn each_fault_is_injected() {
+    let sent = frame(32);
+
+    // Errors, in both directions
+    let (mut serial, port) = faulty(FaultPolicy {
+        serial_error: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+    port.rx.borrow_mut().push_back(sent.clone());
+    assert!(matches!(
+        block_on(serial.send_frame(&sent)),
+        Err(Error::Serial(FaultError::Injected))
+    ));
+    assert!(matches!(
+        block_on(serial.recv(&mut [0; DEFAULT_FRAME_SIZE])),
+        Err(Error::Serial(FaultError::Injected))
+    ));
+    assert_eq!(serial.stats().errors, 2);
+    assert!(port.sent.borrow().is_empty());
+
+    // Dropped receives wait for the next frame
+    let (mut serial, port) = faulty(FaultPolicy {
+        drop_rx: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+    port.rx.borrow_mut().extend([sent.clone(), sent.clone()]);
+    assert!(matches!(
+        block_on(serial.recv(&mut [0; DEFAULT_FRAME_SIZE])),
+        Err(Error::Serial(FaultError::Inner(Empty)))
+    ));
+    assert_eq!(serial.stats().dropped_rx, 2);
+
+    // Exactly one bit flipped
+    let (mut serial, port) = faulty(FaultPolicy {
+        flip_bit: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+    block_on(serial.send_frame(&sent)).unwrap();
+    let flipped: u32 = port.sent.borrow()[0]
+        .iter()
+        .zip(sent.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    assert_eq!(flipped, 1);
+    assert_eq!(serial.stats().flipped, 1);
+
+    // Truncated to a shorter prefix
+    let (mut serial, port) = faulty(FaultPolicy {
+        truncate: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+    port.rx.borrow_mut().push_back(sent.clone());
+    let (_, got) = recv_all(&mut serial).remove(0);
+    assert!(got.len() < sent.len());
+    assert!(sent.starts_with(&got));
+    assert_eq!(serial.stats().truncated, 1);
+}
+
+#[test]
+fn spurious_break_splits_a_frame() {
+    let (mut serial, port) = faulty(FaultPolicy {
+        spurious_break: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+    port.rx.borrow_mut().push_back(frame(32));
+    let got = recv_all(&mut serial);
+
+    // Both halves are timestamped with the end of the original frame
+    assert_eq!(got.len(), 2);
+    assert!(got.iter().all(|(at, _)| *at == Instant::from_ticks(32)));
+    assert_eq!([got[0].1.clone(), got[1].1.clone()].concat(), frame(32));
+    assert!(!got[0].1.is_empty() && !got[1].1.is_empty());
+    assert_eq!(serial.stats().breaks, 1);
+}
+
+#[test]
+fn frames_longer_than_a_pool_frame_are_left_whole() {
+    let long = frame(3 * DEFAULT_FRAME_SIZE);
+    let (mut serial, port) = faulty(FaultPolicy {
+        flip_bit: Chance::ALWAYS,
+        spurious_break: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+
+    // Sent unmodified
+    block_on(serial.send_frame(&long)).unwrap();
+    assert_eq!(port.sent.borrow()[0], long);
+
+    // Received with a flipped bit, but never split, as the tail wouldn't fit
+    port.rx.borrow_mut().push_back(long.clone());
+    let got = recv_all(&mut serial);
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0].1.len(), long.len());
+    assert_eq!(serial.stats().breaks, 0);
+    assert_eq!(serial.stats().flipped, 1);
+
+    // A frame of the pool's size can still be split
+    port.rx.borrow_mut().push_back(frame(DEFAULT_FRAME_SIZE));
+    assert_eq!(recv_all(&mut serial).len(), 2);
+    assert_eq!(serial.stats().breaks, 1);
+}
+
+#[test]
+fn policy_can_be_replaced() {
+    let (mut serial, port) = faulty(FaultPolicy {
+        drop_tx: Chance::ALWAYS,
+        ..FaultPolicy::default()
+    });
+    block_on(serial.send_frame(&[1])).unwrap();
+    serial.set_policy(FaultPolicy::default());
+    block_on(serial.send_frame(&[2])).unwrap();
+    assert_eq!(*port.sent.borrow(), [vec![2]]);
+    assert_eq!(serial.stats().dropped_tx, 1);
+}
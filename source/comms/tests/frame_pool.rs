@@ -0,0 +1,365 @@
+//! Allocation, release, and splitting of frame pools
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use embassy_time::Duration;
+use erdnuss_comms::{
+    frame_pool::{FrameBox, FrameStorage, PoolStats, RawFrameSlice, SendFrameBox, WireFrameBox},
+    CmdAddr,
+};
+use futures::{
+    executor::block_on,
+    task::{waker, ArcWake},
+};
+
+/// A waker counting how often it was woken
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl ArcWake for CountingWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Allocate a frame, marking it with `mark`
+fn alloc(pool: &mut RawFrameSlice<4>, mark: u8) -> FrameBox<4> {
+    let mut fb = pool.allocate_raw().unwrap();
+    assert_eq!(fb.len(), 4);
+    fb[1] = mark;
+    fb
+}
+
+#[test]
+fn allocation_order_and_exhaustion() {
+    static STORAGE: FrameStorage<4, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    assert!(STORAGE.take().is_none());
+    assert_eq!(pool.count_allocatable(), 4);
+
+    let a = alloc(&mut pool, 1);
+    let b = alloc(&mut pool, 2);
+    let c = alloc(&mut pool, 3);
+    let d = alloc(&mut pool, 4);
+    assert_eq!(pool.count_allocatable(), 0);
+    assert!(pool.allocate_raw().is_none());
+    assert_eq!(STORAGE.in_use(), 4);
+
+    // Released frames are counted right away
+    drop(b);
+    drop(d);
+    assert_eq!(pool.count_allocatable(), 2);
+    assert_eq!(STORAGE.in_use(), 2);
+
+    // Reclaiming walks the lent list from the most recent allocation, pushing
+    // each released frame onto the free stack, so the oldest comes out first
+    let b = pool.allocate_raw().unwrap();
+    let d = pool.allocate_raw().unwrap();
+    assert_eq!((b[1], d[1]), (2, 4));
+    assert!(pool.allocate_raw().is_none());
+    drop((a, b, c, d));
+    assert_eq!(pool.count_allocatable(), 4);
+}
+
+#[test]
+fn known_free_frames_are_used_first() {
+    static STORAGE: FrameStorage<4, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    drop(alloc(&mut pool, 9));
+    assert_eq!(pool.count_allocatable(), 4);
+
+    // Frames are taken in ascending order, the released one comes last
+    let marks: Vec<u8> = (0..4).map(|_| pool.allocate_raw().unwrap()[1]).collect();
+    assert_eq!(marks, [0, 0, 0, 9]);
+}
+
+#[test]
+fn frames_are_reused_until_exhausted() {
+    static STORAGE: FrameStorage<3, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    let held = alloc(&mut pool, 1);
+    for i in 0..100 {
+        let fb = alloc(&mut pool, i);
+        assert_eq!(pool.count_allocatable(), 1);
+        drop(fb);
+    }
+    let rest = [alloc(&mut pool, 2), alloc(&mut pool, 3)];
+    assert!(pool.allocate_raw().is_none());
+    drop(held);
+    assert_eq!(pool.allocate_raw().unwrap()[1], 1);
+    drop(rest);
+}
+
+#[test]
+fn split_bounds() {
+    static STORAGE: FrameStorage<4, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    assert!(pool.split(0).is_none());
+    assert!(pool.split(5).is_none());
+    let tail = pool.split(3).unwrap();
+    assert_eq!((pool.capacity(), tail.capacity()), (3, 1));
+
+    let mut empty = RawFrameSlice::<4>::uninit();
+    assert_eq!(empty.count_allocatable(), 0);
+    assert!(empty.allocate_raw().is_none());
+    assert!(empty.split(1).is_none());
+}
+
+#[test]
+fn split_with_lent_frames() {
+    static STORAGE: FrameStorage<4, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    let a = alloc(&mut pool, 1);
+    let b = alloc(&mut pool, 2);
+    let c = alloc(&mut pool, 3);
+    drop(b);
+
+    // Frame 0 stays with `pool`, frames 1..4 move to `tail`
+    let mut tail = pool.split(1).unwrap();
+    assert_eq!(pool.count_allocatable(), 0);
+    assert_eq!(tail.count_allocatable(), 2);
+
+    // Frames released after the split are counted for their new slice
+    drop(c);
+    assert_eq!((pool.count_allocatable(), tail.count_allocatable()), (0, 3));
+    drop(a);
+    assert_eq!((pool.count_allocatable(), tail.count_allocatable()), (1, 3));
+
+    let frames: Vec<_> = (0..3).map(|_| tail.allocate_raw().unwrap()).collect();
+    assert!(tail.allocate_raw().is_none());
+    assert_eq!(pool.allocate_raw().unwrap()[1], 1);
+    drop(frames);
+}
+
+#[test]
+fn drop_wakes_parked_allocation() {
+    static STORAGE: FrameStorage<2, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    let mut other = pool.split(1).unwrap();
+    let held = pool.allocate_raw().unwrap();
+    let unrelated = other.allocate_raw().unwrap();
+
+    let count = Arc::new(CountingWaker::default());
+    let waker = waker(count.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut alloc = pin!(pool.allocate());
+    assert!(alloc.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(count.0.load(Ordering::SeqCst), 0);
+
+    // Only frames of the same slice wake the task
+    drop(unrelated);
+    assert_eq!(count.0.load(Ordering::SeqCst), 0);
+
+    drop(held);
+    assert_eq!(count.0.load(Ordering::SeqCst), 1);
+    assert!(matches!(alloc.as_mut().poll(&mut cx), Poll::Ready(_)));
+}
+
+#[test]
+fn concurrent_waiters_are_only_woken_by_releases() {
+    static STORAGE: FrameStorage<2, 4> = FrameStorage::new();
+    let mut pool_a = STORAGE.take().unwrap();
+    let mut pool_b = pool_a.split(1).unwrap();
+    let held_a = pool_a.allocate_raw().unwrap();
+    let held_b = pool_b.allocate_raw().unwrap();
+
+    let count_a = Arc::new(CountingWaker::default());
+    let count_b = Arc::new(CountingWaker::default());
+    let (waker_a, waker_b) = (waker(count_a.clone()), waker(count_b.clone()));
+    let mut cx_a = Context::from_waker(&waker_a);
+    let mut cx_b = Context::from_waker(&waker_b);
+    let mut alloc_a = pin!(pool_a.allocate());
+    let mut alloc_b = pin!(pool_b.allocate());
+
+    // Registering one waiter never wakes the other, so neither is polled again
+    for _ in 0..3 {
+        assert!(alloc_a.as_mut().poll(&mut cx_a).is_pending());
+        assert!(alloc_b.as_mut().poll(&mut cx_b).is_pending());
+    }
+    assert_eq!(count_a.0.load(Ordering::SeqCst), 0);
+    assert_eq!(count_b.0.load(Ordering::SeqCst), 0);
+
+    drop(held_b);
+    assert_eq!(count_a.0.load(Ordering::SeqCst), 0);
+    assert_eq!(count_b.0.load(Ordering::SeqCst), 1);
+    assert!(matches!(alloc_b.as_mut().poll(&mut cx_b), Poll::Ready(_)));
+
+    drop(held_a);
+    assert_eq!(count_a.0.load(Ordering::SeqCst), 1);
+    assert!(matches!(alloc_a.as_mut().poll(&mut cx_a), Poll::Ready(_)));
+}
+
+#[test]
+fn lent_frames_wake_the_slice_they_were_split_into() {
+    static STORAGE: FrameStorage<2, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    let _first = pool.allocate_raw().unwrap();
+    let second = pool.allocate_raw().unwrap();
+    let mut tail = pool.split(1).unwrap();
+
+    let count = Arc::new(CountingWaker::default());
+    let waker = waker(count.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut alloc = pin!(tail.allocate());
+    assert!(alloc.as_mut().poll(&mut cx).is_pending());
+
+    drop(second);
+    assert_eq!(count.0.load(Ordering::SeqCst), 1);
+    assert!(matches!(alloc.as_mut().poll(&mut cx), Poll::Ready(_)));
+}
+
+#[test]
+fn allocation_times_out() {
+    static STORAGE: FrameStorage<1, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    let held = pool.allocate_raw().unwrap();
+    assert!(block_on(pool.allocate_timeout(Duration::from_millis(10))).is_none());
+    drop(held);
+    assert!(block_on(pool.allocate_timeout(Duration::from_millis(10))).is_some());
+    assert!(
+        block_on(RawFrameSlice::<4>::uninit().allocate_timeout(Duration::from_millis(10)))
+            .is_none()
+    );
+}
+
+#[test]
+fn stats_track_usage() {
+    static STORAGE: FrameStorage<3, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    let stats = |in_use, high_water, alloc_failures| PoolStats {
+        capacity: 3,
+        in_use,
+        high_water,
+        alloc_failures,
+    };
+    assert_eq!(pool.stats(), stats(0, 0, 0));
+
+    let a = pool.allocate_raw().unwrap();
+    let b = pool.allocate_raw().unwrap();
+    assert_eq!(pool.stats(), stats(2, 2, 0));
+    drop(a);
+    assert_eq!(pool.stats(), stats(1, 2, 0));
+
+    // The high-water mark only counts frames that are allocated at once
+    let c = pool.allocate_raw().unwrap();
+    assert_eq!(pool.stats(), stats(2, 2, 0));
+    let d = pool.allocate_raw().unwrap();
+    assert!(pool.allocate_raw().is_none());
+    assert!(pool.allocate_raw().is_none());
+    assert_eq!(pool.stats(), stats(3, 3, 2));
+
+    drop((b, c));
+    pool.reset_stats();
+    assert_eq!(pool.stats(), stats(1, 1, 0));
+    drop(d);
+    assert_eq!(pool.stats(), stats(0, 1, 0));
+}
+
+#[test]
+fn send_frame_box_room() {
+    static STORAGE: FrameStorage<1, 16> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    let mut sfb = SendFrameBox::from(pool.allocate_raw().unwrap());
+
+    sfb.reserve(4);
+    assert_eq!((sfb.headroom(), sfb.tailroom(), sfb.len()), (4, 11, 0));
+    assert!(sfb.put_trailer(12).is_none());
+    sfb.put_trailer(11).unwrap().fill(7);
+    assert!(sfb.put_trailer(1).is_none());
+    assert!(sfb.push_header(5).is_none());
+    sfb.push_header(4).unwrap().copy_from_slice(&[1, 2, 3, 4]);
+    assert!(sfb.push_header(1).is_none());
+    assert_eq!(sfb.len(), 15);
+
+    // Failed pulls and trims leave the payload unchanged
+    assert!(sfb.pull_header(16).is_none());
+    assert!(sfb.trim_trailer(16).is_none());
+    assert_eq!(sfb.pull_header(2).unwrap(), [1, 2]);
+    assert_eq!(sfb.trim_trailer(10).unwrap(), [7; 10]);
+    assert_eq!(sfb.payload(), [3, 4, 7]);
+    assert_eq!((sfb.headroom(), sfb.tailroom()), (2, 10));
+
+    // Removed bytes become room again
+    assert!(sfb.push_header(2).is_some());
+    assert!(sfb.put_trailer(10).is_some());
+}
+
+#[test]
+#[should_panic]
+fn send_frame_box_reserve_too_much() {
+    static STORAGE: FrameStorage<1, 16> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    SendFrameBox::from(pool.allocate_raw().unwrap()).reserve(16);
+}
+
+#[test]
+fn into_inner_without_headroom_keeps_data_in_place() {
+    static STORAGE: FrameStorage<1, 16> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    let mut sfb = SendFrameBox::from(pool.allocate_raw().unwrap());
+
+    sfb.reserve(0);
+    sfb.put_trailer(3).unwrap().copy_from_slice(&[1, 2, 3]);
+    let at = sfb.payload().as_ptr();
+    let fb = sfb.into_inner();
+    assert_eq!(fb.len(), 4);
+    assert_eq!(&fb[1..], [1, 2, 3]);
+    assert_eq!(fb[1..].as_ptr(), at);
+}
+
+#[test]
+fn into_inner_closes_unused_headroom() {
+    static STORAGE: FrameStorage<1, 16> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+    let mut sfb = SendFrameBox::from(pool.allocate_raw().unwrap());
+
+    sfb.reserve(8);
+    sfb.put_trailer(3).unwrap().copy_from_slice(&[1, 2, 3]);
+    sfb.push_header(2).unwrap().copy_from_slice(&[9, 9]);
+    let fb = sfb.into_inner();
+    assert_eq!(&fb[1..], [9, 9, 1, 2, 3]);
+}
+
+#[test]
+fn wire_frame_box_layers() {
+    static STORAGE: FrameStorage<2, 16> = FrameStorage::new();
+    let mut pool = STORAGE.take().unwrap();
+
+    let mut fb = pool.allocate_raw().unwrap();
+    fb.set_len(8);
+    fb[0] = 0x00;
+    let mut fb = WireFrameBox::try_from(fb).map(drop).unwrap_err();
+    fb[0] = CmdAddr::SelectAddr(3).into();
+    fb[1..].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+    let at = fb.as_ptr();
+
+    let mut wfb = WireFrameBox::try_from(fb).ok().unwrap();
+    assert_eq!(wfb.cmd_addr(), CmdAddr::SelectAddr(3));
+    assert!(wfb.pull_header(8).is_none());
+    assert!(wfb.trim_trailer(8).is_none());
+    assert_eq!(wfb.pull_header(2).unwrap(), [1, 2]);
+    assert_eq!(wfb.trim_trailer(3).unwrap(), [5, 6, 7]);
+    assert!(wfb.pull_header(3).is_none());
+    assert!(wfb.trim_trailer(3).is_none());
+    assert_eq!(wfb.payload(), [3, 4]);
+    assert_eq!(wfb.trim_trailer(2).unwrap(), [3, 4]);
+    assert!(wfb.is_empty());
+
+    // The whole frame is returned in place, with the pulled and trimmed bytes
+    let fb = wfb.into_inner();
+    assert_eq!(fb.as_ptr(), at);
+    assert_eq!(&fb[1..], [1, 2, 3, 4, 5, 6, 7]);
+}
@@ -0,0 +1,68 @@
+//! Formatting, parsing, and byte orders of [Mac] addresses
+
+use erdnuss_comms::{mac::ParseMacError, Mac};
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+
+#[test]
+fn display_is_big_endian() {
+    assert_eq!(MAC.to_string(), "01:23:45:67:89:ab:cd:ef");
+    assert_eq!(Mac::new(0x0A).to_string(), "00:00:00:00:00:00:00:0a");
+    assert_eq!(format!("{MAC:?}"), "Mac(01:23:45:67:89:ab:cd:ef)");
+}
+
+#[test]
+fn parse_round_trips() {
+    for mac in [MAC, Mac::new(0), Mac::new(u64::MAX), Mac::new(0xFF00)] {
+        assert_eq!(mac.to_string().parse::<Mac>(), Ok(mac));
+    }
+    assert_eq!("01:23:45:67:89:AB:CD:EF".parse::<Mac>(), Ok(MAC));
+    assert_eq!("0123456789abcdef".parse::<Mac>(), Ok(MAC));
+}
+
+#[test]
+fn parse_rejects_bad_strings() {
+    for s in [
+        "",
+        ":",
+        "01:23:45:67:89:ab:cd",
+        "01:23:45:67:89:ab:cd:ef:00",
+        "01:23:45:67:89:ab:cd:e",
+        "01:23:45:67:89:ab:cd:eff",
+        "1:23:45:67:89:ab:cd:ef0",
+        "01:23:45:67:89:ab:cd:eg",
+        "01-23-45-67-89-ab-cd-ef",
+        "0123456789abcde",
+        "0123456789abcdef0",
+        "+123456789abcdef",
+        "0123456789abcdeg",
+    ] {
+        assert_eq!(s.parse::<Mac>(), Err(ParseMacError), "{s:?}");
+    }
+}
+
+#[test]
+fn byte_orders() {
+    let human = [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF];
+    let mut wire = human;
+    wire.reverse();
+
+    assert_eq!(MAC.to_be_bytes(), human);
+    assert_eq!(MAC.to_wire_bytes(), wire);
+    assert_eq!(Mac::from_be_bytes(human), MAC);
+    assert_eq!(Mac::from_wire_bytes(wire), MAC);
+    assert_eq!(u64::from(MAC), 0x0123_4567_89AB_CDEF);
+    assert_eq!(Mac::from(0x0123_4567_89AB_CDEF), MAC);
+}
+
+#[test]
+fn postcard_is_a_plain_u64() {
+    let mut buf = [0u8; 16];
+    let used = postcard::to_slice(&MAC, &mut buf).unwrap();
+    let mut plain = [0u8; 16];
+    assert_eq!(
+        used,
+        postcard::to_slice(&0x0123_4567_89AB_CDEFu64, &mut plain).unwrap()
+    );
+    assert_eq!(postcard::from_bytes::<Mac>(used), Ok(MAC));
+}
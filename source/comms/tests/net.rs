@@ -0,0 +1,349 @@
+//! Frames between nodes over the network transports
+
+use core::pin::pin;
+use std::{
+    env,
+    process::{Child, Command},
+};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Duration;
+use erdnuss_comms::{
+    clock::{Clock, TokioClock},
+    controller::{Controller, INCOMING_SIZE, OUTGOING_SIZE},
+    frame_pool::{FrameBox, FrameStorage},
+    net::{TcpHub, TcpSerial, UdpBus},
+    target::{self, Target, TgtCfg},
+    FrameSerial, Mac, MAX_TARGETS,
+};
+use futures::future::{select, Either};
+use rand_core::RngCore;
+
+async fn recv(serial: &mut impl FrameSerial) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 64];
+    let got = TokioClock::with_timeout(Duration::from_millis(200), serial.recv(&mut buf)).await;
+    got.ok().map(|res| res.ok().unwrap().frame.to_vec())
+}
+
+async fn hub() -> std::net::SocketAddr {
+    let hub = TcpHub::bind("127.0.0.1:0").await.unwrap();
+    let addr = hub.local_addr().unwrap();
+    tokio::spawn(hub.run());
+    addr
+}
+
+#[tokio::test]
+async fn tcp_hub_passes_frames_to_others() {
+    let addr = hub().await;
+    let mut a = TcpSerial::connect(addr).await.unwrap();
+    let mut b = TcpSerial::connect(addr).await.unwrap();
+    let mut c = TcpSerial::connect(addr).await.unwrap();
+
+    a.send_frame(&[0x21, 1, 2]).await.unwrap();
+    a.send_frame(&[]).await.unwrap();
+    assert_eq!(recv(&mut b).await.unwrap(), [0x21, 1, 2]);
+    assert_eq!(recv(&mut c).await.unwrap(), [0x21, 1, 2]);
+    assert_eq!(recv(&mut b).await.unwrap(), []);
+    assert_eq!(recv(&mut c).await.unwrap(), []);
+
+    // Nodes don't hear themselves
+    c.send_frame(&[0x42]).await.unwrap();
+    assert_eq!(recv(&mut a).await.unwrap(), [0x42]);
+    assert_eq!(recv(&mut b).await.unwrap(), [0x42]);
+    assert_eq!(recv(&mut c).await, None);
+}
+
+#[tokio::test]
+async fn tcp_recv_survives_cancellation() {
+    let addr = hub().await;
+    let mut a = TcpSerial::connect(addr).await.unwrap();
+    let mut b = TcpSerial::connect(addr).await.unwrap();
+
+    // Time out a receive, then receive normally
+    assert_eq!(recv(&mut b).await, None);
+    a.send_frame(&[1, 2, 3]).await.unwrap();
+    a.send_frame(&[4, 5]).await.unwrap();
+    assert_eq!(recv(&mut b).await.unwrap(), [1, 2, 3]);
+    assert_eq!(recv(&mut b).await.unwrap(), [4, 5]);
+}
+
+#[tokio::test]
+async fn tcp_cancelled_send_is_not_written_late() {
+    // A hub that doesn't read anything yet, so the stream backs up
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut a = TcpSerial::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    // Send large frames until one can't be sent, and cancel that send
+    let frame = |i: usize| vec![i as u8; 60_000];
+    let mut sent = 0;
+    while TokioClock::with_timeout(Duration::from_millis(200), a.send_frame(&frame(sent)))
+        .await
+        .is_ok()
+    {
+        sent += 1;
+    }
+
+    let reading = std::thread::spawn(move || {
+        let mut data = vec![];
+        std::io::Read::read_to_end(&mut stream, &mut data).unwrap();
+        data
+    });
+    a.send_frame(&[0xAA]).await.unwrap();
+    drop(a);
+    let data = TokioClock::with_timeout(
+        Duration::from_secs(10),
+        tokio::task::spawn_blocking(|| reading.join().unwrap()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // The cancelled frame was never written, not even ahead of the next one
+    let mut frames = vec![];
+    let mut rest = data.as_slice();
+    while let [l0, l1, tail @ ..] = rest {
+        let len = usize::from(u16::from_le_bytes([*l0, *l1]));
+        frames.push(tail[..len].to_vec());
+        rest = &tail[len..];
+    }
+    let mut expected: Vec<Vec<u8>> = (0..sent).map(frame).collect();
+    expected.push(vec![0xAA]);
+    assert_eq!(frames.len(), expected.len());
+    assert!(frames == expected);
+}
+
+#[tokio::test]
+async fn latency_delays_sends() {
+    let addr = hub().await;
+    let mut a = TcpSerial::connect(addr).await.unwrap();
+    let mut b = TcpSerial::connect(addr).await.unwrap();
+    a.set_latency(Duration::from_millis(50));
+
+    let start = TokioClock::now();
+    a.send_frame(&[7]).await.unwrap();
+    assert_eq!(recv(&mut b).await.unwrap(), [7]);
+    let elapsed = TokioClock::now().as_ticks() - start.as_ticks();
+    assert!(Duration::from_ticks(elapsed) >= Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn udp_bus_passes_frames_to_others() {
+    let group = "239.255.72.86:48586".parse().unwrap();
+    let mut a = UdpBus::join(group).unwrap();
+    let mut b = UdpBus::join(group).unwrap();
+
+    a.send_frame(&[0x21, 1, 2]).await.unwrap();
+    assert_eq!(recv(&mut b).await.unwrap(), [0x21, 1, 2]);
+    assert_eq!(recv(&mut a).await, None);
+}
+
+/// A random number generator that makes the Target claim every offer
+struct Eager;
+
+impl RngCore for Eager {
+    fn next_u32(&mut self) -> u32 {
+        0
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.fill(0);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        dest.fill(0);
+        Ok(())
+    }
+}
+
+struct Cfg;
+
+impl TgtCfg for Cfg {
+    type Mutex = CriticalSectionRawMutex;
+    type Serial = TcpSerial;
+    type Rand = Eager;
+    type Clock = TokioClock;
+
+    const TURNAROUND_DELAY: Duration = Duration::from_micros(10);
+    const ADDRESS_CLAIM_TIMEOUT: Duration = Duration::from_millis(50);
+    const SELECT_TIMEOUT: Duration = Duration::from_millis(500);
+}
+
+#[tokio::test]
+async fn target_joins_over_tcp() {
+    const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+    static CONTROLLER: Controller<
+        CriticalSectionRawMutex,
+        INCOMING_SIZE,
+        OUTGOING_SIZE,
+        TokioClock,
+    > = Controller::uninit();
+    static CTL_STORAGE: FrameStorage<{ INCOMING_SIZE * MAX_TARGETS }> = FrameStorage::new();
+    static TGT_STORAGE: FrameStorage<8> = FrameStorage::new();
+
+    let addr = hub().await;
+    let mut ctl_serial = TcpSerial::connect(addr).await.unwrap();
+    CONTROLLER
+        .init(&mut CTL_STORAGE.take_with_clock::<TokioClock>().unwrap())
+        .await;
+
+    let to_app = Channel::<CriticalSectionRawMutex, FrameBox, { target::INCOMING_SIZE }>::new();
+    let from_app = Channel::<CriticalSectionRawMutex, FrameBox, { target::OUTGOING_SIZE }>::new();
+    let mut target = Target::<Cfg>::new(
+        TcpSerial::connect(addr).await.unwrap(),
+        to_app.sender(),
+        from_app.receiver(),
+        TGT_STORAGE.take_with_clock::<TokioClock>().unwrap(),
+        MAC,
+        Eager,
+    );
+
+    // Replies can miss the REPLY_TIMEOUT on a loaded machine, so keep stepping
+    let stepping = async {
+        while CONTROLLER.connected().await.is_empty() {
+            let _ = CONTROLLER.step(&mut ctl_serial, &mut Eager).await;
+            TokioClock::wait(Duration::from_millis(1)).await;
+        }
+    };
+    let running = target.run();
+    let joined = matches!(
+        TokioClock::with_timeout(
+            Duration::from_secs(10),
+            select(pin!(stepping), pin!(running)),
+        )
+        .await,
+        Ok(Either::Left(_))
+    );
+    assert!(joined);
+    assert_eq!(CONTROLLER.connected().await, [MAC]);
+}
+
+/// The hub a [target_process] connects to
+const HUB_VAR: &str = "ERDNUSS_NET_HUB";
+/// The MAC address of a [target_process]
+const MAC_VAR: &str = "ERDNUSS_NET_MAC";
+
+/// A xorshift generator, so Targets claim offers at random, like on a real bus
+///
+/// Over TCP, claims don't collide, so Targets that always claim together
+/// leave the Controller with a stale claim.
+struct Seeded(u64);
+
+impl RngCore for Seeded {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+struct ProcessCfg;
+
+impl TgtCfg for ProcessCfg {
+    type Mutex = CriticalSectionRawMutex;
+    type Serial = TcpSerial;
+    type Rand = Seeded;
+    type Clock = TokioClock;
+
+    const TURNAROUND_DELAY: Duration = Duration::from_micros(10);
+    const ADDRESS_CLAIM_TIMEOUT: Duration = Duration::from_millis(50);
+    const SELECT_TIMEOUT: Duration = Duration::from_millis(500);
+}
+
+/// Run [target_process] in a separate process, by running this test binary again
+fn spawn_target(hub: std::net::SocketAddr, mac: Mac) -> Child {
+    Command::new(env::current_exe().unwrap())
+        .args(["target_process", "--exact", "--ignored", "--nocapture"])
+        .env(HUB_VAR, hub.to_string())
+        .env(MAC_VAR, mac.to_u64().to_string())
+        .spawn()
+        .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "run as a separate process by tcp_nodes_in_separate_processes"]
+async fn target_process() {
+    static TGT_STORAGE: FrameStorage<8> = FrameStorage::new();
+    let (Ok(hub), Ok(mac)) = (env::var(HUB_VAR), env::var(MAC_VAR)) else {
+        return;
+    };
+    let mac = Mac::new(mac.parse().unwrap());
+
+    let to_app = Channel::<CriticalSectionRawMutex, FrameBox, { target::INCOMING_SIZE }>::new();
+    let from_app = Channel::<CriticalSectionRawMutex, FrameBox, { target::OUTGOING_SIZE }>::new();
+    let mut target = Target::<ProcessCfg>::new(
+        TcpSerial::connect(hub).await.unwrap(),
+        to_app.sender(),
+        from_app.receiver(),
+        TGT_STORAGE.take_with_clock::<TokioClock>().unwrap(),
+        mac,
+        Seeded(mac.to_u64()),
+    );
+    // Normally killed by the parent long before this
+    let _ = TokioClock::with_timeout(Duration::from_secs(30), target.run()).await;
+}
+
+#[tokio::test]
+async fn tcp_nodes_in_separate_processes() {
+    const MACS: [Mac; 2] = [
+        Mac::new(0x0123_4567_89AB_CDEF),
+        Mac::new(0x1111_2222_3333_4444),
+    ];
+    static CONTROLLER: Controller<
+        CriticalSectionRawMutex,
+        INCOMING_SIZE,
+        OUTGOING_SIZE,
+        TokioClock,
+    > = Controller::uninit();
+    static CTL_STORAGE: FrameStorage<{ INCOMING_SIZE * MAX_TARGETS }> = FrameStorage::new();
+
+    let addr = hub().await;
+    let mut ctl_serial = TcpSerial::connect(addr).await.unwrap();
+    CONTROLLER
+        .init(&mut CTL_STORAGE.take_with_clock::<TokioClock>().unwrap())
+        .await;
+    let mut children: Vec<Child> = MACS.iter().map(|mac| spawn_target(addr, *mac)).collect();
+
+    // On a loaded machine, replies can miss the REPLY_TIMEOUT, and Targets
+    // get culled, so only check that each one joined at some point
+    let mut joined = vec![];
+    let stepping = async {
+        while joined.len() < MACS.len() {
+            let _ = CONTROLLER.step(&mut ctl_serial, &mut Eager).await;
+            for mac in CONTROLLER.connected().await {
+                if !joined.contains(&mac) {
+                    joined.push(mac);
+                }
+            }
+            TokioClock::wait(Duration::from_millis(1)).await;
+        }
+    };
+    let res = TokioClock::with_timeout(Duration::from_secs(20), stepping).await;
+    for child in children.iter_mut() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    assert!(res.is_ok(), "only {joined:?} joined");
+    joined.sort();
+    assert_eq!(joined, MACS);
+}
@@ -0,0 +1,83 @@
+//! Allocation tracking with the `pool-debug` feature
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use embassy_time::{Duration, Instant};
+use erdnuss_comms::{
+    clock::Clock,
+    frame_pool::{FrameStorage, OwnerTag},
+};
+
+static NOW: AtomicU64 = AtomicU64::new(0);
+
+/// A clock that only moves when told to
+struct TestClock;
+
+impl TestClock {
+    fn advance(dur: Duration) {
+        NOW.fetch_add(dur.as_ticks(), Ordering::SeqCst);
+    }
+}
+
+impl Clock for TestClock {
+    fn now() -> Instant {
+        Instant::from_ticks(NOW.load(Ordering::SeqCst))
+    }
+
+    async fn wait_until(_at: Instant) {
+        unimplemented!()
+    }
+}
+
+static RADIO: OwnerTag = OwnerTag("radio");
+
+#[test]
+fn outstanding_allocations() {
+    static STORAGE: FrameStorage<4, 4> = FrameStorage::new();
+    let mut pool = STORAGE.take_with_clock::<TestClock>().unwrap();
+    let start = TestClock::now();
+
+    let a = pool.allocate_raw().unwrap();
+    TestClock::advance(Duration::from_millis(10));
+    let mut b = pool.allocate_raw().unwrap();
+    b.set_owner(&RADIO);
+    TestClock::advance(Duration::from_millis(10));
+    let c = pool.allocate_raw().unwrap();
+    drop(a);
+
+    let mut out: Vec<_> = pool.outstanding().collect();
+    out.sort_by_key(|o| o.index);
+    let summary: Vec<_> = out
+        .iter()
+        .map(|o| (o.index, o.owner.map(|t| t.0), o.allocated_at))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (1, Some("radio"), start + Duration::from_millis(10)),
+            (2, None, start + Duration::from_millis(20)),
+        ]
+    );
+
+    // Split slices keep timestamping with the same clock
+    let mut tail = pool.split(3).unwrap();
+    let d = tail.allocate_raw().unwrap();
+    let out: Vec<_> = tail
+        .outstanding()
+        .map(|o| (o.index, o.allocated_at))
+        .collect();
+    assert_eq!(out, [(0, start + Duration::from_millis(20))]);
+
+    TestClock::advance(Duration::from_millis(5));
+    let old: Vec<_> = pool
+        .held_longer_than(Duration::from_millis(10))
+        .map(|o| o.index)
+        .collect();
+    assert_eq!(old, [1]);
+    assert_eq!(pool.held_longer_than(Duration::from_millis(1)).count(), 2);
+    assert_eq!(pool.held_longer_than(Duration::from_millis(20)).count(), 0);
+
+    drop((b, c, d));
+    assert_eq!(pool.outstanding().count(), 0);
+    assert_eq!(tail.outstanding().count(), 0);
+}
@@ -0,0 +1,207 @@
+//! DE pin handling of the [Rs485] driver, with a mock UART and pin
+
+use std::{cell::RefCell, future::pending, rc::Rc};
+
+use embedded_hal::digital::{ErrorType, OutputPin};
+use erdnuss_comms::{
+    rs485::{BreakUart, Rs485, Rs485Error},
+    Error, FrameSerial,
+};
+use futures::{executor::block_on, FutureExt};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    De(bool),
+    RxEnabled(bool),
+    Wrote(Vec<u8>),
+    Flushed,
+    Break,
+}
+
+type Log = Rc<RefCell<Vec<Event>>>;
+
+#[derive(Debug, PartialEq)]
+struct MockError;
+
+impl embedded_hal::digital::Error for MockError {
+    fn kind(&self) -> embedded_hal::digital::ErrorKind {
+        embedded_hal::digital::ErrorKind::Other
+    }
+}
+
+#[derive(Default)]
+struct MockUart {
+    log: Log,
+    /// Never finish writing
+    stall: bool,
+    fail_write: bool,
+    rx: Vec<u8>,
+}
+
+impl BreakUart for MockUart {
+    type Error = MockError;
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if self.stall {
+            pending::<()>().await;
+        }
+        if self.fail_write {
+            return Err(MockError);
+        }
+        self.log.borrow_mut().push(Event::Wrote(data.to_vec()));
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.log.borrow_mut().push(Event::Flushed);
+        Ok(())
+    }
+
+    async fn send_break(&mut self) -> Result<(), Self::Error> {
+        self.log.borrow_mut().push(Event::Break);
+        Ok(())
+    }
+
+    async fn read_to_break(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let ct = self.rx.len();
+        buf.get_mut(..ct)
+            .ok_or(MockError)?
+            .copy_from_slice(&self.rx);
+        Ok(ct)
+    }
+
+    fn set_rx_enabled(&mut self, enabled: bool) {
+        self.log.borrow_mut().push(Event::RxEnabled(enabled));
+    }
+}
+
+struct MockPin {
+    log: Log,
+    fail_high: bool,
+}
+
+impl ErrorType for MockPin {
+    type Error = MockError;
+}
+
+impl OutputPin for MockPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.log.borrow_mut().push(Event::De(false));
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        if self.fail_high {
+            return Err(MockError);
+        }
+        self.log.borrow_mut().push(Event::De(true));
+        Ok(())
+    }
+}
+
+fn port(uart: MockUart, fail_high: bool) -> (Rs485<MockUart, MockPin>, Log) {
+    let log = uart.log.clone();
+    let pin = MockPin {
+        log: log.clone(),
+        fail_high,
+    };
+    (Rs485::new(uart, pin), log)
+}
+
+/// The state of DE after the logged events
+fn de_asserted(log: &Log) -> bool {
+    log.borrow()
+        .iter()
+        .rev()
+        .find_map(|e| match e {
+            Event::De(de) => Some(*de),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+#[test]
+fn send_asserts_de_around_frame() {
+    let (mut port, log) = port(MockUart::default(), false);
+    assert_eq!(*log.borrow(), [Event::De(false)]);
+
+    block_on(port.send_frame(&[1, 2, 3])).unwrap();
+    assert_eq!(
+        *log.borrow(),
+        [
+            Event::De(false),
+            Event::RxEnabled(false),
+            Event::De(true),
+            Event::Wrote(vec![1, 2, 3]),
+            Event::Flushed,
+            Event::Break,
+            Event::De(false),
+            Event::RxEnabled(true),
+        ]
+    );
+}
+
+#[test]
+fn dropped_send_deasserts_de() {
+    let uart = MockUart {
+        stall: true,
+        ..MockUart::default()
+    };
+    let (mut port, log) = port(uart, false);
+
+    let mut send = Box::pin(port.send_frame(&[1, 2, 3]));
+    assert!((&mut send).now_or_never().is_none());
+    assert!(de_asserted(&log));
+
+    drop(send);
+    assert!(!de_asserted(&log));
+    assert_eq!(
+        log.borrow()[log.borrow().len() - 2..],
+        [Event::De(false), Event::RxEnabled(true)]
+    );
+}
+
+#[test]
+fn failed_send_deasserts_de() {
+    let uart = MockUart {
+        fail_write: true,
+        ..MockUart::default()
+    };
+    let (mut port, log) = port(uart, false);
+
+    let res = block_on(port.send_frame(&[1, 2, 3]));
+    assert_eq!(res, Err(Error::Serial(Rs485Error::Uart(MockError))));
+    assert!(!de_asserted(&log));
+    assert_eq!(log.borrow().last(), Some(&Event::RxEnabled(true)));
+}
+
+#[test]
+fn failed_de_reenables_rx() {
+    let (mut port, log) = port(MockUart::default(), true);
+
+    let res = block_on(port.send_frame(&[1, 2, 3]));
+    assert_eq!(res, Err(Error::Serial(Rs485Error::Pin(MockError))));
+    assert_eq!(
+        *log.borrow(),
+        [
+            Event::De(false),
+            Event::RxEnabled(false),
+            Event::De(false),
+            Event::RxEnabled(true),
+        ]
+    );
+}
+
+#[test]
+fn recv_reads_to_break() {
+    let uart = MockUart {
+        rx: vec![0x21, 4, 5],
+        ..MockUart::default()
+    };
+    let (mut port, log) = port(uart, false);
+
+    let mut buf = [0u8; 8];
+    let tf = block_on(port.recv(&mut buf)).unwrap();
+    assert_eq!(tf.frame, [0x21, 4, 5]);
+    assert!(!de_asserted(&log));
+}
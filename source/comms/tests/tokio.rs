@@ -0,0 +1,165 @@
+//! Run a Controller and a Target on the tokio runtime, with paused time
+
+use core::{future::pending, pin::pin};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Duration;
+use erdnuss_comms::{
+    clock::{Clock, TokioClock},
+    controller::{Controller, INCOMING_SIZE, OUTGOING_SIZE},
+    frame_pool::{FrameBox, FrameStorage},
+    target::{self, Target, TgtCfg},
+    Error, FrameSerial, Mac, TimedFrame, MAX_TARGETS,
+};
+use futures::future::{select, Either};
+use rand_core::RngCore;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+
+type TokioController =
+    Controller<CriticalSectionRawMutex, INCOMING_SIZE, OUTGOING_SIZE, TokioClock>;
+
+static CONTROLLER: TokioController = Controller::uninit();
+static CTL_STORAGE: FrameStorage<{ INCOMING_SIZE * MAX_TARGETS }> = FrameStorage::new();
+static TGT_STORAGE: FrameStorage<8> = FrameStorage::new();
+
+/// One end of a point to point link, timestamped with the [TokioClock]
+struct Link {
+    tx: UnboundedSender<Vec<u8>>,
+    rx: UnboundedReceiver<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct Closed;
+
+fn link() -> (Link, Link) {
+    let (atx, brx) = unbounded_channel();
+    let (btx, arx) = unbounded_channel();
+    (Link { tx: atx, rx: arx }, Link { tx: btx, rx: brx })
+}
+
+impl FrameSerial for Link {
+    type SerError = Closed;
+
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.tx
+            .send(frame.to_vec())
+            .map_err(|_| Error::Serial(Closed))
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        let data = self.rx.recv().await.ok_or(Error::Serial(Closed))?;
+        let buf = &mut frame[..data.len()];
+        buf.copy_from_slice(&data);
+        Ok(TimedFrame {
+            end_of_rx: TokioClock::now(),
+            frame: buf,
+        })
+    }
+}
+
+/// A random number generator that makes the Target claim every offer
+struct Eager;
+
+impl RngCore for Eager {
+    fn next_u32(&mut self) -> u32 {
+        0
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.fill(0);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        dest.fill(0);
+        Ok(())
+    }
+}
+
+struct Cfg;
+
+impl TgtCfg for Cfg {
+    type Mutex = CriticalSectionRawMutex;
+    type Serial = Link;
+    type Rand = Eager;
+    type Clock = TokioClock;
+
+    const TURNAROUND_DELAY: Duration = Duration::from_micros(10);
+    const ADDRESS_CLAIM_TIMEOUT: Duration = Duration::from_millis(5);
+    const SELECT_TIMEOUT: Duration = Duration::from_millis(50);
+}
+
+#[tokio::test(start_paused = true)]
+async fn target_joins_on_tokio() {
+    let (mut ctl_link, tgt_link) = link();
+    CONTROLLER
+        .init(&mut CTL_STORAGE.take_with_clock::<TokioClock>().unwrap())
+        .await;
+
+    let to_app = Channel::<CriticalSectionRawMutex, FrameBox, { target::INCOMING_SIZE }>::new();
+    let from_app = Channel::<CriticalSectionRawMutex, FrameBox, { target::OUTGOING_SIZE }>::new();
+    let mut target = Target::<Cfg>::new(
+        tgt_link,
+        to_app.sender(),
+        from_app.receiver(),
+        TGT_STORAGE.take_with_clock::<TokioClock>().unwrap(),
+        MAC,
+        Eager,
+    );
+
+    let stepping = async {
+        while CONTROLLER.connected().await.is_empty() {
+            CONTROLLER.step(&mut ctl_link, &mut Eager).await.unwrap();
+            TokioClock::wait(Duration::from_micros(100)).await;
+        }
+    };
+    let running = target.run();
+    let joined = matches!(
+        TokioClock::with_timeout(
+            Duration::from_millis(500),
+            select(pin!(stepping), pin!(running)),
+        )
+        .await,
+        Ok(Either::Left(_))
+    );
+    assert!(joined);
+    assert_eq!(CONTROLLER.connected().await, [MAC]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn timeouts_follow_paused_time() {
+    let start = TokioClock::now();
+    let res = TokioClock::with_timeout(Duration::from_secs(3600), pending::<()>()).await;
+    assert!(res.is_err());
+    let elapsed = TokioClock::now().as_ticks() - start.as_ticks();
+    assert_eq!(Duration::from_ticks(elapsed), Duration::from_secs(3600));
+}
+
+#[tokio::test(start_paused = true)]
+async fn frame_allocation_times_out() {
+    static STORAGE: FrameStorage<1> = FrameStorage::new();
+    let mut pool = STORAGE.take_with_clock::<TokioClock>().unwrap();
+    let held = pool.allocate_raw().unwrap();
+
+    let start = TokioClock::now();
+    let res = pool
+        .allocate_timeout_with_clock::<TokioClock>(Duration::from_secs(60))
+        .await;
+    assert!(res.is_none());
+    let elapsed = TokioClock::now().as_ticks() - start.as_ticks();
+    assert_eq!(Duration::from_ticks(elapsed), Duration::from_secs(60));
+
+    drop(held);
+    let res = pool
+        .allocate_timeout_with_clock::<TokioClock>(Duration::from_secs(60))
+        .await;
+    assert!(res.is_some());
+}
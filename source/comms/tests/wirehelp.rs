@@ -0,0 +1,127 @@
+//! Encoding and decoding postcard-rpc messages in frames
+
+use erdnuss_comms::{
+    frame_pool::{FrameBox, FrameStorage, RawFrameSlice},
+    wirehelp::{
+        reply_endpoint, reply_error, send_request, send_topic, WhBody, WireError, ERROR_KEY,
+    },
+};
+use postcard::experimental::schema::{NamedType, Schema, SdmTy};
+use postcard_rpc::{endpoint, topic, Endpoint, Topic};
+use serde::{Deserialize, Serialize};
+
+/// A request that borrows from the frame it was received in
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Name<'a>(&'a str);
+
+// postcard only implements `Schema` for owned strings
+impl Schema for Name<'_> {
+    const SCHEMA: &'static NamedType = &NamedType {
+        name: "Name",
+        ty: &SdmTy::String,
+    };
+}
+
+endpoint!(DoubleEndpoint, u32, u64, "test/double");
+endpoint!(OtherEndpoint, u32, u64, "test/other");
+endpoint!(NameEndpoint, Name<'static>, (), "test/name");
+topic!(CountTopic, u32, "test/count");
+
+fn pool() -> RawFrameSlice {
+    let storage: &'static FrameStorage<4> = Box::leak(Box::new(FrameStorage::new()));
+    storage.take().unwrap()
+}
+
+fn request(seq_no: u32, req: u32) -> FrameBox {
+    send_request::<DoubleEndpoint>(pool().allocate_raw().unwrap(), seq_no, &req).unwrap()
+}
+
+/// Append one byte to the end of the frame
+fn append(fb: &mut FrameBox, b: u8) {
+    let len = fb.len();
+    fb.set_len(len + 1);
+    fb[len] = b;
+}
+
+#[test]
+fn request_round_trips() {
+    let fb = request(7, 21);
+    let body = WhBody::try_from(&fb).unwrap();
+    assert_eq!(body.wh.key, DoubleEndpoint::REQ_KEY);
+    assert_eq!(body.wh.seq_no, 7);
+    assert_eq!(body.decode_request::<DoubleEndpoint>(), Some(21));
+}
+
+#[test]
+fn replies_round_trip() {
+    let mut pool = pool();
+
+    let fb = reply_endpoint::<DoubleEndpoint>(pool.allocate_raw().unwrap(), 8, &42).unwrap();
+    let body = WhBody::try_from(&fb).unwrap();
+    assert_eq!(body.wh.seq_no, 8);
+    assert_eq!(body.decode_response::<DoubleEndpoint>(), Some(42));
+
+    let fb = send_topic::<CountTopic>(pool.allocate_raw().unwrap(), 9, &3).unwrap();
+    let body = WhBody::try_from(&fb).unwrap();
+    assert_eq!(body.wh.key, CountTopic::TOPIC_KEY);
+    assert_eq!(body.decode_topic::<CountTopic>(), Some(3));
+
+    let fb = reply_error(pool.allocate_raw().unwrap(), 10, &WireError::DeserFailed).unwrap();
+    let body = WhBody::try_from(&fb).unwrap();
+    assert_eq!(body.wh.key, ERROR_KEY);
+    assert_eq!(body.wh.seq_no, 10);
+    assert_eq!(body.decode_error(), Some(WireError::DeserFailed));
+}
+
+#[test]
+fn key_mismatch_is_rejected() {
+    let fb = request(7, 21);
+    let body = WhBody::try_from(&fb).unwrap();
+    // Same types, different path
+    assert_eq!(body.decode_request::<OtherEndpoint>(), None);
+    // Same path, the other direction, with a different type
+    assert_eq!(body.decode_response::<DoubleEndpoint>(), None);
+    assert_eq!(body.decode_topic::<CountTopic>(), None);
+    assert_eq!(body.decode_error(), None);
+}
+
+#[test]
+fn trailing_garbage_is_rejected() {
+    let mut fb = request(7, 21);
+    append(&mut fb, 0xAA);
+    let body = WhBody::try_from(&fb).unwrap();
+    assert_eq!(body.wh.seq_no, 7);
+    assert_eq!(body.decode_request::<DoubleEndpoint>(), None);
+
+    let mut fb = reply_error(pool().allocate_raw().unwrap(), 10, &WireError::DeserFailed).unwrap();
+    append(&mut fb, 0);
+    assert_eq!(WhBody::try_from(&fb).unwrap().decode_error(), None);
+}
+
+#[test]
+fn truncated_frames_are_rejected() {
+    let mut fb = request(7, 1000);
+    let len = fb.len();
+    fb.set_len(len - 1);
+    let body = WhBody::try_from(&fb).unwrap();
+    assert_eq!(body.decode_request::<DoubleEndpoint>(), None);
+
+    // Just the address, no header
+    fb.set_len(1);
+    assert!(WhBody::try_from(&fb).is_none());
+}
+
+#[test]
+fn decoding_borrows_from_the_frame() {
+    let fb =
+        send_request::<NameEndpoint>(pool().allocate_raw().unwrap(), 1, &Name("erdnuss")).unwrap();
+    // A `Name<'static>` can only borrow from a frame that lives forever
+    let fb: &'static FrameBox = Box::leak(Box::new(fb));
+    let body = WhBody::try_from(fb).unwrap();
+    let Name(name) = body.decode_request::<NameEndpoint>().unwrap();
+    assert_eq!(name, "erdnuss");
+
+    // No copy was made, the name points into the frame
+    let frame = fb.as_ptr_range();
+    assert!(frame.contains(&name.as_ptr()));
+}
@@ -0,0 +1,354 @@
+//! # Erdnuss Host
+//!
+//! A host PC client for an Erdnuss Controller running the
+//! [bridge][erdnuss_comms::bridge] from `erdnuss-comms`.
+//!
+//! The [Client] connects to the bridge over any byte stream, such as a serial
+//! port (see [Client::open_serial()]) or a TCP socket (see [Client::connect_tcp()]).
+//! It can list the connected Targets, send raw frames or postcard-rpc requests
+//! to a Target by MAC address, and receive frames and topology changes from
+//! the bus as [Event]s.
+//!
+//! All methods are blocking. A background thread reads and decodes messages
+//! from the bridge, so that every method can respect a timeout.
+
+#![warn(missing_docs)]
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use erdnuss_comms::{
+    bridge::{
+        BridgeError, BridgeStats, FrameData, FrameReceivedTopic, ListPeersEndpoint, Received,
+        SendTo, SendToEndpoint, StatsEndpoint, TopologyEvent, TopologyTopic, MAX_HOST_MSG,
+        MAX_PAYLOAD,
+    },
+    wirehelp::{WireError, ERROR_KEY},
+    Mac,
+};
+use postcard::experimental::schema::Schema;
+use postcard_rpc::{
+    headered::{extract_header_from_bytes, to_slice_cobs_keyed},
+    Endpoint, Key, Topic, WireHeader,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The default timeout used when waiting for the bridge to respond
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An error when talking to the bridge
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading from or writing to the bridge failed
+    Io(io::Error),
+    /// The bridge closed the connection
+    Closed,
+    /// No response was received before the timeout
+    Timeout,
+    /// The message did not fit in a frame, or in a host link message
+    TooLong,
+    /// A response could not be deserialized
+    Decode,
+    /// The bridge could not send the frame to the Target
+    Bridge(BridgeError),
+    /// The bridge or Target responded with an error
+    Remote(WireError),
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// A message sent by the bridge, that was not a response to a request
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A frame was received from the Target with the given MAC address
+    Received {
+        /// The MAC address of the Target
+        mac: Mac,
+        /// The payload of the frame
+        data: Vec<u8>,
+    },
+    /// A Target has joined or left the bus
+    Topology(TopologyEvent),
+}
+
+/// A connection to a bridge
+pub struct Client {
+    tx: Box<dyn Write + Send>,
+    frames: Receiver<io::Result<Vec<u8>>>,
+    seq_no: u32,
+    events: VecDeque<Event>,
+    timeout: Duration,
+    tcp: Option<TcpStream>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // The background thread holds a clone of the socket, so closing our
+        // half isn't enough to close the connection
+        if let Some(tcp) = self.tcp.take() {
+            let _ = tcp.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Client {
+    /// Connect to a bridge over TCP
+    pub fn connect_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let tx = TcpStream::connect(addr)?;
+        tx.set_nodelay(true)?;
+        let rx = tx.try_clone()?;
+        let tcp = tx.try_clone()?;
+        let mut client = Self::new(rx, tx);
+        client.tcp = Some(tcp);
+        Ok(client)
+    }
+
+    /// Open a bridge attached to a serial port, such as `/dev/ttyACM0`
+    ///
+    /// The port is used as-is, so any settings like the baud rate must already
+    /// be configured. USB CDC-ACM ports, the usual way a bridge is attached,
+    /// ignore these settings.
+    pub fn open_serial(path: impl AsRef<Path>) -> io::Result<Self> {
+        let tx = OpenOptions::new().read(true).write(true).open(path)?;
+        let rx: File = tx.try_clone()?;
+        Ok(Self::new(rx, tx))
+    }
+
+    /// Create a client over any pair of byte streams
+    ///
+    /// `rx` is moved to a background thread, which reads and decodes messages
+    /// from the bridge until the stream is closed.
+    pub fn new<R, W>(rx: R, tx: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (frame_tx, frames) = mpsc::channel();
+        thread::spawn(move || read_frames(rx, frame_tx));
+        Self {
+            tx: Box::new(tx),
+            frames,
+            seq_no: 0,
+            events: VecDeque::new(),
+            timeout: DEFAULT_TIMEOUT,
+            tcp: None,
+        }
+    }
+
+    /// Set the timeout used when waiting for the bridge to respond
+    ///
+    /// Defaults to [DEFAULT_TIMEOUT].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Get the MAC addresses of all Targets connected to the bus
+    pub fn list(&mut self) -> Result<Vec<Mac>, Error> {
+        let peers = self.bridge_request::<ListPeersEndpoint>(&())?;
+        Ok(peers.into_iter().collect())
+    }
+
+    /// Get the statistics of the bridge
+    pub fn stats(&mut self) -> Result<BridgeStats, Error> {
+        self.bridge_request::<StatsEndpoint>(&())
+    }
+
+    /// Send a frame to the Target with the given MAC address
+    ///
+    /// Returns once the bridge has enqueued the frame for sending.
+    pub fn send(&mut self, mac: Mac, data: &[u8]) -> Result<(), Error> {
+        let data = FrameData::from_slice(data).map_err(|_| Error::TooLong)?;
+        self.bridge_request::<SendToEndpoint>(&SendTo { mac, data })?
+            .map_err(Error::Bridge)
+    }
+
+    /// Send a postcard-rpc request to a Target, and wait for the matching response
+    ///
+    /// A response from the Target with the [ERROR_KEY] is returned as [Error::Remote].
+    pub fn call<E>(
+        &mut self,
+        mac: Mac,
+        req: &E::Request,
+        timeout: Duration,
+    ) -> Result<E::Response, Error>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        let body = postcard::to_stdvec(req).map_err(|_| Error::TooLong)?;
+        let (wh, body) = self.call_raw(mac, E::REQ_KEY, &body, timeout)?;
+        if wh.key != E::RESP_KEY {
+            return Err(Error::Decode);
+        }
+        postcard::from_bytes(&body).map_err(|_| Error::Decode)
+    }
+
+    /// Send a request with the given key and serialized body to a Target, and wait
+    /// for the frame from that Target with the same `seq_no`
+    ///
+    /// Returns the header and body of the response, which may have any key, except
+    /// for the [ERROR_KEY], which is returned as [Error::Remote].
+    pub fn call_raw(
+        &mut self,
+        mac: Mac,
+        key: Key,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<(WireHeader, Vec<u8>), Error> {
+        let seq_no = self.next_seq_no();
+        let mut frame =
+            postcard::to_stdvec(&WireHeader { key, seq_no }).map_err(|_| Error::TooLong)?;
+        frame.extend_from_slice(body);
+        if frame.len() > MAX_PAYLOAD {
+            return Err(Error::TooLong);
+        }
+        self.send(mac, &frame)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let pos = self.events.iter().position(|ev| match ev {
+                Event::Received { mac: m, data } => {
+                    *m == mac
+                        && extract_header_from_bytes(data)
+                            .map(|(wh, _)| wh.seq_no == seq_no)
+                            .unwrap_or(false)
+                }
+                Event::Topology(_) => false,
+            });
+            if let Some(Event::Received { data, .. }) = pos.and_then(|i| self.events.remove(i)) {
+                let (wh, body) = extract_header_from_bytes(&data).map_err(|_| Error::Decode)?;
+                if wh.key == ERROR_KEY {
+                    let err = postcard::from_bytes(body).map_err(|_| Error::Decode)?;
+                    return Err(Error::Remote(err));
+                }
+                return Ok((wh, body.to_vec()));
+            }
+            // Wait for another event to arrive, and check again
+            let msg = self.recv_until(deadline)?;
+            self.handle_unsolicited(msg);
+        }
+    }
+
+    /// Wait for the next [Event] from the bridge
+    ///
+    /// Returns [Error::Timeout] if no event arrives before `timeout`.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Event, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(ev) = self.events.pop_front() {
+                return Ok(ev);
+            }
+            let msg = self.recv_until(deadline)?;
+            self.handle_unsolicited(msg);
+        }
+    }
+
+    /// Send a request to the bridge itself, and wait for the response
+    fn bridge_request<E>(&mut self, req: &E::Request) -> Result<E::Response, Error>
+    where
+        E: Endpoint,
+        E::Request: Serialize + Schema,
+        E::Response: DeserializeOwned,
+    {
+        let seq_no = self.next_seq_no();
+        let mut buf = [0u8; MAX_HOST_MSG];
+        let used =
+            to_slice_cobs_keyed(seq_no, E::REQ_KEY, req, &mut buf).map_err(|_| Error::TooLong)?;
+        self.tx.write_all(used)?;
+        self.tx.flush()?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let (wh, body) = self.recv_until(deadline)?;
+            if wh.seq_no == seq_no && wh.key == E::RESP_KEY {
+                return postcard::from_bytes(&body).map_err(|_| Error::Decode);
+            }
+            if wh.seq_no == seq_no && wh.key == ERROR_KEY {
+                let err = postcard::from_bytes(&body).map_err(|_| Error::Decode)?;
+                return Err(Error::Remote(err));
+            }
+            self.handle_unsolicited((wh, body));
+        }
+    }
+
+    /// Queue a topic message from the bridge as an [Event]
+    fn handle_unsolicited(&mut self, (wh, body): (WireHeader, Vec<u8>)) {
+        if wh.key == FrameReceivedTopic::TOPIC_KEY {
+            if let Ok(Received { mac, data }) = postcard::from_bytes(&body) {
+                self.events.push_back(Event::Received {
+                    mac,
+                    data: data.to_vec(),
+                });
+            }
+        } else if wh.key == TopologyTopic::TOPIC_KEY {
+            if let Ok(ev) = postcard::from_bytes(&body) {
+                self.events.push_back(Event::Topology(ev));
+            }
+        }
+        // Anything else is a late response to an earlier request, drop it
+    }
+
+    /// Wait for the next message from the bridge, split into its header and body
+    fn recv_until(&mut self, deadline: Instant) -> Result<(WireHeader, Vec<u8>), Error> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let frame = match self.frames.recv_timeout(timeout) {
+            Ok(frame) => frame?,
+            Err(RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Closed),
+        };
+        let (wh, body) = extract_header_from_bytes(&frame).map_err(|_| Error::Decode)?;
+        Ok((wh, body.to_vec()))
+    }
+
+    fn next_seq_no(&mut self) -> u32 {
+        let seq_no = self.seq_no;
+        self.seq_no = seq_no.wrapping_add(1);
+        seq_no
+    }
+}
+
+/// Read COBS frames from the bridge, until the stream closes or errors
+fn read_frames<R: Read>(mut rx: R, frames: mpsc::Sender<io::Result<Vec<u8>>>) {
+    let mut acc = Vec::new();
+    let mut chunk = [0u8; 256];
+    loop {
+        let ct = match rx.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(ct) => ct,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let _ = frames.send(Err(e));
+                return;
+            }
+        };
+        for &b in &chunk[..ct] {
+            if b != 0 {
+                acc.push(b);
+                continue;
+            }
+            let mut frame = core::mem::take(&mut acc);
+            // Malformed frames are skipped
+            if let Ok(len) = cobs::decode_in_place(&mut frame) {
+                frame.truncate(len);
+                if frames.send(Ok(frame)).is_err() {
+                    // The client was dropped
+                    return;
+                }
+            }
+        }
+    }
+}
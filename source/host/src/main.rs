@@ -0,0 +1,186 @@
+//! The `erdnuss` CLI, for talking to a Controller bridge
+
+use std::{process::ExitCode, time::Duration};
+
+use erdnuss_comms::{
+    capture::{Direction, PcapngReader},
+    decode::{Decoder, EventKind},
+    Mac,
+};
+use erdnuss_host::{Client, Error, Event};
+use postcard_rpc::Key;
+
+const USAGE: &str = "\
+Usage: erdnuss (--serial PATH | --tcp ADDR) COMMAND
+       erdnuss decode CAPTURE
+
+Commands:
+  list                List the MAC addresses of all connected Targets
+  send MAC HEX        Send a frame to a Target
+  call MAC KEY HEX    Send a postcard-rpc request to a Target, and print the response
+  monitor             Print frames and topology changes from the bus, until interrupted
+  decode CAPTURE      Pretty-print the frames of a pcapng capture of bus traffic
+
+MACs are given as eight hex bytes, most significant first, with or without colons,
+e.g. `send 01:23:45:67:89:ab:cd:ef 01ff`. Keys and payloads are given in hex.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    if let ["decode", path] = args.as_slice() {
+        return decode(path);
+    }
+
+    let (client, cmd) = match args.as_slice() {
+        ["--serial", path, cmd @ ..] => (Client::open_serial(path), cmd),
+        ["--tcp", addr, cmd @ ..] => (Client::connect_tcp(addr), cmd),
+        _ => return usage(),
+    };
+    let mut client = match client {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to connect: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let res = match cmd {
+        ["list"] => list(&mut client),
+        ["send", mac, data] => match (parse_mac(mac), parse_hex(data)) {
+            (Some(mac), Some(data)) => client.send(mac, &data),
+            _ => return usage(),
+        },
+        ["call", mac, key, data] => match (parse_mac(mac), parse_key(key), parse_hex(data)) {
+            (Some(mac), Some(key), Some(data)) => call(&mut client, mac, key, &data),
+            _ => return usage(),
+        },
+        ["monitor"] => monitor(&mut client),
+        _ => return usage(),
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::FAILURE
+}
+
+fn list(client: &mut Client) -> Result<(), Error> {
+    for mac in client.list()? {
+        println!("{mac}");
+    }
+    Ok(())
+}
+
+fn call(client: &mut Client, mac: Mac, key: Key, data: &[u8]) -> Result<(), Error> {
+    let (wh, body) = client.call_raw(mac, key, data, Duration::from_secs(1))?;
+    println!("key: {}", to_hex(&wh.key.to_bytes()));
+    println!("body: {}", to_hex(&body));
+    Ok(())
+}
+
+fn monitor(client: &mut Client) -> Result<(), Error> {
+    loop {
+        match client.next_event(Duration::from_secs(60)) {
+            Ok(Event::Received { mac, data }) => println!("{mac} <- {}", to_hex(&data)),
+            Ok(Event::Topology(ev)) => println!("{ev:?}"),
+            Err(Error::Timeout) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn decode(path: &str) -> ExitCode {
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Failed to read {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut decoder = Decoder::new();
+    let mut violations = 0;
+    for frame in PcapngReader::new(&data) {
+        let frame = match frame {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("Error reading capture: {e:?}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let ev = decoder.feed(frame.at, frame.frame);
+        let dir = match frame.dir {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        let mut line = format!("{:>12}us {dir} {}", ev.at.as_micros(), describe(&ev.kind));
+        if let Some(lat) = ev.latency {
+            line += &format!(" after {}us", lat.as_micros());
+        }
+        if let Some(wh) = &ev.header {
+            line += &format!(" [key {} seq {}]", to_hex(&wh.key.to_bytes()), wh.seq_no);
+        }
+        let has_body = matches!(ev.kind, EventKind::Select { .. } | EventKind::Reply { .. });
+        if has_body && !ev.payload.is_empty() {
+            line += &format!(" {}", to_hex(ev.payload));
+        }
+        println!("{line}");
+        for v in ev.violations.iter() {
+            println!("    !! {v:?}");
+            violations += 1;
+        }
+    }
+
+    println!("{violations} protocol violation(s)");
+    ExitCode::SUCCESS
+}
+
+fn describe(kind: &EventKind) -> String {
+    match kind {
+        EventKind::Select { addr } => format!("select {addr}"),
+        EventKind::Reply { addr } => format!("reply {addr}"),
+        EventKind::Offer { addr, challenge } => format!("offer {addr} {}", to_hex(challenge)),
+        EventKind::Claim {
+            addr,
+            mac: Some(mac),
+        } => format!("claim {addr} by {mac}"),
+        EventKind::Claim { addr, mac: None } => format!("claim {addr}"),
+        EventKind::Success { addr, mac } => format!("success {addr} for {mac}"),
+        EventKind::Joined { addr, mac } => format!("joined {addr} as {mac}"),
+        EventKind::Invalid => "invalid".into(),
+    }
+}
+
+fn parse_mac(s: &str) -> Option<Mac> {
+    s.parse().ok()
+}
+
+fn parse_key(s: &str) -> Option<Key> {
+    let bytes: [u8; 8] = parse_hex(s)?.try_into().ok()?;
+    // SAFETY: The key is provided by the user, and is only used to address
+    // a request, mismatched keys are answered with an error by the Target.
+    Some(unsafe { Key::from_bytes(bytes) })
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,254 @@
+//! Run the client against a bridge served over TCP on localhost, with the
+//! bus simulated by `erdnuss-sim`
+
+use std::{
+    cell::RefCell,
+    io::{self, Read as _, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    rc::Rc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use embassy_time::Timer;
+use erdnuss_comms::{
+    bridge::{run, BridgeError, BridgeIoError, BridgeStats, TopologyEvent},
+    define_dispatch,
+    frame_pool::{FrameBox, FrameStorage},
+    wirehelp::{WhBody, WireError},
+    Mac,
+};
+use erdnuss_host::{Client, Error, Event};
+use erdnuss_sim::{NodeId, Sim, SimConfig};
+use postcard_rpc::{endpoint, Endpoint};
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+endpoint!(DoubleEndpoint, u32, u64, "test/double");
+endpoint!(UnknownEndpoint, u32, u64, "test/unknown");
+
+async fn double(_ctx: &mut (), req: u32) -> u64 {
+    u64::from(req) * 2
+}
+
+define_dispatch! {
+    async fn dispatch(ctx: &mut ());
+    DoubleEndpoint => double,
+}
+
+/// Answer requests with [dispatch], and echo any other frame
+async fn handle(frame: FrameBox) -> Option<FrameBox> {
+    if WhBody::try_from(&frame).is_some() {
+        dispatch(&mut (), frame).await
+    } else {
+        Some(frame)
+    }
+}
+
+/// A non-blocking TcpStream, which waits for data in virtual time, so the
+/// rest of the simulation keeps running
+struct AsyncTcp(TcpStream);
+
+impl embedded_io_async::ErrorType for AsyncTcp {
+    type Error = io::Error;
+}
+
+impl embedded_io_async::Read for AsyncTcp {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    Timer::after(embassy_time::Duration::from_micros(100)).await
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl embedded_io_async::Write for AsyncTcp {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        loop {
+            match self.0.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    Timer::after(embassy_time::Duration::from_micros(100)).await
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+
+type BridgeResult = Result<(), BridgeIoError<io::Error, io::Error>>;
+
+/// Serve a bridge on localhost, over a simulated bus with a Target for each
+/// of `macs`, until the client disconnects
+fn serve(macs: &'static [Mac]) -> (SocketAddr, JoinHandle<BridgeResult>) {
+    serve_with(macs, embassy_time::Duration::from_millis(5), |_, _| {})
+}
+
+/// Like [serve()], polling the Controller every `poll_interval`, and running
+/// `script` on the simulation with each Target's node before serving
+fn serve_with(
+    macs: &'static [Mac],
+    poll_interval: embassy_time::Duration,
+    script: impl FnOnce(&mut Sim, &[NodeId]) + Send + 'static,
+) -> (SocketAddr, JoinHandle<BridgeResult>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let bridge = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let rx = AsyncTcp(stream.try_clone().unwrap());
+        let tx = AsyncTcp(stream);
+
+        let mut sim = Sim::new(SimConfig::default());
+        let mut nodes = Vec::new();
+        for mac in macs {
+            let node = sim.add_target(*mac);
+            sim.set_handler(node, handle);
+            nodes.push(node);
+        }
+
+        let out: Rc<RefCell<Option<BridgeResult>>> = Rc::default();
+        let task_out = out.clone();
+        let ctl = sim.controller();
+        sim.spawn(async move {
+            let storage: &'static FrameStorage<4> = Box::leak(Box::new(FrameStorage::new()));
+            let mut pool = storage.take().unwrap();
+            let res = run(ctl, &mut pool, rx, tx, poll_interval).await;
+            *task_out.borrow_mut() = Some(res);
+        });
+        script(&mut sim, &nodes);
+
+        // Virtual time runs ahead of the client, which is fine
+        while out.borrow().is_none() {
+            sim.run_for(embassy_time::Duration::from_millis(10));
+        }
+        out.take().unwrap()
+    });
+    (addr, bridge)
+}
+
+#[test]
+fn client_over_tcp() {
+    let (addr, bridge) = serve(&[]);
+    let mut client = Client::connect_tcp(addr).unwrap();
+
+    // No Targets are connected
+    assert_eq!(client.list().unwrap(), Vec::<Mac>::new());
+
+    // Frames to unknown Targets are rejected by the bridge
+    let res = client.send(MAC, &[1, 2, 3]);
+    assert!(matches!(
+        res,
+        Err(Error::Bridge(BridgeError::NoMatchingMac))
+    ));
+
+    let key = unsafe { postcard_rpc::Key::from_bytes([1; 8]) };
+    let res = client.call_raw(MAC, key, &[], Duration::from_millis(50));
+    assert!(matches!(
+        res,
+        Err(Error::Bridge(BridgeError::NoMatchingMac))
+    ));
+
+    // Payloads must fit in a frame
+    let res = client.send(MAC, &[0; 300]);
+    assert!(matches!(res, Err(Error::TooLong)));
+
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.send_errors, 2);
+    assert_eq!(stats.pool_capacity, 4);
+    assert_eq!(stats.pool_in_use, 0);
+
+    // Closing the connection ends the bridge
+    drop(client);
+    bridge.join().unwrap().unwrap();
+}
+
+#[test]
+fn client_talks_to_a_target() {
+    let (addr, bridge) = serve(&[MAC]);
+    let mut client = Client::connect_tcp(addr).unwrap();
+    client.set_timeout(TIMEOUT);
+
+    // monitor: the Target joining is reported
+    assert_eq!(
+        client.next_event(TIMEOUT).unwrap(),
+        Event::Topology(TopologyEvent::Joined(MAC))
+    );
+
+    // list
+    assert_eq!(client.list().unwrap(), [MAC]);
+
+    // send, and monitor the echo
+    client.send(MAC, &[1, 2, 3]).unwrap();
+    assert_eq!(
+        client.next_event(TIMEOUT).unwrap(),
+        Event::Received {
+            mac: MAC,
+            data: vec![1, 2, 3],
+        }
+    );
+
+    // call, with a response and with an error
+    assert_eq!(
+        client.call::<DoubleEndpoint>(MAC, &21, TIMEOUT).unwrap(),
+        42
+    );
+    match client.call::<UnknownEndpoint>(MAC, &21, TIMEOUT) {
+        Err(Error::Remote(WireError::UnknownKey(key))) => {
+            assert_eq!(key, UnknownEndpoint::REQ_KEY.to_bytes())
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+
+    let BridgeStats {
+        frames_to_targets,
+        frames_from_targets,
+        send_errors,
+        pool_in_use,
+        ..
+    } = client.stats().unwrap();
+    assert_eq!((frames_to_targets, frames_from_targets), (3, 3));
+    assert_eq!(send_errors, 0);
+    assert_eq!(pool_in_use, 0);
+
+    drop(client);
+    bridge.join().unwrap().unwrap();
+}
+
+#[test]
+fn rejoin_between_polls_is_reported() {
+    const POLL: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+    let (addr, bridge) = serve_with(&[MAC], POLL, |sim, nodes| {
+        // Joined before the bridge's second poll, which reports it
+        sim.assert_all_joined_by(embassy_time::Duration::from_millis(500));
+        sim.run_until(POLL + embassy_time::Duration::from_millis(1));
+
+        // Leave and rejoin before the third poll
+        sim.power_off(nodes[0]);
+        assert!(sim.run_until_cond(POLL * 2, |s| s.connected().is_empty()));
+        sim.power_on(nodes[0]);
+        sim.assert_all_joined_by(POLL * 2);
+    });
+    let mut client = Client::connect_tcp(addr).unwrap();
+
+    for ev in [
+        TopologyEvent::Joined(MAC),
+        TopologyEvent::Left(MAC),
+        TopologyEvent::Joined(MAC),
+    ] {
+        assert_eq!(client.next_event(TIMEOUT).unwrap(), Event::Topology(ev));
+    }
+
+    drop(client);
+    bridge.join().unwrap().unwrap();
+}
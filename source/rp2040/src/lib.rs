@@ -1,6 +1,6 @@
 #![no_std]
 
-use erdnuss_comms::TimedFrame;
+use erdnuss_comms::{Mac, TimedFrame};
 use embassy_rp::{
     flash::{Blocking, Flash},
     gpio::{AnyPin, Output},
@@ -10,7 +10,7 @@ use embassy_rp::{
 use embassy_time::Instant;
 use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
 
-pub fn get_unique_id(flash: &mut FLASH) -> Option<u64> {
+pub fn get_unique_id(flash: &mut FLASH) -> Option<Mac> {
     let mut flash: Flash<'_, FLASH, Blocking, { 2 * 1024 * 1024 }> = Flash::new_blocking(flash);
 
     // TODO: For different flash chips, we want to handle things
@@ -20,15 +20,15 @@ pub fn get_unique_id(flash: &mut FLASH) -> Option<u64> {
     //
     // let jedec = flash.blocking_jedec_id().unwrap();
 
-    let mut id = [0u8; core::mem::size_of::<u64>()];
+    let mut id = [0u8; 8];
     flash.blocking_unique_id(&mut id).unwrap();
-    Some(u64::from_be_bytes(id))
+    Some(Mac::from_be_bytes(id))
 }
 
-pub fn get_rand(unique_id: u64) -> ChaCha8Rng {
+pub fn get_rand(unique_id: Mac) -> ChaCha8Rng {
     // TODO: Get some real entropy
     let mut seed = [0u8; 32];
-    let uid = unique_id.to_le_bytes();
+    let uid = unique_id.to_wire_bytes();
     seed.chunks_exact_mut(8).for_each(|c| {
         c.copy_from_slice(&uid);
     });
@@ -0,0 +1,205 @@
+//! A simulated RS-485 bus
+//!
+//! Every frame sent by one node is delivered to every other powered node,
+//! once the whole frame has been "transmitted" at the configured baud rate.
+//! Nodes never hear their own frames.
+//!
+//! If two nodes transmit at the same time, both frames collide, and no node
+//! receives either of them. Frames that collide are still recorded in the
+//! [BusFrame] log, for inspection by tests.
+//!
+//! With address matching enabled, a port only receives the frames accepted by
+//! the [AddrFilter] its node last set, like a UART in 9-bit mode with a
+//! hardware address match. The frames it sleeps through are counted.
+
+use std::{cell::RefCell, collections::VecDeque, future::poll_fn, rc::Rc, task::Poll, task::Waker};
+
+use embassy_time::{Duration, Instant, Timer};
+use erdnuss_comms::{addr_mark::AddrFilter, Error, FrameSerial, TimedFrame};
+
+/// The number of received frames a node buffers before dropping the oldest,
+/// like a DMA ring buffer being overrun
+const RX_DEPTH: usize = 16;
+
+/// A frame seen on the bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusFrame {
+    /// The time the frame finished transmitting
+    pub at: Instant,
+    /// The bus port of the sender, `0` is the Controller
+    pub from: usize,
+    /// The contents of the frame
+    pub data: Vec<u8>,
+    /// Did the frame collide with another, and was lost?
+    pub collided: bool,
+}
+
+/// An error returned by a [SimSerial]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusError {
+    /// The received frame didn't fit in the provided buffer
+    Overrun,
+}
+
+#[derive(Default)]
+struct Port {
+    powered: bool,
+    rx: VecDeque<(Instant, Vec<u8>)>,
+    waker: Option<Waker>,
+    filter: AddrFilter,
+    /// Frames not received due to the `filter`
+    muted: u32,
+}
+
+struct Transmission {
+    id: u64,
+    end: Instant,
+    collided: bool,
+}
+
+pub(crate) struct BusState {
+    baud: u64,
+    addr_match: bool,
+    ports: Vec<Port>,
+    in_flight: Vec<Transmission>,
+    next_id: u64,
+    pub(crate) log: Vec<BusFrame>,
+}
+
+/// A handle to the shared bus
+pub(crate) type Bus = Rc<RefCell<BusState>>;
+
+pub(crate) fn new_bus(baud: u32, addr_match: bool) -> Bus {
+    Rc::new(RefCell::new(BusState {
+        baud: u64::from(baud),
+        addr_match,
+        ports: Vec::new(),
+        in_flight: Vec::new(),
+        next_id: 0,
+        log: Vec::new(),
+    }))
+}
+
+impl BusState {
+    /// Add an unpowered port to the bus, returning its index
+    pub(crate) fn add_port(&mut self) -> usize {
+        self.ports.push(Port::default());
+        self.ports.len() - 1
+    }
+
+    /// The time taken to transmit a frame of `len` bytes
+    fn airtime(&self, len: usize) -> Duration {
+        // Ten bits per byte, plus a one byte line break at the end
+        let bits = 10 * (len as u64 + 1);
+        Duration::from_ticks((bits * embassy_time::TICK_HZ).div_ceil(self.baud))
+    }
+
+    /// Power a port on or off, discarding anything it has buffered
+    pub(crate) fn set_powered(&mut self, port: usize, powered: bool) {
+        let p = &mut self.ports[port];
+        p.powered = powered;
+        p.rx.clear();
+        p.waker = None;
+        p.filter = AddrFilter::Any;
+        p.muted = 0;
+    }
+
+    /// The number of frames a port didn't receive due to its address filter
+    pub(crate) fn muted(&self, port: usize) -> u32 {
+        self.ports[port].muted
+    }
+}
+
+/// A [FrameSerial] attached to one port of the simulated bus
+pub struct SimSerial {
+    bus: Bus,
+    port: usize,
+}
+
+impl SimSerial {
+    pub(crate) fn new(bus: Bus, port: usize) -> Self {
+        Self { bus, port }
+    }
+}
+
+impl FrameSerial for SimSerial {
+    type SerError = BusError;
+
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        let (id, end) = {
+            let mut bus = self.bus.borrow_mut();
+            let now = Instant::now();
+            let end = now + bus.airtime(frame.len());
+            bus.in_flight.retain(|t| t.end > now);
+            let collided = !bus.in_flight.is_empty();
+            bus.in_flight.iter_mut().for_each(|t| t.collided = true);
+            let id = bus.next_id;
+            bus.next_id += 1;
+            bus.in_flight.push(Transmission { id, end, collided });
+            (id, end)
+        };
+
+        Timer::at(end).await;
+
+        let mut bus = self.bus.borrow_mut();
+        let pos = bus.in_flight.iter().position(|t| t.id == id);
+        let collided = pos.is_none_or(|i| bus.in_flight.swap_remove(i).collided);
+        bus.log.push(BusFrame {
+            at: end,
+            from: self.port,
+            data: frame.to_vec(),
+            collided,
+        });
+        if !collided {
+            let addr_match = bus.addr_match;
+            for (i, p) in bus.ports.iter_mut().enumerate() {
+                if i == self.port || !p.powered {
+                    continue;
+                }
+                if addr_match && !frame.first().is_none_or(|b| p.filter.accepts(*b)) {
+                    p.muted += 1;
+                    continue;
+                }
+                if p.rx.len() >= RX_DEPTH {
+                    p.rx.pop_front();
+                }
+                p.rx.push_back((end, frame.to_vec()));
+                if let Some(w) = p.waker.take() {
+                    w.wake();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        let (end_of_rx, data) = poll_fn(|cx| {
+            let mut bus = self.bus.borrow_mut();
+            let port = &mut bus.ports[self.port];
+            match port.rx.pop_front() {
+                Some(got) => Poll::Ready(got),
+                None => {
+                    port.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+
+        let buf = frame
+            .get_mut(..data.len())
+            .ok_or(Error::Serial(BusError::Overrun))?;
+        buf.copy_from_slice(&data);
+        Ok(TimedFrame {
+            end_of_rx,
+            frame: buf,
+        })
+    }
+
+    fn set_addr_filter(&mut self, filter: AddrFilter) {
+        self.bus.borrow_mut().ports[self.port].filter = filter;
+    }
+}
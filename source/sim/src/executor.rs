@@ -0,0 +1,123 @@
+//! A single-threaded executor that drives virtual time
+//!
+//! Tasks are polled until none of them can make progress. Then, time jumps
+//! straight to the next timer deadline, the timers that are due are fired,
+//! and polling starts again.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+use embassy_time::Instant;
+
+use crate::time;
+
+/// The number of polls without time advancing, after which the executor
+/// assumes the tasks are spinning, rather than waiting on a timer or each other
+const LIVELOCK_POLLS: usize = 1_000_000;
+
+/// A handle to a spawned task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TaskId(usize);
+
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+struct Task<'a> {
+    fut: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    flag: Arc<Flag>,
+    waker: Waker,
+}
+
+/// The executor, owning all tasks of a simulation
+#[derive(Default)]
+pub(crate) struct Executor<'a> {
+    tasks: Vec<Option<Task<'a>>>,
+}
+
+impl<'a> Executor<'a> {
+    /// Spawn a task, which is first polled on the next call to [Executor::run_until]
+    pub(crate) fn spawn(&mut self, fut: impl Future<Output = ()> + 'a) -> TaskId {
+        let flag = Arc::new(Flag(AtomicBool::new(true)));
+        let task = Task {
+            fut: Box::pin(fut),
+            waker: Waker::from(flag.clone()),
+            flag,
+        };
+        match self.tasks.iter().position(Option::is_none) {
+            Some(i) => {
+                self.tasks[i] = Some(task);
+                TaskId(i)
+            }
+            None => {
+                self.tasks.push(Some(task));
+                TaskId(self.tasks.len() - 1)
+            }
+        }
+    }
+
+    /// Drop a task, cancelling it wherever it is waiting
+    pub(crate) fn cancel(&mut self, id: TaskId) {
+        if let Some(slot) = self.tasks.get_mut(id.0) {
+            *slot = None;
+        }
+    }
+
+    /// Poll all tasks, advancing virtual time as needed, until `deadline`
+    ///
+    /// When this returns, the current time is exactly `deadline`, and all
+    /// tasks that were woken at or before it have been polled.
+    pub(crate) fn run_until(&mut self, deadline: Instant) {
+        let mut polls = 0;
+        loop {
+            let mut progressed = false;
+            for slot in self.tasks.iter_mut() {
+                let Some(task) = slot else {
+                    continue;
+                };
+                if !task.flag.0.swap(false, Ordering::AcqRel) {
+                    continue;
+                }
+                progressed = true;
+                polls += 1;
+                let mut cx = Context::from_waker(&task.waker);
+                if task.fut.as_mut().poll(&mut cx) == Poll::Ready(()) {
+                    *slot = None;
+                }
+            }
+
+            if progressed {
+                assert!(
+                    polls < LIVELOCK_POLLS,
+                    "tasks made no progress in virtual time after {LIVELOCK_POLLS} polls",
+                );
+                continue;
+            }
+
+            // Everyone is idle, jump ahead to the next timer, or the deadline
+            polls = 0;
+            match time::next_alarm() {
+                Some(at) if at <= deadline.as_ticks() => time::advance_to(at),
+                _ => {
+                    time::advance_to(deadline.as_ticks());
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,554 @@
+//! # Erdnuss Sim
+//!
+//! A deterministic simulation of an Erdnuss bus, for testing the
+//! [Controller] and [Target] state machines of `erdnuss-comms`.
+//!
+//! A [Sim] runs one Controller and any number of Targets and Sniffers, each
+//! running the real `erdnuss-comms` code over a [SimSerial] port of a
+//! simulated RS-485 bus.
+//! Each node's port is wrapped in a [FaultySerial], so faults can be injected
+//! per node with a [FaultPolicy]. With [SimConfig::addr_match], the bus
+//! simulates address-mark framing, where Targets sleep through the frames
+//! that aren't for them.
+//!
+//! Time is virtual. This crate provides the `embassy-time` driver, and time
+//! only advances when every node is waiting, jumping straight to the next
+//! timer deadline. This means that:
+//!
+//! * Timeouts like [REPLY_TIMEOUT][erdnuss_comms::controller::REPLY_TIMEOUT]
+//!   are exact, no matter how loaded the test machine is
+//! * Seconds of bus time run in milliseconds
+//! * The same [SimConfig::seed] always gives the same sequence of events
+//!
+//! To test a single node frame by frame instead, drive it with [block_on()]
+//! over a [ScriptSerial], see the [script] module. This works for both the
+//! async and the blocking interfaces of the Controller and Target.
+//!
+//! Only one [Sim] can exist at a time in a process, as the time driver is
+//! global. [Sim::new()] waits for any other to be dropped, so tests using
+//! this crate can still be run in parallel.
+//!
+//! ```rust,no_run
+//! use embassy_time::Duration;
+//! use erdnuss_sim::{Sim, SimConfig};
+//!
+//! let mut sim = Sim::new(SimConfig::default());
+//! let a = sim.add_target(0xA);
+//! sim.add_target(0xB);
+//! sim.assert_all_joined_by(Duration::from_millis(500));
+//!
+//! sim.power_cycle(a, Duration::from_millis(50));
+//! sim.assert_all_joined_by(sim.now() + Duration::from_millis(500));
+//! ```
+
+#![warn(missing_docs)]
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    sync::{Mutex, MutexGuard},
+};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use erdnuss_comms::{
+    clock::EmbassyClock,
+    controller::REQUEST_FRAMES,
+    fault::{FaultPolicy, FaultySerial},
+    frame_pool::{FrameBox, FrameStorage},
+    sniffer::{PeerTable, Sniffed, Sniffer, SNIFFED_SIZE},
+    target::{TgtCfg, INCOMING_SIZE, OUTGOING_SIZE},
+    Controller, Mac, MAX_TARGETS,
+};
+use futures::{future::join, FutureExt};
+use rand_core::RngCore;
+
+mod bus;
+mod executor;
+mod rng;
+pub mod script;
+mod time;
+
+use crate::{
+    bus::{new_bus, Bus},
+    executor::{Executor, TaskId},
+};
+pub use crate::{
+    bus::{BusError, BusFrame, SimSerial},
+    rng::SimRng,
+    script::ScriptSerial,
+};
+
+#[cfg(doc)]
+use erdnuss_comms::target::Target;
+
+/// The mutex used by all nodes
+pub type SimMutex = CriticalSectionRawMutex;
+
+/// The serial port type used by all nodes
+pub type SimPort = FaultySerial<SimSerial, SimRng>;
+
+/// Computes a Target's reply to a frame it received, see [Sim::set_handler()]
+pub type Handler = Box<dyn FnMut(FrameBox) -> Pin<Box<dyn Future<Output = Option<FrameBox>>>>>;
+
+/// The default baud rate of the bus
+pub const DEFAULT_BAUD: u32 = 4_000_000;
+
+/// The number of frames the Controller needs for its peers' incoming queues,
+/// and for building requests
+const CONTROLLER_FRAMES: usize = 4 * MAX_TARGETS + REQUEST_FRAMES;
+
+/// The number of frames each Target has
+const TARGET_FRAMES: usize = 8;
+
+/// The number of frames each Sniffer has
+const SNIFFER_FRAMES: usize = 4;
+
+/// The longest [block_on()] will run a future for
+pub const BLOCK_ON_LIMIT: Duration = Duration::from_secs(60);
+
+/// Only one simulation can use the global time driver at a time
+static ACTIVE: Mutex<()> = Mutex::new(());
+
+/// The [TgtCfg] used by all simulated Targets
+pub struct SimTarget;
+
+impl TgtCfg for SimTarget {
+    type Mutex = SimMutex;
+    type Serial = SimPort;
+    type Clock = EmbassyClock;
+    type Rand = SimRng;
+
+    const TURNAROUND_DELAY: Duration = Duration::from_micros(10);
+    const ADDRESS_CLAIM_TIMEOUT: Duration = Duration::from_millis(5);
+    const SELECT_TIMEOUT: Duration = Duration::from_millis(50);
+}
+
+/// Configuration of a [Sim]
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// The seed used to derive every random number in the simulation
+    pub seed: u64,
+    /// The baud rate of the bus
+    pub baud: u32,
+    /// The time the Controller waits between calls to [Controller::step()]
+    pub step_interval: Duration,
+    /// Simulate address-mark framing, where Targets only receive the frames
+    /// accepted by their [AddrFilter][erdnuss_comms::addr_mark::AddrFilter]
+    pub addr_match: bool,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            baud: DEFAULT_BAUD,
+            step_interval: Duration::from_micros(100),
+            addr_match: false,
+        }
+    }
+}
+
+/// A node on the simulated bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// The Controller, which is always present
+    pub const CONTROLLER: Self = Self(0);
+
+    /// The bus port of this node, as used in [BusFrame::from]
+    pub fn port(&self) -> usize {
+        self.0
+    }
+}
+
+/// What runs on a node
+#[derive(Clone, Copy)]
+enum Role {
+    Controller,
+    Target(Mac),
+    Sniffer(&'static PeerTable<SimMutex>),
+}
+
+struct Node {
+    role: Role,
+    faults: FaultPolicy,
+    task: Option<TaskId>,
+    received: Rc<RefCell<Vec<Vec<u8>>>>,
+    handler: Rc<RefCell<Option<Handler>>>,
+}
+
+/// A simulated bus, with one Controller and any number of Targets
+pub struct Sim {
+    exec: Executor<'static>,
+    bus: Bus,
+    rng: SimRng,
+    cfg: SimConfig,
+    start: Instant,
+    nodes: Vec<Node>,
+    controller: &'static Controller<SimMutex>,
+    steps: Rc<RefCell<Vec<(Instant, Instant)>>>,
+    // Dropped last, after all tasks
+    _active: MutexGuard<'static, ()>,
+}
+
+impl Sim {
+    /// Create a new simulation, with a powered on Controller and no Targets
+    pub fn new(cfg: SimConfig) -> Self {
+        let active = lock_time();
+        let bus = new_bus(cfg.baud, cfg.addr_match);
+        bus.borrow_mut().add_port();
+
+        let mut sim = Self {
+            exec: Executor::default(),
+            bus,
+            rng: SimRng::new(cfg.seed),
+            cfg,
+            start: Instant::now(),
+            nodes: vec![Node {
+                role: Role::Controller,
+                faults: FaultPolicy::default(),
+                task: None,
+                received: Rc::default(),
+                handler: Rc::default(),
+            }],
+            controller: Box::leak(Box::new(Controller::uninit())),
+            steps: Rc::default(),
+            _active: active,
+        };
+        sim.power_on(NodeId::CONTROLLER);
+        sim
+    }
+
+    /// Add a powered on Target with the given MAC address
+    pub fn add_target(&mut self, mac: impl Into<Mac>) -> NodeId {
+        self.add_target_with_faults(mac, FaultPolicy::default())
+    }
+
+    /// Add a powered on Target, injecting faults into its serial port
+    pub fn add_target_with_faults(&mut self, mac: impl Into<Mac>, faults: FaultPolicy) -> NodeId {
+        self.add_node(Role::Target(mac.into()), faults)
+    }
+
+    /// Add a powered on [Sniffer], which keeps `table` up to date
+    ///
+    /// The sniffed frames themselves are discarded. The table is kept when the
+    /// Sniffer is power cycled, like a table shared with application tasks.
+    pub fn add_sniffer(&mut self, table: &'static PeerTable<SimMutex>) -> NodeId {
+        self.add_node(Role::Sniffer(table), FaultPolicy::default())
+    }
+
+    fn add_node(&mut self, role: Role, faults: FaultPolicy) -> NodeId {
+        let port = self.bus.borrow_mut().add_port();
+        assert_eq!(port, self.nodes.len());
+        self.nodes.push(Node {
+            role,
+            faults,
+            task: None,
+            received: Rc::default(),
+            handler: Rc::default(),
+        });
+        let id = NodeId(port);
+        self.power_on(id);
+        id
+    }
+
+    /// Change the faults injected for a node
+    ///
+    /// The new policy is used from the next time the node is powered on.
+    pub fn set_faults(&mut self, node: NodeId, faults: FaultPolicy) {
+        self.nodes[node.0].faults = faults;
+    }
+
+    /// Reply to every frame a Target receives with the frame returned by `handler`,
+    /// if any, such as a function generated with
+    /// [define_dispatch!][erdnuss_comms::define_dispatch]
+    ///
+    /// The handler is used immediately, and kept when the Target is power cycled.
+    /// Received frames are still recorded for [Sim::received()].
+    pub fn set_handler<F, Fut>(&mut self, node: NodeId, mut handler: F)
+    where
+        F: FnMut(FrameBox) -> Fut + 'static,
+        Fut: Future<Output = Option<FrameBox>> + 'static,
+    {
+        *self.nodes[node.0].handler.borrow_mut() = Some(Box::new(move |f| Box::pin(handler(f))));
+    }
+
+    /// The time since the simulation started
+    pub fn now(&self) -> Duration {
+        // NOTE: `Instant - Instant` can panic through `defmt`, which isn't linked
+        Duration::from_ticks(Instant::now().as_ticks() - self.start.as_ticks())
+    }
+
+    /// The Controller, for use by tasks started with [Sim::spawn()]
+    ///
+    /// Powering the Controller off and on replaces it with a new one.
+    pub fn controller(&self) -> &'static Controller<SimMutex> {
+        self.controller
+    }
+
+    /// The MAC addresses of all Targets the Controller considers connected
+    pub fn connected(&self) -> Vec<Mac> {
+        // `connected` never waits
+        self.controller
+            .connected()
+            .now_or_never()
+            .map(|c| c.into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Is every powered on Target connected to the Controller?
+    pub fn all_joined(&self) -> bool {
+        self.missing().is_empty()
+    }
+
+    /// The start and end time of every Controller step so far
+    pub fn steps(&self) -> Vec<(Instant, Instant)> {
+        self.steps.borrow().clone()
+    }
+
+    /// Every frame sent on the bus so far
+    pub fn bus_log(&self) -> Vec<BusFrame> {
+        self.bus.borrow().log.clone()
+    }
+
+    /// The frames a Target has received from the Controller, since it was
+    /// last powered on
+    pub fn received(&self, node: NodeId) -> Vec<Vec<u8>> {
+        self.nodes[node.0].received.borrow().clone()
+    }
+
+    /// The number of frames a node slept through since it was last powered
+    /// on, with [SimConfig::addr_match]
+    pub fn muted(&self, node: NodeId) -> u32 {
+        self.bus.borrow().muted(node.0)
+    }
+
+    /// Is the node powered on?
+    pub fn is_powered(&self, node: NodeId) -> bool {
+        self.nodes[node.0].task.is_some()
+    }
+
+    /// Spawn an application task, such as one using the [Sim::controller()]
+    pub fn spawn(&mut self, fut: impl Future<Output = ()> + 'static) {
+        self.exec.spawn(fut);
+    }
+
+    /// Run the simulation until `at`, measured from the start of the simulation
+    pub fn run_until(&mut self, at: Duration) {
+        self.exec.run_until(self.start + at);
+    }
+
+    /// Run the simulation for `dur`
+    pub fn run_for(&mut self, dur: Duration) {
+        self.exec.run_until(Instant::now() + dur);
+    }
+
+    /// Run the simulation until `cond` is true, or until `deadline`
+    ///
+    /// `cond` is checked every 100us of virtual time. Returns whether `cond`
+    /// became true.
+    pub fn run_until_cond(
+        &mut self,
+        deadline: Duration,
+        mut cond: impl FnMut(&Sim) -> bool,
+    ) -> bool {
+        loop {
+            if cond(self) {
+                return true;
+            }
+            let now = self.now();
+            if now >= deadline {
+                return false;
+            }
+            self.run_until(deadline.min(now + Duration::from_micros(100)));
+        }
+    }
+
+    /// Run until every powered on Target is connected, panicking if that
+    /// hasn't happened by `deadline`
+    pub fn assert_all_joined_by(&mut self, deadline: Duration) {
+        if !self.run_until_cond(deadline, Sim::all_joined) {
+            panic!(
+                "Targets {:?} not joined by {}us, connected: {:?}",
+                self.missing(),
+                deadline.as_micros(),
+                self.connected(),
+            );
+        }
+    }
+
+    /// Power off a node, dropping any frames it has buffered
+    pub fn power_off(&mut self, node: NodeId) {
+        if let Some(task) = self.nodes[node.0].task.take() {
+            self.exec.cancel(task);
+        }
+        self.bus.borrow_mut().set_powered(node.0, false);
+    }
+
+    /// Power on a node, starting it from scratch
+    pub fn power_on(&mut self, node: NodeId) {
+        self.power_off(node);
+        self.bus.borrow_mut().set_powered(node.0, true);
+
+        let n = &self.nodes[node.0];
+        let serial = FaultySerial::new(
+            SimSerial::new(self.bus.clone(), node.0),
+            SimRng::new(self.rng.next_u64()),
+            n.faults.clone(),
+        );
+        let rand = SimRng::new(self.rng.next_u64());
+
+        let task = match n.role {
+            Role::Controller => {
+                self.controller = Box::leak(Box::new(Controller::uninit()));
+                self.exec.spawn(run_controller(
+                    self.controller,
+                    serial,
+                    rand,
+                    self.cfg.step_interval,
+                    self.steps.clone(),
+                ))
+            }
+            Role::Target(mac) => {
+                let received = n.received.clone();
+                received.borrow_mut().clear();
+                let handler = n.handler.clone();
+                self.exec
+                    .spawn(run_target(mac, serial, rand, received, handler))
+            }
+            Role::Sniffer(table) => self.exec.spawn(run_sniffer(serial, table)),
+        };
+        self.nodes[node.0].task = Some(task);
+    }
+
+    /// Power off a node, run for `off_for`, then power it back on
+    pub fn power_cycle(&mut self, node: NodeId, off_for: Duration) {
+        self.power_off(node);
+        self.run_for(off_for);
+        self.power_on(node);
+    }
+
+    fn missing(&self) -> Vec<Mac> {
+        let connected = self.connected();
+        self.nodes
+            .iter()
+            .filter(|n| n.task.is_some())
+            .filter_map(|n| match n.role {
+                Role::Target(mac) => Some(mac),
+                _ => None,
+            })
+            .filter(|mac| !connected.contains(mac))
+            .collect()
+    }
+}
+
+/// Wait for exclusive use of the time driver
+fn lock_time() -> MutexGuard<'static, ()> {
+    // A test panicking while holding the lock poisons it, that's fine
+    ACTIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run a future to completion in virtual time, without a [Sim]
+///
+/// This is intended for driving a [Controller] or [Target] directly, for
+/// example over a [ScriptSerial]. Like [Sim::new()], this waits for any other
+/// simulation to complete first.
+///
+/// Panics if the future is still pending after [BLOCK_ON_LIMIT] of virtual
+/// time. Use [with_timeout][embassy_time::with_timeout] for futures that
+/// never complete, like [Target::run()].
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let _active = lock_time();
+    let out = RefCell::new(None);
+    let mut exec = Executor::default();
+    exec.spawn(async {
+        *out.borrow_mut() = Some(fut.await);
+    });
+
+    let deadline = Instant::now() + BLOCK_ON_LIMIT;
+    while out.borrow().is_none() {
+        assert!(
+            Instant::now() < deadline,
+            "future still pending after {}ms",
+            BLOCK_ON_LIMIT.as_millis(),
+        );
+        exec.run_until(Instant::now() + Duration::from_millis(1));
+    }
+    drop(exec);
+    out.into_inner().unwrap()
+}
+
+async fn run_controller(
+    ctl: &'static Controller<SimMutex>,
+    mut serial: SimPort,
+    mut rand: SimRng,
+    step_interval: Duration,
+    steps: Rc<RefCell<Vec<(Instant, Instant)>>>,
+) {
+    let storage: &'static FrameStorage<CONTROLLER_FRAMES> =
+        Box::leak(Box::new(FrameStorage::new()));
+    let mut pool = storage.take().unwrap();
+    ctl.init(&mut pool).await;
+    loop {
+        // Errors are expected when injecting faults, just keep stepping
+        let start = Instant::now();
+        let _ = ctl.step(&mut serial, &mut rand).await;
+        steps.borrow_mut().push((start, Instant::now()));
+        Timer::after(step_interval).await;
+    }
+}
+
+async fn run_target(
+    mac: Mac,
+    serial: SimPort,
+    rand: SimRng,
+    received: Rc<RefCell<Vec<Vec<u8>>>>,
+    handler: Rc<RefCell<Option<Handler>>>,
+) {
+    let storage: &'static FrameStorage<TARGET_FRAMES> = Box::leak(Box::new(FrameStorage::new()));
+    let to_app: &'static Channel<SimMutex, FrameBox, INCOMING_SIZE> =
+        Box::leak(Box::new(Channel::new()));
+    let from_app: &'static Channel<SimMutex, FrameBox, OUTGOING_SIZE> =
+        Box::leak(Box::new(Channel::new()));
+
+    let mut target = erdnuss_comms::target::Target::<SimTarget>::new(
+        serial,
+        to_app.sender(),
+        from_app.receiver(),
+        storage.take().unwrap(),
+        mac,
+        rand,
+    );
+    let app = async {
+        loop {
+            let frame = to_app.receive().await;
+            received.borrow_mut().push(frame.to_vec());
+            // Don't hold the borrow while handling, the handler may be replaced
+            let reply = handler.borrow_mut().as_mut().map(|h| h(frame));
+            if let Some(reply) = reply {
+                if let Some(reply) = reply.await {
+                    from_app.send(reply).await;
+                }
+            }
+        }
+    };
+    join(target.run(), app).await;
+}
+
+async fn run_sniffer(serial: SimPort, table: &'static PeerTable<SimMutex>) {
+    let storage: &'static FrameStorage<SNIFFER_FRAMES> = Box::leak(Box::new(FrameStorage::new()));
+    let to_app: &'static Channel<SimMutex, Sniffed, SNIFFED_SIZE> =
+        Box::leak(Box::new(Channel::new()));
+
+    let mut sniffer = Sniffer::new(serial, to_app.sender(), storage.take().unwrap(), table);
+    let app = async {
+        loop {
+            drop(to_app.receive().await);
+        }
+    };
+    join(sniffer.run(), app).await;
+}
@@ -0,0 +1,333 @@
+//! A scripted [FrameSerial], for testing one node at a time
+//!
+//! A [Script] lists, in order, every frame a node is expected to send, and
+//! what it receives in between. A [ScriptSerial] plays the script back, and
+//! panics with a diff of the expected and actual frames as soon as the node
+//! strays from it.
+//!
+//! Frames sent by the node are matched against a [Pattern], which can leave
+//! some bytes as wildcards, like the random challenge of an offer. Canned
+//! replies can be computed from the last frame the node sent, for example to
+//! answer that challenge.
+//!
+//! ```rust
+//! use erdnuss_comms::CmdAddr;
+//! use erdnuss_sim::script::{Pattern, Script};
+//!
+//! // An offer of address 0, which nobody claims
+//! let script = Script::new()
+//!     .expect(Pattern::new().byte(CmdAddr::DiscoveryOffer(0).into()).any(8))
+//!     .timeout();
+//! ```
+//!
+//! Script steps are only consumed by the node, never by the passing of time:
+//! a [Script::timeout()] step makes the next receive wait forever, which the
+//! node is expected to give up on with its own timeout.
+//!
+//! A [ScriptSerial] is also a [BlockingFrameSerial], for testing the blocking
+//! Controller and Target. There, a receive that times out advances virtual
+//! time by its timeout, so blocking nodes should be run inside of
+//! [block_on()][crate::block_on], which holds the time driver. Receiving
+//! while the script expects a frame to be sent is not an error, as a polled
+//! node may be waiting to send a delayed reply.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt::{self, Write},
+    future::pending,
+    rc::Rc,
+};
+
+use embassy_time::{Duration, Instant};
+use erdnuss_comms::{blocking::BlockingFrameSerial, Error, FrameSerial, TimedFrame};
+
+use crate::{bus::BusError, time};
+
+/// A reply computed from the last frame the node sent
+type ReplyFn = Box<dyn FnMut(&[u8]) -> Vec<u8>>;
+
+/// A matcher for a frame sent by the node under test
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    /// Create an empty pattern, which only matches an empty frame
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match one exact byte
+    pub fn byte(mut self, b: u8) -> Self {
+        self.bytes.push(Some(b));
+        self
+    }
+
+    /// Match a sequence of exact bytes
+    pub fn bytes(mut self, bs: &[u8]) -> Self {
+        self.bytes.extend(bs.iter().copied().map(Some));
+        self
+    }
+
+    /// Match `n` bytes of any value
+    pub fn any(mut self, n: usize) -> Self {
+        self.bytes.extend((0..n).map(|_| None));
+        self
+    }
+
+    /// Does `frame` match this pattern?
+    pub fn matches(&self, frame: &[u8]) -> bool {
+        self.bytes.len() == frame.len()
+            && self
+                .bytes
+                .iter()
+                .zip(frame)
+                .all(|(p, b)| p.is_none_or(|p| p == *b))
+    }
+}
+
+impl From<&[u8]> for Pattern {
+    fn from(value: &[u8]) -> Self {
+        Pattern::new().bytes(value)
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Pattern {
+    fn from(value: [u8; N]) -> Self {
+        Pattern::new().bytes(&value)
+    }
+}
+
+impl fmt::Display for Pattern {
+    /// Hex bytes, with `__` for wildcards
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.bytes.iter().enumerate() {
+            if i != 0 {
+                f.write_char(' ')?;
+            }
+            match b {
+                Some(b) => write!(f, "{b:02x}")?,
+                None => f.write_str("__")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Step {
+    Expect(Pattern),
+    Reply(ReplyFn),
+    Timeout,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Expect(p) => write!(f, "expect [{p}]"),
+            Step::Reply(_) => f.write_str("reply"),
+            Step::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+/// An ordered list of frames sent and received by a node
+#[derive(Default)]
+pub struct Script {
+    steps: VecDeque<Step>,
+}
+
+impl Script {
+    /// Create an empty script
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The node sends a frame matching `pattern`
+    pub fn expect(mut self, pattern: impl Into<Pattern>) -> Self {
+        self.steps.push_back(Step::Expect(pattern.into()));
+        self
+    }
+
+    /// The node receives `frame`
+    pub fn reply(self, frame: &[u8]) -> Self {
+        let frame = frame.to_vec();
+        self.reply_with(move |_| frame.clone())
+    }
+
+    /// The node receives the frame returned by `f`, which is given the last
+    /// frame the node sent, or an empty slice if it hasn't sent one yet
+    pub fn reply_with(mut self, f: impl FnMut(&[u8]) -> Vec<u8> + 'static) -> Self {
+        self.steps.push_back(Step::Reply(Box::new(f)));
+        self
+    }
+
+    /// The node receives nothing, its next receive never completes
+    pub fn timeout(mut self) -> Self {
+        self.steps.push_back(Step::Timeout);
+        self
+    }
+}
+
+struct State {
+    steps: VecDeque<Step>,
+    done: usize,
+    last_sent: Vec<u8>,
+}
+
+impl State {
+    fn fail(&self, msg: fmt::Arguments<'_>) -> ! {
+        let total = self.done + self.steps.len();
+        panic!("ScriptSerial, step {} of {total}: {msg}", self.done + 1);
+    }
+
+    /// Check a frame sent by the node against the next step
+    fn sent(&mut self, frame: &[u8]) {
+        match self.steps.front() {
+            Some(Step::Expect(p)) if p.matches(frame) => {}
+            Some(Step::Expect(p)) => self.fail(format_args!(
+                "unexpected frame sent\n  expected: {p}\n  actual:   {}",
+                Pattern::from(frame),
+            )),
+            Some(step) => self.fail(format_args!(
+                "expected {step}, but a frame was sent\n  actual:   {}",
+                Pattern::from(frame),
+            )),
+            None => self.fail(format_args!(
+                "script finished, but a frame was sent\n  actual:   {}",
+                Pattern::from(frame),
+            )),
+        }
+        self.steps.pop_front();
+        self.done += 1;
+        self.last_sent = frame.to_vec();
+    }
+
+    /// Play back the next step for a receive, returning the received frame,
+    /// or [None] if nothing is received
+    ///
+    /// Unless `polling`, the next step must not be a send.
+    fn received(&mut self, polling: bool) -> Option<Vec<u8>> {
+        match self.steps.front() {
+            Some(Step::Expect(_)) if polling => None,
+            Some(Step::Expect(p)) => self.fail(format_args!(
+                "expected a frame to be sent, but the node is receiving\n  expected: {p}",
+            )),
+            None => None,
+            Some(_) => {
+                self.done += 1;
+                match self.steps.pop_front() {
+                    Some(Step::Reply(mut f)) => Some(f(&self.last_sent)),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Copy a received frame into the node's buffer
+fn deliver<'a>(frame: &'a mut [u8], data: &[u8]) -> Result<TimedFrame<'a>, Error<BusError>> {
+    let buf = frame
+        .get_mut(..data.len())
+        .ok_or(Error::Serial(BusError::Overrun))?;
+    buf.copy_from_slice(data);
+    Ok(TimedFrame {
+        end_of_rx: Instant::now(),
+        frame: buf,
+    })
+}
+
+/// A [FrameSerial] that plays back a [Script]
+///
+/// Once the script is exhausted, receives never complete, and any send panics.
+pub struct ScriptSerial {
+    state: Rc<RefCell<State>>,
+}
+
+/// A handle for checking the progress of a [ScriptSerial], after it has been
+/// moved into the node under test
+pub struct ScriptHandle {
+    state: Rc<RefCell<State>>,
+}
+
+impl ScriptSerial {
+    /// Create a serial port that plays back `script`
+    pub fn new(script: Script) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                steps: script.steps,
+                done: 0,
+                last_sent: Vec::new(),
+            })),
+        }
+    }
+
+    /// Get a handle to check the progress of the script
+    pub fn handle(&self) -> ScriptHandle {
+        ScriptHandle {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl ScriptHandle {
+    /// The number of steps not yet played back
+    pub fn remaining(&self) -> usize {
+        self.state.borrow().steps.len()
+    }
+
+    /// Panic if any steps were not played back
+    pub fn assert_done(&self) {
+        let state = self.state.borrow();
+        if let Some(next) = state.steps.front() {
+            state.fail(format_args!("script not finished, next step is {next}"));
+        }
+    }
+}
+
+impl FrameSerial for ScriptSerial {
+    type SerError = BusError;
+
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.state.borrow_mut().sent(frame);
+        Ok(())
+    }
+
+    async fn recv<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<TimedFrame<'a>, Error<Self::SerError>> {
+        let data = self.state.borrow_mut().received(false);
+
+        // Timeouts, and the end of the script, never complete
+        let Some(data) = data else {
+            return pending().await;
+        };
+        deliver(frame, &data)
+    }
+}
+
+impl BlockingFrameSerial for ScriptSerial {
+    type SerError = BusError;
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<Self::SerError>> {
+        self.state.borrow_mut().sent(frame);
+        Ok(())
+    }
+
+    fn recv_timeout<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<TimedFrame<'a>>, Error<Self::SerError>> {
+        let data = self.state.borrow_mut().received(true);
+
+        // Nothing was received, so the whole timeout passes
+        let Some(data) = data else {
+            time::advance_to(Instant::now().as_ticks().saturating_add(timeout.as_ticks()));
+            return Ok(None);
+        };
+        deliver(frame, &data).map(Some)
+    }
+}
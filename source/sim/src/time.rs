@@ -0,0 +1,114 @@
+//! A virtual time driver for `embassy-time`
+//!
+//! Time never advances on its own: the [executor][crate::executor] moves it
+//! forward to the next alarm whenever all tasks are idle. Time is shared by
+//! the whole process, and never goes backwards, so each [Sim][crate::Sim]
+//! measures time relative to when it was created.
+
+use std::sync::Mutex;
+
+use embassy_time::driver::{AlarmHandle, Driver};
+
+/// The number of alarms that can be allocated, only one is used by the
+/// generic timer queue
+const MAX_ALARMS: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Alarm {
+    callback: Option<fn(*mut ())>,
+    // Stored as an integer, as raw pointers are not `Send`
+    ctx: usize,
+    at: Option<u64>,
+}
+
+struct State {
+    now: u64,
+    allocated: usize,
+    alarms: [Alarm; MAX_ALARMS],
+}
+
+struct VirtualDriver {
+    state: Mutex<State>,
+}
+
+const NO_ALARM: Alarm = Alarm {
+    callback: None,
+    ctx: 0,
+    at: None,
+};
+
+embassy_time::time_driver_impl!(static DRIVER: VirtualDriver = VirtualDriver {
+    state: Mutex::new(State {
+        now: 0,
+        allocated: 0,
+        alarms: [NO_ALARM; MAX_ALARMS],
+    }),
+});
+
+impl VirtualDriver {
+    fn with<U>(&self, f: impl FnOnce(&mut State) -> U) -> U {
+        // A test panicking while holding the lock doesn't corrupt the state
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut state)
+    }
+}
+
+impl Driver for VirtualDriver {
+    fn now(&self) -> u64 {
+        self.with(|s| s.now)
+    }
+
+    unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+        self.with(|s| {
+            let id = s.allocated;
+            if id >= MAX_ALARMS {
+                return None;
+            }
+            s.allocated += 1;
+            Some(AlarmHandle::new(id as u8))
+        })
+    }
+
+    fn set_alarm_callback(&self, alarm: AlarmHandle, callback: fn(*mut ()), ctx: *mut ()) {
+        self.with(|s| {
+            let a = &mut s.alarms[usize::from(alarm.id())];
+            a.callback = Some(callback);
+            a.ctx = ctx as usize;
+        })
+    }
+
+    fn set_alarm(&self, alarm: AlarmHandle, timestamp: u64) -> bool {
+        self.with(|s| {
+            if timestamp <= s.now {
+                return false;
+            }
+            s.alarms[usize::from(alarm.id())].at = Some(timestamp);
+            true
+        })
+    }
+}
+
+/// The time of the earliest pending alarm, in ticks
+pub(crate) fn next_alarm() -> Option<u64> {
+    DRIVER.with(|s| s.alarms.iter().filter_map(|a| a.at).min())
+}
+
+/// Advance time to `ticks`, firing any alarms that are due
+///
+/// Does nothing if `ticks` is in the past.
+pub(crate) fn advance_to(ticks: u64) {
+    DRIVER.with(|s| s.now = s.now.max(ticks));
+    // Callbacks may set new alarms, so never hold the lock while calling them
+    while let Some((callback, ctx)) = DRIVER.with(|s| {
+        let now = s.now;
+        s.alarms
+            .iter_mut()
+            .find(|a| a.at.is_some_and(|at| at <= now))
+            .and_then(|a| {
+                a.at = None;
+                a.callback.map(|cb| (cb, a.ctx))
+            })
+    }) {
+        callback(ctx as *mut ());
+    }
+}
@@ -0,0 +1,291 @@
+//! postcard-rpc requests and topics, between the Controller and simulated Targets
+
+use std::{cell::RefCell, rc::Rc};
+
+use embassy_time::{Duration, Instant, Timer};
+use erdnuss_comms::{
+    controller::{RecvError, RequestError},
+    define_dispatch,
+    frame_pool::{FrameBox, FrameStorage, RawFrameSlice, SendFrameBox, DEFAULT_FRAME_SIZE},
+    wirehelp::{reply_endpoint, send_topic, WhBody, WireError},
+    CmdAddr, Mac,
+};
+use erdnuss_sim::{NodeId, Sim, SimConfig};
+use futures::FutureExt;
+use postcard_rpc::{endpoint, topic, Endpoint};
+
+endpoint!(DoubleEndpoint, u32, u32, "test/double");
+endpoint!(UnknownEndpoint, u32, u32, "test/unknown");
+topic!(CountTopic, u32, "test/count");
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+const TIMEOUT: Duration = Duration::from_millis(20);
+
+async fn double(_ctx: &mut (), req: u32) -> u32 {
+    req * 2
+}
+
+define_dispatch! {
+    async fn dispatch(ctx: &mut ());
+    DoubleEndpoint => double,
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// A simulation with one joined Target, at [MAC]
+fn joined() -> (Sim, NodeId) {
+    let mut sim = Sim::new(SimConfig::default());
+    let node = sim.add_target(MAC);
+    sim.assert_all_joined_by(ms(500));
+    (sim, node)
+}
+
+/// A simulation with one joined Target, answering requests with [dispatch]
+fn dispatching() -> Sim {
+    let (mut sim, node) = joined();
+    sim.set_handler(node, |frame| async move { dispatch(&mut (), frame).await });
+    sim
+}
+
+fn pool() -> RawFrameSlice {
+    let storage: &'static FrameStorage<4> = Box::leak(Box::new(FrameStorage::new()));
+    storage.take().unwrap()
+}
+
+/// The result of a request, once the simulation has run
+type Outcome = Rc<RefCell<Option<Result<u32, RequestError>>>>;
+
+/// Spawn a task making a request for `req` to [MAC]
+fn spawn_request(sim: &mut Sim, req: u32) -> Outcome {
+    spawn_request_to::<DoubleEndpoint>(sim, req)
+}
+
+/// Spawn a task making a request to [MAC], for the Endpoint `E`
+fn spawn_request_to<E>(sim: &mut Sim, req: u32) -> Outcome
+where
+    E: Endpoint<Request = u32, Response = u32> + 'static,
+{
+    let out = Outcome::default();
+    let task_out = out.clone();
+    let ctl = sim.controller();
+    sim.spawn(async move {
+        let resp = ctl.request::<E>(MAC, &req, TIMEOUT).await;
+        *task_out.borrow_mut() = Some(resp);
+    });
+    out
+}
+
+#[test]
+fn request_gets_its_response() {
+    let mut sim = dispatching();
+    let out = spawn_request(&mut sim, 21);
+    sim.run_for(ms(10));
+    assert_eq!(out.borrow_mut().take().unwrap().unwrap(), 42);
+}
+
+#[test]
+fn error_reply_is_returned() {
+    let mut sim = dispatching();
+    let out = spawn_request_to::<UnknownEndpoint>(&mut sim, 21);
+    sim.run_for(ms(10));
+    match out.borrow_mut().take().unwrap() {
+        Err(RequestError::Remote(WireError::UnknownKey(key))) => {
+            assert_eq!(key, UnknownEndpoint::REQ_KEY.to_bytes())
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+    // The error frame was taken by the request
+    let left = sim.controller().recv_from(MAC).now_or_never().unwrap();
+    assert!(matches!(left, Err(RecvError::NoMessage)));
+}
+
+#[test]
+fn concurrent_requests_to_one_target() {
+    let mut sim = dispatching();
+    let outs: Vec<Outcome> = (1..=3).map(|i| spawn_request(&mut sim, i)).collect();
+    sim.run_for(ms(10));
+    for (i, out) in (1..=3).zip(outs) {
+        assert_eq!(out.borrow_mut().take().unwrap().unwrap(), i * 2);
+    }
+}
+
+#[test]
+fn mismatched_seq_no_is_left_for_recv_from() {
+    // Answer every request with the wrong sequence number
+    let (mut sim, node) = joined();
+    sim.set_handler(node, |frame: FrameBox| async move {
+        let seq_no = WhBody::try_from(&frame)?.wh.seq_no;
+        reply_endpoint::<DoubleEndpoint>(frame, seq_no.wrapping_add(1), &0)
+    });
+    let out = spawn_request(&mut sim, 21);
+    sim.run_for(ms(50));
+    assert!(matches!(
+        out.borrow_mut().take().unwrap(),
+        Err(RequestError::Timeout)
+    ));
+
+    // `recv_from` never waits
+    let frame = sim.controller().recv_from(MAC).now_or_never().unwrap();
+    let frame = frame.unwrap().into_inner();
+    let body = WhBody::try_from(&frame).unwrap();
+    assert_eq!(body.wh.key, DoubleEndpoint::RESP_KEY);
+    assert_eq!(body.wh.seq_no, 1);
+}
+
+#[test]
+fn request_times_out_without_response() {
+    let (mut sim, _) = joined();
+    let out = spawn_request(&mut sim, 21);
+    let start = Instant::now();
+    let done = sim.run_until_cond(sim.now() + ms(100), |_| out.borrow().is_some());
+    assert!(done);
+    assert!(Instant::now().as_ticks() - start.as_ticks() >= TIMEOUT.as_ticks());
+    assert!(matches!(
+        out.borrow_mut().take().unwrap(),
+        Err(RequestError::Timeout)
+    ));
+    // The Target is still connected
+    assert_eq!(sim.connected(), [MAC]);
+}
+
+#[test]
+fn late_response_is_discarded() {
+    // Answer every request, but only after it has timed out
+    let (mut sim, node) = joined();
+    sim.set_handler(node, |frame| async move {
+        Timer::after(TIMEOUT + ms(5)).await;
+        dispatch(&mut (), frame).await
+    });
+    let out = spawn_request(&mut sim, 21);
+    let done = sim.run_until_cond(sim.now() + ms(100), |_| out.borrow().is_some());
+    assert!(done);
+    assert!(matches!(
+        out.borrow_mut().take().unwrap(),
+        Err(RequestError::Timeout)
+    ));
+
+    // The response arrives, but is never handed out
+    sim.run_for(ms(20));
+    let responses = sim
+        .bus_log()
+        .iter()
+        .filter(|f| f.data.len() > 1 && f.data[0] == CmdAddr::ReplyFromAddr(0).into())
+        .count();
+    assert_eq!(responses, 1);
+    let left = sim.controller().recv_from(MAC).now_or_never().unwrap();
+    assert!(matches!(left, Err(RecvError::NoMessage)));
+
+    // Later requests are unaffected
+    sim.set_handler(node, |frame| async move { dispatch(&mut (), frame).await });
+    let out = spawn_request(&mut sim, 4);
+    sim.run_for(ms(10));
+    assert_eq!(out.borrow_mut().take().unwrap().unwrap(), 8);
+    assert_eq!(sim.connected(), [MAC]);
+}
+
+/// Answer every frame with the next [CountTopic] message, starting from zero
+fn counting(sim: &mut Sim, node: NodeId) {
+    let mut count = 0;
+    sim.set_handler(node, move |mut frame: FrameBox| {
+        let n = count;
+        count += 1;
+        async move {
+            frame.set_len(DEFAULT_FRAME_SIZE);
+            send_topic::<CountTopic>(frame, n, &n)
+        }
+    });
+}
+
+/// Spawn a task sending `n` frames to each of `macs`, one every millisecond
+fn spawn_pokes(sim: &mut Sim, macs: Vec<Mac>, n: usize) {
+    let ctl = sim.controller();
+    sim.spawn(async move {
+        let mut pool = pool();
+        for _ in 0..n {
+            for mac in macs.iter() {
+                let mut sfb = SendFrameBox::from(pool.allocate_raw().unwrap());
+                sfb.reserve(0);
+                sfb.put_trailer(1).unwrap()[0] = 0;
+                ctl.send(*mac, sfb).await.unwrap();
+            }
+            Timer::after(ms(1)).await;
+        }
+    });
+}
+
+#[test]
+fn unread_subscription_keeps_the_newest_messages() {
+    let (mut sim, node) = joined();
+    counting(&mut sim, node);
+    let mut sub = sim.controller().subscribe::<CountTopic>().unwrap();
+
+    // Nobody reads the subscription, which holds all the Target's incoming frames
+    spawn_pokes(&mut sim, vec![MAC], 10);
+    sim.run_for(ms(50));
+
+    // One incoming frame is always kept free, to receive into
+    assert_eq!(sim.connected(), [MAC]);
+    assert_eq!(sub.dropped(), 7);
+    let got: Vec<u32> = std::iter::from_fn(|| sub.try_recv())
+        .map(|(_, n)| n)
+        .collect();
+    assert_eq!(got, [7, 8, 9]);
+}
+
+#[test]
+fn full_subscription_drops_the_oldest_message() {
+    const OTHER: Mac = Mac::new(0x1111_2222_3333_4444);
+    let mut sim = Sim::new(SimConfig::default());
+    for mac in [MAC, OTHER] {
+        let node = sim.add_target(mac);
+        counting(&mut sim, node);
+    }
+    sim.assert_all_joined_by(ms(500));
+    let mut sub = sim.controller().subscribe::<CountTopic>().unwrap();
+
+    spawn_pokes(&mut sim, vec![MAC, OTHER], 5);
+    sim.run_for(ms(50));
+
+    let mut connected = sim.connected();
+    connected.sort();
+    assert_eq!(connected, [MAC, OTHER]);
+    assert_eq!(sub.dropped(), 6);
+    let got: Vec<(Mac, u32)> = std::iter::from_fn(|| sub.try_recv()).collect();
+    assert_eq!(got, [(MAC, 3), (OTHER, 3), (MAC, 4), (OTHER, 4)]);
+
+    // Once read, there is room again
+    spawn_pokes(&mut sim, vec![OTHER], 1);
+    sim.run_for(ms(10));
+    assert_eq!(sub.try_recv(), Some((OTHER, 5)));
+    assert_eq!(sub.dropped(), 6);
+}
+
+#[test]
+fn messages_from_a_dropped_target_are_discarded() {
+    let (mut sim, node) = joined();
+    counting(&mut sim, node);
+    let mut sub = sim.controller().subscribe::<CountTopic>().unwrap();
+
+    // The unread messages hold three of the Target's incoming frames
+    spawn_pokes(&mut sim, vec![MAC], 3);
+    sim.run_for(ms(10));
+    sim.power_off(node);
+    sim.run_for(ms(10));
+    assert!(sim.connected().is_empty());
+    assert_eq!(sub.dropped(), 3);
+    assert_eq!(sub.try_recv(), None);
+
+    // With its frames free again, the same address is offered to the Target
+    sim.power_on(node);
+    sim.assert_all_joined_by(sim.now() + ms(500));
+    let joins = sim
+        .bus_log()
+        .iter()
+        .filter(|f| {
+            f.data.first().map(|b| CmdAddr::try_from(*b)) == Some(Ok(CmdAddr::DiscoverySuccess(0)))
+        })
+        .count();
+    assert_eq!(joins, 2);
+}
@@ -0,0 +1,346 @@
+//! Controller and Target scenarios, run in virtual time
+
+use std::{cell::RefCell, rc::Rc};
+
+use embassy_time::{Duration, Instant, Timer};
+use erdnuss_comms::{
+    decode::{Decoder, EventKind, Violation},
+    fault::{Chance, FaultPolicy},
+    frame_pool::{FrameStorage, SendFrameBox},
+    sniffer::PeerTable,
+    Mac,
+};
+use erdnuss_sim::{BusFrame, NodeId, Sim, SimConfig, SimMutex};
+
+const MACS: [Mac; 4] = [
+    Mac::new(0x0123_4567_89AB_CDEF),
+    Mac::new(0x1111_2222_3333_4444),
+    Mac::new(0xDEAD_BEEF_CAFE_F00D),
+    Mac::new(0x0000_0000_0000_0001),
+];
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// The times at which each MAC completed a discovery handshake
+fn joins(log: &[BusFrame]) -> Vec<(Instant, Mac)> {
+    let mut decoder = Decoder::new();
+    log.iter()
+        .filter(|f| !f.collided)
+        .filter_map(|f| match decoder.feed(f.at, &f.data).kind {
+            EventKind::Joined { mac, .. } => Some((f.at, mac)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn all_targets_join() {
+    let mut sim = Sim::new(SimConfig::default());
+    for mac in MACS {
+        sim.add_target(mac);
+    }
+    sim.assert_all_joined_by(ms(500));
+
+    let mut connected = sim.connected();
+    connected.sort();
+    let mut expected = MACS.to_vec();
+    expected.sort();
+    assert_eq!(connected, expected);
+}
+
+#[test]
+fn macs_round_trip_through_discovery() {
+    let macs: Vec<Mac> = ["01:23:45:67:89:ab:cd:ef", "de:ad:be:ef:ca:fe:f0:0d"]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+    let mut sim = Sim::new(SimConfig::default());
+    for mac in &macs {
+        sim.add_target(*mac);
+    }
+    sim.assert_all_joined_by(ms(500));
+
+    let mut connected = sim.connected();
+    connected.sort();
+    assert_eq!(connected, macs);
+
+    // The Controller recovers each MAC from its claim, and confirms it
+    let mut decoder = Decoder::new();
+    let (mut claimed, mut confirmed) = (vec![], vec![]);
+    for f in sim.bus_log().iter().filter(|f| !f.collided) {
+        match decoder.feed(f.at, &f.data).kind {
+            EventKind::Claim { mac: Some(mac), .. } => claimed.push(mac),
+            EventKind::Joined { mac, .. } => confirmed.push(mac.to_string()),
+            _ => {}
+        }
+    }
+    assert!(macs.iter().all(|mac| claimed.contains(mac)));
+    confirmed.sort();
+    assert_eq!(
+        confirmed,
+        ["01:23:45:67:89:ab:cd:ef", "de:ad:be:ef:ca:fe:f0:0d"]
+    );
+}
+
+#[test]
+fn same_seed_same_bus() {
+    let run = |seed| {
+        let mut sim = Sim::new(SimConfig {
+            seed,
+            ..SimConfig::default()
+        });
+        // Time only advances while the simulation runs
+        let start = Instant::now().as_ticks();
+        for mac in MACS {
+            sim.add_target(mac);
+        }
+        sim.run_until(ms(100));
+        sim.bus_log()
+            .into_iter()
+            .map(|f| (f.at.as_ticks() - start, f.from, f.data, f.collided))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+#[test]
+fn power_cycled_target_rejoins() {
+    let mut sim = Sim::new(SimConfig::default());
+    let nodes: Vec<NodeId> = MACS.iter().map(|mac| sim.add_target(*mac)).collect();
+    sim.assert_all_joined_by(ms(500));
+
+    let cycled_at = Instant::now();
+    sim.power_cycle(nodes[2], ms(10));
+    sim.assert_all_joined_by(sim.now() + ms(500));
+
+    let rejoins: Vec<_> = joins(&sim.bus_log())
+        .into_iter()
+        .filter(|(at, _)| *at > cycled_at)
+        .collect();
+    assert_eq!(rejoins.len(), 1);
+    assert_eq!(rejoins[0].1, MACS[2]);
+}
+
+#[test]
+fn silent_target_is_culled() {
+    let mut sim = Sim::new(SimConfig::default());
+    let nodes: Vec<NodeId> = MACS.iter().map(|mac| sim.add_target(*mac)).collect();
+    sim.assert_all_joined_by(ms(500));
+
+    // Four missed selects, each costing a REPLY_TIMEOUT, plus the steps between
+    sim.power_off(nodes[0]);
+    sim.run_for(ms(10));
+    assert!(!sim.connected().contains(&MACS[0]));
+    assert_eq!(sim.connected().len(), MACS.len() - 1);
+}
+
+#[test]
+fn sniffer_tracks_the_controllers_peers() {
+    let table: &'static PeerTable<SimMutex> = Box::leak(Box::new(PeerTable::new()));
+    let mut sim = Sim::new(SimConfig::default());
+    sim.add_sniffer(table);
+    // A Target missing a third of its selects collects strikes, and is
+    // sometimes culled after four in a row
+    let flaky = FaultPolicy {
+        drop_rx: Chance::percent(33),
+        ..FaultPolicy::default()
+    };
+    let nodes: Vec<NodeId> = MACS
+        .iter()
+        .enumerate()
+        .map(|(i, mac)| match i {
+            0 => sim.add_target_with_faults(*mac, flaky.clone()),
+            _ => sim.add_target(*mac),
+        })
+        .collect();
+
+    // The Sniffer only hears of a missed select at the Controller's next frame
+    let lag = ms(2);
+    let mut since: Option<Duration> = None;
+    let mut check = |sim: &mut Sim, dur: Duration| {
+        let end = sim.now() + dur;
+        sim.run_until_cond(end, |sim| {
+            let mut connected = sim.connected();
+            connected.sort();
+            let mut sniffed = table.connected().to_vec();
+            sniffed.sort();
+            if connected == sniffed {
+                since = None;
+            } else {
+                let start = *since.get_or_insert(sim.now());
+                assert!(
+                    sim.now() - start <= lag,
+                    "Controller: {connected:?}, Sniffer: {sniffed:?}"
+                );
+            }
+            false
+        });
+    };
+
+    // Joins, strikes, and culls of the flaky Target
+    check(&mut sim, ms(500));
+    assert!(MACS[1..].iter().all(|mac| table.connected().contains(mac)));
+
+    // A silent Target is culled by both
+    sim.power_off(nodes[1]);
+    check(&mut sim, ms(20));
+    assert!(!table.connected().contains(&MACS[1]));
+
+    // And rejoins both
+    sim.power_on(nodes[1]);
+    check(&mut sim, ms(500));
+    assert!(table.connected().contains(&MACS[1]));
+
+    let mut decoder = Decoder::new();
+    let (mut strikes, mut joins) = (0, 0);
+    for f in sim.bus_log().iter().filter(|f| !f.collided) {
+        let ev = decoder.feed(f.at, &f.data);
+        strikes += ev
+            .violations
+            .iter()
+            .filter(|v| matches!(v, Violation::NoResponse { .. }))
+            .count();
+        if matches!(ev.kind, EventKind::Joined { mac, .. } if mac == MACS[0]) {
+            joins += 1;
+        }
+    }
+    // Most strikes didn't lead to a cull
+    assert!(strikes > 4 * joins, "{strikes} strikes, {joins} joins");
+}
+
+#[test]
+fn targets_rejoin_rebooted_controller() {
+    let mut sim = Sim::new(SimConfig::default());
+    for mac in MACS {
+        sim.add_target(mac);
+    }
+    sim.assert_all_joined_by(ms(500));
+
+    // Targets only look for a new address after their SELECT_TIMEOUT
+    sim.power_cycle(NodeId::CONTROLLER, ms(1));
+    assert!(sim.connected().is_empty());
+    sim.assert_all_joined_by(sim.now() + ms(500));
+}
+
+#[test]
+fn noisy_bus_recovers() {
+    let noise = FaultPolicy {
+        drop_rx: Chance::percent(1),
+        flip_bit: Chance::percent(1),
+        truncate: Chance::percent(1),
+        spurious_break: Chance::percent(1),
+        delay_rx: Chance::percent(1),
+        serial_error: Chance::percent(1),
+        ..FaultPolicy::default()
+    };
+    let mut sim = Sim::new(SimConfig::default());
+    sim.set_faults(NodeId::CONTROLLER, noise.clone());
+    sim.power_on(NodeId::CONTROLLER);
+    for mac in MACS {
+        sim.add_target_with_faults(mac, noise.clone());
+    }
+    sim.assert_all_joined_by(ms(1000));
+    sim.run_for(ms(1000));
+
+    // Faults cost Targets strikes, and sometimes their address, but they
+    // always find their way back
+    let mut decoder = Decoder::new();
+    let strikes = sim
+        .bus_log()
+        .iter()
+        .filter(|f| !f.collided)
+        .flat_map(|f| decoder.feed(f.at, &f.data).violations)
+        .filter(|v| matches!(v, Violation::NoResponse { .. }))
+        .count();
+    assert!(strikes > 0);
+    sim.assert_all_joined_by(sim.now() + ms(1000));
+}
+
+#[test]
+fn addr_matching_targets_sleep_through_other_frames() {
+    let mut sim = Sim::new(SimConfig {
+        addr_match: true,
+        ..SimConfig::default()
+    });
+    let nodes: Vec<NodeId> = MACS.iter().map(|mac| sim.add_target(*mac)).collect();
+    sim.assert_all_joined_by(ms(500));
+    let muted: Vec<u32> = nodes.iter().map(|n| sim.muted(*n)).collect();
+
+    // Once joined, a Target only wakes up for its own selects, and stays joined
+    sim.run_for(ms(100));
+    assert_eq!(sim.connected().len(), MACS.len());
+    for (node, before) in nodes.iter().zip(muted) {
+        assert!(sim.muted(*node) > before);
+    }
+}
+
+#[test]
+fn without_addr_matching_nothing_is_muted() {
+    let mut sim = Sim::new(SimConfig::default());
+    let nodes: Vec<NodeId> = MACS.iter().map(|mac| sim.add_target(*mac)).collect();
+    sim.assert_all_joined_by(ms(500));
+    sim.run_for(ms(100));
+    assert!(nodes.iter().all(|n| sim.muted(*n) == 0));
+}
+
+#[test]
+fn send_and_recv_from_do_not_wait_for_a_step() {
+    let mut sim = Sim::new(SimConfig::default());
+    for mac in MACS {
+        sim.add_target(mac);
+    }
+    sim.assert_all_joined_by(ms(500));
+
+    // Call `send` and `recv_from` at an interval unrelated to the steps, so
+    // that most calls land in the middle of one
+    let calls: Rc<RefCell<Vec<(Instant, Instant)>>> = Rc::default();
+    let ctl = sim.controller();
+    let app_calls = calls.clone();
+    sim.spawn(async move {
+        let storage: &'static FrameStorage<8> = Box::leak(Box::new(FrameStorage::new()));
+        let mut pool = storage.take().unwrap();
+        for i in 0..2000 {
+            let mac = MACS[i % MACS.len()];
+            let start = Instant::now();
+            if let Some(fb) = pool.allocate_raw() {
+                let mut sfb = SendFrameBox::from(fb);
+                sfb.reserve(0);
+                sfb.put_trailer(1).unwrap()[0] = i as u8;
+                // A full queue is fine, only the time taken matters
+                let _ = ctl.send(mac, sfb).await;
+            }
+            let _ = ctl.recv_from(mac).await;
+            app_calls.borrow_mut().push((start, Instant::now()));
+            Timer::after(Duration::from_micros(13)).await;
+        }
+    });
+    sim.run_for(ms(100));
+
+    let steps = sim.steps();
+    let calls = calls.borrow();
+    assert_eq!(calls.len(), 2000);
+
+    // Previously, the Controller was locked for a whole step, so a call
+    // during a step waited for the rest of it
+    let mut during = 0;
+    let (mut waited, mut baseline) = (0, 0);
+    for (start, end) in calls.iter() {
+        waited += end.as_ticks() - start.as_ticks();
+        if let Some((_, step_end)) = steps.iter().find(|(s, e)| s < start && start < e) {
+            during += 1;
+            baseline += step_end.as_ticks() - start.as_ticks();
+        }
+    }
+    println!(
+        "{during} of {} calls during a step: waited {}us in total, whole-step lock: {}us",
+        calls.len(),
+        Duration::from_ticks(waited).as_micros(),
+        Duration::from_ticks(baseline).as_micros(),
+    );
+    assert!(during > calls.len() / 4);
+    assert!(baseline > 0);
+    assert_eq!(waited, 0);
+}
@@ -0,0 +1,483 @@
+//! The Controller and Target helpers, one frame at a time
+//!
+//! Every test runs twice, against the async and the blocking interfaces.
+
+use std::{future::Future, ops::Deref, pin::pin, task::Context};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{with_timeout, Duration, Instant};
+use erdnuss_comms::{
+    addr_mark::{AddrFilter, AddrMatch},
+    blocking::{BlockingFrameSerial, BlockingTarget, BlockingTgtCfg},
+    clock::EmbassyClock,
+    controller::StepError,
+    frame_pool::{FrameBox, FrameStorage},
+    target::{Target, TgtCfg, INCOMING_SIZE, OUTGOING_SIZE},
+    CmdAddr, Controller, FrameSerial, Mac,
+};
+use erdnuss_sim::{
+    block_on,
+    script::{Pattern, Script},
+    ScriptSerial, SimRng,
+};
+use futures::task::noop_waker_ref;
+use rand_core::RngCore;
+
+const MAC: Mac = Mac::new(0x0123_4567_89AB_CDEF);
+
+type Ctl = Controller<CriticalSectionRawMutex>;
+
+/// Which interface of the Controller or Target to test
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Async,
+    Blocking,
+}
+
+/// Generate a test for each [Mode], calling the function of the same name
+macro_rules! both_modes {
+    ($($(#[$attr:meta])* $name:ident,)*) => {
+        mod async_mode {
+            $(
+                #[test]
+                $(#[$attr])*
+                fn $name() {
+                    super::$name(super::Mode::Async)
+                }
+            )*
+        }
+
+        mod blocking_mode {
+            $(
+                #[test]
+                $(#[$attr])*
+                fn $name() {
+                    super::$name(super::Mode::Blocking)
+                }
+            )*
+        }
+    };
+}
+
+both_modes! {
+    offer_unclaimed,
+    #[should_panic(expected = "step 1 of 2: unexpected frame sent\n  expected: 81 __")]
+    unexpected_frame_fails,
+    offer_bad_claim,
+    discovery,
+    pending_without_ack_is_dropped,
+    serve_peers_passes_on_replies,
+    serve_peers_culls_after_four_strikes,
+    serve_peers_resets_strikes,
+    target_joins_and_replies,
+    target_ignores_success_for_others,
+    target_retries_after_claim_timeout,
+    target_rejoins_after_select_timeout,
+    addr_match_mutes_other_frames,
+}
+
+/// A Controller, stepped through the interface chosen by [Mode]
+#[derive(Clone, Copy)]
+struct Stepper {
+    ctl: &'static Ctl,
+    mode: Mode,
+}
+
+impl Deref for Stepper {
+    type Target = Ctl;
+
+    fn deref(&self) -> &Ctl {
+        self.ctl
+    }
+}
+
+fn new_controller(mode: Mode) -> Stepper {
+    let ctl: &'static Ctl = Box::leak(Box::new(Controller::uninit()));
+    let storage: &'static FrameStorage<124> = Box::leak(Box::new(FrameStorage::new()));
+    block_on(ctl.init(&mut storage.take().unwrap()));
+    Stepper { ctl, mode }
+}
+
+fn offer(addr: u8) -> Pattern {
+    Pattern::new()
+        .byte(CmdAddr::DiscoveryOffer(addr).into())
+        .any(8)
+}
+
+fn success(addr: u8) -> Vec<u8> {
+    let mut f = vec![CmdAddr::DiscoverySuccess(addr).into()];
+    f.extend_from_slice(&MAC.to_wire_bytes());
+    f
+}
+
+fn select(addr: u8) -> [u8; 1] {
+    [CmdAddr::SelectAddr(addr).into()]
+}
+
+fn reply(addr: u8) -> [u8; 1] {
+    [CmdAddr::ReplyFromAddr(addr).into()]
+}
+
+/// A claim of the offer that was just sent, by [MAC]
+fn claim(offer: &[u8]) -> Vec<u8> {
+    let CmdAddr::DiscoveryOffer(addr) = CmdAddr::try_from(offer[0]).unwrap() else {
+        panic!("not an offer");
+    };
+    let mut f = vec![CmdAddr::DiscoveryClaim(addr).into()];
+    f.extend(
+        offer[1..9]
+            .iter()
+            .zip(MAC.to_wire_bytes())
+            .map(|(a, b)| a ^ b),
+    );
+    f
+}
+
+/// Step the Controller once through `script`, checking it was followed exactly
+fn step(ctl: Stepper, script: Script) {
+    let mut serial = ScriptSerial::new(script);
+    let handle = serial.handle();
+    let mut rng = SimRng::new(0);
+    match ctl.mode {
+        Mode::Async => block_on(ctl.step(&mut serial, &mut rng)).unwrap(),
+        // Blocking receives advance virtual time, which block_on holds for us
+        Mode::Blocking => block_on(async { ctl.step_blocking(&mut serial, &mut rng) }).unwrap(),
+    }
+    handle.assert_done();
+}
+
+/// Take the Controller through discovery of [MAC] at address 0
+fn join(ctl: Stepper) {
+    step(ctl, Script::new().expect(offer(0)).reply_with(claim));
+    step(
+        ctl,
+        Script::new()
+            .expect(success(0).as_slice())
+            .reply(&reply(0))
+            .expect(offer(1))
+            .timeout(),
+    );
+    assert_eq!(connected(ctl), [MAC]);
+}
+
+fn connected(ctl: Stepper) -> Vec<Mac> {
+    block_on(ctl.connected()).into_iter().collect()
+}
+
+fn offer_unclaimed(mode: Mode) {
+    let ctl = new_controller(mode);
+    step(ctl, Script::new().expect(offer(0)).timeout());
+    step(ctl, Script::new().expect(offer(0)).timeout());
+    assert!(connected(ctl).is_empty());
+}
+
+fn unexpected_frame_fails(mode: Mode) {
+    let ctl = new_controller(mode);
+    step(ctl, Script::new().expect(offer(1)).timeout());
+}
+
+fn offer_bad_claim(mode: Mode) {
+    let ctl = new_controller(mode);
+    // A claim for the wrong address is ignored, and the address offered again
+    step(
+        ctl,
+        Script::new().expect(offer(0)).reply_with(|o| {
+            let mut c = claim(o);
+            c[0] = CmdAddr::DiscoveryClaim(1).into();
+            c
+        }),
+    );
+    step(ctl, Script::new().expect(offer(0)).timeout());
+}
+
+fn discovery(mode: Mode) {
+    let ctl = new_controller(mode);
+    join(ctl);
+}
+
+fn pending_without_ack_is_dropped(mode: Mode) {
+    let ctl = new_controller(mode);
+    step(ctl, Script::new().expect(offer(0)).reply_with(claim));
+    // No ack, one strike is enough to lose the address
+    step(
+        ctl,
+        Script::new()
+            .expect(success(0).as_slice())
+            .timeout()
+            .expect(offer(0))
+            .timeout(),
+    );
+    assert!(connected(ctl).is_empty());
+}
+
+fn serve_peers_passes_on_replies(mode: Mode) {
+    let ctl = new_controller(mode);
+    join(ctl);
+    step(
+        ctl,
+        Script::new()
+            .expect(select(0))
+            .reply(&[reply(0)[0], 1, 2, 3])
+            .expect(offer(1))
+            .timeout(),
+    );
+    let frame = block_on(ctl.recv_from(MAC)).unwrap();
+    assert_eq!(frame.cmd_addr(), CmdAddr::ReplyFromAddr(0));
+    assert_eq!(frame.payload(), &[1, 2, 3]);
+}
+
+fn serve_peers_culls_after_four_strikes(mode: Mode) {
+    let ctl = new_controller(mode);
+    join(ctl);
+    for _ in 0..3 {
+        step(
+            ctl,
+            Script::new()
+                .expect(select(0))
+                .timeout()
+                .expect(offer(1))
+                .timeout(),
+        );
+        assert_eq!(connected(ctl), [MAC]);
+    }
+    // A reply from the wrong address is also a strike
+    step(
+        ctl,
+        Script::new()
+            .expect(select(0))
+            .reply(&reply(1))
+            .expect(offer(0))
+            .timeout(),
+    );
+    assert!(connected(ctl).is_empty());
+}
+
+fn serve_peers_resets_strikes(mode: Mode) {
+    let ctl = new_controller(mode);
+    join(ctl);
+    for i in 0..8 {
+        let script = Script::new().expect(select(0));
+        let script = if i % 2 == 0 {
+            script.timeout()
+        } else {
+            script.reply(&reply(0))
+        };
+        step(ctl, script.expect(offer(1)).timeout());
+    }
+    assert_eq!(connected(ctl), [MAC]);
+}
+
+/// A random number generator that makes the Target claim every offer
+struct Eager;
+
+impl RngCore for Eager {
+    fn next_u32(&mut self) -> u32 {
+        0
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.fill(0);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        dest.fill(0);
+        Ok(())
+    }
+}
+
+struct Cfg;
+
+impl TgtCfg for Cfg {
+    type Mutex = CriticalSectionRawMutex;
+    type Serial = ScriptSerial;
+    type Clock = EmbassyClock;
+    type Rand = Eager;
+
+    const TURNAROUND_DELAY: Duration = Duration::from_micros(10);
+    const ADDRESS_CLAIM_TIMEOUT: Duration = Duration::from_millis(5);
+    const SELECT_TIMEOUT: Duration = Duration::from_millis(50);
+}
+
+impl BlockingTgtCfg for Cfg {
+    type Mutex = CriticalSectionRawMutex;
+    type Serial = ScriptSerial;
+    type Clock = EmbassyClock;
+    type Rand = Eager;
+
+    const TURNAROUND_DELAY: Duration = <Cfg as TgtCfg>::TURNAROUND_DELAY;
+    const ADDRESS_CLAIM_TIMEOUT: Duration = <Cfg as TgtCfg>::ADDRESS_CLAIM_TIMEOUT;
+    const SELECT_TIMEOUT: Duration = <Cfg as TgtCfg>::SELECT_TIMEOUT;
+}
+
+const CHALLENGE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+fn offer_frame(addr: u8) -> Vec<u8> {
+    let mut f = vec![CmdAddr::DiscoveryOffer(addr).into()];
+    f.extend_from_slice(&CHALLENGE);
+    f
+}
+
+fn claim_frame(addr: u8) -> Vec<u8> {
+    claim(&offer_frame(addr))
+}
+
+/// Run a Target through `script` for `dur`, returning the frames it passed
+/// to the application
+fn run_target(mode: Mode, script: Script, dur: Duration) -> Vec<Vec<u8>> {
+    let to_app = Channel::<CriticalSectionRawMutex, FrameBox, INCOMING_SIZE>::new();
+    let from_app = Channel::<CriticalSectionRawMutex, FrameBox, OUTGOING_SIZE>::new();
+
+    let serial = ScriptSerial::new(script);
+    let handle = serial.handle();
+    let storage: &'static FrameStorage<8> = Box::leak(Box::new(FrameStorage::new()));
+    let pool = storage.take().unwrap();
+    match mode {
+        Mode::Async => {
+            let mut target = Target::<Cfg>::new(
+                serial,
+                to_app.sender(),
+                from_app.receiver(),
+                pool,
+                MAC,
+                Eager,
+            );
+            assert!(block_on(with_timeout(dur, target.run())).is_err());
+        }
+        Mode::Blocking => {
+            let mut target = BlockingTarget::<Cfg>::new(
+                serial,
+                to_app.sender(),
+                from_app.receiver(),
+                pool,
+                MAC,
+                Eager,
+            );
+            block_on(async {
+                let end = Instant::now() + dur;
+                while Instant::now() < end {
+                    let left = end.saturating_duration_since(Instant::now());
+                    target.poll(left).unwrap();
+                }
+            });
+        }
+    }
+    handle.assert_done();
+
+    let mut got = Vec::new();
+    while let Ok(f) = to_app.try_receive() {
+        got.push(f.to_vec());
+    }
+    got
+}
+
+fn join_script(addr: u8) -> Script {
+    Script::new()
+        .reply(&offer_frame(addr))
+        .expect(claim_frame(addr).as_slice())
+        .reply(&success(addr))
+        .expect(reply(addr))
+}
+
+fn target_joins_and_replies(mode: Mode) {
+    let script = join_script(3)
+        // Selects of other Targets are ignored
+        .reply(&select(4))
+        .reply(&select(3))
+        .expect(reply(3))
+        .reply(&[select(3)[0], 9, 9])
+        .expect(reply(3));
+    let got = run_target(mode, script, Duration::from_millis(10));
+    assert_eq!(got, [vec![select(3)[0], 9, 9]]);
+}
+
+fn target_ignores_success_for_others(mode: Mode) {
+    let mut other = success(3);
+    other[1] ^= 0xFF;
+    let script = Script::new()
+        .reply(&offer_frame(3))
+        .expect(claim_frame(3).as_slice())
+        .reply(&other)
+        .timeout();
+    run_target(mode, script, Duration::from_millis(1));
+}
+
+fn target_retries_after_claim_timeout(mode: Mode) {
+    let script = Script::new()
+        .reply(&offer_frame(3))
+        .expect(claim_frame(3).as_slice())
+        .timeout();
+    // After ADDRESS_CLAIM_TIMEOUT, the Target waits for the next offer
+    let script = script
+        .reply(&offer_frame(5))
+        .expect(claim_frame(5).as_slice());
+    run_target(mode, script, Duration::from_millis(10));
+}
+
+fn target_rejoins_after_select_timeout(mode: Mode) {
+    let script = join_script(3)
+        .timeout()
+        // After SELECT_TIMEOUT, the Target looks for a new address
+        .reply(&offer_frame(7))
+        .expect(claim_frame(7).as_slice());
+    run_target(mode, script, Duration::from_millis(100));
+}
+
+fn addr_match_mutes_other_frames(mode: Mode) {
+    let script = Script::new()
+        .reply(&select(4))
+        .reply(&offer_frame(3))
+        .reply(&[select(3)[0], 9, 9]);
+    let mut serial = AddrMatch::new(ScriptSerial::new(script));
+    let mut buf = [0u8; 16];
+    let got = match mode {
+        Mode::Async => block_on(async {
+            FrameSerial::set_addr_filter(&mut serial, AddrFilter::Joined(3));
+            let tf = FrameSerial::recv(&mut serial, &mut buf).await.unwrap();
+            tf.frame.to_vec()
+        }),
+        Mode::Blocking => block_on(async {
+            BlockingFrameSerial::set_addr_filter(&mut serial, AddrFilter::Joined(3));
+            loop {
+                let timeout = Duration::from_millis(1);
+                if let Some(tf) = serial.recv_timeout(&mut buf, timeout).unwrap() {
+                    break tf.frame.to_vec();
+                }
+            }
+        }),
+    };
+    assert_eq!(got, [select(3)[0], 9, 9]);
+    assert_eq!(serial.muted(), 2);
+}
+
+#[test]
+fn blocking_step_is_refused_during_an_async_step() {
+    let ctl = new_controller(Mode::Async);
+    let mut rng = SimRng::new(0);
+
+    // Suspend an async step while it waits for a claim
+    let mut serial = ScriptSerial::new(Script::new().expect(offer(0)).timeout());
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut stepping = pin!(ctl.step(&mut serial, &mut rng));
+    assert!(stepping.as_mut().poll(&mut cx).is_pending());
+
+    // The blocking step returns at once, without touching the bus
+    let mut blocked = ScriptSerial::new(Script::new());
+    let mut other_rng = SimRng::new(1);
+    let res = ctl.step_blocking(&mut blocked, &mut other_rng);
+    assert!(matches!(res, Err(StepError::Busy)));
+    blocked.handle().assert_done();
+
+    // Once the async step completes, blocking steps work again
+    block_on(stepping.as_mut()).unwrap();
+    step(
+        Stepper {
+            ctl: ctl.ctl,
+            mode: Mode::Blocking,
+        },
+        Script::new().expect(offer(0)).timeout(),
+    );
+}